@@ -1,6 +1,12 @@
 use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use agent_fetch::{DomainPattern, FetchPolicy, FetchRequest, SafeClient};
+use agent_fetch::{
+    AddressFamily, CertificatePin, ClientCertificate, DnsCachePolicy, DnsOverTls, DomainPattern,
+    DomainQuota, DomainRateLimit, FetchPolicy, FetchRequest, HttpVersion, ProxyPolicy, ProxyRoute,
+    RedirectMode, RedirectScope, RetryPolicy, RootCertificates, SafeClient, TlsVersion,
+};
+use ipnet::IpNet;
 use napi::bindgen_prelude::*;
 use napi_derive::napi;
 
@@ -8,23 +14,194 @@ use napi_derive::napi;
 pub struct SafeHttpClientOptions {
     pub allowed_domains: Option<Vec<String>>,
     pub blocked_domains: Option<Vec<String>>,
+    pub allowed_tlds: Option<Vec<String>>,
+    pub blocked_tlds: Option<Vec<String>>,
     pub deny_private_ips: Option<bool>,
+    pub blocked_ip_ranges: Option<Vec<String>>,
+    pub allowed_ip_ranges: Option<Vec<String>>,
+    pub allowed_address_families: Option<Vec<String>>,
+    pub dns_cache: Option<DnsCacheOptions>,
+    pub dns_servers: Option<Vec<String>>,
+    /// Host names mapped to the addresses to use for them instead of a lookup.
+    pub dns_overrides: Option<HashMap<String, Vec<String>>>,
+    pub trust_dns_overrides: Option<bool>,
+    pub dns_over_tls: Option<DnsOverTlsOptions>,
+    pub allowed_ports: Option<Vec<u32>>,
+    pub blocked_ports: Option<Vec<u32>>,
+    pub strict_ports: Option<bool>,
+    pub allow_host_header_override: Option<bool>,
+    pub read_only: Option<bool>,
     pub allowed_methods: Option<Vec<String>>,
+    pub allow_extension_methods: Option<bool>,
     pub allowed_schemes: Option<Vec<String>>,
+    pub upgrade_insecure_requests: Option<bool>,
+    pub insecure_fallback_domains: Option<Vec<String>>,
+    pub default_headers: Option<HashMap<String, String>>,
+    pub max_url_bytes: Option<f64>,
     pub max_request_body_bytes: Option<f64>,
+    pub gzip_request_bytes: Option<f64>,
+    pub expect_continue_bytes: Option<f64>,
+    pub expect_continue_timeout_ms: Option<f64>,
     pub max_response_body_bytes: Option<f64>,
+    pub decompress_responses: Option<bool>,
+    pub max_decompressed_body_bytes: Option<f64>,
+    pub allowed_content_types: Option<Vec<String>>,
+    pub preflight_head: Option<bool>,
+    pub max_sse_event_bytes: Option<f64>,
+    pub max_sse_lifetime_ms: Option<f64>,
+    pub sse_revalidate_ms: Option<f64>,
+    pub max_ws_message_bytes: Option<f64>,
+    pub max_ws_lifetime_ms: Option<f64>,
+    pub dns_timeout_ms: Option<f64>,
     pub connect_timeout_ms: Option<f64>,
     pub request_timeout_ms: Option<f64>,
+    pub connection_revalidate_ms: Option<f64>,
+    pub http2: Option<bool>,
+    pub http2_prior_knowledge: Option<bool>,
+    pub http2_max_concurrent_streams: Option<u32>,
+    /// Any of `"http1"`, `"http2"`, `"http3"`.
+    pub allowed_http_versions: Option<Vec<String>>,
+    /// `"1.2"` or `"1.3"`.
+    pub min_tls_version: Option<String>,
+    pub client_certificates: Option<Vec<ClientCertificateOptions>>,
+    pub root_certificates: Option<Vec<RootCertificatesOptions>>,
+    pub use_system_roots: Option<bool>,
+    pub certificate_pins: Option<Vec<CertificatePinOptions>>,
+    pub capture_tls_details: Option<bool>,
+    pub proxy: Option<ProxyOptions>,
+    pub require_proxy: Option<bool>,
     pub max_redirects: Option<u32>,
+    /// `"follow"` or `"manual"`.
+    pub redirect_mode: Option<String>,
+    /// `"any"`, `"same_registrable_domain"`, or `"same_host"`.
+    pub redirect_scope: Option<String>,
+    pub redirect_strip_headers: Option<Vec<String>>,
+    pub block_redirect_downgrade: Option<bool>,
     pub max_concurrent_requests: Option<f64>,
+    pub max_concurrent_requests_per_domain: Option<f64>,
+    pub max_concurrency_wait_ms: Option<f64>,
     pub max_requests_per_minute: Option<u32>,
+    pub max_requests_per_hour: Option<u32>,
+    pub max_requests_per_day: Option<u32>,
+    pub max_requests_per_minute_per_domain: Option<u32>,
+    pub min_request_interval_ms: Option<f64>,
+    pub max_queue_wait_ms: Option<f64>,
+    pub per_domain_limits: Option<Vec<DomainRateLimitOptions>>,
+    pub domain_quotas: Option<Vec<DomainQuotaOptions>>,
+    pub rate_limit_exempt_domains: Option<Vec<String>>,
+    pub respect_server_rate_limits: Option<bool>,
+    pub coalesce_requests: Option<bool>,
+    pub retry: Option<RetryOptions>,
+    pub hedge_after_ms: Option<f64>,
+    pub max_total_bytes: Option<f64>,
+    pub max_total_bytes_per_domain: Option<f64>,
+    pub max_buffered_bytes: Option<f64>,
+}
+
+#[napi(object)]
+pub struct DomainRateLimitOptions {
+    pub pattern: String,
+    pub max_requests_per_minute: Option<u32>,
+    pub max_requests_per_hour: Option<u32>,
+    pub max_requests_per_day: Option<u32>,
+}
+
+/// Set one of `pem_file` and `pkcs12_file`.
+#[napi(object)]
+pub struct ClientCertificateOptions {
+    pub pattern: String,
+    pub pem_file: Option<String>,
+    pub pkcs12_file: Option<String>,
+    pub pkcs12_password: Option<String>,
+}
+
+/// Set one of `file` and `pem`.
+#[napi(object)]
+pub struct RootCertificatesOptions {
+    pub file: Option<String>,
+    pub pem: Option<String>,
+}
+
+/// Base64 SHA-256 hashes of the key or the whole certificate servers matching `pattern`
+/// must present.
+#[napi(object)]
+pub struct CertificatePinOptions {
+    pub pattern: String,
+    pub spki_sha256: Option<Vec<String>>,
+    pub cert_sha256: Option<Vec<String>>,
+}
+
+#[napi(object)]
+pub struct ProxyOptions {
+    /// The proxy's `http`, `https`, `socks5`, or `socks5h` URL.
+    pub url: String,
+    /// Domain patterns reached directly instead of through a proxy.
+    pub no_proxy: Option<Vec<String>>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Other proxies for matching domains; the first match is used.
+    pub routes: Option<Vec<ProxyRouteOptions>>,
+}
+
+#[napi(object)]
+pub struct ProxyRouteOptions {
+    pub pattern: String,
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+/// Unset fields keep their defaults.
+#[napi(object)]
+pub struct DnsCacheOptions {
+    pub max_entries: Option<u32>,
+    pub min_ttl_secs: Option<f64>,
+    pub max_ttl_secs: Option<f64>,
+    pub negative_ttl_secs: Option<f64>,
+}
+
+#[napi(object)]
+pub struct DnsOverTlsOptions {
+    /// Resolver addresses, e.g. `10.0.0.53:853`.
+    pub servers: Vec<String>,
+    /// The name the resolvers' certificates must be valid for, also sent as SNI.
+    pub server_name: String,
+}
+
+/// Unset fields keep their defaults.
+#[napi(object)]
+pub struct RetryOptions {
+    pub max_attempts: Option<u32>,
+    pub initial_backoff_ms: Option<f64>,
+    pub max_backoff_ms: Option<f64>,
+    pub max_retry_after_ms: Option<f64>,
+    pub retry_non_idempotent: Option<bool>,
+}
+
+#[napi(object)]
+pub struct DomainQuotaOptions {
+    pub pattern: String,
+    pub max_requests: f64,
+    /// Defaults to one day.
+    pub window_secs: Option<f64>,
 }
 
 #[napi(object)]
 pub struct FetchOptions {
+    /// Alternative URLs tried in order while earlier ones fail with transient errors.
+    pub mirrors: Option<Vec<String>>,
     pub method: Option<String>,
     pub headers: Option<HashMap<String, String>>,
     pub body: Option<Buffer>,
+    pub profile: Option<String>,
+    /// `"manual"` returns redirects instead of following them.
+    pub redirect: Option<String>,
+    /// Per-request limits; each can only be lower than the policy's.
+    pub request_timeout_ms: Option<f64>,
+    pub connect_timeout_ms: Option<f64>,
+    pub max_response_body_bytes: Option<f64>,
+    /// How much the request counts against the rate limits and quotas (default: 1, at least 1).
+    pub rate_limit_cost: Option<u32>,
 }
 
 #[napi(object)]
@@ -32,6 +209,96 @@ pub struct FetchResult {
     pub status: u32,
     pub headers: HashMap<String, String>,
     pub body: Buffer,
+    /// The URL the response was served from, after redirects.
+    pub url: String,
+    pub redirects: Vec<RedirectHopResult>,
+    /// E.g. `"HTTP/1.1"`.
+    pub http_version: String,
+    pub timings: FetchTimingsResult,
+    /// Milliseconds the response's `Retry-After` header asks to wait, if present.
+    pub retry_after_ms: Option<f64>,
+    /// The server's own rate limit, if the response reports it.
+    pub rate_limit: Option<ServerRateLimitResult>,
+    /// Present under `capture_tls_details` for responses received over TLS.
+    pub tls: Option<TlsDetailsResult>,
+}
+
+/// From the response's `RateLimit-*` or `X-RateLimit-*` headers.
+#[napi(object)]
+pub struct ServerRateLimitResult {
+    pub limit: Option<f64>,
+    pub remaining: Option<f64>,
+    /// Milliseconds until the server's window resets.
+    pub reset_ms: Option<f64>,
+}
+
+/// The TLS session and the server's leaf certificate.
+#[napi(object)]
+pub struct TlsDetailsResult {
+    /// `"1.2"` or `"1.3"`.
+    pub version: Option<String>,
+    pub subject: String,
+    pub issuer: String,
+    pub subject_alt_names: Vec<String>,
+    /// Milliseconds since the Unix epoch.
+    pub not_before_ms: f64,
+    pub not_after_ms: f64,
+    /// Base64 SHA-256 hash of the DER certificate.
+    pub sha256: String,
+    /// Base64 SHA-256 hash of the certificate's key.
+    pub spki_sha256: String,
+}
+
+/// Phase durations in milliseconds.
+#[napi(object)]
+pub struct FetchTimingsResult {
+    pub dns_ms: f64,
+    /// Absent if only pooled connections were used.
+    pub connect_ms: Option<f64>,
+    /// Absent if no TLS handshake was made.
+    pub tls_ms: Option<f64>,
+    pub time_to_first_byte_ms: f64,
+    pub body_ms: f64,
+    pub total_ms: f64,
+}
+
+#[napi(object)]
+pub struct RedirectHopResult {
+    pub url: String,
+    pub status: u32,
+}
+
+fn parse_ip_ranges(ranges: &[String]) -> Result<Vec<IpNet>> {
+    ranges
+        .iter()
+        .map(|r| {
+            r.parse()
+                .map_err(|e| Error::from_reason(format!("invalid IP range {r}: {e}")))
+        })
+        .collect()
+}
+
+fn parse_ports(ports: &[u32]) -> Result<Vec<u16>> {
+    ports
+        .iter()
+        .map(|&p| u16::try_from(p).map_err(|_| Error::from_reason(format!("invalid port {p}"))))
+        .collect()
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn epoch_millis(time: SystemTime) -> f64 {
+    millis(time.duration_since(UNIX_EPOCH).unwrap_or_default())
+}
+
+fn parse_redirect_mode(mode: &str) -> Result<RedirectMode> {
+    match mode {
+        "follow" => Ok(RedirectMode::Follow),
+        "manual" => Ok(RedirectMode::Manual),
+        _ => Err(Error::from_reason(format!("invalid redirect mode {mode}"))),
+    }
 }
 
 #[napi]
@@ -39,73 +306,485 @@ pub struct SafeHttpClient {
     client: SafeClient,
 }
 
+fn policy_from_options(options: Option<SafeHttpClientOptions>) -> Result<FetchPolicy> {
+    let mut policy = FetchPolicy::default();
+
+    if let Some(opts) = options {
+        if let Some(domains) = opts.allowed_domains {
+            policy.allowed_domains = Some(
+                domains
+                    .into_iter()
+                    .map(|d| DomainPattern(d).into())
+                    .collect(),
+            );
+        }
+        if let Some(domains) = opts.blocked_domains {
+            policy.blocked_domains = domains.into_iter().map(DomainPattern).collect();
+        }
+        if let Some(v) = opts.allowed_tlds {
+            policy.allowed_tlds = Some(v);
+        }
+        if let Some(v) = opts.blocked_tlds {
+            policy.blocked_tlds = v;
+        }
+        if let Some(v) = opts.deny_private_ips {
+            policy.deny_private_ips = v;
+        }
+        if let Some(ranges) = opts.blocked_ip_ranges {
+            policy.blocked_ip_ranges = parse_ip_ranges(&ranges)?;
+        }
+        if let Some(ranges) = opts.allowed_ip_ranges {
+            policy.allowed_ip_ranges = parse_ip_ranges(&ranges)?;
+        }
+        if let Some(families) = opts.allowed_address_families {
+            policy.allowed_address_families = families
+                .iter()
+                .map(|f| match f.to_ascii_lowercase().as_str() {
+                    "ipv4" => Ok(AddressFamily::Ipv4),
+                    "ipv6" => Ok(AddressFamily::Ipv6),
+                    _ => Err(Error::from_reason(format!("invalid address family {f}"))),
+                })
+                .collect::<Result<_>>()?;
+        }
+        if let Some(c) = opts.dns_cache {
+            let defaults = DnsCachePolicy::default();
+            policy.dns_cache = DnsCachePolicy {
+                max_entries: c.max_entries.map_or(defaults.max_entries, |v| v as usize),
+                min_ttl_secs: c.min_ttl_secs.map_or(defaults.min_ttl_secs, |v| v as u64),
+                max_ttl_secs: c.max_ttl_secs.map_or(defaults.max_ttl_secs, |v| v as u64),
+                negative_ttl_secs: c
+                    .negative_ttl_secs
+                    .map_or(defaults.negative_ttl_secs, |v| v as u64),
+            };
+        }
+        if let Some(v) = opts.dns_servers {
+            policy.dns_servers = v
+                .iter()
+                .map(|s| {
+                    s.parse()
+                        .map_err(|_| Error::from_reason(format!("invalid DNS server {s}")))
+                })
+                .collect::<Result<_>>()?;
+        }
+        if let Some(v) = opts.dns_overrides {
+            policy.dns_overrides = v
+                .into_iter()
+                .map(|(host, ips)| {
+                    let ips = ips
+                        .iter()
+                        .map(|ip| {
+                            ip.parse()
+                                .map_err(|_| Error::from_reason(format!("invalid address {ip}")))
+                        })
+                        .collect::<Result<_>>()?;
+                    Ok((host, ips))
+                })
+                .collect::<Result<_>>()?;
+        }
+        if let Some(v) = opts.trust_dns_overrides {
+            policy.trust_dns_overrides = v;
+        }
+        if let Some(d) = opts.dns_over_tls {
+            policy.dns_over_tls = Some(DnsOverTls {
+                servers: d
+                    .servers
+                    .iter()
+                    .map(|s| {
+                        s.parse()
+                            .map_err(|_| Error::from_reason(format!("invalid DNS server {s}")))
+                    })
+                    .collect::<Result<_>>()?,
+                server_name: d.server_name,
+            });
+        }
+        if let Some(v) = opts.allowed_ports {
+            policy.allowed_ports = Some(parse_ports(&v)?);
+        }
+        if let Some(v) = opts.blocked_ports {
+            policy.blocked_ports = parse_ports(&v)?;
+        }
+        if let Some(v) = opts.strict_ports {
+            policy.strict_ports = v;
+        }
+        if let Some(v) = opts.allow_host_header_override {
+            policy.allow_host_header_override = v;
+        }
+        if let Some(v) = opts.read_only {
+            policy.read_only = v;
+        }
+        if let Some(v) = opts.allowed_methods {
+            policy.allowed_methods = v;
+        }
+        if let Some(v) = opts.allow_extension_methods {
+            policy.allow_extension_methods = v;
+        }
+        if let Some(v) = opts.allowed_schemes {
+            policy.allowed_schemes = v;
+        }
+        if let Some(v) = opts.upgrade_insecure_requests {
+            policy.upgrade_insecure_requests = v;
+        }
+        if let Some(domains) = opts.insecure_fallback_domains {
+            policy.insecure_fallback_domains = domains.into_iter().map(DomainPattern).collect();
+        }
+        if let Some(v) = opts.default_headers {
+            policy.default_headers = v;
+        }
+        if let Some(v) = opts.max_url_bytes {
+            policy.max_url_bytes = v as usize;
+        }
+        if let Some(v) = opts.max_request_body_bytes {
+            policy.max_request_body_bytes = v as usize;
+        }
+        if let Some(v) = opts.gzip_request_bytes {
+            policy.gzip_request_bytes = Some(v as usize);
+        }
+        if let Some(v) = opts.expect_continue_bytes {
+            policy.expect_continue_bytes = Some(v as usize);
+        }
+        if let Some(v) = opts.expect_continue_timeout_ms {
+            policy.expect_continue_timeout_ms = v as u64;
+        }
+        if let Some(v) = opts.max_response_body_bytes {
+            policy.max_response_body_bytes = v as usize;
+        }
+        if let Some(v) = opts.decompress_responses {
+            policy.decompress_responses = v;
+        }
+        if let Some(v) = opts.max_decompressed_body_bytes {
+            policy.max_decompressed_body_bytes = v as usize;
+        }
+        if let Some(v) = opts.allowed_content_types {
+            policy.allowed_content_types = Some(v);
+        }
+        if let Some(v) = opts.preflight_head {
+            policy.preflight_head = v;
+        }
+        if let Some(v) = opts.max_sse_event_bytes {
+            policy.max_sse_event_bytes = v as usize;
+        }
+        if let Some(v) = opts.max_sse_lifetime_ms {
+            policy.max_sse_lifetime_ms = v as u64;
+        }
+        if let Some(v) = opts.sse_revalidate_ms {
+            policy.sse_revalidate_ms = Some(v as u64);
+        }
+        if let Some(v) = opts.max_ws_message_bytes {
+            policy.max_ws_message_bytes = v as usize;
+        }
+        if let Some(v) = opts.max_ws_lifetime_ms {
+            policy.max_ws_lifetime_ms = v as u64;
+        }
+        if let Some(v) = opts.dns_timeout_ms {
+            policy.dns_timeout_ms = v as u64;
+        }
+        if let Some(v) = opts.connect_timeout_ms {
+            policy.connect_timeout_ms = v as u64;
+        }
+        if let Some(v) = opts.request_timeout_ms {
+            policy.request_timeout_ms = v as u64;
+        }
+        if let Some(v) = opts.connection_revalidate_ms {
+            policy.connection_revalidate_ms = Some(v as u64);
+        }
+        if let Some(v) = opts.http2 {
+            policy.http2 = v;
+        }
+        if let Some(v) = opts.http2_prior_knowledge {
+            policy.http2_prior_knowledge = v;
+        }
+        if let Some(v) = opts.http2_max_concurrent_streams {
+            policy.http2_max_concurrent_streams = Some(v);
+        }
+        if let Some(versions) = opts.allowed_http_versions {
+            policy.allowed_http_versions = versions
+                .iter()
+                .map(|v| match v.to_ascii_lowercase().as_str() {
+                    "http1" => Ok(HttpVersion::Http1),
+                    "http2" => Ok(HttpVersion::Http2),
+                    "http3" => Ok(HttpVersion::Http3),
+                    _ => Err(Error::from_reason(format!("invalid HTTP version {v}"))),
+                })
+                .collect::<Result<_>>()?;
+        }
+        if let Some(v) = opts.min_tls_version {
+            policy.min_tls_version = match v.as_str() {
+                "1.2" => TlsVersion::Tls1_2,
+                "1.3" => TlsVersion::Tls1_3,
+                _ => return Err(Error::from_reason(format!("invalid TLS version {v}"))),
+            };
+        }
+        if let Some(certs) = opts.client_certificates {
+            policy.client_certificates = certs
+                .into_iter()
+                .map(|c| ClientCertificate {
+                    pattern: DomainPattern(c.pattern),
+                    pem_file: c.pem_file.map(Into::into),
+                    pkcs12_file: c.pkcs12_file.map(Into::into),
+                    pkcs12_password: c.pkcs12_password,
+                })
+                .collect();
+        }
+        if let Some(roots) = opts.root_certificates {
+            policy.root_certificates = roots
+                .into_iter()
+                .map(|r| RootCertificates {
+                    file: r.file.map(Into::into),
+                    pem: r.pem,
+                })
+                .collect();
+        }
+        if let Some(v) = opts.use_system_roots {
+            policy.use_system_roots = v;
+        }
+        if let Some(v) = opts.capture_tls_details {
+            policy.capture_tls_details = v;
+        }
+        if let Some(pins) = opts.certificate_pins {
+            policy.certificate_pins = pins
+                .into_iter()
+                .map(|p| CertificatePin {
+                    pattern: DomainPattern(p.pattern),
+                    spki_sha256: p.spki_sha256.unwrap_or_default(),
+                    cert_sha256: p.cert_sha256.unwrap_or_default(),
+                })
+                .collect();
+        }
+        if let Some(p) = opts.proxy {
+            policy.proxy = Some(ProxyPolicy {
+                url: p.url,
+                no_proxy: p
+                    .no_proxy
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(DomainPattern)
+                    .collect(),
+                username: p.username,
+                password: p.password,
+                routes: p
+                    .routes
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|r| ProxyRoute {
+                        pattern: DomainPattern(r.pattern),
+                        url: r.url,
+                        username: r.username,
+                        password: r.password,
+                    })
+                    .collect(),
+            });
+        }
+        if let Some(v) = opts.require_proxy {
+            policy.require_proxy = v;
+        }
+        if let Some(v) = opts.max_redirects {
+            policy.max_redirects = v as u8;
+        }
+        if let Some(v) = opts.redirect_strip_headers {
+            policy.redirect_strip_headers = v;
+        }
+        if let Some(v) = opts.block_redirect_downgrade {
+            policy.block_redirect_downgrade = v;
+        }
+        if let Some(mode) = opts.redirect_mode {
+            policy.redirect_mode = parse_redirect_mode(&mode)?;
+        }
+        if let Some(scope) = opts.redirect_scope {
+            policy.redirect_scope = match scope.as_str() {
+                "any" => RedirectScope::Any,
+                "same_registrable_domain" => RedirectScope::SameRegistrableDomain,
+                "same_host" => RedirectScope::SameHost,
+                _ => {
+                    return Err(Error::from_reason(format!(
+                        "invalid redirect scope {scope}"
+                    )))
+                }
+            };
+        }
+        if let Some(v) = opts.max_concurrent_requests {
+            policy.max_concurrent_requests = v as usize;
+        }
+        if let Some(v) = opts.max_concurrent_requests_per_domain {
+            policy.max_concurrent_requests_per_domain = Some(v as usize);
+        }
+        if let Some(v) = opts.max_concurrency_wait_ms {
+            policy.max_concurrency_wait_ms = v as u64;
+        }
+        if let Some(v) = opts.max_requests_per_minute {
+            policy.max_requests_per_minute = v;
+        }
+        if let Some(v) = opts.max_requests_per_hour {
+            policy.max_requests_per_hour = Some(v);
+        }
+        if let Some(v) = opts.max_requests_per_day {
+            policy.max_requests_per_day = Some(v);
+        }
+        if let Some(v) = opts.max_requests_per_minute_per_domain {
+            policy.max_requests_per_minute_per_domain = Some(v);
+        }
+        if let Some(v) = opts.min_request_interval_ms {
+            policy.min_request_interval_ms = v as u64;
+        }
+        if let Some(v) = opts.max_queue_wait_ms {
+            policy.max_queue_wait_ms = v as u64;
+        }
+        if let Some(limits) = opts.per_domain_limits {
+            policy.per_domain_limits = limits
+                .into_iter()
+                .map(|l| DomainRateLimit {
+                    pattern: DomainPattern(l.pattern),
+                    max_requests_per_minute: l.max_requests_per_minute,
+                    max_requests_per_hour: l.max_requests_per_hour,
+                    max_requests_per_day: l.max_requests_per_day,
+                })
+                .collect();
+        }
+        if let Some(quotas) = opts.domain_quotas {
+            policy.domain_quotas = quotas
+                .into_iter()
+                .map(|q| DomainQuota {
+                    pattern: DomainPattern(q.pattern),
+                    max_requests: q.max_requests as u64,
+                    window_secs: q.window_secs.map_or(86_400, |s| s as u64),
+                })
+                .collect();
+        }
+        if let Some(domains) = opts.rate_limit_exempt_domains {
+            policy.rate_limit_exempt_domains = domains.into_iter().map(DomainPattern).collect();
+        }
+        if let Some(v) = opts.respect_server_rate_limits {
+            policy.respect_server_rate_limits = v;
+        }
+        if let Some(v) = opts.coalesce_requests {
+            policy.coalesce_requests = v;
+        }
+        if let Some(v) = opts.hedge_after_ms {
+            policy.hedge_after_ms = Some(v as u64);
+        }
+        if let Some(r) = opts.retry {
+            let defaults = RetryPolicy::default();
+            policy.retry = RetryPolicy {
+                max_attempts: r.max_attempts.unwrap_or(defaults.max_attempts),
+                initial_backoff_ms: r
+                    .initial_backoff_ms
+                    .map_or(defaults.initial_backoff_ms, |v| v as u64),
+                max_backoff_ms: r
+                    .max_backoff_ms
+                    .map_or(defaults.max_backoff_ms, |v| v as u64),
+                max_retry_after_ms: r
+                    .max_retry_after_ms
+                    .map_or(defaults.max_retry_after_ms, |v| v as u64),
+                retry_non_idempotent: r
+                    .retry_non_idempotent
+                    .unwrap_or(defaults.retry_non_idempotent),
+            };
+        }
+        if let Some(v) = opts.max_total_bytes {
+            policy.max_total_bytes = Some(v as u64);
+        }
+        if let Some(v) = opts.max_total_bytes_per_domain {
+            policy.max_total_bytes_per_domain = Some(v as u64);
+        }
+        if let Some(v) = opts.max_buffered_bytes {
+            policy.max_buffered_bytes = Some(v as usize);
+        }
+    }
+
+    Ok(policy)
+}
+
 #[napi]
 impl SafeHttpClient {
     #[napi(constructor)]
-    pub fn new(options: Option<SafeHttpClientOptions>) -> Self {
-        let mut policy = FetchPolicy::default();
+    pub fn new(options: Option<SafeHttpClientOptions>) -> Result<Self> {
+        Ok(Self {
+            client: SafeClient::new(policy_from_options(options)?),
+        })
+    }
 
-        if let Some(opts) = options {
-            if let Some(domains) = opts.allowed_domains {
-                policy.allowed_domains = Some(domains.into_iter().map(DomainPattern).collect());
-            }
-            if let Some(domains) = opts.blocked_domains {
-                policy.blocked_domains = domains.into_iter().map(DomainPattern).collect();
-            }
-            if let Some(v) = opts.deny_private_ips {
-                policy.deny_private_ips = v;
-            }
-            if let Some(v) = opts.allowed_methods {
-                policy.allowed_methods = v;
-            }
-            if let Some(v) = opts.allowed_schemes {
-                policy.allowed_schemes = v;
-            }
-            if let Some(v) = opts.max_request_body_bytes {
-                policy.max_request_body_bytes = v as usize;
-            }
-            if let Some(v) = opts.max_response_body_bytes {
-                policy.max_response_body_bytes = v as usize;
-            }
-            if let Some(v) = opts.connect_timeout_ms {
-                policy.connect_timeout_ms = v as u64;
-            }
-            if let Some(v) = opts.request_timeout_ms {
-                policy.request_timeout_ms = v as u64;
-            }
-            if let Some(v) = opts.max_redirects {
-                policy.max_redirects = v as u8;
-            }
-            if let Some(v) = opts.max_concurrent_requests {
-                policy.max_concurrent_requests = v as usize;
-            }
-            if let Some(v) = opts.max_requests_per_minute {
-                policy.max_requests_per_minute = v;
-            }
-        }
+    /// A new client with its own policy that shares this client's rate limits and
+    /// concurrency pool, so several tools together stay within one budget.
+    #[napi]
+    pub fn with_shared_rate_limits(
+        &self,
+        options: Option<SafeHttpClientOptions>,
+    ) -> Result<SafeHttpClient> {
+        Ok(Self {
+            client: SafeClient::new(policy_from_options(options)?)
+                .with_rate_limiter(self.client.rate_limiter()),
+        })
+    }
 
-        Self {
-            client: SafeClient::new(policy),
-        }
+    /// Save rate-limit windows, quotas and paused hosts to `path`, picking up what an
+    /// earlier process saved there, so short-lived scripts keep their budgets across runs.
+    #[napi]
+    pub fn persist_rate_limits(&self, path: String) {
+        self.client.rate_limiter().persist_to(path);
     }
 
+    /// Replace the client's policy without losing rate-limit state.
     #[napi]
-    pub async fn fetch(&self, url: String, options: Option<FetchOptions>) -> Result<FetchResult> {
-        let (method, headers, body) = match options {
-            Some(opts) => (
-                opts.method.unwrap_or_else(|| "GET".into()),
-                opts.headers.unwrap_or_default(),
-                opts.body.map(|b| b.to_vec()),
-            ),
-            None => ("GET".into(), HashMap::new(), None),
-        };
+    pub fn update_policy(&self, options: Option<SafeHttpClientOptions>) -> Result<()> {
+        self.client.update_policy(policy_from_options(options)?);
+        Ok(())
+    }
+
+    /// Register (or replace) a named policy profile selectable per request.
+    #[napi]
+    pub fn add_profile(&self, name: String, options: Option<SafeHttpClientOptions>) -> Result<()> {
+        self.client.add_profile(name, policy_from_options(options)?);
+        Ok(())
+    }
+
+    /// Make every later fetch fail until `enable` is called.
+    #[napi]
+    pub fn disable(&self) {
+        self.client.disable();
+    }
 
-        let request = FetchRequest {
+    #[napi]
+    pub fn enable(&self) {
+        self.client.enable();
+    }
+
+    /// Resolve, check, and handshake with the URL's origin ahead of the first fetch.
+    #[napi]
+    pub async fn preconnect(&self, url: String) -> Result<()> {
+        self.client
+            .preconnect(&url)
+            .await
+            .map_err(|e| Error::from_reason(e.to_string()))
+    }
+
+    #[napi]
+    pub async fn fetch(&self, url: String, options: Option<FetchOptions>) -> Result<FetchResult> {
+        let mut request = FetchRequest {
             url,
-            method,
-            headers,
-            body,
+            ..Default::default()
         };
+        if let Some(opts) = options {
+            if let Some(method) = opts.method {
+                request.method = method;
+            }
+            request.mirrors = opts.mirrors.unwrap_or_default();
+            request.headers = opts.headers.unwrap_or_default();
+            request.body = opts.body.map(|b| b.to_vec().into());
+            request.profile = opts.profile;
+            request.redirect = opts
+                .redirect
+                .as_deref()
+                .map(parse_redirect_mode)
+                .transpose()?;
+            request.request_timeout_ms = opts.request_timeout_ms.map(|v| v as u64);
+            request.connect_timeout_ms = opts.connect_timeout_ms.map(|v| v as u64);
+            request.max_response_body_bytes = opts.max_response_body_bytes.map(|v| v as usize);
+            if let Some(v) = opts.rate_limit_cost {
+                if v == 0 {
+                    return Err(Error::from_reason("rateLimitCost must be at least 1"));
+                }
+                request.rate_limit_cost = v;
+            }
+        }
 
         let response = self
             .client
@@ -117,6 +796,46 @@ impl SafeHttpClient {
             status: response.status as u32,
             headers: response.headers,
             body: Buffer::from(response.body),
+            url: response.url,
+            redirects: response
+                .redirects
+                .into_iter()
+                .map(|hop| RedirectHopResult {
+                    url: hop.url,
+                    status: hop.status as u32,
+                })
+                .collect(),
+            http_version: format!("{:?}", response.version),
+            timings: FetchTimingsResult {
+                dns_ms: millis(response.timings.dns),
+                connect_ms: response.timings.connect.map(millis),
+                tls_ms: response.timings.tls.map(millis),
+                time_to_first_byte_ms: millis(response.timings.time_to_first_byte),
+                body_ms: millis(response.timings.body),
+                total_ms: millis(response.timings.total),
+            },
+            retry_after_ms: response.retry_after.map(millis),
+            rate_limit: response.rate_limit.map(|limit| ServerRateLimitResult {
+                limit: limit.limit.map(|v| v as f64),
+                remaining: limit.remaining.map(|v| v as f64),
+                reset_ms: limit.reset.map(millis),
+            }),
+            tls: response.tls.map(|tls| TlsDetailsResult {
+                version: tls.version.map(|v| {
+                    match v {
+                        TlsVersion::Tls1_2 => "1.2",
+                        TlsVersion::Tls1_3 => "1.3",
+                    }
+                    .to_string()
+                }),
+                subject: tls.subject,
+                issuer: tls.issuer,
+                subject_alt_names: tls.subject_alt_names,
+                not_before_ms: epoch_millis(tls.not_before),
+                not_after_ms: epoch_millis(tls.not_after),
+                sha256: tls.sha256,
+                spki_sha256: tls.spki_sha256,
+            }),
         })
     }
 }
@@ -0,0 +1,39 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+/// A request for a domain outside the allowlist, awaiting a decision.
+#[derive(Debug, Clone)]
+pub struct ApprovalRequest {
+    /// The host that isn't on the allowlist.
+    pub host: String,
+    /// The full URL being fetched, after query-parameter stripping.
+    pub url: String,
+    pub method: String,
+    /// The policy profile the request was made under, if any.
+    pub profile: Option<String>,
+}
+
+/// The answer to an [`ApprovalRequest`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Approval {
+    /// Keep the request rejected with `FetchError::DomainNotAllowed`.
+    Deny,
+    /// Allow this request only; the next request to the host asks again.
+    Once,
+    /// Allow this and every later request to the host for the given duration.
+    For(Duration),
+}
+
+/// Future returned by [`ApprovalHandler::request_approval`].
+pub type Approving<'a> = Pin<Box<dyn Future<Output = Approval> + Send + 'a>>;
+
+/// Called when a request targets a domain that isn't on the allowlist, so the user can
+/// grant an exception ("ask before fetching unknown sites"). Registered with
+/// [`SafeClient::with_approval`](crate::SafeClient::with_approval).
+///
+/// Only the allowlist can be overridden: blocked domains, TLD rules, IP rules and every
+/// other check still apply to approved domains.
+pub trait ApprovalHandler: Send + Sync {
+    fn request_approval<'a>(&'a self, request: &'a ApprovalRequest) -> Approving<'a>;
+}
@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use tokio_util::sync::CancellationToken;
+
+use crate::client::{FetchRequest, FetchResponse, SafeClient};
+use crate::error::FetchError;
+
+/// How [`SafeClient::fetch_all`] runs a batch.
+#[derive(Debug, Clone)]
+pub struct BatchOptions {
+    /// Max requests of the batch in flight at once (default: 8). Never more than the
+    /// policy's `max_concurrent_requests`, so the batch doesn't trip the limiter on its own.
+    pub max_parallel: usize,
+    /// Stop at the first request that fails: requests still in flight are aborted and
+    /// the rest never sent, all with [`FetchError::Cancelled`] (default: false). Responses
+    /// with an error status don't count as failures.
+    pub fail_fast: bool,
+    /// Time allowed for the whole batch. Requests still running or not yet sent when it
+    /// passes fail with [`FetchError::RequestTimeout`].
+    pub deadline: Option<Duration>,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        Self {
+            max_parallel: 8,
+            fail_fast: false,
+            deadline: None,
+        }
+    }
+}
+
+impl SafeClient {
+    /// Fetch every request in `requests`, up to `options.max_parallel` at a time, each
+    /// under the full policy as with [`fetch`](Self::fetch). Results come back in the
+    /// order of `requests`.
+    pub async fn fetch_all(
+        &self,
+        requests: Vec<FetchRequest>,
+        options: BatchOptions,
+    ) -> Vec<Result<FetchResponse, FetchError>> {
+        let parallel = options
+            .max_parallel
+            .min(self.policy().max_concurrent_requests)
+            .max(1);
+        let deadline = options
+            .deadline
+            .map(|deadline| tokio::time::Instant::now() + deadline);
+        let fail_fast = options.fail_fast;
+        let stop = CancellationToken::new();
+
+        let mut results: Vec<_> = (0..requests.len()).map(|_| None).collect();
+        let mut fetches = futures_util::stream::iter(requests.into_iter().enumerate())
+            .map(|(i, request)| {
+                let stop = &stop;
+                async move {
+                    let fetch = async {
+                        match deadline {
+                            Some(at) => tokio::time::timeout_at(at, self.fetch(request))
+                                .await
+                                .unwrap_or(Err(FetchError::RequestTimeout)),
+                            None => self.fetch(request).await,
+                        }
+                    };
+                    let result = stop
+                        .run_until_cancelled(fetch)
+                        .await
+                        .unwrap_or(Err(FetchError::Cancelled));
+                    if fail_fast && result.is_err() {
+                        stop.cancel();
+                    }
+                    (i, result)
+                }
+            })
+            .buffer_unordered(parallel);
+        while let Some((i, result)) = fetches.next().await {
+            results[i] = Some(result);
+        }
+        results.into_iter().flatten().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::FetchPolicy;
+
+    #[tokio::test]
+    async fn results_keep_request_order() {
+        let client = SafeClient::new(FetchPolicy {
+            blocked_domains: vec![crate::policy::DomainPattern("blocked.example".into())],
+            ..FetchPolicy::default()
+        });
+        let get = |url: &str| FetchRequest {
+            url: url.into(),
+            ..Default::default()
+        };
+        let results = client
+            .fetch_all(
+                vec![
+                    get("ftp://a.example/"),
+                    get("https://blocked.example/"),
+                    get("not a url"),
+                ],
+                BatchOptions::default(),
+            )
+            .await;
+        assert!(matches!(
+            results[0],
+            Err(FetchError::SchemeNotAllowed { .. })
+        ));
+        assert!(matches!(results[1], Err(FetchError::DomainBlocked { .. })));
+        assert!(matches!(results[2], Err(FetchError::InvalidUrl(_))));
+    }
+}
@@ -0,0 +1,386 @@
+use std::io::Write;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{fmt, io};
+
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures_util::{Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::sync::CancellationToken;
+
+pub(crate) type ByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+/// The body of a [`FetchRequest`](crate::FetchRequest).
+///
+/// A streamed body is sent without being buffered, and the request-size limit is enforced
+/// as it is read. A stream of known length (e.g. a file) is also checked against the limit
+/// before anything is sent, and sent with a `Content-Length`. A stream can only be sent
+/// once: clones of a request share the same stream, so a streamed request can't be retried
+/// (including the `insecure_fallback_domains` retry).
+#[derive(Clone)]
+pub enum FetchBody {
+    Bytes(Bytes),
+    Stream {
+        chunks: Arc<Mutex<Option<ByteStream>>>,
+        len: Option<u64>,
+    },
+}
+
+impl FetchBody {
+    pub fn from_stream<S>(stream: S) -> Self
+    where
+        S: Stream<Item = io::Result<Bytes>> + Send + 'static,
+    {
+        Self::Stream {
+            chunks: Arc::new(Mutex::new(Some(Box::pin(stream)))),
+            len: None,
+        }
+    }
+
+    /// A stream that yields exactly `len` bytes. Sending fails if it yields a different
+    /// amount.
+    pub fn from_sized_stream<S>(stream: S, len: u64) -> Self
+    where
+        S: Stream<Item = io::Result<Bytes>> + Send + 'static,
+    {
+        Self::Stream {
+            chunks: Arc::new(Mutex::new(Some(Box::pin(exactly(stream, len))))),
+            len: Some(len),
+        }
+    }
+
+    /// Stream the file at `path`. Its size is taken when it is opened.
+    pub async fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = tokio::fs::File::open(path).await?;
+        let len = file.metadata().await?.len();
+        Ok(Self::from_sized_stream(read_chunks(file), len))
+    }
+
+    /// Stream the body from `reader` in chunks of up to 64 KB.
+    pub fn from_reader<R>(reader: R) -> Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        Self::from_stream(read_chunks(reader))
+    }
+
+    /// Call `progress` with the bytes sent so far and the total size, if known, as the
+    /// body is sent.
+    pub fn with_progress(
+        self,
+        progress: impl Fn(u64, Option<u64>) + Send + Sync + 'static,
+    ) -> Self {
+        let len = self.size().map(|size| size as u64);
+        let sent = AtomicU64::new(0);
+        let chunks = self.into_chunks().inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                let total =
+                    sent.fetch_add(chunk.len() as u64, Ordering::SeqCst) + chunk.len() as u64;
+                progress(total, len);
+            }
+        });
+        Self::Stream {
+            chunks: Arc::new(Mutex::new(Some(Box::pin(chunks)))),
+            len,
+        }
+    }
+
+    /// Size of the body, if known: always for an in-memory body, and for a stream created
+    /// with a length.
+    pub fn size(&self) -> Option<usize> {
+        match self {
+            Self::Bytes(bytes) => Some(bytes.len()),
+            Self::Stream { len, .. } => len.map(|len| len as usize),
+        }
+    }
+
+    /// The body, gzipped. An in-memory body is compressed at once, keeping a known size,
+    /// on a blocking thread if it is large; a stream is compressed as it is read, and its
+    /// size is then unknown.
+    pub(crate) async fn gzip(self) -> io::Result<Self> {
+        if let Self::Bytes(bytes) = self {
+            if bytes.len() < BLOCKING_GZIP_BYTES {
+                return gzip_bytes(&bytes).map(Self::Bytes);
+            }
+            return tokio::task::spawn_blocking(move || gzip_bytes(&bytes))
+                .await
+                .map_err(io::Error::other)?
+                .map(Self::Bytes);
+        }
+        let encoder = GzEncoder::new(Vec::new(), Compression::default());
+        let compressed =
+            futures_util::stream::unfold(Some((self.into_chunks(), encoder)), |state| async move {
+                let (mut chunks, mut encoder) = state?;
+                loop {
+                    let written = match chunks.next().await {
+                        Some(Ok(chunk)) => encoder.write_all(&chunk),
+                        Some(Err(e)) => return Some((Err(e), None)),
+                        None => {
+                            return match encoder.finish() {
+                                Ok(tail) => Some((Ok(tail.into()), None)),
+                                Err(e) => Some((Err(e), None)),
+                            };
+                        }
+                    };
+                    if let Err(e) = written {
+                        return Some((Err(e), None));
+                    }
+                    let output = std::mem::take(encoder.get_mut());
+                    if !output.is_empty() {
+                        return Some((Ok(output.into()), Some((chunks, encoder))));
+                    }
+                }
+            });
+        Ok(Self::from_stream(compressed))
+    }
+
+    /// The body as a stream of chunks. A stream that was already sent yields an error.
+    pub(crate) fn into_chunks(self) -> ByteStream {
+        match self {
+            Self::Bytes(bytes) => Box::pin(futures_util::stream::once(async { Ok(bytes) })),
+            Self::Stream { chunks, .. } => chunks.lock().unwrap().take().unwrap_or_else(|| {
+                Box::pin(futures_util::stream::once(async {
+                    Err(io::Error::other("body stream already sent"))
+                }))
+            }),
+        }
+    }
+
+    /// Convert into a `reqwest` body, failing once more than `limit` bytes have been read.
+    /// `sent` tracks the bytes read so far, so the caller can tell an aborted upload from
+    /// a transport error. With `hold`, the body is held back as for `Expect: 100-continue`:
+    /// see [`held`]. Returns `None` if the stream was already sent.
+    pub(crate) fn into_reqwest(
+        self,
+        limit: usize,
+        sent: Arc<AtomicUsize>,
+        hold: Option<(Duration, CancellationToken)>,
+    ) -> Option<reqwest::Body> {
+        let chunks = match self {
+            Self::Bytes(bytes) if hold.is_none() => return Some(bytes.into()),
+            Self::Bytes(bytes) => Box::pin(futures_util::stream::once(async { Ok(bytes) })),
+            Self::Stream { chunks, .. } => chunks.lock().unwrap().take()?,
+        };
+        let limited = chunks.map(move |chunk| {
+            let chunk = chunk?;
+            let total = sent.fetch_add(chunk.len(), Ordering::SeqCst) + chunk.len();
+            if total > limit {
+                return Err(io::Error::other("request body too large"));
+            }
+            Ok(chunk)
+        });
+        Some(match hold {
+            Some((wait, answered)) => {
+                reqwest::Body::wrap_stream(held(wait, answered).chain(limited))
+            }
+            None => reqwest::Body::wrap_stream(limited),
+        })
+    }
+}
+
+/// In-memory bodies at least this large are gzipped on a blocking thread.
+const BLOCKING_GZIP_BYTES: usize = 64 * 1024;
+
+fn gzip_bytes(bytes: &[u8]) -> io::Result<Bytes> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bytes)?;
+    Ok(encoder.finish()?.into())
+}
+
+/// Nothing for up to `wait` once the body is first polled, i.e. once the request headers
+/// are out, then the end of the stream. The HTTP stack doesn't report interim `100
+/// Continue` responses, so the full wait is always spent, unless `answered` is cancelled
+/// first: the server has responded without the body, which is then never sent.
+fn held(wait: Duration, answered: CancellationToken) -> impl Stream<Item = io::Result<Bytes>> {
+    futures_util::stream::once(async move {
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => None,
+            _ = answered.cancelled() => Some(Err(io::Error::other(
+                "the server responded before the request body was sent",
+            ))),
+        }
+    })
+    .filter_map(std::future::ready)
+}
+
+/// `stream`, ending in an error once it yields more than `len` bytes, or if it ends short.
+fn exactly<S>(stream: S, len: u64) -> impl Stream<Item = io::Result<Bytes>> + Send
+where
+    S: Stream<Item = io::Result<Bytes>> + Send + 'static,
+{
+    let state = Some((Box::pin(stream), 0u64));
+    futures_util::stream::unfold(state, move |state| async move {
+        let (mut stream, read) = state?;
+        match stream.next().await {
+            Some(Ok(chunk)) => {
+                let read = read + chunk.len() as u64;
+                if read > len {
+                    let e = io::Error::other(format!("body stream is longer than {len} bytes"));
+                    return Some((Err(e), None));
+                }
+                Some((Ok(chunk), Some((stream, read))))
+            }
+            Some(Err(e)) => Some((Err(e), None)),
+            None if read < len => {
+                let e = io::Error::other(format!("body stream ended after {read} of {len} bytes"));
+                Some((Err(e), None))
+            }
+            None => None,
+        }
+    })
+}
+
+/// Read `reader` in chunks of up to 64 KB.
+fn read_chunks<R>(reader: R) -> impl Stream<Item = io::Result<Bytes>> + Send
+where
+    R: AsyncRead + Send + Unpin + 'static,
+{
+    futures_util::stream::unfold(reader, |mut reader| async move {
+        let mut buf = vec![0; 64 * 1024];
+        match reader.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(Bytes::from(buf)), reader))
+            }
+            Err(e) => Some((Err(e), reader)),
+        }
+    })
+}
+
+impl fmt::Debug for FetchBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bytes(bytes) => f.debug_tuple("Bytes").field(&bytes.len()).finish(),
+            Self::Stream { len, .. } => f.debug_struct("Stream").field("len", len).finish(),
+        }
+    }
+}
+
+impl From<Vec<u8>> for FetchBody {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::Bytes(bytes.into())
+    }
+}
+
+impl From<Bytes> for FetchBody {
+    fn from(bytes: Bytes) -> Self {
+        Self::Bytes(bytes)
+    }
+}
+
+impl From<String> for FetchBody {
+    fn from(text: String) -> Self {
+        Self::Bytes(text.into())
+    }
+}
+
+impl From<&'static str> for FetchBody {
+    fn from(text: &'static str) -> Self {
+        Self::Bytes(Bytes::from_static(text.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streamed_body_is_sent_once() {
+        let body = FetchBody::from_reader(&b"hello"[..]);
+        assert_eq!(body.size(), None);
+        let clone = body.clone();
+        let sent = Arc::new(AtomicUsize::new(0));
+        assert!(body.into_reqwest(10, sent.clone(), None).is_some());
+        assert!(clone.into_reqwest(10, sent, None).is_none());
+
+        assert_eq!(FetchBody::from("abc").size(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn sized_streams_must_match_their_length() {
+        let read = |len| async move {
+            let chunks = futures_util::stream::iter([Ok(Bytes::from("hel")), Ok("lo".into())]);
+            let mut body = FetchBody::from_sized_stream(chunks, len).into_chunks();
+            let mut read = Vec::new();
+            while let Some(chunk) = body.next().await {
+                read.extend_from_slice(&chunk?);
+            }
+            io::Result::Ok(read)
+        };
+        assert_eq!(read(5).await.unwrap(), b"hello");
+        assert!(read(6)
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("after 5 of 6"));
+        assert!(read(4)
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("longer than 4"));
+    }
+
+    #[tokio::test]
+    async fn gzipped_bodies_decode_to_the_original() {
+        let decode = |bytes: &[u8]| {
+            let mut text = String::new();
+            std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(bytes), &mut text)
+                .unwrap();
+            text
+        };
+        let text = "compressible ".repeat(1000);
+
+        let FetchBody::Bytes(bytes) = FetchBody::from(text.clone()).gzip().await.unwrap() else {
+            panic!("in-memory bodies stay in memory");
+        };
+        assert!(bytes.len() < text.len() / 10);
+        assert_eq!(decode(&bytes), text);
+
+        let body = FetchBody::from_sized_stream(
+            futures_util::stream::iter(
+                text.as_bytes()
+                    .chunks(1000)
+                    .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+                    .collect::<Vec<_>>(),
+            ),
+            text.len() as u64,
+        )
+        .gzip()
+        .await
+        .unwrap();
+        assert_eq!(body.size(), None);
+        let mut compressed = Vec::new();
+        let mut chunks = body.into_chunks();
+        while let Some(chunk) = chunks.next().await {
+            compressed.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(decode(&compressed), text);
+    }
+
+    #[tokio::test]
+    async fn file_bodies_are_sized_and_report_progress() {
+        let path = std::env::temp_dir().join(format!("agent-fetch-body-{}", std::process::id()));
+        tokio::fs::write(&path, vec![b'x'; 100_000]).await.unwrap();
+        let body = FetchBody::from_file(&path).await.unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert_eq!(body.size(), Some(100_000));
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let seen = reports.clone();
+        let body = body.with_progress(move |sent, total| seen.lock().unwrap().push((sent, total)));
+        assert_eq!(body.size(), Some(100_000));
+        let mut chunks = body.into_chunks();
+        while chunks.next().await.is_some() {}
+        let reports = reports.lock().unwrap();
+        assert_eq!(
+            *reports,
+            [(65_536, Some(100_000)), (100_000, Some(100_000))]
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::error::FetchError;
+
+/// Cumulative response-body byte budget for a client, overall and per domain.
+pub struct TransferBudget {
+    state: Mutex<BudgetState>,
+}
+
+struct BudgetState {
+    max_total: Option<u64>,
+    max_per_domain: Option<u64>,
+    total: u64,
+    per_domain: HashMap<String, u64>,
+}
+
+impl TransferBudget {
+    pub fn new(max_total: Option<u64>, max_per_domain: Option<u64>) -> Self {
+        Self {
+            state: Mutex::new(BudgetState {
+                max_total,
+                max_per_domain,
+                total: 0,
+                per_domain: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Change the limits without resetting the bytes already counted.
+    pub fn set_limits(&self, max_total: Option<u64>, max_per_domain: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        state.max_total = max_total;
+        state.max_per_domain = max_per_domain;
+    }
+
+    /// Total bytes read so far.
+    pub fn total(&self) -> u64 {
+        self.state.lock().unwrap().total
+    }
+
+    /// Bytes read so far from `domain`.
+    pub fn domain_total(&self, domain: &str) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.per_domain.get(domain).copied().unwrap_or(0)
+    }
+
+    /// Fail if reading `bytes` more from `domain` would exceed a budget, without counting
+    /// them. Used before a request is sent and against a response's `Content-Length`.
+    pub fn check(&self, domain: &str, bytes: u64) -> Result<(), FetchError> {
+        let state = self.state.lock().unwrap();
+        state.check(domain, bytes)
+    }
+
+    /// Count `bytes` read from `domain`. They are counted even when they push a budget
+    /// over its limit, since they have already been transferred; the error then stops the
+    /// read.
+    pub fn record(&self, domain: &str, bytes: u64) -> Result<(), FetchError> {
+        let mut state = self.state.lock().unwrap();
+        state.total = state.total.saturating_add(bytes);
+        let used = state.per_domain.entry(domain.to_string()).or_insert(0);
+        *used = used.saturating_add(bytes);
+        state.check(domain, 0)
+    }
+}
+
+impl BudgetState {
+    fn check(&self, domain: &str, bytes: u64) -> Result<(), FetchError> {
+        if let Some(limit) = self.max_total {
+            if self.total.saturating_add(bytes) > limit {
+                return Err(FetchError::BudgetExceeded {
+                    scope: "client".into(),
+                    limit,
+                });
+            }
+        }
+        if let Some(limit) = self.max_per_domain {
+            let used = self.per_domain.get(domain).copied().unwrap_or(0);
+            if used.saturating_add(bytes) > limit {
+                return Err(FetchError::BudgetExceeded {
+                    scope: domain.to_string(),
+                    limit,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enforces_total_and_per_domain_budgets() {
+        let budget = TransferBudget::new(Some(100), Some(60));
+
+        assert!(budget.record("a.com", 50).is_ok());
+        assert!(budget.check("a.com", 10).is_ok());
+        let err = budget.check("a.com", 11).unwrap_err();
+        assert!(err.to_string().contains("a.com"), "got: {err}");
+
+        // Bytes over the limit still count.
+        assert!(budget.record("a.com", 20).is_err());
+        assert_eq!(budget.domain_total("a.com"), 70);
+        assert!(budget.check("a.com", 0).is_err());
+
+        assert!(budget.record("b.com", 30).is_ok());
+        let err = budget.record("b.com", 1).unwrap_err();
+        assert!(err.to_string().contains("client"), "got: {err}");
+        assert_eq!(budget.total(), 101);
+    }
+
+    #[test]
+    fn set_limits_keeps_usage() {
+        let budget = TransferBudget::new(None, None);
+        assert!(budget.record("a.com", 1000).is_ok());
+        budget.set_limits(Some(500), None);
+        assert!(budget.check("b.com", 0).is_err());
+        budget.set_limits(Some(2000), None);
+        assert!(budget.check("b.com", 1000).is_ok());
+    }
+}
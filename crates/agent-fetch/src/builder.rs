@@ -0,0 +1,629 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+use ipnet::IpNet;
+
+use crate::error::FetchError;
+use crate::policy::{
+    AddressFamily, AllowedDomain, CertificatePin, ClientCertificate, DnsCachePolicy, DnsOverTls,
+    DomainPattern, DomainQuota, DomainRateLimit, DomainRule, FetchPolicy, HttpVersion, ProxyPolicy,
+    RedirectMode, RedirectScope, RootCertificates, TlsVersion,
+};
+
+/// Typed builder for [`FetchPolicy`], starting from the defaults.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use agent_fetch::FetchPolicy;
+///
+/// let policy = FetchPolicy::builder()
+///     .allow_domain("*.example.com")
+///     .block_domain("internal.example.com")
+///     .block_private_ips(true)
+///     .timeout(Duration::from_secs(5))
+///     .build()
+///     .unwrap();
+/// assert!(policy.check_domain("api.example.com").is_ok());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct FetchPolicyBuilder {
+    policy: FetchPolicy,
+}
+
+impl FetchPolicy {
+    pub fn builder() -> FetchPolicyBuilder {
+        FetchPolicyBuilder::default()
+    }
+}
+
+impl FetchPolicyBuilder {
+    /// Start from an existing policy instead of the defaults.
+    pub fn from_policy(policy: FetchPolicy) -> Self {
+        Self { policy }
+    }
+
+    /// Add a domain to the allowlist. The first call switches the policy from
+    /// "all public domains" to allowlist mode.
+    pub fn allow_domain(mut self, pattern: impl Into<String>) -> Self {
+        self.policy
+            .allowed_domains
+            .get_or_insert_with(Vec::new)
+            .push(DomainPattern(pattern.into()).into());
+        self
+    }
+
+    /// Add a domain to the allowlist until `expires_at`, after which the entry is ignored.
+    pub fn allow_domain_until(
+        mut self,
+        pattern: impl Into<String>,
+        expires_at: SystemTime,
+    ) -> Self {
+        self.policy
+            .allowed_domains
+            .get_or_insert_with(Vec::new)
+            .push(AllowedDomain::until(
+                DomainPattern(pattern.into()),
+                expires_at,
+            ));
+        self
+    }
+
+    pub fn block_domain(mut self, pattern: impl Into<String>) -> Self {
+        self.policy
+            .blocked_domains
+            .push(DomainPattern(pattern.into()));
+        self
+    }
+
+    pub fn domain_rule(mut self, rule: DomainRule) -> Self {
+        self.policy.domain_rules.push(rule);
+        self
+    }
+
+    pub fn allow_tld(mut self, tld: impl Into<String>) -> Self {
+        self.policy
+            .allowed_tlds
+            .get_or_insert_with(Vec::new)
+            .push(tld.into());
+        self
+    }
+
+    pub fn block_tld(mut self, tld: impl Into<String>) -> Self {
+        self.policy.blocked_tlds.push(tld.into());
+        self
+    }
+
+    pub fn block_private_ips(mut self, deny: bool) -> Self {
+        self.policy.deny_private_ips = deny;
+        self
+    }
+
+    pub fn block_ip_range(mut self, range: IpNet) -> Self {
+        self.policy.blocked_ip_ranges.push(range);
+        self
+    }
+
+    pub fn allow_ip_range(mut self, range: IpNet) -> Self {
+        self.policy.allowed_ip_ranges.push(range);
+        self
+    }
+
+    /// Replace the address families the client may connect over.
+    pub fn allow_address_families(
+        mut self,
+        families: impl IntoIterator<Item = AddressFamily>,
+    ) -> Self {
+        self.policy.allowed_address_families = families.into_iter().collect();
+        self
+    }
+
+    /// Cache DNS answers per `cache`, e.g. [`DnsCachePolicy::default`] with a tighter
+    /// `max_ttl_secs`.
+    pub fn dns_cache(mut self, cache: DnsCachePolicy) -> Self {
+        self.policy.dns_cache = cache;
+        self
+    }
+
+    /// Send DNS lookups to `server` instead of the system's resolvers. Call once per server.
+    pub fn dns_server(mut self, server: SocketAddr) -> Self {
+        self.policy.dns_servers.push(server);
+        self
+    }
+
+    /// Answer lookups of `host` with `ips` instead of asking DNS. The addresses are still
+    /// checked unless [`trust_dns_overrides`](Self::trust_dns_overrides) is on.
+    pub fn dns_override(mut self, host: impl Into<String>, ips: Vec<IpAddr>) -> Self {
+        self.policy.dns_overrides.insert(host.into(), ips);
+        self
+    }
+
+    /// Skip the IP checks for the addresses of [`dns_override`](Self::dns_override)s.
+    pub fn trust_dns_overrides(mut self, trusted: bool) -> Self {
+        self.policy.trust_dns_overrides = trusted;
+        self
+    }
+
+    /// Resolve over TLS with the resolvers in `dot` instead of the system's.
+    pub fn dns_over_tls(mut self, dot: DnsOverTls) -> Self {
+        self.policy.dns_over_tls = Some(dot);
+        self
+    }
+
+    /// Replace the HTTP versions requests may use.
+    pub fn allow_http_versions(mut self, versions: impl IntoIterator<Item = HttpVersion>) -> Self {
+        self.policy.allowed_http_versions = versions.into_iter().collect();
+        self
+    }
+
+    pub fn allow_port(mut self, port: u16) -> Self {
+        self.policy
+            .allowed_ports
+            .get_or_insert_with(Vec::new)
+            .push(port);
+        self
+    }
+
+    pub fn block_port(mut self, port: u16) -> Self {
+        self.policy.blocked_ports.push(port);
+        self
+    }
+
+    pub fn strict_ports(mut self, strict: bool) -> Self {
+        self.policy.strict_ports = strict;
+        self
+    }
+
+    /// Replace the allowed HTTP methods.
+    pub fn allow_methods<I, S>(mut self, methods: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.policy.allowed_methods = methods.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Let the allowed methods include extension methods such as `PROPFIND`.
+    pub fn allow_extension_methods(mut self, allow: bool) -> Self {
+        self.policy.allow_extension_methods = allow;
+        self
+    }
+
+    /// Replace the allowed URL schemes.
+    pub fn allow_schemes<I, S>(mut self, schemes: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.policy.allowed_schemes = schemes.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.policy.read_only = read_only;
+        self
+    }
+
+    pub fn block_request_header(mut self, name: impl Into<String>) -> Self {
+        self.policy.blocked_request_headers.push(name.into());
+        self
+    }
+
+    /// Let requests carry a `Host` header for another host than the URL's; see
+    /// [`FetchPolicy::allow_host_header_override`].
+    pub fn allow_host_header_override(mut self, allow: bool) -> Self {
+        self.policy.allow_host_header_override = allow;
+        self
+    }
+
+    pub fn default_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.policy
+            .default_headers
+            .insert(name.into(), value.into());
+        self
+    }
+
+    pub fn max_url_bytes(mut self, bytes: usize) -> Self {
+        self.policy.max_url_bytes = bytes;
+        self
+    }
+
+    pub fn max_request_body_bytes(mut self, bytes: usize) -> Self {
+        self.policy.max_request_body_bytes = bytes;
+        self
+    }
+
+    /// Gzip request bodies of at least `bytes`.
+    pub fn gzip_request_bodies(mut self, bytes: usize) -> Self {
+        self.policy.gzip_request_bytes = Some(bytes);
+        self
+    }
+
+    /// Send `Expect: 100-continue` with bodies of at least `bytes`, holding them back for
+    /// up to `timeout`.
+    pub fn expect_continue(mut self, bytes: usize, timeout: Duration) -> Self {
+        self.policy.expect_continue_bytes = Some(bytes);
+        self.policy.expect_continue_timeout_ms = duration_ms(timeout);
+        self
+    }
+
+    pub fn max_response_body_bytes(mut self, bytes: usize) -> Self {
+        self.policy.max_response_body_bytes = bytes;
+        self
+    }
+
+    pub fn decompress_responses(mut self, decompress: bool) -> Self {
+        self.policy.decompress_responses = decompress;
+        self
+    }
+
+    pub fn max_decompressed_body_bytes(mut self, bytes: usize) -> Self {
+        self.policy.max_decompressed_body_bytes = bytes;
+        self
+    }
+
+    pub fn max_sse_event_bytes(mut self, bytes: usize) -> Self {
+        self.policy.max_sse_event_bytes = bytes;
+        self
+    }
+
+    pub fn max_sse_lifetime(mut self, lifetime: Duration) -> Self {
+        self.policy.max_sse_lifetime_ms = duration_ms(lifetime);
+        self
+    }
+
+    /// Re-check open event streams against the policy every `interval`.
+    pub fn sse_revalidate_every(mut self, interval: Duration) -> Self {
+        self.policy.sse_revalidate_ms = Some(duration_ms(interval));
+        self
+    }
+
+    pub fn max_ws_message_bytes(mut self, bytes: usize) -> Self {
+        self.policy.max_ws_message_bytes = bytes;
+        self
+    }
+
+    pub fn max_ws_lifetime(mut self, lifetime: Duration) -> Self {
+        self.policy.max_ws_lifetime_ms = duration_ms(lifetime);
+        self
+    }
+
+    /// Add a media type (e.g. `application/json` or `image/*`) to the allowed response
+    /// types. The first call switches the policy to allowlist mode.
+    pub fn allow_content_type(mut self, media_type: impl Into<String>) -> Self {
+        self.policy
+            .allowed_content_types
+            .get_or_insert_with(Vec::new)
+            .push(media_type.into());
+        self
+    }
+
+    pub fn preflight_head(mut self, preflight: bool) -> Self {
+        self.policy.preflight_head = preflight;
+        self
+    }
+
+    /// How long a DNS lookup may take.
+    pub fn dns_timeout(mut self, timeout: Duration) -> Self {
+        self.policy.dns_timeout_ms = duration_ms(timeout);
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.policy.connect_timeout_ms = duration_ms(timeout);
+        self
+    }
+
+    /// Overall request timeout.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.policy.request_timeout_ms = duration_ms(timeout);
+        self
+    }
+
+    /// Re-validate the peers of kept-alive connections once they have been reused for
+    /// `age`, connecting again if an address no longer checks out.
+    pub fn connection_revalidate_every(mut self, age: Duration) -> Self {
+        self.policy.connection_revalidate_ms = Some(duration_ms(age));
+        self
+    }
+
+    pub fn http2(mut self, enabled: bool) -> Self {
+        self.policy.http2 = enabled;
+        self
+    }
+
+    /// Speak HTTP/2 without negotiating it, including h2c to `http` URLs.
+    pub fn http2_prior_knowledge(mut self, enabled: bool) -> Self {
+        self.policy.http2_prior_knowledge = enabled;
+        self
+    }
+
+    /// Cap the requests in flight at once to one origin while HTTP/2 is on.
+    pub fn http2_max_concurrent_streams(mut self, streams: u32) -> Self {
+        self.policy.http2_max_concurrent_streams = Some(streams);
+        self
+    }
+
+    pub fn min_tls_version(mut self, version: TlsVersion) -> Self {
+        self.policy.min_tls_version = version;
+        self
+    }
+
+    /// Present a client certificate to servers matching its pattern.
+    pub fn client_certificate(mut self, cert: ClientCertificate) -> Self {
+        self.policy.client_certificates.push(cert);
+        self
+    }
+
+    /// Trust these root certificates for TLS, besides the system's unless
+    /// [`use_system_roots`](Self::use_system_roots) turns those off.
+    pub fn root_certificates(mut self, roots: RootCertificates) -> Self {
+        self.policy.root_certificates.push(roots);
+        self
+    }
+
+    pub fn use_system_roots(mut self, enabled: bool) -> Self {
+        self.policy.use_system_roots = enabled;
+        self
+    }
+
+    /// Require servers matching the pin's pattern to present one of its keys or
+    /// certificates.
+    pub fn certificate_pin(mut self, pin: CertificatePin) -> Self {
+        self.policy.certificate_pins.push(pin);
+        self
+    }
+
+    pub fn capture_tls_details(mut self, enabled: bool) -> Self {
+        self.policy.capture_tls_details = enabled;
+        self
+    }
+
+    /// Send requests through a proxy; see [`FetchPolicy::proxy`].
+    pub fn proxy(mut self, proxy: ProxyPolicy) -> Self {
+        self.policy.proxy = Some(proxy);
+        self
+    }
+
+    /// Refuse every request that wouldn't go through the proxy.
+    pub fn require_proxy(mut self, required: bool) -> Self {
+        self.policy.require_proxy = required;
+        self
+    }
+
+    pub fn max_redirects(mut self, redirects: u8) -> Self {
+        self.policy.max_redirects = redirects;
+        self
+    }
+
+    pub fn redirect_mode(mut self, mode: RedirectMode) -> Self {
+        self.policy.redirect_mode = mode;
+        self
+    }
+
+    pub fn redirect_scope(mut self, scope: RedirectScope) -> Self {
+        self.policy.redirect_scope = scope;
+        self
+    }
+
+    pub fn upgrade_insecure_requests(mut self, upgrade: bool) -> Self {
+        self.policy.upgrade_insecure_requests = upgrade;
+        self
+    }
+
+    /// Let upgraded requests to domains matching `pattern` fall back to plain `http`.
+    pub fn insecure_fallback_domain(mut self, pattern: impl Into<String>) -> Self {
+        self.policy
+            .insecure_fallback_domains
+            .push(DomainPattern(pattern.into()));
+        self
+    }
+
+    /// Drop the caller header `name` from redirect hops that change host or port.
+    pub fn strip_header_on_redirect(mut self, name: impl Into<String>) -> Self {
+        self.policy.redirect_strip_headers.push(name.into());
+        self
+    }
+
+    pub fn block_redirect_downgrade(mut self, block: bool) -> Self {
+        self.policy.block_redirect_downgrade = block;
+        self
+    }
+
+    pub fn max_concurrent_requests(mut self, requests: usize) -> Self {
+        self.policy.max_concurrent_requests = requests;
+        self
+    }
+
+    /// Limit how many requests may be in flight to each host at once.
+    pub fn max_concurrent_requests_per_domain(mut self, requests: usize) -> Self {
+        self.policy.max_concurrent_requests_per_domain = Some(requests);
+        self
+    }
+
+    /// Wait up to `wait` for a concurrency slot when all are taken. `Duration::ZERO` fails
+    /// at once.
+    pub fn max_concurrency_wait(mut self, wait: Duration) -> Self {
+        self.policy.max_concurrency_wait_ms = duration_ms(wait);
+        self
+    }
+
+    pub fn max_requests_per_minute(mut self, requests: u32) -> Self {
+        self.policy.max_requests_per_minute = requests;
+        self
+    }
+
+    /// Limit requests per hour, on top of the per-minute limit.
+    pub fn max_requests_per_hour(mut self, requests: u32) -> Self {
+        self.policy.max_requests_per_hour = Some(requests);
+        self
+    }
+
+    /// Limit requests per day, on top of the per-minute limit.
+    pub fn max_requests_per_day(mut self, requests: u32) -> Self {
+        self.policy.max_requests_per_day = Some(requests);
+        self
+    }
+
+    /// Limit requests to each host, in a window of its own, on top of the global limit.
+    pub fn max_requests_per_minute_per_domain(mut self, requests: u32) -> Self {
+        self.policy.max_requests_per_minute_per_domain = Some(requests);
+        self
+    }
+
+    /// Space requests to the same host at least `interval` apart.
+    pub fn min_request_interval(mut self, interval: Duration) -> Self {
+        self.policy.min_request_interval_ms = duration_ms(interval);
+        self
+    }
+
+    /// Wait up to `wait` for room when a rate limit is full, instead of failing at once.
+    pub fn max_queue_wait(mut self, wait: Duration) -> Self {
+        self.policy.max_queue_wait_ms = duration_ms(wait);
+        self
+    }
+
+    /// Limit requests to domains matching `pattern`, on top of the global limit.
+    pub fn domain_rate_limit(mut self, pattern: impl Into<String>, per_minute: u32) -> Self {
+        self.policy.per_domain_limits.push(DomainRateLimit {
+            pattern: DomainPattern(pattern.into()),
+            max_requests_per_minute: Some(per_minute),
+            max_requests_per_hour: None,
+            max_requests_per_day: None,
+        });
+        self
+    }
+
+    /// Leave domains matching `pattern` out of the global and per-host rate and concurrency
+    /// limits.
+    pub fn rate_limit_exempt_domain(mut self, pattern: impl Into<String>) -> Self {
+        self.policy
+            .rate_limit_exempt_domains
+            .push(DomainPattern(pattern.into()));
+        self
+    }
+
+    /// Cap requests to domains matching `pattern` at `max_requests` per `window`, e.g. a
+    /// paid API's daily budget.
+    pub fn domain_quota(
+        mut self,
+        pattern: impl Into<String>,
+        max_requests: u64,
+        window: Duration,
+    ) -> Self {
+        self.policy.domain_quotas.push(DomainQuota {
+            pattern: DomainPattern(pattern.into()),
+            max_requests,
+            window_secs: window.as_secs(),
+        });
+        self
+    }
+
+    /// Whether to pause requests to a host that reports its rate limit spent.
+    pub fn respect_server_rate_limits(mut self, respect: bool) -> Self {
+        self.policy.respect_server_rate_limits = respect;
+        self
+    }
+
+    pub fn coalesce_requests(mut self, coalesce: bool) -> Self {
+        self.policy.coalesce_requests = coalesce;
+        self
+    }
+
+    /// Send a `GET` a second time if it has no response after `delay`, and use whichever
+    /// response arrives first.
+    pub fn hedge_after(mut self, delay: Duration) -> Self {
+        self.policy.hedge_after_ms = Some(duration_ms(delay));
+        self
+    }
+
+    /// Make up to `max_attempts` attempts per request, backing off exponentially from
+    /// `initial_backoff` between them.
+    pub fn retry(mut self, max_attempts: u32, initial_backoff: Duration) -> Self {
+        self.policy.retry.max_attempts = max_attempts;
+        self.policy.retry.initial_backoff_ms = duration_ms(initial_backoff);
+        self
+    }
+
+    /// Cap the response-body bytes the client may read over its lifetime.
+    pub fn max_total_bytes(mut self, bytes: u64) -> Self {
+        self.policy.max_total_bytes = Some(bytes);
+        self
+    }
+
+    /// Cap the response-body bytes the client may read from any one host over its lifetime.
+    pub fn max_total_bytes_per_domain(mut self, bytes: u64) -> Self {
+        self.policy.max_total_bytes_per_domain = Some(bytes);
+        self
+    }
+
+    /// Cap the response-body bytes buffered at once across the client's fetches.
+    pub fn max_buffered_bytes(mut self, bytes: usize) -> Self {
+        self.policy.max_buffered_bytes = Some(bytes);
+        self
+    }
+
+    /// Validate the policy (see [`FetchPolicy::validate`]) and return it.
+    pub fn build(self) -> Result<FetchPolicy, FetchError> {
+        self.policy.validate()?;
+        Ok(self.policy)
+    }
+}
+
+fn duration_ms(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_policy() {
+        let policy = FetchPolicy::builder()
+            .allow_domain("*.example.com")
+            .block_domain("evil.example.com")
+            .block_ip_range("203.0.113.0/24".parse().unwrap())
+            .allow_port(443)
+            .allow_methods(["GET"])
+            .connect_timeout(Duration::from_millis(1500))
+            .timeout(Duration::from_secs(5))
+            .build()
+            .unwrap();
+
+        assert_eq!(policy.connect_timeout_ms, 1500);
+        assert_eq!(policy.request_timeout_ms, 5000);
+        assert_eq!(policy.allowed_ports, Some(vec![443]));
+        assert!(policy.check_domain("api.example.com").is_ok());
+        assert!(policy.check_domain("evil.example.com").is_err());
+        assert!(policy.check_method("api.example.com", "POST").is_err());
+    }
+
+    #[test]
+    fn rejects_zero_timeout() {
+        let err = FetchPolicy::builder()
+            .timeout(Duration::ZERO)
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().contains("request_timeout_ms"), "got: {err}");
+    }
+
+    #[test]
+    fn rejects_shadowed_allowlist() {
+        let err = FetchPolicy::builder()
+            .allow_domain("api.example.com")
+            .block_domain("*.example.com")
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().contains("entirely blocked"), "got: {err}");
+    }
+
+    #[test]
+    fn rejects_invalid_pattern() {
+        assert!(FetchPolicy::builder()
+            .allow_domain("api.*.com")
+            .build()
+            .is_err());
+    }
+}
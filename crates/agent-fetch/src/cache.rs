@@ -0,0 +1,657 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use bytes::{Bytes, BytesMut};
+
+use crate::client::FetchRequest;
+use crate::policy::FetchPolicy;
+
+/// A response kept by a [`CacheStore`], with its body already decoded.
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub url: String,
+    pub status: u16,
+    /// Response headers, with lowercase names. They describe the decoded body, so
+    /// `Content-Encoding` is absent for a body that was decompressed.
+    pub headers: HashMap<String, String>,
+    pub body: Bytes,
+    pub version: http::Version,
+    /// When the response was received, or last revalidated.
+    pub stored_at: SystemTime,
+    /// The values the request sent for the headers named in the response's `Vary`, by
+    /// lowercase name. Headers the request didn't send are absent.
+    pub vary: HashMap<String, String>,
+}
+
+/// Storage for the response cache enabled with
+/// [`SafeClient::with_cache`](crate::SafeClient::with_cache), keyed by normalized URL and,
+/// for requests made under a policy profile, its name. A store may drop entries whenever
+/// it likes; a missing entry is just a cache miss.
+pub trait CacheStore: Send + Sync {
+    fn get(&self, key: &str) -> Option<CachedResponse>;
+    fn put(&self, key: &str, response: CachedResponse);
+    fn remove(&self, key: &str);
+}
+
+/// Lets a store be shared, e.g. to read [`LruCacheStore::stats`] while a client uses it.
+impl<T: CacheStore + ?Sized> CacheStore for Arc<T> {
+    fn get(&self, key: &str) -> Option<CachedResponse> {
+        (**self).get(key)
+    }
+
+    fn put(&self, key: &str, response: CachedResponse) {
+        (**self).put(key, response)
+    }
+
+    fn remove(&self, key: &str) {
+        (**self).remove(key)
+    }
+}
+
+/// Counters for sizing an [`LruCacheStore`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub entries: usize,
+    /// Total [`CachedResponse::size`] of the stored entries.
+    pub bytes: usize,
+    /// Lookups that found an entry, whether it was fresh or had to be revalidated.
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries dropped to make room for newer ones.
+    pub evictions: u64,
+    /// Responses not stored because they alone exceed the byte limit.
+    pub rejected: u64,
+}
+
+/// A bounded in-memory [`CacheStore`] that evicts the least recently used entries once
+/// either limit is reached.
+#[derive(Debug)]
+pub struct LruCacheStore {
+    max_entries: usize,
+    max_bytes: usize,
+    inner: Mutex<Lru>,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    /// Entries with the tick they were last used at.
+    entries: HashMap<String, (CachedResponse, u64)>,
+    /// Keys by last use, oldest first.
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl Lru {
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        if let Some((_, used)) = self.entries.get_mut(key) {
+            self.order.remove(used);
+            *used = self.tick;
+            self.order.insert(self.tick, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        let Some((entry, used)) = self.entries.remove(key) else {
+            return false;
+        };
+        self.order.remove(&used);
+        self.stats.entries -= 1;
+        self.stats.bytes -= entry.size();
+        true
+    }
+}
+
+impl LruCacheStore {
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        Self {
+            max_entries,
+            max_bytes,
+            inner: Mutex::new(Lru::default()),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock().unwrap().stats
+    }
+
+    pub fn clear(&self) {
+        let mut lru = self.inner.lock().unwrap();
+        let stats = lru.stats;
+        *lru = Lru {
+            stats: CacheStats {
+                entries: 0,
+                bytes: 0,
+                ..stats
+            },
+            ..Lru::default()
+        };
+    }
+}
+
+impl Default for LruCacheStore {
+    /// 1000 entries and 64 MB.
+    fn default() -> Self {
+        Self::new(1000, 64 * 1024 * 1024)
+    }
+}
+
+impl CacheStore for LruCacheStore {
+    fn get(&self, key: &str) -> Option<CachedResponse> {
+        let mut lru = self.inner.lock().unwrap();
+        lru.touch(key);
+        let entry = lru.entries.get(key).map(|(entry, _)| entry.clone());
+        match entry {
+            Some(_) => lru.stats.hits += 1,
+            None => lru.stats.misses += 1,
+        }
+        entry
+    }
+
+    fn put(&self, key: &str, response: CachedResponse) {
+        let mut lru = self.inner.lock().unwrap();
+        lru.remove(key);
+        let size = response.size();
+        if size > self.max_bytes || self.max_entries == 0 {
+            lru.stats.rejected += 1;
+            return;
+        }
+        while lru.stats.entries >= self.max_entries || lru.stats.bytes + size > self.max_bytes {
+            let Some(oldest) = lru.order.values().next().cloned() else {
+                break;
+            };
+            lru.remove(&oldest);
+            lru.stats.evictions += 1;
+        }
+        lru.tick += 1;
+        let tick = lru.tick;
+        lru.entries.insert(key.to_string(), (response, tick));
+        lru.order.insert(tick, key.to_string());
+        lru.stats.entries += 1;
+        lru.stats.bytes += size;
+    }
+
+    fn remove(&self, key: &str) {
+        self.inner.lock().unwrap().remove(key);
+    }
+}
+
+/// Statuses cacheable by default (RFC 9110 §15.1), less redirects: a stored redirect would
+/// be replayed without being followed.
+const CACHEABLE_STATUSES: [u16; 9] = [200, 203, 204, 300, 404, 405, 410, 414, 501];
+
+/// The `Cache-Control` directives the cache acts on.
+#[derive(Debug, Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    max_age: Option<u64>,
+}
+
+impl CacheControl {
+    fn parse(headers: &HashMap<String, String>) -> Self {
+        let mut cc = Self::default();
+        let directives = header(headers, "cache-control")
+            .into_iter()
+            .flat_map(|value| value.split(','));
+        for directive in directives {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name, Some(value.trim().trim_matches('"'))),
+                None => (directive, None),
+            };
+            match name.trim().to_ascii_lowercase().as_str() {
+                "no-store" => cc.no_store = true,
+                "no-cache" => cc.no_cache = true,
+                // An invalid max-age makes the response stale (RFC 9111 §4.2.1).
+                "max-age" => cc.max_age = Some(value.and_then(|v| v.parse().ok()).unwrap_or(0)),
+                _ => {}
+            }
+        }
+        cc
+    }
+}
+
+/// A header value by case-insensitive name.
+fn header<'a>(headers: &'a HashMap<String, String>, name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// Parse an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`, the only date format
+/// servers may send (RFC 9110 §5.6.7).
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    let [_, day, month, year, time, "GMT"] = parts[..] else {
+        return None;
+    };
+    let month = MONTHS.iter().position(|m| *m == month)? + 1;
+    humantime::parse_rfc3339(&format!("{year}-{month:02}-{day}T{time}Z")).ok()
+}
+
+/// The delay a `Retry-After` header asks for, given in seconds or as an HTTP date
+/// (RFC 9110 §10.2.3). A date in the past means no delay.
+pub(crate) fn retry_after(headers: &HashMap<String, String>, now: SystemTime) -> Option<Duration> {
+    let value = headers.get("retry-after")?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = parse_http_date(value)?;
+    Some(at.duration_since(now).unwrap_or_default())
+}
+
+/// How long a response is fresh for, from `Cache-Control: max-age` or else `Expires`.
+/// `None` if it states no lifetime; heuristic freshness isn't used.
+fn freshness_lifetime(headers: &HashMap<String, String>, received: SystemTime) -> Option<Duration> {
+    let cc = CacheControl::parse(headers);
+    if cc.no_cache {
+        return Some(Duration::ZERO);
+    }
+    if let Some(max_age) = cc.max_age {
+        return Some(Duration::from_secs(max_age));
+    }
+    let expires = header(headers, "expires")?;
+    let date = header(headers, "date")
+        .and_then(parse_http_date)
+        .unwrap_or(received);
+    // An invalid `Expires` means already expired.
+    Some(
+        parse_http_date(expires)
+            .and_then(|expires| expires.duration_since(date).ok())
+            .unwrap_or_default(),
+    )
+}
+
+fn has_validators(headers: &HashMap<String, String>) -> bool {
+    header(headers, "etag").is_some() || header(headers, "last-modified").is_some()
+}
+
+impl CachedResponse {
+    /// Time since the response was generated: its `Age` when received plus the time it has
+    /// been stored.
+    pub fn age(&self, now: SystemTime) -> Duration {
+        let initial = header(&self.headers, "age")
+            .and_then(|age| age.trim().parse().ok())
+            .map_or(Duration::ZERO, Duration::from_secs);
+        initial + now.duration_since(self.stored_at).unwrap_or_default()
+    }
+
+    /// Whether the response can be served without contacting the server.
+    pub fn is_fresh(&self, now: SystemTime) -> bool {
+        freshness_lifetime(&self.headers, self.stored_at).is_some_and(|life| self.age(now) < life)
+    }
+
+    /// Approximate memory held by the entry: the body plus header names and values.
+    pub fn size(&self) -> usize {
+        self.body.len()
+            + self
+                .headers
+                .iter()
+                .chain(&self.vary)
+                .map(|(k, v)| k.len() + v.len())
+                .sum::<usize>()
+    }
+
+    /// Whether a request with `headers` selects this response, per its `Vary`.
+    fn matches(&self, headers: &HashMap<String, String>) -> bool {
+        let Some(vary) = header(&self.headers, "vary") else {
+            return true;
+        };
+        vary.split(',')
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .all(|name| header(headers, &name) == self.vary.get(&name).map(String::as_str))
+    }
+
+    /// Make `request` conditional on this response's validators. Returns `false`, leaving
+    /// the request alone, if there are no validators or the request has its own conditions.
+    pub(crate) fn add_conditions(&self, request: &mut FetchRequest) -> bool {
+        let own = ["if-none-match", "if-modified-since"]
+            .iter()
+            .any(|name| header(&request.headers, name).is_some());
+        if own || !has_validators(&self.headers) {
+            return false;
+        }
+        if let Some(etag) = header(&self.headers, "etag") {
+            request
+                .headers
+                .insert("If-None-Match".into(), etag.to_string());
+        }
+        if let Some(modified) = header(&self.headers, "last-modified") {
+            request
+                .headers
+                .insert("If-Modified-Since".into(), modified.to_string());
+        }
+        true
+    }
+
+    /// Update the entry from a 304 that revalidated it (RFC 9111 §4.3.4).
+    pub(crate) fn refresh(&mut self, headers: &http::HeaderMap, now: SystemTime) {
+        for (name, value) in headers {
+            let skip = [
+                http::header::CONTENT_LENGTH,
+                http::header::CONTENT_ENCODING,
+                http::header::TRANSFER_ENCODING,
+            ];
+            if skip.contains(name) {
+                continue;
+            }
+            if let Ok(value) = value.to_str() {
+                self.headers.insert(name.to_string(), value.to_string());
+            }
+        }
+        self.stored_at = now;
+    }
+}
+
+/// The key of responses to `url` fetched under `profile`. Profiles have their own
+/// policies (e.g. headers or response limits), so they don't share entries. URLs contain
+/// no spaces, so a key can't be confused with another profile's.
+pub(crate) fn key(url: &str, profile: Option<&str>) -> String {
+    match profile {
+        Some(profile) => format!("{profile} {url}"),
+        None => url.to_string(),
+    }
+}
+
+/// Whether a request may be answered from, and stored in, the cache: a bodiless `GET`
+/// without credentials that doesn't forbid storing. A `Range` request is left out, as a
+/// stored full response doesn't answer it and its partial response mustn't replace one.
+pub(crate) fn is_cacheable_request(request: &FetchRequest, policy: &FetchPolicy) -> bool {
+    let credentialed = ["authorization", "cookie"].iter().any(|name| {
+        header(&request.headers, name).is_some() || header(&policy.default_headers, name).is_some()
+    });
+    request.method.eq_ignore_ascii_case("GET")
+        && request.body.is_none()
+        && !credentialed
+        && header(&request.headers, "range").is_none()
+        && !CacheControl::parse(&request.headers).no_store
+}
+
+/// What the cache holds for a request.
+pub(crate) enum Lookup {
+    /// A response that can be served as is.
+    Fresh(CachedResponse),
+    /// A response that must be revalidated before it is served.
+    Stale(CachedResponse),
+    Miss,
+}
+
+pub(crate) fn lookup(
+    store: &dyn CacheStore,
+    key: &str,
+    request: &FetchRequest,
+    now: SystemTime,
+) -> Lookup {
+    let Some(entry) = store.get(key).filter(|e| e.matches(&request.headers)) else {
+        return Lookup::Miss;
+    };
+    let cc = CacheControl::parse(&request.headers);
+    let acceptable_age = cc.max_age.is_none_or(|max| entry.age(now).as_secs() < max);
+    if entry.is_fresh(now) && !cc.no_cache && acceptable_age {
+        Lookup::Fresh(entry)
+    } else {
+        Lookup::Stale(entry)
+    }
+}
+
+/// Whether a final response may be stored: a cacheable status, storing not forbidden, and
+/// either a freshness lifetime or validators to revalidate it with.
+pub(crate) fn is_storable(status: u16, headers: &HashMap<String, String>) -> bool {
+    CACHEABLE_STATUSES.contains(&status)
+        && !CacheControl::parse(headers).no_store
+        && header(headers, "vary").is_none_or(|vary| vary.trim() != "*")
+        && (freshness_lifetime(headers, SystemTime::now()).is_some() || has_validators(headers))
+}
+
+/// Collects a response body as it is read and stores the response once it is complete.
+pub(crate) struct CacheWriter {
+    store: Arc<dyn CacheStore>,
+    key: String,
+    entry: CachedResponse,
+    body: BytesMut,
+}
+
+impl CacheWriter {
+    /// `entry` is the response without its body; `request` supplies the `Vary` values.
+    pub(crate) fn new(
+        store: Arc<dyn CacheStore>,
+        key: String,
+        mut entry: CachedResponse,
+        request: &FetchRequest,
+    ) -> Self {
+        if let Some(vary) = header(&entry.headers, "vary") {
+            entry.vary = vary
+                .split(',')
+                .map(|name| name.trim().to_ascii_lowercase())
+                .filter_map(|name| {
+                    let value = header(&request.headers, &name)?.to_string();
+                    Some((name, value))
+                })
+                .collect();
+        }
+        Self {
+            store,
+            key,
+            entry,
+            body: BytesMut::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, chunk: &[u8]) {
+        self.body.extend_from_slice(chunk);
+    }
+
+    pub(crate) fn finish(self) {
+        let entry = CachedResponse {
+            body: self.body.freeze(),
+            ..self.entry
+        };
+        self.store.put(&self.key, entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_retry_after_in_both_forms() {
+        let now = parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        let headers = |value: &str| HashMap::from([("retry-after".to_string(), value.to_string())]);
+        assert_eq!(
+            retry_after(&headers("120"), now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            retry_after(&headers("Sun, 06 Nov 1994 08:50:07 GMT"), now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            retry_after(&headers("Sun, 06 Nov 1994 08:00:00 GMT"), now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after(&headers("soon"), now), None);
+        assert_eq!(retry_after(&HashMap::new(), now), None);
+    }
+
+    #[test]
+    fn lru_evicts_least_recently_used() {
+        let response = |body: &'static [u8]| CachedResponse {
+            body: Bytes::from_static(body),
+            ..entry(&[], SystemTime::UNIX_EPOCH)
+        };
+        let store = LruCacheStore::new(2, 25);
+        store.put("a", response(b"0123456789"));
+        store.put("b", response(b"0123456789"));
+        assert!(store.get("a").is_some());
+        // Over the entry limit: "b" is the least recently used.
+        store.put("c", response(b"0123456789"));
+        assert!(store.get("b").is_none());
+        assert!(store.get("a").is_some());
+        // Over the byte limit: "c" goes, then "a".
+        store.put("d", response(b"01234567890123456789"));
+        assert!(store.get("a").is_none());
+        assert!(store.get("d").is_some());
+        // Larger than the whole cache.
+        store.put("e", response(&[0; 26]));
+        assert!(store.get("e").is_none());
+
+        assert_eq!(
+            store.stats(),
+            CacheStats {
+                entries: 1,
+                bytes: 20,
+                hits: 3,
+                misses: 3,
+                evictions: 3,
+                rejected: 1,
+            }
+        );
+        store.remove("d");
+        assert_eq!(store.stats().bytes, 0);
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn entry(pairs: &[(&str, &str)], stored_at: SystemTime) -> CachedResponse {
+        CachedResponse {
+            url: "https://example.com/".into(),
+            status: 200,
+            headers: headers(pairs),
+            body: Bytes::from_static(b"hello"),
+            version: http::Version::HTTP_11,
+            stored_at,
+            vary: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn parses_http_dates() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777))
+        );
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("0"), None);
+    }
+
+    #[test]
+    fn freshness_from_max_age_and_expires() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let later = |secs| now + Duration::from_secs(secs);
+
+        let e = entry(&[("cache-control", "public, max-age=60")], now);
+        assert!(e.is_fresh(later(59)));
+        assert!(!e.is_fresh(later(60)));
+
+        // `Age` counts toward the lifetime; `max-age` wins over `Expires`.
+        let e = entry(
+            &[
+                ("cache-control", "max-age=60"),
+                ("age", "50"),
+                ("expires", "Thu, 01 Jan 2099 00:00:00 GMT"),
+            ],
+            now,
+        );
+        assert!(!e.is_fresh(later(10)));
+
+        let e = entry(
+            &[
+                ("date", "Mon, 12 Jan 1970 13:46:40 GMT"),
+                ("expires", "Mon, 12 Jan 1970 13:48:20 GMT"),
+            ],
+            now,
+        );
+        assert!(e.is_fresh(later(99)));
+        assert!(!e.is_fresh(later(100)));
+
+        assert!(!entry(&[("expires", "0")], now).is_fresh(now));
+        assert!(!entry(&[("cache-control", "no-cache, max-age=60")], now).is_fresh(now));
+        assert!(!entry(&[("etag", "\"v1\"")], now).is_fresh(now));
+    }
+
+    #[test]
+    fn storability() {
+        assert!(is_storable(
+            200,
+            &headers(&[("cache-control", "max-age=60")])
+        ));
+        assert!(is_storable(404, &headers(&[("etag", "\"v1\"")])));
+        assert!(!is_storable(200, &headers(&[])));
+        assert!(!is_storable(
+            302,
+            &headers(&[("cache-control", "max-age=60")])
+        ));
+        assert!(!is_storable(
+            500,
+            &headers(&[("cache-control", "max-age=60")])
+        ));
+        assert!(!is_storable(
+            200,
+            &headers(&[("cache-control", "no-store, max-age=60")])
+        ));
+        assert!(!is_storable(
+            200,
+            &headers(&[("cache-control", "max-age=60"), ("vary", "*")])
+        ));
+    }
+
+    #[test]
+    fn vary_selects_by_request_headers() {
+        let mut e = entry(&[("vary", "Accept-Language")], SystemTime::UNIX_EPOCH);
+        e.vary.insert("accept-language".into(), "en".into());
+        assert!(e.matches(&headers(&[("Accept-Language", "en")])));
+        assert!(!e.matches(&headers(&[("Accept-Language", "de")])));
+        assert!(!e.matches(&headers(&[])));
+    }
+
+    #[test]
+    fn only_plain_gets_are_cacheable() {
+        let policy = FetchPolicy::default();
+        let get = FetchRequest {
+            url: "https://example.com/".into(),
+            ..Default::default()
+        };
+        assert!(is_cacheable_request(&get, &policy));
+        assert!(!is_cacheable_request(
+            &FetchRequest {
+                method: "POST".into(),
+                ..get.clone()
+            },
+            &policy
+        ));
+        assert!(!is_cacheable_request(
+            &FetchRequest {
+                headers: headers(&[("Authorization", "Bearer x")]),
+                ..get.clone()
+            },
+            &policy
+        ));
+        assert!(!is_cacheable_request(
+            &FetchRequest {
+                headers: headers(&[("Cache-Control", "no-store")]),
+                ..get.clone()
+            },
+            &policy
+        ));
+        assert!(!is_cacheable_request(
+            &FetchRequest {
+                headers: headers(&[("Range", "bytes=1-")]),
+                ..get.clone()
+            },
+            &policy
+        ));
+    }
+}
@@ -1,24 +1,120 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
-use bytes::Bytes;
 use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use rustls::sign::CertifiedKey;
+use rustls::RootCertStore;
+use serde::de::DeserializeOwned;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::error::Elapsed;
+use tokio_util::sync::CancellationToken;
+use tower_layer::Layer;
+use tower_service::Service;
 
+use crate::approval::{Approval, ApprovalHandler, ApprovalRequest};
+use crate::body::FetchBody;
+use crate::budget::TransferBudget;
+use crate::cache::{self, CacheStore, CacheWriter, CachedResponse, Lookup, LruCacheStore};
+use crate::clock::{Clock, SystemClock};
+use crate::coalesce::{self, Call, InFlight};
+use crate::conditional::{MemoryValidatorStore, ValidatorStore};
+use crate::content::{self, decode_text, parse_content_type};
+use crate::decision::Decision;
+use crate::decompress::ACCEPT_ENCODING;
 use crate::dns::SafeDnsResolver;
 use crate::error::FetchError;
-use crate::policy::FetchPolicy;
-use crate::rate_limit::RateLimiter;
-use crate::url_check::{validate_url, ValidatedUrl};
+use crate::feed::DomainBlocklist;
+use crate::hook::{PolicyHook, RequestContext, Verdict};
+use crate::memory::BufferBudget;
+use crate::policy::{FetchPolicy, HttpVersion, ProxyPolicy, RedirectMode};
+use crate::rate_limit::{self, Permit, RateLimitBackend, RateLimiter, ServerRateLimit};
+use crate::stream::{BodyReader, StreamingResponse};
+use crate::timing::{self, ConnectTimer, FetchTimings};
+use crate::tls::{self, TlsDetails};
+use crate::url_check::{apply_query_policy, upgrade_insecure, validate_url, ValidatedUrl};
+
+/// The longest a host's `Retry-After` or rate-limit reset pauses requests to it, so a
+/// misbehaving server can't shut a host out for good.
+const MAX_SERVER_PAUSE: Duration = Duration::from_secs(86_400);
 
 /// A request to be executed by the safe client.
 #[derive(Debug, Clone)]
 pub struct FetchRequest {
     pub url: String,
+    /// Alternative URLs for the same resource, tried in order while the earlier ones fail
+    /// with a [retryable](FetchError::is_retryable) error. Each is validated against the
+    /// policy on its own.
+    pub mirrors: Vec<String>,
     pub method: String,
     pub headers: HashMap<String, String>,
-    pub body: Option<Vec<u8>>,
+    pub body: Option<FetchBody>,
+    /// Name of a policy profile registered with [`SafeClient::add_profile`]. `None` uses the
+    /// client's main policy.
+    pub profile: Option<String>,
+    /// Set to [`RedirectMode::Manual`] to get redirects back instead of following them,
+    /// whatever the policy's `redirect_mode`. `None` uses the policy's mode.
+    pub redirect: Option<RedirectMode>,
+    /// Cancelling this token aborts the fetch, including reading the body, with
+    /// [`FetchError::Cancelled`]. The concurrency slot and any partly read body are released
+    /// right away.
+    pub cancel: Option<CancellationToken>,
+    /// Overall timeout for this request, if shorter than the policy's `request_timeout_ms`.
+    pub request_timeout_ms: Option<u64>,
+    /// Connect timeout for this request, if shorter than the policy's `connect_timeout_ms`.
+    pub connect_timeout_ms: Option<u64>,
+    /// Response-size limit for this request, if lower than the policy's limit for the
+    /// domain.
+    pub max_response_body_bytes: Option<usize>,
+    /// How much this request counts against each rate-limit window and quota (default: 1),
+    /// e.g. an API's cost in points for the query being sent. Every attempt, redirect and
+    /// retry costs as much again. A cost of 0 counts as 1.
+    pub rate_limit_cost: u32,
+}
+
+impl Default for FetchRequest {
+    /// An empty `GET` request; set at least `url`.
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            mirrors: Vec::new(),
+            method: "GET".into(),
+            headers: HashMap::new(),
+            body: None,
+            profile: None,
+            redirect: None,
+            cancel: None,
+            request_timeout_ms: None,
+            connect_timeout_ms: None,
+            max_response_body_bytes: None,
+            rate_limit_cost: 1,
+        }
+    }
+}
+
+impl FetchRequest {
+    /// Send `fields` as an `application/x-www-form-urlencoded` body, replacing any body
+    /// and `Content-Type` already set.
+    pub fn form<K: AsRef<str>, V: AsRef<str>>(self, fields: &[(K, V)]) -> Self {
+        let body = url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(fields)
+            .finish();
+        self.with_body("application/x-www-form-urlencoded", body.into())
+    }
+
+    pub(crate) fn with_body(mut self, content_type: impl Into<String>, body: FetchBody) -> Self {
+        self.headers
+            .retain(|name, _| !name.eq_ignore_ascii_case("content-type"));
+        self.headers
+            .insert("Content-Type".into(), content_type.into());
+        self.body = Some(body);
+        self
+    }
 }
 
 /// The response returned by the safe client.
@@ -27,114 +123,1779 @@ pub struct FetchResponse {
     pub status: u16,
     pub headers: HashMap<String, String>,
     pub body: Vec<u8>,
+    /// The URL the response was served from, after redirects.
+    pub url: String,
+    /// The redirects followed to get here, in order.
+    pub redirects: Vec<RedirectHop>,
+    /// The HTTP version negotiated for the final response.
+    pub version: http::Version,
+    pub timings: FetchTimings,
+    /// The delay the response's `Retry-After` header asks for, e.g. on a 429 or 503.
+    pub retry_after: Option<Duration>,
+    /// The server's own rate limit, if the response reports it.
+    pub rate_limit: Option<ServerRateLimit>,
+    /// The TLS session of the final response, under `capture_tls_details`. `None` for
+    /// plain `http` and responses served from the cache.
+    pub tls: Option<TlsDetails>,
+}
+
+impl FetchResponse {
+    /// The body decoded as text, using the `charset` of the `Content-Type` header (UTF-8
+    /// if absent or unknown).
+    pub fn text(&self) -> String {
+        let charset = self
+            .headers
+            .get("content-type")
+            .and_then(|value| parse_content_type(value).1);
+        decode_text(&self.body, charset.as_deref())
+    }
+
+    /// The body parsed as JSON.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, FetchError> {
+        let body = self
+            .body
+            .strip_prefix(b"\xef\xbb\xbf")
+            .unwrap_or(&self.body);
+        serde_json::from_slice(body).map_err(|e| FetchError::InvalidJson(e.to_string()))
+    }
+}
+
+/// A redirect followed on the way to a response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedirectHop {
+    /// The URL that answered with the redirect.
+    pub url: String,
+    pub status: u16,
+}
+
+/// Outcome of a successful [`SafeClient::check`]: the request as it would be sent.
+#[derive(Debug, Clone)]
+pub struct ValidationReport {
+    /// The normalized URL, after query-parameter stripping.
+    pub url: String,
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+    pub method: String,
+    /// Decisions of the scheme, domain, time-window, path, URL, port, and method checks, in
+    /// that order.
+    pub decisions: Vec<Decision>,
+    /// Validated addresses the request would connect to, if DNS resolution was requested.
+    pub resolved_addrs: Option<Vec<SocketAddr>>,
 }
 
-/// Custom DNS resolver that pins connections to pre-validated IP addresses.
-/// This defeats DNS rebinding attacks by resolving once through our safe resolver
-/// and then feeding those addresses to reqwest.
-struct PinnedResolver {
-    addrs: Vec<SocketAddr>,
+/// reqwest DNS resolver that resolves through our safe resolver, so every connection the
+/// pool opens goes to an address validated at connect time. This defeats DNS rebinding:
+/// there is no gap between checking an address and connecting to it, and kept-alive
+/// connections are only ever reused for the host they were validated for.
+struct ValidatingResolver {
+    dns: SafeDnsResolver,
+    /// The configured proxies' hosts, resolved without the IP checks.
+    proxies: Vec<String>,
+    /// Where the pool's connections may be pinned to, by host.
+    peers: Peers,
 }
 
-impl Resolve for PinnedResolver {
-    fn resolve(&self, _name: Name) -> Resolving {
-        let addrs: Vec<SocketAddr> = self.addrs.clone();
+impl Resolve for ValidatingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let dns = self.dns.clone();
+        let peers = self.peers.clone();
+        let trusted = self
+            .proxies
+            .iter()
+            .any(|proxy| proxy.eq_ignore_ascii_case(name.as_str()));
         Box::pin(async move {
+            let addrs = if trusted {
+                timing::time_dns(dns.resolve_trusted(name.as_str(), 0)).await?
+            } else {
+                let addrs = timing::time_dns(dns.resolve(name.as_str(), 0)).await?;
+                let latest = addrs.iter().map(SocketAddr::ip).take(MAX_PEERS_PER_HOST);
+                (peers.lock().unwrap()).insert(name.as_str().to_string(), latest.collect());
+                addrs
+            };
             let iter: Addrs = Box::new(addrs.into_iter());
             Ok(iter)
         })
     }
 }
 
-/// The safe HTTP client that enforces all policies.
-pub struct SafeClient {
+tokio::task_local! {
+    /// Connect timeout of the request being sent on this task, when it sets its own.
+    static CONNECT_TIMEOUT: Duration;
+    /// How long the response being fetched on this task may take to read, for a long-lived
+    /// body such as an event stream. Replaces the request timeout.
+    pub(crate) static LIFETIME: Duration;
+}
+
+/// Connector layer that applies [`FetchRequest::connect_timeout_ms`]. The policy's
+/// connect timeout still applies on top of it.
+#[derive(Clone)]
+struct RequestConnectTimeout;
+
+impl<S> Layer<S> for RequestConnectTimeout {
+    type Service = ConnectWithTimeout<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ConnectWithTimeout { inner }
+    }
+}
+
+#[derive(Clone)]
+struct ConnectWithTimeout<S> {
+    inner: S,
+}
+
+impl<S, R> Service<R> for ConnectWithTimeout<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+    S::Error: From<Elapsed>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let fut = self.inner.call(request);
+        let timeout = CONNECT_TIMEOUT.try_with(|timeout| *timeout).ok();
+        Box::pin(async move {
+            match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, fut)
+                    .await
+                    .unwrap_or_else(|elapsed| Err(elapsed.into())),
+                None => fut.await,
+            }
+        })
+    }
+}
+
+/// The policy and the components derived from it. Swapped as a unit on policy updates so
+/// an in-flight request always sees a consistent view.
+struct ClientState {
     policy: FetchPolicy,
     dns_resolver: SafeDnsResolver,
-    rate_limiter: RateLimiter,
+    /// The `root_certificates`, with the system's unless `use_system_roots` is off, or why
+    /// they couldn't be loaded.
+    roots: Result<Arc<RootCertStore>, String>,
+    /// The identity of each of `client_certificates`, or why it couldn't be loaded.
+    identities: Vec<Result<Arc<CertifiedKey>, String>>,
+    /// The hashes of each of `certificate_pins`, or why they are invalid.
+    pins: Vec<Result<Arc<tls::PinSet>, String>>,
+    /// Connection pools by the client certificate and pins of the hosts they serve. Built on
+    /// first use, and again when their peers fail re-validation.
+    pools: Mutex<HashMap<PoolKey, Pools>>,
+    /// Whether `https` requests are sent over HTTP/3.
+    http3: bool,
+    /// Per-origin slots enforcing `http2_max_concurrent_streams`, for origins with requests
+    /// holding or waiting for one.
+    streams: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
 }
 
-impl SafeClient {
-    pub fn new(policy: FetchPolicy) -> Self {
-        let dns_resolver = SafeDnsResolver::new(policy.deny_private_ips);
-        let rate_limiter = RateLimiter::new(
-            policy.max_requests_per_minute,
-            policy.max_concurrent_requests,
-        );
+/// Indexes into `client_certificates` and `certificate_pins`.
+type PoolKey = (Option<usize>, Option<usize>);
+
+/// The checked addresses last handed to a pool's connector, by host: the latest connection
+/// opened for the host is pinned to one of them. Replaced on every lookup, so hosts with
+/// rotating DNS don't collect every address they ever answered with.
+type Peers = Arc<Mutex<HashMap<String, HashSet<IpAddr>>>>;
 
+/// The most addresses kept in [`Peers`] per host.
+const MAX_PEERS_PER_HOST: usize = 16;
+
+/// The reqwest clients for one TLS client identity and set of pins, or none. Their pools
+/// key connections by scheme, host, and port, so a connection is never reused for another
+/// host, even one that resolves to the same address.
+#[derive(Clone)]
+struct Pools {
+    /// Connection pool shared by every request it serves.
+    http: reqwest::Client,
+    /// HTTP/1.1-only client for WebSocket handshakes, which can't be made over HTTP/2.
+    ws_http: reqwest::Client,
+    /// The TLS configuration of `http`, sharing its session cache.
+    tls: Arc<rustls::ClientConfig>,
+    /// The pins `http` and `ws_http` check servers' certificates against.
+    pins: Option<Arc<tls::PinSet>>,
+    /// Where the connections of both clients are pinned to.
+    peers: Peers,
+    /// When the pools were built or their peers last re-validated. Once this is older than
+    /// `connection_revalidate_ms`, the peers are checked again before connections are reused.
+    built: Instant,
+}
+
+impl ClientState {
+    fn new(policy: FetchPolicy, dns_resolver: SafeDnsResolver) -> Self {
+        // QUIC can't go through an HTTP proxy.
+        let http3 = cfg!(feature = "http3")
+            && policy.allows_http_version(HttpVersion::Http3)
+            && policy.proxy.is_none();
+        let identities = policy
+            .client_certificates
+            .iter()
+            .map(|cert| {
+                tls::load_identity(cert)
+                    .map_err(|e| format!("client certificate for {}: {e}", cert.pattern.0))
+            })
+            .collect();
+        let pins = policy
+            .certificate_pins
+            .iter()
+            .map(|pin| tls::PinSet::new(pin).map(Arc::new))
+            .collect();
         Self {
+            roots: tls::root_store(&policy.root_certificates, policy.use_system_roots)
+                .map(Arc::new),
+            identities,
+            pins,
+            pools: Mutex::new(HashMap::new()),
             policy,
             dns_resolver,
+            http3,
+            streams: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The connection pools for requests to `host`: those presenting the first of
+    /// `client_certificates` matching it, if any, and checking the first of
+    /// `certificate_pins` matching it, if any.
+    ///
+    /// Pools older than `connection_revalidate_ms` have their peers re-validated: each host
+    /// they connected to is resolved and checked again, and the addresses it last answered
+    /// with must still be among the answers. If any isn't, the pools are replaced,
+    /// so requests stop reusing their connections. Requests still in flight keep the old
+    /// connections until they finish.
+    async fn pools_for(&self, host: &str) -> Result<Pools, FetchError> {
+        let policy = &self.policy;
+        let cert = (policy.client_certificates.iter()).position(|c| c.pattern.matches(host));
+        let pin = (policy.certificate_pins.iter()).position(|p| p.pattern.matches(host));
+        let max_age = policy.connection_revalidate_ms.map(Duration::from_millis);
+        let current = self.pools.lock().unwrap().get(&(cert, pin)).cloned();
+        let stale = match current {
+            Some(pools) if max_age.is_none_or(|max_age| pools.built.elapsed() < max_age) => {
+                return Ok(pools)
+            }
+            Some(pools) if self.peers_check_out(&pools.peers).await => {
+                let mut all = self.pools.lock().unwrap();
+                if let Some(current) = all.get_mut(&(cert, pin)) {
+                    if Arc::ptr_eq(&current.peers, &pools.peers) {
+                        current.built = Instant::now();
+                    }
+                }
+                return Ok(pools);
+            }
+            stale => stale,
+        };
+        let mut all = self.pools.lock().unwrap();
+        // Another request may have replaced them meanwhile.
+        if let Some(current) = all.get(&(cert, pin)) {
+            if stale.is_none_or(|stale| !Arc::ptr_eq(&current.peers, &stale.peers)) {
+                return Ok(current.clone());
+            }
+        }
+        let built = self
+            .build_pools(cert, pin)
+            .map_err(FetchError::InvalidPolicy)?;
+        all.insert((cert, pin), built.clone());
+        Ok(built)
+    }
+
+    /// Whether each host in `peers` still resolves, passes the IP checks, and answers with
+    /// every address it last answered with.
+    async fn peers_check_out(&self, peers: &Peers) -> bool {
+        let peers = peers.lock().unwrap().clone();
+        for (host, pinned) in peers {
+            let Ok(addrs) = self.dns_resolver.resolve(&host, 0).await else {
+                return false;
+            };
+            if !pinned
+                .iter()
+                .all(|ip| addrs.iter().any(|addr| addr.ip() == *ip))
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn build_pools(&self, cert: Option<usize>, pin: Option<usize>) -> Result<Pools, String> {
+        let policy = &self.policy;
+        let roots = self.roots.as_ref().map_err(Clone::clone)?;
+        let identity = cert
+            .map(|i| self.identities[i].as_ref().map_err(Clone::clone))
+            .transpose()?;
+        let pins = pin
+            .map(|i| self.pins[i].as_ref().map_err(Clone::clone))
+            .transpose()?;
+        // The TLS configuration is built here rather than by reqwest, so the connections'
+        // handshakes can be timed. It offers the protocols reqwest would.
+        let config = |alpn: &[&[u8]]| {
+            tls::client_config(
+                roots.clone(),
+                identity.cloned(),
+                policy.min_tls_version,
+                alpn,
+            )
+        };
+        let peers = Peers::default();
+        let builder = |tls: rustls::ClientConfig| {
+            let mut builder = reqwest::Client::builder()
+                .dns_resolver(Arc::new(ValidatingResolver {
+                    dns: self.dns_resolver.clone(),
+                    proxies: policy.proxy.iter().flat_map(ProxyPolicy::hosts).collect(),
+                    peers: peers.clone(),
+                }))
+                .connect_timeout(Duration::from_millis(policy.connect_timeout_ms))
+                .tls_backend_preconfigured(tls)
+                .redirect(reqwest::redirect::Policy::none())
+                .connector_layer(ConnectTimer)
+                .connector_layer(RequestConnectTimeout);
+            if let Some(pins) = pins {
+                builder = builder.connector_layer(tls::CheckPins(pins.clone()));
+            }
+            builder = builder.tls_info(pins.is_some() || policy.capture_tls_details);
+            // Proxy environment variables are ignored: only the policy picks a proxy.
+            match &policy.proxy {
+                Some(proxy) => builder.proxy(proxy_for(proxy)),
+                None => builder.no_proxy(),
+            }
+        };
+        let http1_only = !policy.http2 || !policy.allows_http_version(HttpVersion::Http2);
+        let prior_knowledge = !http1_only
+            && (policy.http2_prior_knowledge || !policy.allows_http_version(HttpVersion::Http1));
+        let tls = Arc::new(if http1_only {
+            config(&[b"http/1.1"])
+        } else if prior_knowledge {
+            config(&[b"h2"])
+        } else {
+            config(&[b"h2", b"http/1.1"])
+        });
+        let http = builder(rustls::ClientConfig::clone(&tls));
+        let http = if http1_only {
+            http.http1_only()
+        } else if prior_knowledge {
+            http.http2_prior_knowledge()
+        } else {
+            http
+        };
+        let failed = |e: reqwest::Error| format!("failed to initialize HTTP client: {e}");
+        Ok(Pools {
+            http: (http.timeout(Duration::from_millis(policy.request_timeout_ms)))
+                .build()
+                .map_err(failed)?,
+            // No client-wide timeout: it would cut the upgraded connection off.
+            ws_http: builder(config(&[b"http/1.1"]))
+                .http1_only()
+                .build()
+                .map_err(failed)?,
+            tls,
+            pins: pins.cloned(),
+            peers,
+            built: Instant::now(),
+        })
+    }
+
+    /// Wait up to `wait` for a slot to send a request to `url`'s origin under
+    /// `http2_max_concurrent_streams`. `None` if no cap applies.
+    async fn stream_slot(
+        &self,
+        url: &url::Url,
+        wait: Duration,
+    ) -> Result<Option<StreamSlot>, FetchError> {
+        let http2 = self.policy.http2 && self.policy.allows_http_version(HttpVersion::Http2);
+        let Some(max) = self.policy.http2_max_concurrent_streams.filter(|_| http2) else {
+            return Ok(None);
+        };
+        let origin = url.origin().ascii_serialization();
+        let slots = self
+            .streams
+            .lock()
+            .unwrap()
+            .entry(origin.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(max as usize)))
+            .clone();
+        let acquired = tokio::time::timeout(wait, slots.acquire_owned()).await;
+        let slot = StreamSlot {
+            permit: acquired.ok().and_then(Result::ok),
+            origin,
+            streams: self.streams.clone(),
+        };
+        if slot.permit.is_none() {
+            return Err(FetchError::RequestTimeout);
+        }
+        Ok(Some(slot))
+    }
+}
+
+/// A request's slot under `http2_max_concurrent_streams`. The origin's entry is removed
+/// once the last request holding or waiting for a slot lets go.
+pub(crate) struct StreamSlot {
+    /// `None` for a wait that timed out, which only prunes the entry.
+    permit: Option<OwnedSemaphorePermit>,
+    origin: String,
+    streams: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        let mut streams = self.streams.lock().unwrap();
+        drop(self.permit.take());
+        // Others take the lock to join, so only the map's reference left means nobody has.
+        if streams
+            .get(&self.origin)
+            .is_some_and(|slots| Arc::strong_count(slots) == 1)
+        {
+            streams.remove(&self.origin);
+        }
+    }
+}
+
+/// How long a connection attempt runs before the next address is tried alongside it, as
+/// the pools' connector waits before falling back to the other family.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(300);
+
+/// A TCP connection to the first of `addrs` that accepts one, racing them as Happy Eyeballs
+/// does (RFC 8305 §5): the next address is tried once the previous attempt fails or has run
+/// for [`CONNECTION_ATTEMPT_DELAY`]. `addrs` come from the resolver with the families
+/// interleaved, so an unreachable family can't use up the connect timeout alone.
+async fn connect_any(addrs: &[SocketAddr]) -> std::io::Result<tokio::net::TcpStream> {
+    let mut last = std::io::Error::other("no addresses to connect to");
+    let mut attempts = tokio::task::JoinSet::new();
+    let mut next = addrs.iter().copied();
+    loop {
+        match next.next() {
+            Some(addr) => {
+                attempts.spawn(tokio::net::TcpStream::connect(addr));
+            }
+            None if attempts.is_empty() => return Err(last),
+            None => {}
+        }
+        let finished = match next.len() {
+            0 => attempts.join_next().await,
+            _ => match tokio::time::timeout(CONNECTION_ATTEMPT_DELAY, attempts.join_next()).await {
+                Ok(finished) => finished,
+                Err(_) => continue,
+            },
+        };
+        match finished {
+            Some(Ok(Ok(stream))) => return Ok(stream),
+            Some(Ok(Err(e))) => last = e,
+            Some(Err(e)) => last = std::io::Error::other(e),
+            None => {}
+        }
+    }
+}
+
+/// `proxy` as a reqwest proxy, picking the proxy for each destination.
+fn proxy_for(proxy: &ProxyPolicy) -> reqwest::Proxy {
+    let proxy = proxy.clone();
+    reqwest::Proxy::custom(move |url| proxy.url_for(url.host_str()?))
+}
+
+/// The answer to a WebSocket handshake, from [`SafeClient::handshake`].
+pub(crate) struct Handshake<'a> {
+    pub(crate) response: reqwest::Response,
+    pub(crate) host: String,
+    pub(crate) budget: &'a TransferBudget,
+    pub(crate) permit: Permit<'a>,
+}
+
+/// The safe HTTP client that enforces all policies.
+///
+/// Besides its main policy, a client can hold named policy profiles (e.g. "browsing",
+/// "downloads") selected per request via [`FetchRequest::profile`]. Profiles share the
+/// client's rate limiter and DNS resolver; the main policy's rate limits apply to all.
+pub struct SafeClient {
+    state: RwLock<Arc<ClientState>>,
+    profiles: RwLock<HashMap<String, Arc<ClientState>>>,
+    rate_limiter: Arc<RateLimiter>,
+    /// Set when `rate_limiter` came from [`with_rate_limiter`](Self::with_rate_limiter), so
+    /// this client's policy doesn't configure it.
+    shared_rate_limiter: bool,
+    budget: TransferBudget,
+    buffers: BufferBudget,
+    hooks: Vec<Arc<dyn PolicyHook>>,
+    approval: Option<Arc<dyn ApprovalHandler>>,
+    /// Hosts granted a time-limited allowlist exception, keyed by profile and host, with
+    /// the grant's expiry (`None` if it's too far off to represent).
+    approved: Mutex<HashMap<ApprovalKey, Option<Instant>>>,
+    blocklists: RwLock<HashMap<String, Arc<DomainBlocklist>>>,
+    clock: Arc<dyn Clock>,
+    validators: Arc<dyn ValidatorStore>,
+    cache: Option<Arc<dyn CacheStore>>,
+    in_flight: InFlight,
+    /// Kill switch: while set, every fetch fails with `FetchError::Disabled`.
+    disabled: AtomicBool,
+}
+
+impl SafeClient {
+    pub fn new(policy: FetchPolicy) -> Self {
+        let dns_resolver = SafeDnsResolver::new(&policy);
+        let rate_limiter = Arc::new(RateLimiter::from_policy(&policy));
+        let budget = TransferBudget::new(policy.max_total_bytes, policy.max_total_bytes_per_domain);
+        let buffers = BufferBudget::new(policy.max_buffered_bytes);
+
+        Self {
+            state: RwLock::new(Arc::new(ClientState::new(policy, dns_resolver))),
+            profiles: RwLock::new(HashMap::new()),
             rate_limiter,
+            shared_rate_limiter: false,
+            budget,
+            buffers,
+            hooks: Vec::new(),
+            approval: None,
+            approved: Mutex::new(HashMap::new()),
+            blocklists: RwLock::new(HashMap::new()),
+            clock: Arc::new(SystemClock),
+            validators: Arc::new(MemoryValidatorStore::default()),
+            cache: None,
+            in_flight: InFlight::default(),
+            disabled: AtomicBool::new(false),
+        }
+    }
+
+    /// Use `clock` instead of the system clock for time-dependent rules.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Cache responses in `store`, honoring `Cache-Control`, `Expires`, and `Vary`.
+    ///
+    /// Only bodiless `GET`s without `Authorization` or `Cookie` headers are cached, and only
+    /// responses that weren't redirected. A fresh response is served after the policy checks
+    /// and hooks pass, without touching the network or the rate limits; a stale one with
+    /// validators is revalidated with a conditional request.
+    pub fn with_cache(mut self, store: impl CacheStore + 'static) -> Self {
+        self.cache = Some(Arc::new(store));
+        self
+    }
+
+    /// Cache responses as [`with_cache`](Self::with_cache) does, in an [`LruCacheStore`]
+    /// of the default size: 1000 entries and 64 MB.
+    pub fn with_memory_cache(self) -> Self {
+        self.with_cache(LruCacheStore::default())
+    }
+
+    /// Keep the validators used by [`fetch_conditional`](Self::fetch_conditional) in
+    /// `store` instead of in memory.
+    pub fn with_validator_store(mut self, store: impl ValidatorStore + 'static) -> Self {
+        self.validators = Arc::new(store);
+        self
+    }
+
+    pub(crate) fn validator_store(&self) -> &dyn ValidatorStore {
+        self.validators.as_ref()
+    }
+
+    /// Ask `handler` before rejecting a request whose domain isn't on the allowlist.
+    pub fn with_approval(mut self, handler: impl ApprovalHandler + 'static) -> Self {
+        self.approval = Some(Arc::new(handler));
+        self
+    }
+
+    /// Count requests against `limiter`, e.g. another client's
+    /// [`rate_limiter`](Self::rate_limiter), so clients with different policies share one
+    /// request budget and concurrency pool. The limiter keeps the limits it was given: this
+    /// client's policy doesn't change them, even on [`update_policy`](Self::update_policy).
+    pub fn with_rate_limiter(mut self, limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = limiter;
+        self.shared_rate_limiter = true;
+        self
+    }
+
+    /// The rate limiter this client's requests count against, to share with
+    /// [`with_rate_limiter`](Self::with_rate_limiter).
+    pub fn rate_limiter(&self) -> Arc<RateLimiter> {
+        self.rate_limiter.clone()
+    }
+
+    /// Keep the per-minute rate-limit windows in `backend`, so every client using it shares
+    /// them, e.g. workers in separate processes calling the same API.
+    pub fn with_rate_limit_backend(self, backend: impl RateLimitBackend + 'static) -> Self {
+        self.rate_limiter.set_backend(Arc::new(backend));
+        self
+    }
+
+    /// Save the rate-limit windows, quotas and paused hosts to `path`, and pick up what an
+    /// earlier process saved there, so a CLI run doesn't get a fresh daily quota each time.
+    /// See [`RateLimiter::persist_to`].
+    pub fn with_persistent_rate_limits(self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.rate_limiter.persist_to(path);
+        self
+    }
+
+    /// Withdraw the time-limited approvals for `host`, under every profile. Returns `true`
+    /// if one was active.
+    pub fn revoke_approval(&self, host: &str) -> bool {
+        let mut active = false;
+        self.approved.lock().unwrap().retain(|(_, h), expiry| {
+            if h != host {
+                return true;
+            }
+            active |= approval_active(*expiry);
+            false
+        });
+        active
+    }
+
+    /// Add a hook consulted after the built-in checks pass. Hooks run in the order they
+    /// were added, for every profile; the first to deny rejects the request.
+    pub fn with_hook(mut self, hook: impl PolicyHook + 'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Register (or replace) a named policy profile.
+    pub fn add_profile(&self, name: impl Into<String>, policy: FetchPolicy) {
+        let dns_resolver = self.state().dns_resolver.with_policy(&policy);
+        self.profiles.write().unwrap().insert(
+            name.into(),
+            Arc::new(ClientState::new(policy, dns_resolver)),
+        );
+    }
+
+    /// Remove a named policy profile, returning its policy if it existed.
+    pub fn remove_profile(&self, name: &str) -> Option<FetchPolicy> {
+        self.profiles
+            .write()
+            .unwrap()
+            .remove(name)
+            .map(|state| state.policy.clone())
+    }
+
+    /// Return a copy of a named profile's policy.
+    pub fn profile(&self, name: &str) -> Option<FetchPolicy> {
+        self.profiles
+            .read()
+            .unwrap()
+            .get(name)
+            .map(|state| state.policy.clone())
+    }
+
+    /// Return a copy of the policy currently in effect.
+    pub fn policy(&self) -> FetchPolicy {
+        self.state().policy.clone()
+    }
+
+    /// Atomically replace the policy. Requests already in flight finish under the old
+    /// policy; every later request sees the new one. Rate-limit windows and in-flight
+    /// permits carry over, with the new limits applied from now on, unless the rate limiter
+    /// is shared; so do the bytes counted against the transfer budget.
+    pub fn update_policy(&self, policy: FetchPolicy) {
+        let mut state = self.state.write().unwrap();
+        let dns_resolver = state.dns_resolver.with_policy(&policy);
+        if !self.shared_rate_limiter {
+            self.rate_limiter.set_policy(&policy);
+        }
+        self.budget
+            .set_limits(policy.max_total_bytes, policy.max_total_bytes_per_domain);
+        self.buffers.set_limit(policy.max_buffered_bytes);
+        *state = Arc::new(ClientState::new(policy, dns_resolver));
+    }
+
+    /// Register (or atomically replace) a named domain blocklist. Blocklists apply under
+    /// every profile, on top of each policy's `blocked_domains`.
+    pub fn set_blocklist(&self, name: impl Into<String>, list: DomainBlocklist) {
+        self.blocklists
+            .write()
+            .unwrap()
+            .insert(name.into(), Arc::new(list));
+    }
+
+    /// Remove a named blocklist. Returns `true` if it existed.
+    pub fn remove_blocklist(&self, name: &str) -> bool {
+        self.blocklists.write().unwrap().remove(name).is_some()
+    }
+
+    fn check_blocklists(&self, domain: &str) -> Result<(), FetchError> {
+        for (name, list) in self.blocklists.read().unwrap().iter() {
+            if let Some(entry) = list.find(domain) {
+                return Err(FetchError::DomainBlocked {
+                    domain: domain.to_string(),
+                    decision: Decision::deny(format!("blocklists[{name}]"), entry),
+                });
+            }
         }
+        Ok(())
+    }
+
+    /// Halt all egress: every later fetch, and every redirect hop of a fetch already in
+    /// progress, fails with `FetchError::Disabled` until [`enable`](Self::enable) is called.
+    pub fn disable(&self) {
+        self.disabled.store(true, Ordering::SeqCst);
+    }
+
+    /// Resume fetching after [`disable`](Self::disable).
+    pub fn enable(&self) {
+        self.disabled.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.disabled.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn ensure_enabled(&self) -> Result<(), FetchError> {
+        if self.disabled.load(Ordering::SeqCst) {
+            return Err(FetchError::Disabled);
+        }
+        Ok(())
+    }
+
+    /// Total response-body bytes read by this client so far, counted against
+    /// `max_total_bytes`.
+    pub fn bytes_transferred(&self) -> u64 {
+        self.budget.total()
+    }
+
+    fn state(&self) -> Arc<ClientState> {
+        self.state.read().unwrap().clone()
+    }
+
+    fn state_for(&self, profile: Option<&str>) -> Result<Arc<ClientState>, FetchError> {
+        match profile {
+            None => Ok(self.state()),
+            Some(name) => self
+                .profiles
+                .read()
+                .unwrap()
+                .get(name)
+                .cloned()
+                .ok_or_else(|| FetchError::UnknownProfile(name.to_string())),
+        }
+    }
+
+    /// A copy of a profile's policy, or of the main policy.
+    pub(crate) fn policy_for(&self, profile: Option<&str>) -> Result<FetchPolicy, FetchError> {
+        Ok(self.state_for(profile)?.policy.clone())
+    }
+
+    /// The response-size limit for `host` under a profile's policy.
+    pub(crate) fn response_body_limit(
+        &self,
+        profile: Option<&str>,
+        host: &str,
+    ) -> Result<usize, FetchError> {
+        Ok(self.state_for(profile)?.policy.response_body_limit(host))
     }
 
     /// Execute a fetch request through the full validation pipeline.
+    ///
+    /// With `upgrade_insecure_requests`, an `http` URL is fetched over `https` instead. If
+    /// that fails to connect and the host is in `insecure_fallback_domains`, the original
+    /// request is retried.
+    ///
+    /// With `coalesce_requests`, a `GET` identical to one already in flight waits for that
+    /// one's response instead of sending its own. It is still checked against the policy and
+    /// hooks first, as a cache hit is.
     pub async fn fetch(&self, request: FetchRequest) -> Result<FetchResponse, FetchError> {
-        let validated = validate_url(&request.url)?;
-        self.policy.check_scheme(&validated.scheme)?;
-        self.policy.check_domain(&validated.host)?;
-        self.policy.check_method(&request.method)?;
-
-        if let Some(ref body) = request.body {
-            if body.len() > self.policy.max_request_body_bytes {
-                return Err(FetchError::RequestBodyTooLarge {
-                    size: body.len(),
-                    limit: self.policy.max_request_body_bytes,
+        let key = coalesce::key(&request).filter(|_| {
+            self.state_for(request.profile.as_deref())
+                .is_ok_and(|state| state.policy.coalesce_requests)
+        });
+        let Some(key) = key else {
+            return self.fetch_once(request).await;
+        };
+        loop {
+            match self.in_flight.join(key.clone()) {
+                Call::Lead(leader) => {
+                    let result = self.fetch_once(request).await;
+                    leader.finish(&result);
+                    return result;
+                }
+                Call::Follow(follower) => {
+                    self.check(&request, false).await?;
+                    let outcome =
+                        cancellable(request.cancel.as_ref(), async { Ok(follower.wait().await) })
+                            .await?;
+                    // No outcome means the leader was cancelled: go again.
+                    if let Some(result) = outcome {
+                        self.ensure_enabled()?;
+                        return result;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn fetch_once(&self, request: FetchRequest) -> Result<FetchResponse, FetchError> {
+        let started = Instant::now();
+        let cancel = request.cancel.clone();
+        let (reader, timings) =
+            timing::measure(cancellable(cancel.as_ref(), self.send(request))).await;
+        let mut reader = reader?.cancel_on(cancel);
+        let status = reader.status();
+        let headers = reader.headers();
+        let retry_after = cache::retry_after(&headers, self.clock.now());
+        let rate_limit = ServerRateLimit::from_headers(&headers, self.clock.now());
+        let url = reader.url();
+        let version = reader.version();
+        let tls = reader.tls();
+        let redirects = reader.take_redirects();
+        let body_started = Instant::now();
+        // Room for the declared size is waited for; anything beyond it must fit at once,
+        // since waiting while holding bytes could deadlock fetches on each other.
+        let wait = Duration::from_millis(self.state().policy.request_timeout_ms);
+        let declared = reader.content_length().unwrap_or(0) as usize;
+        let mut buffered = self.buffers.reserve(declared, wait).await?;
+        let mut body = Vec::new();
+        while let Some(chunk) = reader.next_chunk().await? {
+            let size = body.len() + chunk.len();
+            if size > buffered.bytes() {
+                buffered.grow(size - buffered.bytes())?;
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(FetchResponse {
+            status,
+            headers,
+            body,
+            url,
+            redirects,
+            version,
+            timings: timings.finish(body_started.elapsed(), started.elapsed()),
+            retry_after,
+            rate_limit,
+            tls,
+        })
+    }
+
+    /// Fetch a JSON response and deserialize it. Fails with `UnexpectedStatus` unless the
+    /// status is 2xx, with `UnexpectedContentType` if the response declares a type other
+    /// than JSON, and with `InvalidJson` if the body doesn't parse as `T`.
+    pub async fn fetch_json<T: DeserializeOwned>(
+        &self,
+        request: FetchRequest,
+    ) -> Result<T, FetchError> {
+        self.fetch_expecting(request, "application/json", content::is_json)
+            .await?
+            .json()
+    }
+
+    /// Fetch a text response and decode it with [`FetchResponse::text`]. Fails with
+    /// `UnexpectedStatus` unless the status is 2xx, and with `UnexpectedContentType` if the
+    /// response declares a non-text type such as an image.
+    pub async fn fetch_text(&self, request: FetchRequest) -> Result<String, FetchError> {
+        Ok(self
+            .fetch_expecting(request, "text", content::is_text)
+            .await?
+            .text())
+    }
+
+    /// Fetch, then check that the status is 2xx and that the declared media type, if any,
+    /// is one `accepts`.
+    async fn fetch_expecting(
+        &self,
+        request: FetchRequest,
+        expected: &str,
+        accepts: fn(&str) -> bool,
+    ) -> Result<FetchResponse, FetchError> {
+        let response = self.fetch(request).await?;
+        if !(200..300).contains(&response.status) {
+            return Err(FetchError::UnexpectedStatus {
+                status: response.status,
+            });
+        }
+        if let Some(value) = response.headers.get("content-type") {
+            let (media_type, _) = parse_content_type(value);
+            if !accepts(&media_type) {
+                return Err(FetchError::UnexpectedContentType {
+                    expected: expected.into(),
+                    actual: media_type,
                 });
             }
         }
+        Ok(response)
+    }
 
-        let _permit = self.rate_limiter.acquire(&validated.host).await?;
+    /// Like [`fetch`](Self::fetch), but return as soon as the response headers arrive and
+    /// yield the body as a stream of chunks instead of buffering it.
+    pub async fn fetch_stream(
+        &self,
+        request: FetchRequest,
+    ) -> Result<StreamingResponse<'_>, FetchError> {
+        let started = Instant::now();
+        let cancel = request.cancel.clone();
+        let (reader, timings) =
+            timing::measure(cancellable(cancel.as_ref(), self.send(request))).await;
+        let mut reader = reader?.cancel_on(cancel);
+        Ok(StreamingResponse {
+            status: reader.status(),
+            headers: reader.headers(),
+            url: reader.url(),
+            redirects: reader.take_redirects(),
+            version: reader.version(),
+            timings: timings.finish(Duration::ZERO, started.elapsed()),
+            retry_after: cache::retry_after(&reader.headers(), self.clock.now()),
+            rate_limit: ServerRateLimit::from_headers(&reader.headers(), self.clock.now()),
+            tls: reader.tls(),
+            body: reader.into_stream(),
+        })
+    }
 
-        let port = validated.url.port_or_known_default().unwrap_or(443);
-        let addrs = self.dns_resolver.resolve(&validated.host, port).await?;
+    /// Send `request`, then its mirrors in turn while they fail with retryable errors.
+    pub(crate) async fn send(
+        &self,
+        mut request: FetchRequest,
+    ) -> Result<BodyReader<'_>, FetchError> {
+        let mirrors = std::mem::take(&mut request.mirrors);
+        // A streamed body is consumed by the first URL tried.
+        let streamed = matches!(request.body, Some(FetchBody::Stream { .. }));
+        // Boxed to keep the future of a fetch small.
+        let mut result = Box::pin(self.send_retrying(request.clone())).await;
+        for mirror in mirrors {
+            match &result {
+                Err(e) if e.is_retryable() && !streamed => {}
+                _ => break,
+            }
+            let request = FetchRequest {
+                url: mirror,
+                ..request.clone()
+            };
+            result = Box::pin(self.send_retrying(request)).await;
+        }
+        result
+    }
+
+    /// Send `request`, retrying it under the policy's `retry` settings.
+    async fn send_retrying(&self, request: FetchRequest) -> Result<BodyReader<'_>, FetchError> {
+        let state = self.state_for(request.profile.as_deref())?;
+        let retry = &state.policy.retry;
+        // A streamed body is consumed by the first attempt.
+        let retryable = retry.allows_method(&request.method)
+            && !matches!(request.body, Some(FetchBody::Stream { .. }));
+        let mut attempt = 1;
+        loop {
+            let result = self.send_hedged(&state, &request).await;
+            if let Ok(reader) = &result {
+                self.heed_server_limits(&state.policy, reader);
+            }
+            if !retryable || attempt >= retry.max_attempts {
+                return result;
+            }
+            let delay = match &result {
+                Err(e) if e.is_retryable() => retry.backoff(attempt),
+                Ok(reader) if matches!(reader.status(), 429 | 503) => {
+                    match cache::retry_after(&reader.headers(), self.clock.now()) {
+                        Some(delay) if delay > Duration::from_millis(retry.max_retry_after_ms) => {
+                            return result;
+                        }
+                        Some(delay) => delay,
+                        None => retry.backoff(attempt),
+                    }
+                }
+                _ => return result,
+            };
+            // Release the connection and concurrency slot while waiting.
+            drop(result);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Pause requests to the host that sent `reader` for as long as its rate-limit headers or
+    /// `Retry-After` ask, under `respect_server_rate_limits`.
+    fn heed_server_limits(&self, policy: &FetchPolicy, reader: &BodyReader<'_>) {
+        if !policy.respect_server_rate_limits {
+            return;
+        }
+        let headers = reader.headers();
+        let now = self.clock.now();
+        let retry_after =
+            cache::retry_after(&headers, now).filter(|_| matches!(reader.status(), 429 | 503));
+        let exhausted =
+            ServerRateLimit::from_headers(&headers, now).and_then(|l| l.exhausted_for());
+        let Some(pause) = retry_after.max(exhausted) else {
+            return;
+        };
+        let Ok(url) = url::Url::parse(&reader.url()) else {
+            return;
+        };
+        if let Some(host) = url.host_str() {
+            let host = host.to_lowercase();
+            let until = rate_limit::after(Instant::now(), pause.min(MAX_SERVER_PAUSE));
+            self.rate_limiter
+                .pause_host(host.trim_end_matches('.'), until);
+        }
+    }
+
+    /// Send `request`, and again after `hedge_after_ms` if it's a bodiless `GET` still
+    /// waiting for its response. The first response wins and the other request is dropped.
+    async fn send_hedged(
+        &self,
+        state: &ClientState,
+        request: &FetchRequest,
+    ) -> Result<BodyReader<'_>, FetchError> {
+        let delay = state
+            .policy
+            .hedge_after_ms
+            .filter(|_| request.method.eq_ignore_ascii_case("GET") && request.body.is_none());
+        let Some(delay) = delay else {
+            return self.send_once(state, request.clone()).await;
+        };
+        // Boxed, as a request future is too large to hold two of on the stack.
+        let mut first = Box::pin(self.send_once(state, request.clone()));
+        let mut second = Box::pin(async {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            self.send_once(state, request.clone()).await
+        });
+        // A failure that sending again could fix leaves the race to the other request.
+        tokio::select! {
+            result = &mut first => match result {
+                Err(e) if e.is_retryable() => second.await,
+                result => result,
+            },
+            result = &mut second => match result {
+                Err(e) if e.is_retryable() => first.await,
+                result => result,
+            },
+        }
+    }
+
+    async fn send_once(
+        &self,
+        state: &ClientState,
+        request: FetchRequest,
+    ) -> Result<BodyReader<'_>, FetchError> {
+        let upgraded = state
+            .policy
+            .upgrade_insecure_requests
+            .then(|| upgrade_insecure(&request.url))
+            .flatten();
+        let Some(upgraded) = upgraded else {
+            return self.send_with(state, request).await;
+        };
+        let fallback = upgraded.host_str().is_some_and(|host| {
+            state
+                .policy
+                .allows_insecure_fallback(host.trim_end_matches('.'))
+        });
+        let upgraded_request = FetchRequest {
+            url: upgraded.into(),
+            ..request.clone()
+        };
+        match self.send_with(state, upgraded_request).await {
+            Err(FetchError::ConnectionTimeout) if fallback => self.send_with(state, request).await,
+            result => result,
+        }
+    }
+
+    async fn send_with(
+        &self,
+        state: &ClientState,
+        mut request: FetchRequest,
+    ) -> Result<BodyReader<'_>, FetchError> {
+        self.ensure_enabled()?;
+        let (validated, _) = self.validate_request(state, &request, true).await?;
+        if matches!(validated.scheme.as_str(), "ws" | "wss") {
+            return Err(FetchError::InvalidUrl(
+                "ws and wss URLs are opened with SafeClient::websocket".into(),
+            ));
+        }
+
+        // Policy checks passed: a plain GET may now be answered from the cache.
+        let cache = self
+            .cache
+            .clone()
+            .filter(|_| cache::is_cacheable_request(&request, &state.policy));
+        let key = cache::key(validated.url.as_str(), request.profile.as_deref());
+        let mut revalidating = None;
+        if let Some(store) = &cache {
+            match cache::lookup(store.as_ref(), &key, &request, self.clock.now()) {
+                Lookup::Fresh(entry) => {
+                    return self.serve_cached(state, &request, &validated, entry).await
+                }
+                Lookup::Stale(entry) => {
+                    if entry.add_conditions(&mut request) {
+                        revalidating = Some(entry);
+                    }
+                }
+                Lookup::Miss => {}
+            }
+        }
+
+        self.budget.check(&validated.host, 0)?;
+
+        let permit = self
+            .rate_limiter
+            .acquire_weighted(&validated.host, request.rate_limit_cost)
+            .await?;
+
+        let addrs =
+            timing::time_dns(state.dns_resolver.resolve(&validated.host, validated.port)).await?;
+
+        self.run_hooks(&RequestContext {
+            url: validated.url.to_string(),
+            scheme: validated.scheme.clone(),
+            host: validated.host.clone(),
+            port: validated.port,
+            method: request.method.to_ascii_uppercase(),
+            headers: request.headers.clone(),
+            profile: request.profile.clone(),
+            resolved_addrs: addrs.clone(),
+            redirects_followed: 0,
+        })
+        .await?;
+
+        // Validation may have waited on an approval handler or hook; re-check before sending.
+        self.ensure_enabled()?;
+        let execute = async {
+            if state.policy.preflight_head && request.method.eq_ignore_ascii_case("GET") {
+                self.preflight(state, &request, &validated).await?;
+            }
+            self.execute_request(state, &request, &validated).await
+        };
+        let (mut response, host, redirects, slot) = match request.connect_timeout_ms {
+            Some(ms) => {
+                CONNECT_TIMEOUT
+                    .scope(Duration::from_millis(ms), execute)
+                    .await?
+            }
+            None => execute.await?,
+        };
+
+        if let Some(store) = &self.cache {
+            if let (Some(mut entry), 304) = (revalidating, response.status().as_u16()) {
+                entry.refresh(response.headers(), self.clock.now());
+                store.put(&key, entry.clone());
+                return self.cached_reader(state, &request, host, entry);
+            }
+            // A successful unsafe request invalidates what is stored for its URL, under
+            // every profile.
+            let safe = ["GET", "HEAD", "OPTIONS"]
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(&request.method));
+            if !safe && response.status().as_u16() < 400 {
+                let url = validated.url.as_str();
+                store.remove(&cache::key(url, None));
+                for profile in self.profiles.read().unwrap().keys() {
+                    store.remove(&cache::key(url, Some(profile)));
+                }
+            }
+        }
+
+        if !is_redirect(response.status()) {
+            check_content_type(&state.policy, response.headers())?;
+        }
+        let storable = redirects.is_empty();
+        let limit = response_limit(state, &request, &host);
+        // Pinned connections keep their certificate for checking regardless.
+        if !state.policy.capture_tls_details {
+            response.extensions_mut().remove::<reqwest::tls::TlsInfo>();
+        }
+        let reader =
+            BodyReader::new(response, host, redirects, limit, &self.budget, permit)?.holding(slot);
+        let reader = if state.policy.decompress_responses {
+            reader.decompress(state.policy.max_decompressed_body_bytes)
+        } else {
+            reader
+        };
+        let Some(store) = cache else {
+            return Ok(reader);
+        };
+        let headers = reader.headers();
+        if !storable || !cache::is_storable(reader.status(), &headers) {
+            store.remove(&key);
+            return Ok(reader);
+        }
+        let entry = CachedResponse {
+            url: reader.url(),
+            status: reader.status(),
+            headers,
+            body: Default::default(),
+            version: reader.version(),
+            stored_at: self.clock.now(),
+            vary: HashMap::new(),
+        };
+        Ok(reader.cache_into(CacheWriter::new(store, key, entry, &request)))
+    }
+
+    /// Send the opening handshake of a `ws`/`wss` request over HTTP/1.1, with `headers` on
+    /// top of the caller's, under the same checks, limits, DNS pinning, and hooks as a
+    /// fetch. Redirects are not followed. Returns the response with the host it came from
+    /// and the request's concurrency slot, which the connection keeps while open.
+    pub(crate) async fn handshake(
+        &self,
+        request: &FetchRequest,
+        headers: &[(http::HeaderName, String)],
+    ) -> Result<Handshake<'_>, FetchError> {
+        self.ensure_enabled()?;
+        let state = self.state_for(request.profile.as_deref())?;
+        let (validated, _) = self.validate_request(&state, request, true).await?;
+        if !matches!(validated.scheme.as_str(), "ws" | "wss") {
+            return Err(FetchError::InvalidUrl(
+                "WebSocket URLs must use ws or wss".into(),
+            ));
+        }
+
+        self.budget.check(&validated.host, 0)?;
+        let permit = self
+            .rate_limiter
+            .acquire_weighted(&validated.host, request.rate_limit_cost)
+            .await?;
+        let addrs =
+            timing::time_dns(state.dns_resolver.resolve(&validated.host, validated.port)).await?;
+        self.run_hooks(&RequestContext {
+            url: validated.url.to_string(),
+            scheme: validated.scheme.clone(),
+            host: validated.host.clone(),
+            port: validated.port,
+            method: "GET".into(),
+            headers: request.headers.clone(),
+            profile: request.profile.clone(),
+            resolved_addrs: addrs,
+            redirects_followed: 0,
+        })
+        .await?;
+        self.ensure_enabled()?;
+
+        // The handshake is plain HTTP/1.1: `ws` maps to `http` and `wss` to `https`.
+        state.policy.check_http_version(http::Version::HTTP_11)?;
+        let mut url = validated.url.clone();
+        let scheme = if validated.scheme == "wss" {
+            "https"
+        } else {
+            "http"
+        };
+        url.set_scheme(scheme)
+            .map_err(|_| FetchError::InvalidUrl(validated.url.to_string()))?;
+        let mut builder = state.pools_for(&validated.host).await?.ws_http.get(url);
+        for (name, value) in state.policy.filter_request_headers(&request.headers)? {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        for (name, value) in headers {
+            builder = builder.header(name, value.as_str());
+        }
+        let timeout = Duration::from_millis(
+            request
+                .request_timeout_ms
+                .unwrap_or(u64::MAX)
+                .min(state.policy.request_timeout_ms),
+        );
+        let send = async {
+            let send = builder.send();
+            match request.connect_timeout_ms {
+                Some(ms) => CONNECT_TIMEOUT.scope(Duration::from_millis(ms), send).await,
+                None => send.await,
+            }
+        };
+        let response = tokio::time::timeout(timeout, send)
+            .await
+            .map_err(|_| FetchError::RequestTimeout)?
+            .map_err(classify_reqwest_error)?;
+        Ok(Handshake {
+            response,
+            host: validated.host,
+            budget: &self.budget,
+            permit,
+        })
+    }
+
+    /// Answer a request with a fresh cached response. Hooks are consulted as for
+    /// [`check`](Self::check), without resolved addresses since nothing is sent.
+    async fn serve_cached(
+        &self,
+        state: &ClientState,
+        request: &FetchRequest,
+        validated: &ValidatedUrl,
+        entry: CachedResponse,
+    ) -> Result<BodyReader<'_>, FetchError> {
+        self.run_hooks(&RequestContext {
+            url: validated.url.to_string(),
+            scheme: validated.scheme.clone(),
+            host: validated.host.clone(),
+            port: validated.port,
+            method: request.method.to_ascii_uppercase(),
+            headers: request.headers.clone(),
+            profile: request.profile.clone(),
+            resolved_addrs: Vec::new(),
+            redirects_followed: 0,
+        })
+        .await?;
+        self.ensure_enabled()?;
+        self.cached_reader(state, request, validated.host.clone(), entry)
+    }
+
+    /// Replay `entry` under the current policy's response checks.
+    fn cached_reader(
+        &self,
+        state: &ClientState,
+        request: &FetchRequest,
+        host: String,
+        entry: CachedResponse,
+    ) -> Result<BodyReader<'_>, FetchError> {
+        let headers: http::HeaderMap = entry
+            .headers
+            .iter()
+            .filter_map(|(k, v)| Some((k.parse().ok()?, v.parse().ok()?)))
+            .collect();
+        check_content_type(&state.policy, &headers)?;
+        let limit = response_limit(state, request, &host);
+        BodyReader::cached(entry, host, limit)
+    }
+
+    /// Prepare the way to `url`'s origin ahead of the first real request. The URL goes
+    /// through the policy checks and hooks as for a fetch, except those on the method, and
+    /// its host is resolved and the addresses checked. Then a connection is opened to them
+    /// and, for `https` and `wss`, the TLS handshake made and any certificate pins checked.
+    /// Nothing is sent: there are no retries or mirrors, and no rate limit is charged.
+    ///
+    /// The HTTP stack only pools connections it opened for a request, so this one is then
+    /// closed. What carries over is the resolver's cached answer and the TLS session, which
+    /// the first real connection resumes. Through a proxy, nothing is connected.
+    pub async fn preconnect(&self, url: &str) -> Result<(), FetchError> {
+        self.ensure_enabled()?;
+        let state = self.state();
+        let upgraded = (state.policy.upgrade_insecure_requests)
+            .then(|| upgrade_insecure(url))
+            .flatten();
+        let request = FetchRequest {
+            url: upgraded.map_or_else(|| url.into(), String::from),
+            ..Default::default()
+        };
+        let (validated, _) = self.validate_destination(&state, &request, false).await?;
+        let addrs = (state.dns_resolver)
+            .resolve(&validated.host, validated.port)
+            .await?;
+        self.run_hooks(&RequestContext {
+            url: validated.url.to_string(),
+            scheme: validated.scheme.clone(),
+            host: validated.host.clone(),
+            port: validated.port,
+            method: String::new(),
+            headers: HashMap::new(),
+            profile: None,
+            resolved_addrs: addrs.clone(),
+            redirects_followed: 0,
+        })
+        .await?;
+        let proxied = (state.policy.proxy.as_ref())
+            .is_some_and(|proxy| proxy.url_for(&validated.host).is_some());
+        if proxied {
+            return Ok(());
+        }
+
+        let pools = state.pools_for(&validated.host).await?;
+        let secure = matches!(validated.scheme.as_str(), "https" | "wss");
+        let connect = async {
+            let stream = connect_any(&addrs).await.map_err(|e| {
+                FetchError::HttpError(format!("connect to {}: {e}", validated.host))
+            })?;
+            if secure {
+                tls::handshake(pools.tls, pools.pins.as_deref(), &validated.host, stream).await?;
+            }
+            Ok(())
+        };
+        let timeout = Duration::from_millis(state.policy.connect_timeout_ms);
+        tokio::time::timeout(timeout, connect)
+            .await
+            .unwrap_or(Err(FetchError::ConnectionTimeout))
+    }
+
+    /// Run the validation pipeline without sending anything or consuming rate-limit
+    /// capacity. With `resolve_dns`, the host is also resolved and every address checked
+    /// against the IP rules, as a real fetch would. Hooks are consulted too, but the
+    /// approval handler is not: only approvals already granted count.
+    pub async fn check(
+        &self,
+        request: &FetchRequest,
+        resolve_dns: bool,
+    ) -> Result<ValidationReport, FetchError> {
+        let state = self.state_for(request.profile.as_deref())?;
+
+        let upgraded;
+        let request = match state
+            .policy
+            .upgrade_insecure_requests
+            .then(|| upgrade_insecure(&request.url))
+            .flatten()
+        {
+            Some(url) => {
+                upgraded = FetchRequest {
+                    url: url.into(),
+                    ..request.clone()
+                };
+                &upgraded
+            }
+            None => request,
+        };
+
+        let (validated, decisions) = self.validate_request(&state, request, false).await?;
+
+        let resolved_addrs = if resolve_dns {
+            Some(
+                state
+                    .dns_resolver
+                    .resolve(&validated.host, validated.port)
+                    .await?,
+            )
+        } else {
+            None
+        };
+
+        let ctx = RequestContext {
+            url: validated.url.to_string(),
+            scheme: validated.scheme,
+            host: validated.host,
+            port: validated.port,
+            method: request.method.to_ascii_uppercase(),
+            headers: request.headers.clone(),
+            profile: request.profile.clone(),
+            resolved_addrs: resolved_addrs.clone().unwrap_or_default(),
+            redirects_followed: 0,
+        };
+        self.run_hooks(&ctx).await?;
+
+        Ok(ValidationReport {
+            url: ctx.url,
+            scheme: ctx.scheme,
+            host: ctx.host,
+            port: ctx.port,
+            method: ctx.method,
+            decisions,
+            resolved_addrs,
+        })
+    }
+
+    async fn run_hooks(&self, ctx: &RequestContext) -> Result<(), FetchError> {
+        for hook in &self.hooks {
+            if let Verdict::Deny(reason) = hook.evaluate(ctx).await {
+                return Err(FetchError::HookDenied(reason));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the domain against the blocklists and the policy, falling back to an active
+    /// approval or, if `ask` is set, the approval handler when the domain isn't on the
+    /// allowlist.
+    async fn check_domain(
+        &self,
+        policy: &FetchPolicy,
+        validated: &ValidatedUrl,
+        method: &str,
+        profile: Option<&str>,
+        ask: bool,
+    ) -> Result<Decision, FetchError> {
+        self.check_blocklists(&validated.host)?;
+        let err = match policy.check_domain_at(&validated.host, self.clock.now()) {
+            Err(err @ FetchError::DomainNotAllowed { .. }) => err,
+            other => return other,
+        };
+
+        let host = &validated.host;
+        // Approvals hold for the profile they were granted under only.
+        let key = (profile.map(str::to_string), host.clone());
+        let granted = {
+            let mut approved = self.approved.lock().unwrap();
+            match approved.get(&key) {
+                Some(&expiry) if approval_active(expiry) => true,
+                Some(_) => {
+                    approved.remove(&key);
+                    false
+                }
+                None => false,
+            }
+        };
+        if granted {
+            return Ok(Decision::allow("approval", host));
+        }
 
-        self.execute_request(&request, &validated, addrs).await
+        let Some(handler) = self.approval.as_ref().filter(|_| ask) else {
+            return Err(err);
+        };
+        let approval = handler
+            .request_approval(&ApprovalRequest {
+                host: host.clone(),
+                url: validated.url.to_string(),
+                method: method.to_ascii_uppercase(),
+                profile: profile.map(str::to_string),
+            })
+            .await;
+        match approval {
+            Approval::Deny => Err(err),
+            Approval::Once => Ok(Decision::allow("approval", host)),
+            Approval::For(duration) => {
+                self.approved
+                    .lock()
+                    .unwrap()
+                    .insert(key, Instant::now().checked_add(duration));
+                Ok(Decision::allow("approval", host))
+            }
+        }
     }
 
-    fn build_client(&self, addrs: Vec<SocketAddr>) -> Result<reqwest::Client, FetchError> {
-        reqwest::Client::builder()
-            .dns_resolver(Arc::new(PinnedResolver { addrs }))
-            .connect_timeout(Duration::from_millis(self.policy.connect_timeout_ms))
-            .timeout(Duration::from_millis(self.policy.request_timeout_ms))
-            .redirect(reqwest::redirect::Policy::none())
-            .build()
-            .map_err(|e: reqwest::Error| FetchError::HttpError(e.to_string()))
+    /// The policy checks on where a request goes that need no network access, shared by
+    /// [`validate_request`](Self::validate_request) and `preconnect`. Returns the decision of
+    /// each check that passed, in order.
+    async fn validate_destination(
+        &self,
+        state: &ClientState,
+        request: &FetchRequest,
+        ask_approval: bool,
+    ) -> Result<(ValidatedUrl, Vec<Decision>), FetchError> {
+        let policy = &state.policy;
+        // reqwest would quietly connect directly instead.
+        if let Some(url) = policy
+            .proxy
+            .iter()
+            .find_map(|p| p.invalid_urls().first().copied())
+        {
+            return Err(FetchError::InvalidPolicy(format!(
+                "invalid proxy URL: {url:?}"
+            )));
+        }
+
+        let mut validated = validate_url(&request.url, policy)?;
+        apply_query_policy(&mut validated.url, &policy.query_params)?;
+        let decisions = vec![
+            policy.check_scheme(&validated.scheme)?,
+            self.check_domain(
+                policy,
+                &validated,
+                &request.method,
+                request.profile.as_deref(),
+                ask_approval,
+            )
+            .await?,
+            policy.check_time(&validated.host, self.clock.now())?,
+            policy.check_path(&validated.host, validated.url.path())?,
+            policy.check_url(validated.url.as_str())?,
+            policy.check_port(validated.port)?,
+            policy.check_strict_port(&validated.host, &validated.scheme, validated.port)?,
+            policy.check_proxy(&validated.host)?,
+            policy.check_pinned_scheme(&validated.host, &validated.scheme)?,
+        ];
+        // A client certificate that couldn't be loaded fails its hosts' requests up front.
+        state.pools_for(&validated.host).await?;
+        Ok((validated, decisions))
+    }
+
+    /// Policy checks that need no network access, shared by `fetch` and `check`: those of
+    /// [`validate_destination`](Self::validate_destination), then the method, headers, and
+    /// body size. Returns the decision of each check that passed, in order.
+    async fn validate_request(
+        &self,
+        state: &ClientState,
+        request: &FetchRequest,
+        ask_approval: bool,
+    ) -> Result<(ValidatedUrl, Vec<Decision>), FetchError> {
+        let policy = &state.policy;
+        let (validated, mut decisions) = self
+            .validate_destination(state, request, ask_approval)
+            .await?;
+        decisions.push(policy.check_method(&validated.host, &request.method)?);
+        let headers = policy.filter_request_headers(&request.headers)?;
+        policy.check_host_header(&headers, &validated.url)?;
+
+        // Streams of unknown length, and bodies to be gzipped, are checked against the
+        // limit as they are sent.
+        let size = request.body.as_ref().and_then(FetchBody::size);
+        if let Some(size) =
+            size.filter(|&size| !policy.gzips_request_body(Some(size), &request.headers))
+        {
+            let limit = policy.request_body_limit(&validated.host);
+            if size > limit {
+                return Err(FetchError::RequestBodyTooLarge { size, limit });
+            }
+        }
+
+        Ok((validated, decisions))
+    }
+
+    /// Send `request` as a `HEAD` and reject it if the response it announces is over the
+    /// size limit or of a disallowed type. A `HEAD` answered with anything but 2xx proves
+    /// nothing either way, so the real request goes ahead.
+    async fn preflight(
+        &self,
+        state: &ClientState,
+        request: &FetchRequest,
+        validated: &ValidatedUrl,
+    ) -> Result<(), FetchError> {
+        let head = FetchRequest {
+            method: "HEAD".into(),
+            body: None,
+            ..request.clone()
+        };
+        let (response, host, _, _) = self.execute_request(state, &head, validated).await?;
+        if !response.status().is_success() {
+            return Ok(());
+        }
+        check_content_type(&state.policy, response.headers())?;
+        // Not `response.content_length()`: a `HEAD` response has an empty body.
+        let declared = response
+            .headers()
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<usize>().ok());
+        let limit = response_limit(state, request, &host);
+        match declared {
+            Some(size) if size > limit => Err(FetchError::ResponseBodyTooLarge { size, limit }),
+            _ => Ok(()),
+        }
     }
 
     async fn execute_request(
         &self,
+        state: &ClientState,
         request: &FetchRequest,
         validated: &ValidatedUrl,
-        addrs: Vec<SocketAddr>,
-    ) -> Result<FetchResponse, FetchError> {
-        let client = self.build_client(addrs)?;
+    ) -> Result<
+        (
+            reqwest::Response,
+            String,
+            Vec<RedirectHop>,
+            Option<StreamSlot>,
+        ),
+        FetchError,
+    > {
+        let method: http::Method =
+            request
+                .method
+                .parse()
+                .map_err(|_| FetchError::MethodNotAllowed {
+                    method: request.method.clone(),
+                    decision: Decision::deny_unmatched("allowed_methods"),
+                })?;
 
-        let method: http::Method = request
-            .method
-            .parse()
-            .map_err(|_| FetchError::MethodNotAllowed(request.method.clone()))?;
+        let timeout = LIFETIME.try_with(|lifetime| *lifetime).ok().or_else(|| {
+            request
+                .request_timeout_ms
+                .map(|ms| Duration::from_millis(ms.min(state.policy.request_timeout_ms)))
+        });
 
-        let mut req_builder = client.request(method, validated.url.as_str());
+        // Redirects are followed with a `GET`, or a `HEAD` for a `HEAD`.
+        let hop_method = if method == http::Method::HEAD {
+            http::Method::HEAD
+        } else {
+            http::Method::GET
+        };
+        // reqwest only speaks HTTP/3 when asked to, request by request. Its QUIC connections
+        // bypass the connector, and so the pin checks.
+        let version = |url: &url::Url| {
+            let pinned = url
+                .host_str()
+                .is_some_and(|host| state.policy.certificate_pin(host).is_some());
+            (state.http3 && url.scheme() == "https" && !pinned).then_some(http::Version::HTTP_3)
+        };
+        let mut req_builder = state
+            .pools_for(&validated.host)
+            .await?
+            .http
+            .request(method, validated.url.as_str());
+        if let Some(version) = version(&validated.url) {
+            req_builder = req_builder.version(version);
+        }
+        if let Some(timeout) = timeout {
+            req_builder = req_builder.timeout(timeout);
+        }
 
-        for (key, value) in &request.headers {
+        // Caller headers that passed the header policy. They are forwarded on redirect hops,
+        // except body headers (hops are bodiless), `Host`, and, once the host or port changes,
+        // `redirect_strip_headers`.
+        let mut forwarded: HashMap<String, String> = HashMap::new();
+        let headers = state.policy.filter_request_headers(&request.headers)?;
+        state.policy.check_host_header(&headers, &validated.url)?;
+        // Ask for encodings we can decode, unless the caller negotiates encodings itself.
+        let accept_encoding = (state.policy.decompress_responses
+            && !headers
+                .iter()
+                .any(|(k, _)| k.eq_ignore_ascii_case("accept-encoding")))
+        .then_some(ACCEPT_ENCODING);
+        if let Some(value) = accept_encoding {
+            req_builder = req_builder.header(http::header::ACCEPT_ENCODING, value);
+        }
+        for (key, value) in headers {
             req_builder = req_builder.header(key.as_str(), value.as_str());
+            let is_default = state
+                .policy
+                .default_headers
+                .keys()
+                .any(|h| h.eq_ignore_ascii_case(key));
+            let key_lower = key.to_ascii_lowercase();
+            if !is_default && !key_lower.starts_with("content-") && key_lower != "host" {
+                forwarded.insert(key.clone(), value.clone());
+            }
         }
 
-        if let Some(ref body) = request.body {
-            req_builder = req_builder.body(Bytes::from(body.clone()));
+        let body_limit = state.policy.request_body_limit(&validated.host);
+        let body_sent = Arc::new(AtomicUsize::new(0));
+        // Cancelled once the response arrives, so a held-back body is never sent after it.
+        let answered = CancellationToken::new();
+        if let Some(mut body) = request.body.clone() {
+            if state
+                .policy
+                .gzips_request_body(body.size(), &request.headers)
+            {
+                body = body
+                    .gzip()
+                    .await
+                    .map_err(|e| FetchError::HttpError(format!("gzip request body: {e}")))?;
+                // Compression can make an in-memory body bigger, and it wasn't checked before.
+                if let Some(size) = body.size().filter(|&size| size > body_limit) {
+                    return Err(FetchError::RequestBodyTooLarge {
+                        size,
+                        limit: body_limit,
+                    });
+                }
+                req_builder = req_builder.header(http::header::CONTENT_ENCODING, "gzip");
+            }
+            let mut hold = None;
+            if let (Some(threshold), Some(size)) = (state.policy.expect_continue_bytes, body.size())
+            {
+                if size >= threshold {
+                    req_builder = req_builder.header(http::header::EXPECT, "100-continue");
+                    let wait = Duration::from_millis(state.policy.expect_continue_timeout_ms);
+                    hold = Some((wait, answered.clone()));
+                }
+            }
+            // Streamed bodies are sent with their length, if known.
+            let streamed = matches!(body, FetchBody::Stream { .. }) || hold.is_some();
+            if let (true, Some(len)) = (streamed, body.size()) {
+                req_builder = req_builder.header(http::header::CONTENT_LENGTH, len);
+            }
+            let body = body
+                .into_reqwest(body_limit, body_sent.clone(), hold)
+                .ok_or_else(|| FetchError::HttpError("request body stream already sent".into()))?;
+            req_builder = req_builder.body(body);
         }
 
         let mut current_url = validated.url.clone();
+        let mut current_host = validated.host.clone();
+        let mut current_port = validated.port;
         let mut redirects_followed: u8 = 0;
-        let mut response: reqwest::Response =
-            req_builder.send().await.map_err(classify_reqwest_error)?;
+        let mut redirects = Vec::new();
+        let wait = timeout.unwrap_or(Duration::from_millis(state.policy.request_timeout_ms));
+        let queued = Instant::now();
+        let mut slot = state.stream_slot(&validated.url, wait).await?;
+        if slot.is_some() {
+            req_builder = req_builder.timeout(time_left(wait, queued)?);
+        }
+        let sent = Instant::now();
+        let mut response: reqwest::Response = req_builder.send().await.map_err(|e| {
+            let size = body_sent.load(Ordering::SeqCst);
+            if size > body_limit {
+                FetchError::RequestBodyTooLarge {
+                    size,
+                    limit: body_limit,
+                }
+            } else {
+                classify_reqwest_error(e)
+            }
+        })?;
+        answered.cancel();
+        state.policy.check_http_version(response.version())?;
 
-        while response.status().is_redirection() {
+        let manual = state.policy.redirect_mode == RedirectMode::Manual
+            || request.redirect == Some(RedirectMode::Manual);
+        while !manual && is_redirect(response.status()) {
+            self.ensure_enabled()?;
             redirects_followed += 1;
-            if redirects_followed > self.policy.max_redirects {
+            if redirects_followed > state.policy.max_redirects {
                 return Err(FetchError::TooManyRedirects {
-                    limit: self.policy.max_redirects,
+                    limit: state.policy.max_redirects,
                 });
             }
 
@@ -149,88 +1910,336 @@ impl SafeClient {
                 .join(&location)
                 .map_err(|e| FetchError::InvalidUrl(e.to_string()))?;
 
-            let redirect_validated = validate_url(redirect_url.as_str())?;
-            self.policy.check_scheme(&redirect_validated.scheme)?;
-            self.policy.check_domain(&redirect_validated.host)?;
-
-            let redirect_port = redirect_validated
-                .url
-                .port_or_known_default()
-                .unwrap_or(443);
-            let redirect_addrs = self
-                .dns_resolver
-                .resolve(&redirect_validated.host, redirect_port)
-                .await
-                .map_err(|e| match e {
-                    FetchError::PrivateIpBlocked { resolved_ip, .. } => {
-                        FetchError::RedirectToPrivateIp {
-                            url: redirect_url.to_string(),
-                            resolved_ip,
-                        }
+            let mut redirect_validated = validate_url(redirect_url.as_str(), &state.policy)?;
+            apply_query_policy(&mut redirect_validated.url, &state.policy.query_params)?;
+            state.policy.check_scheme(&redirect_validated.scheme)?;
+            state
+                .policy
+                .check_redirect_downgrade(&current_url, &redirect_validated.url)?;
+            state
+                .policy
+                .check_redirect_scope(&validated.host, &redirect_validated.host)?;
+            self.check_domain(
+                &state.policy,
+                &redirect_validated,
+                hop_method.as_str(),
+                request.profile.as_deref(),
+                true,
+            )
+            .await?;
+            state
+                .policy
+                .check_time(&redirect_validated.host, self.clock.now())?;
+            state
+                .policy
+                .check_path(&redirect_validated.host, redirect_validated.url.path())?;
+            state.policy.check_url(redirect_validated.url.as_str())?;
+            state
+                .policy
+                .check_method(&redirect_validated.host, hop_method.as_str())?;
+            state.policy.check_port(redirect_validated.port)?;
+            state.policy.check_strict_port(
+                &redirect_validated.host,
+                &redirect_validated.scheme,
+                redirect_validated.port,
+            )?;
+            state.policy.check_proxy(&redirect_validated.host)?;
+            state
+                .policy
+                .check_pinned_scheme(&redirect_validated.host, &redirect_validated.scheme)?;
+
+            if redirect_validated.host != current_host || redirect_validated.port != current_port {
+                forwarded.retain(|name, _| !state.policy.strips_on_redirect(name));
+            }
+
+            let redirect_addrs = timing::time_dns(
+                state
+                    .dns_resolver
+                    .resolve(&redirect_validated.host, redirect_validated.port),
+            )
+            .await
+            .map_err(|e| match e {
+                FetchError::PrivateIpBlocked { resolved_ip, .. } => {
+                    FetchError::RedirectToPrivateIp {
+                        url: redirect_url.to_string(),
+                        resolved_ip,
                     }
-                    other => other,
-                })?;
+                }
+                other => other,
+            })?;
 
-            let redirect_client = self.build_client(redirect_addrs)?;
+            self.run_hooks(&RequestContext {
+                url: redirect_validated.url.to_string(),
+                scheme: redirect_validated.scheme.clone(),
+                host: redirect_validated.host.clone(),
+                port: redirect_validated.port,
+                method: hop_method.to_string(),
+                headers: forwarded.clone(),
+                profile: request.profile.clone(),
+                resolved_addrs: redirect_addrs,
+                redirects_followed,
+            })
+            .await?;
+            self.rate_limiter
+                .acquire_hop(&redirect_validated.host, request.rate_limit_cost)
+                .await?;
 
+            redirects.push(RedirectHop {
+                url: current_url.to_string(),
+                status: response.status().as_u16(),
+            });
             current_url = redirect_validated.url.clone();
-            response = redirect_client
-                .get(redirect_validated.url.as_str())
+            current_host = redirect_validated.host.clone();
+            current_port = redirect_validated.port;
+            let mut redirect_builder = state
+                .pools_for(&redirect_validated.host)
+                .await?
+                .http
+                .request(hop_method.clone(), redirect_validated.url.as_str());
+            if let Some(version) = version(&redirect_validated.url) {
+                redirect_builder = redirect_builder.version(version);
+            }
+            if let Some(timeout) = timeout {
+                redirect_builder = redirect_builder.timeout(timeout);
+            }
+            if let Some(value) = accept_encoding {
+                redirect_builder = redirect_builder.header(http::header::ACCEPT_ENCODING, value);
+            }
+            // `Host` named the original host, if it was checked at all.
+            for (key, value) in state.policy.default_headers.iter().chain(&forwarded) {
+                if !key.eq_ignore_ascii_case("host") {
+                    redirect_builder = redirect_builder.header(key.as_str(), value.as_str());
+                }
+            }
+            // Free this hop's slot before taking the next one.
+            drop(response);
+            drop(slot);
+            let queued = Instant::now();
+            slot = state.stream_slot(&redirect_validated.url, wait).await?;
+            if slot.is_some() {
+                redirect_builder = redirect_builder.timeout(time_left(wait, queued)?);
+            }
+            response = redirect_builder
                 .send()
                 .await
                 .map_err(classify_reqwest_error)?;
+            state.policy.check_http_version(response.version())?;
         }
+        timing::record_first_byte(sent.elapsed());
 
-        self.read_body_limited(response).await
+        Ok((response, current_host, redirects, slot))
     }
+}
 
-    async fn read_body_limited(
-        &self,
-        response: reqwest::Response,
-    ) -> Result<FetchResponse, FetchError> {
-        let status = response.status().as_u16();
+/// The profile an approval was granted under (`None` for the default policy) and the host.
+type ApprovalKey = (Option<String>, String);
 
-        let headers: HashMap<String, String> = response
-            .headers()
-            .iter()
-            .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
-            .collect();
+/// What is left of `timeout` after waiting since `start`, so time spent queued for a
+/// stream slot counts against the request.
+fn time_left(timeout: Duration, start: Instant) -> Result<Duration, FetchError> {
+    timeout
+        .checked_sub(start.elapsed())
+        .filter(|left| !left.is_zero())
+        .ok_or(FetchError::RequestTimeout)
+}
 
-        if let Some(cl) = response.content_length() {
-            if cl as usize > self.policy.max_response_body_bytes {
-                return Err(FetchError::ResponseBodyTooLarge {
-                    size: cl as usize,
-                    limit: self.policy.max_response_body_bytes,
-                });
-            }
-        }
+/// Whether an approval expiring at `expiry` still holds.
+fn approval_active(expiry: Option<Instant>) -> bool {
+    expiry.is_none_or(|at| at > Instant::now())
+}
 
-        let body = response
-            .bytes()
-            .await
-            .map_err(|e| FetchError::HttpError(e.to_string()))?;
+/// A status that sends the client elsewhere with `Location`. Other 3xx responses, such as
+/// 304 Not Modified, are final.
+fn is_redirect(status: http::StatusCode) -> bool {
+    matches!(status.as_u16(), 301 | 302 | 303 | 307 | 308)
+}
 
-        if body.len() > self.policy.max_response_body_bytes {
-            return Err(FetchError::ResponseBodyTooLarge {
-                size: body.len(),
-                limit: self.policy.max_response_body_bytes,
-            });
-        }
+/// The response-size limit for `request` to `host`: the policy's, or the request's own if
+/// lower.
+fn response_limit(state: &ClientState, request: &FetchRequest, host: &str) -> usize {
+    let limit = state.policy.response_body_limit(host);
+    request
+        .max_response_body_bytes
+        .map_or(limit, |max| max.min(limit))
+}
 
-        Ok(FetchResponse {
-            status,
-            headers,
-            body: body.to_vec(),
-        })
+/// Check the declared `Content-Type` of a response, if any, against `allowed_content_types`.
+fn check_content_type(policy: &FetchPolicy, headers: &http::HeaderMap) -> Result<(), FetchError> {
+    let Some(value) = headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return Ok(());
+    };
+    policy.check_content_type(&parse_content_type(value).0)?;
+    Ok(())
+}
+
+/// Run `fut` to completion unless `cancel` fires first, in which case `fut` is dropped.
+pub(crate) async fn cancellable<T>(
+    cancel: Option<&CancellationToken>,
+    fut: impl Future<Output = Result<T, FetchError>>,
+) -> Result<T, FetchError> {
+    match cancel {
+        Some(token) => token
+            .run_until_cancelled(fut)
+            .await
+            .unwrap_or(Err(FetchError::Cancelled)),
+        None => fut.await,
+    }
+}
+
+/// Run `fut` until `deadline`, failing with [`FetchError::RequestTimeout`] once it passes.
+/// `None` is a deadline too far off to represent, and never passes.
+pub(crate) async fn until<T>(
+    deadline: Option<tokio::time::Instant>,
+    fut: impl Future<Output = T>,
+) -> Result<T, FetchError> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, fut)
+            .await
+            .map_err(|_| FetchError::RequestTimeout),
+        None => Ok(fut.await),
     }
 }
 
 fn classify_reqwest_error(e: reqwest::Error) -> FetchError {
+    // A connect-time address rejected by `ValidatingResolver` surfaces as its policy error.
+    let mut source = std::error::Error::source(&e);
+    let mut reset = false;
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<FetchError>() {
+            return err.clone();
+        }
+        if let Some(err) = err.downcast_ref::<tls::PinMismatch>() {
+            return FetchError::CertificatePinMismatch {
+                host: e.url().and_then(url::Url::host_str).unwrap_or("").into(),
+                fingerprint: err.fingerprint.clone(),
+                decision: Decision::deny("certificate_pins", err.pattern.clone()),
+            };
+        }
+        reset |= err
+            .downcast_ref::<hyper::Error>()
+            .is_some_and(hyper::Error::is_incomplete_message)
+            || err.downcast_ref::<std::io::Error>().is_some_and(|err| {
+                use std::io::ErrorKind::*;
+                matches!(
+                    err.kind(),
+                    ConnectionReset | ConnectionAborted | BrokenPipe | UnexpectedEof
+                )
+            });
+        source = err.source();
+    }
     if e.is_connect() {
         FetchError::ConnectionTimeout
     } else if e.is_timeout() {
         FetchError::RequestTimeout
+    } else if reset {
+        FetchError::ConnectionReset(e.to_string())
     } else {
         FetchError::HttpError(e.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stream_slots_are_pruned_once_released() {
+        let policy = FetchPolicy {
+            http2_max_concurrent_streams: Some(1),
+            ..Default::default()
+        };
+        let state = ClientState::new(policy.clone(), SafeDnsResolver::new(&policy));
+        let url = url::Url::parse("https://example.com/").unwrap();
+        let wait = Duration::from_millis(10);
+
+        let slot = state.stream_slot(&url, wait).await.unwrap();
+        assert!(slot.is_some());
+        // A wait that times out leaves the entry to the request holding the slot.
+        let waited = state.stream_slot(&url, wait).await;
+        assert!(matches!(waited, Err(FetchError::RequestTimeout)));
+        assert_eq!(state.streams.lock().unwrap().len(), 1);
+        drop(slot);
+        assert!(state.streams.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn pools_are_replaced_once_a_peer_fails_revalidation() {
+        let policy = FetchPolicy {
+            connection_revalidate_ms: Some(0),
+            ..Default::default()
+        };
+        let state = ClientState::new(policy.clone(), SafeDnsResolver::new(&policy));
+        let pools = state.pools_for("1.1.1.1").await.unwrap();
+        pools.peers.lock().unwrap().insert(
+            "1.1.1.1".into(),
+            HashSet::from(["1.1.1.1".parse().unwrap()]),
+        );
+        let kept = state.pools_for("1.1.1.1").await.unwrap();
+        assert!(Arc::ptr_eq(&kept.peers, &pools.peers));
+
+        // The host no longer answers with the address a connection is pinned to.
+        kept.peers.lock().unwrap().insert(
+            "1.1.1.1".into(),
+            HashSet::from(["1.0.0.1".parse().unwrap()]),
+        );
+        let replaced = state.pools_for("1.1.1.1").await.unwrap();
+        assert!(!Arc::ptr_eq(&replaced.peers, &pools.peers));
+    }
+
+    #[tokio::test]
+    async fn connections_race_past_an_address_that_hangs() {
+        // A listener whose backlog is full leaves further connection attempts hanging.
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let full = socket.listen(1).unwrap();
+        let mut queued = Vec::new();
+        for _ in 0..4 {
+            let connect = tokio::net::TcpStream::connect(full.local_addr().unwrap());
+            if let Ok(Ok(stream)) = tokio::time::timeout(Duration::from_millis(100), connect).await
+            {
+                queued.push(stream);
+            }
+        }
+        let open = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addrs = [full.local_addr().unwrap(), open.local_addr().unwrap()];
+
+        let started = Instant::now();
+        let stream = tokio::time::timeout(Duration::from_secs(5), connect_any(&addrs))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addrs[1]);
+        assert!(started.elapsed() >= CONNECTION_ATTEMPT_DELAY);
+    }
+
+    #[tokio::test]
+    async fn peers_hold_only_the_latest_answer() {
+        let peers = Peers::default();
+        let resolve = |ips: Vec<IpAddr>| {
+            let policy = FetchPolicy {
+                dns_overrides: [("fixture.test".to_string(), ips)].into(),
+                ..Default::default()
+            };
+            let resolver = ValidatingResolver {
+                dns: SafeDnsResolver::new(&policy),
+                proxies: Vec::new(),
+                peers: peers.clone(),
+            };
+            async move {
+                let resolving = resolver.resolve("fixture.test".parse().unwrap());
+                assert!(resolving.await.is_ok());
+                let peers = resolver.peers.lock().unwrap();
+                peers["fixture.test"].clone()
+            }
+        };
+        let ip = |last: u8| IpAddr::from([1, 1, 1, last]);
+
+        resolve(vec![ip(1), ip(2)]).await;
+        assert_eq!(resolve(vec![ip(3)]).await, HashSet::from([ip(3)]));
+        let many = resolve((1..=40).map(ip).collect()).await;
+        assert_eq!(many.len(), MAX_PEERS_PER_HOST);
+    }
+}
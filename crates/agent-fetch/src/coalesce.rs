@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use tokio::sync::watch;
+
+use crate::client::{FetchRequest, FetchResponse};
+use crate::error::FetchError;
+
+type Outcome = Option<Result<FetchResponse, FetchError>>;
+
+/// Identical `GET`s in flight, so concurrent callers can share one network request.
+#[derive(Default)]
+pub(crate) struct InFlight {
+    calls: Mutex<HashMap<String, watch::Receiver<Outcome>>>,
+}
+
+/// A caller's part in a coalesced fetch.
+pub(crate) enum Call<'a> {
+    /// Nobody is fetching this yet: fetch, then hand the outcome to [`Leader::finish`].
+    Lead(Leader<'a>),
+    /// Someone else is fetching this: wait for their outcome with [`Follower::wait`].
+    Follow(Follower),
+}
+
+pub(crate) struct Leader<'a> {
+    in_flight: &'a InFlight,
+    key: String,
+    tx: watch::Sender<Outcome>,
+}
+
+pub(crate) struct Follower {
+    rx: watch::Receiver<Outcome>,
+}
+
+impl InFlight {
+    pub(crate) fn join(&self, key: String) -> Call<'_> {
+        let mut calls = self.calls.lock().unwrap();
+        if let Some(rx) = calls.get(&key) {
+            return Call::Follow(Follower { rx: rx.clone() });
+        }
+        let (tx, rx) = watch::channel(None);
+        calls.insert(key.clone(), rx);
+        Call::Lead(Leader {
+            in_flight: self,
+            key,
+            tx,
+        })
+    }
+}
+
+impl Leader<'_> {
+    /// Share `result` with the callers waiting on this fetch. A cancellation is the leader's
+    /// own, so it is not shared: followers retry instead, as they do if the leader is
+    /// dropped.
+    pub(crate) fn finish(self, result: &Result<FetchResponse, FetchError>) {
+        if !matches!(result, Err(FetchError::Cancelled)) {
+            self.tx.send_replace(Some(result.clone()));
+        }
+    }
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        let mut calls = self.in_flight.calls.lock().unwrap();
+        if calls
+            .get(&self.key)
+            .is_some_and(|rx| rx.same_channel(&self.tx.subscribe()))
+        {
+            calls.remove(&self.key);
+        }
+    }
+}
+
+impl Follower {
+    /// The leader's outcome, or `None` if it gave up without one.
+    pub(crate) async fn wait(mut self) -> Outcome {
+        self.rx
+            .wait_for(Option::is_some)
+            .await
+            .ok()
+            .and_then(|outcome| outcome.clone())
+    }
+}
+
+/// What makes two requests interchangeable: everything that can change the response or
+/// how it is fetched. `None` if `request` can't be shared at all.
+pub(crate) fn key(request: &FetchRequest) -> Option<String> {
+    if !request.method.eq_ignore_ascii_case("GET") || request.body.is_some() {
+        return None;
+    }
+    let mut headers: Vec<(String, &str)> = request
+        .headers
+        .iter()
+        .map(|(name, value)| (name.to_ascii_lowercase(), value.as_str()))
+        .collect();
+    headers.sort();
+    Some(format!(
+        "{:?}",
+        (
+            &request.url,
+            &request.mirrors,
+            headers,
+            &request.profile,
+            request.redirect,
+            request.request_timeout_ms,
+            request.connect_timeout_ms,
+            request.max_response_body_bytes,
+        )
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: u16) -> FetchResponse {
+        FetchResponse {
+            status,
+            headers: HashMap::new(),
+            body: b"shared".to_vec(),
+            url: "https://example.com/".into(),
+            redirects: Vec::new(),
+            version: http::Version::HTTP_11,
+            timings: Default::default(),
+            retry_after: None,
+            rate_limit: None,
+            tls: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn followers_share_the_leaders_outcome() {
+        let in_flight = InFlight::default();
+        let Call::Lead(leader) = in_flight.join("a".into()) else {
+            panic!("first caller should lead");
+        };
+        let Call::Follow(follower) = in_flight.join("a".into()) else {
+            panic!("second caller should follow");
+        };
+        assert!(matches!(in_flight.join("b".into()), Call::Lead(_)));
+
+        leader.finish(&Ok(response(200)));
+        assert_eq!(follower.wait().await.unwrap().unwrap().body, b"shared");
+        // The call is over: the next caller leads a new one.
+        assert!(matches!(in_flight.join("a".into()), Call::Lead(_)));
+    }
+
+    #[tokio::test]
+    async fn cancellation_is_not_shared() {
+        let in_flight = InFlight::default();
+        let Call::Lead(leader) = in_flight.join("a".into()) else {
+            panic!("first caller should lead");
+        };
+        let Call::Follow(follower) = in_flight.join("a".into()) else {
+            panic!("second caller should follow");
+        };
+        leader.finish(&Err(FetchError::Cancelled));
+        assert!(follower.wait().await.is_none());
+    }
+
+    #[test]
+    fn keys_distinguish_what_changes_the_response() {
+        let get = |url: &str| FetchRequest {
+            url: url.into(),
+            ..Default::default()
+        };
+        let mut with_header = get("https://a.example/");
+        with_header
+            .headers
+            .insert("Accept".into(), "text/html".into());
+        let mut same_header = get("https://a.example/");
+        same_header
+            .headers
+            .insert("accept".into(), "text/html".into());
+
+        assert_eq!(
+            key(&get("https://a.example/")),
+            key(&get("https://a.example/"))
+        );
+        assert_ne!(
+            key(&get("https://a.example/")),
+            key(&get("https://b.example/"))
+        );
+        assert_ne!(key(&with_header), key(&get("https://a.example/")));
+        assert_eq!(key(&with_header), key(&same_header));
+        let post = FetchRequest {
+            method: "POST".into(),
+            ..get("https://a.example/")
+        };
+        assert_eq!(key(&post), None);
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::cache;
+use crate::client::{FetchRequest, FetchResponse, SafeClient};
+use crate::error::FetchError;
+
+/// The validators a response identified its version with, sent back on the next
+/// [`SafeClient::fetch_conditional`] of the same URL that selects the same response.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// The headers named in the response's `Vary`, by lowercase name, with the values the
+    /// request sent for them, `None` for those it didn't send. Only requests sending the
+    /// same values are made conditional on these validators.
+    pub vary: HashMap<String, Option<String>>,
+}
+
+impl Validators {
+    fn from_headers(headers: &HashMap<String, String>) -> Self {
+        Self {
+            etag: headers.get("etag").cloned(),
+            last_modified: headers.get("last-modified").cloned(),
+            vary: HashMap::new(),
+        }
+    }
+
+    /// The validators of a response with `headers` to a request with `request_headers`.
+    fn of_response(
+        headers: &HashMap<String, String>,
+        request_headers: &HashMap<String, String>,
+    ) -> Self {
+        let vary = headers.get("vary").map_or_else(HashMap::new, |vary| {
+            vary.split(',')
+                .map(|name| name.trim().to_ascii_lowercase())
+                .filter(|name| !name.is_empty())
+                .map(|name| {
+                    let value = request_header(request_headers, &name);
+                    (name, value)
+                })
+                .collect()
+        });
+        Self {
+            vary,
+            ..Self::from_headers(headers)
+        }
+    }
+
+    /// Whether a request with `headers` selects the response these validators came from.
+    fn matches(&self, headers: &HashMap<String, String>) -> bool {
+        (self.vary.iter()).all(|(name, value)| request_header(headers, name) == *value)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+fn request_header(headers: &HashMap<String, String>, name: &str) -> Option<String> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.clone())
+}
+
+/// Where [`SafeClient::fetch_conditional`] keeps validators between fetches, keyed by
+/// request URL and, for requests made under a policy profile, its name, as the response
+/// cache is. Swap in a persistent store with
+/// [`SafeClient::with_validator_store`](crate::SafeClient::with_validator_store) to keep
+/// them across restarts.
+pub trait ValidatorStore: Send + Sync {
+    fn get(&self, url: &str) -> Option<Validators>;
+    fn put(&self, url: &str, validators: Validators);
+    fn remove(&self, url: &str);
+}
+
+/// The default store: validators held in memory for the life of the client.
+#[derive(Debug, Default)]
+pub struct MemoryValidatorStore {
+    entries: Mutex<HashMap<String, Validators>>,
+}
+
+impl ValidatorStore for MemoryValidatorStore {
+    fn get(&self, url: &str) -> Option<Validators> {
+        self.entries.lock().unwrap().get(url).cloned()
+    }
+
+    fn put(&self, url: &str, validators: Validators) {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), validators);
+    }
+
+    fn remove(&self, url: &str) {
+        self.entries.lock().unwrap().remove(url);
+    }
+}
+
+/// Result of [`SafeClient::fetch_conditional`].
+#[derive(Debug, Clone)]
+pub enum ConditionalResponse {
+    /// The resource was fetched: it changed, no validators were stored for it yet, or the
+    /// server ignored them. Any status other than 304 lands here.
+    Modified(FetchResponse),
+    /// The server answered 304: the copy from the last fetch is still current. The response
+    /// has no body.
+    NotModified(FetchResponse),
+}
+
+impl ConditionalResponse {
+    pub fn is_modified(&self) -> bool {
+        matches!(self, Self::Modified(_))
+    }
+
+    pub fn response(&self) -> &FetchResponse {
+        match self {
+            Self::Modified(response) | Self::NotModified(response) => response,
+        }
+    }
+
+    pub fn into_response(self) -> FetchResponse {
+        match self {
+            Self::Modified(response) | Self::NotModified(response) => response,
+        }
+    }
+}
+
+impl SafeClient {
+    /// Fetch `request`, revalidating against the last response for its URL. Validators
+    /// stored from that response are sent as `If-None-Match` and `If-Modified-Since`, unless
+    /// the request sets them itself, and a 304 comes back as
+    /// [`ConditionalResponse::NotModified`]. Validators of each 2xx response replace the
+    /// stored ones.
+    ///
+    /// The caller keeps the body of the earlier response; only validators are stored.
+    pub async fn fetch_conditional(
+        &self,
+        mut request: FetchRequest,
+    ) -> Result<ConditionalResponse, FetchError> {
+        let key = cache::key(&request.url, request.profile.as_deref());
+        let request_headers = request.headers.clone();
+        let stored = (self.validator_store().get(&key)).filter(|v| v.matches(&request_headers));
+        if let Some(ref stored) = stored {
+            let has = |name: &str| request.headers.keys().any(|h| h.eq_ignore_ascii_case(name));
+            let conditions = [
+                ("If-None-Match", &stored.etag),
+                ("If-Modified-Since", &stored.last_modified),
+            ];
+            if !conditions.iter().any(|(name, _)| has(name)) {
+                for (name, value) in conditions {
+                    if let Some(value) = value {
+                        request.headers.insert(name.into(), value.clone());
+                    }
+                }
+            }
+        }
+
+        let response = self.fetch(request).await?;
+        if response.status == 304 {
+            // A 304 may carry updated validators; keep the stored ones for any it omits.
+            let fresh = Validators::of_response(&response.headers, &request_headers);
+            let stored = stored.unwrap_or_default();
+            let vary = if response.headers.contains_key("vary") {
+                fresh.vary
+            } else {
+                stored.vary
+            };
+            self.validator_store().put(
+                &key,
+                Validators {
+                    etag: fresh.etag.or(stored.etag),
+                    last_modified: fresh.last_modified.or(stored.last_modified),
+                    vary,
+                },
+            );
+            return Ok(ConditionalResponse::NotModified(response));
+        }
+        if (200..300).contains(&response.status) {
+            let validators = Validators::of_response(&response.headers, &request_headers);
+            if validators.is_empty() {
+                self.validator_store().remove(&key);
+            } else {
+                self.validator_store().put(&key, validators);
+            }
+        }
+        Ok(ConditionalResponse::Modified(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_store_round_trips() {
+        let store = MemoryValidatorStore::default();
+        assert_eq!(store.get("https://a.example/"), None);
+        let validators = Validators::from_headers(&HashMap::from([
+            ("etag".to_string(), "\"v1\"".to_string()),
+            ("content-type".to_string(), "text/plain".to_string()),
+        ]));
+        assert_eq!(validators.etag.as_deref(), Some("\"v1\""));
+        assert!(!validators.is_empty());
+        store.put("https://a.example/", validators.clone());
+        assert_eq!(store.get("https://a.example/"), Some(validators));
+        store.remove("https://a.example/");
+        assert_eq!(store.get("https://a.example/"), None);
+    }
+
+    #[test]
+    fn validators_only_match_requests_selecting_the_same_response() {
+        let headers = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            (pairs.iter())
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let validators = Validators::of_response(
+            &headers(&[("etag", "\"v1\""), ("vary", "Accept-Language, Accept")]),
+            &headers(&[("Accept-Language", "en")]),
+        );
+        assert_eq!(validators.vary["accept-language"].as_deref(), Some("en"));
+        assert_eq!(validators.vary["accept"], None);
+        assert!(validators.matches(&headers(&[("accept-language", "en")])));
+        assert!(!validators.matches(&headers(&[("Accept-Language", "de")])));
+        assert!(!validators.matches(&headers(&[])));
+        assert!(!validators.matches(&headers(&[("Accept-Language", "en"), ("Accept", "*/*")])));
+    }
+}
@@ -0,0 +1,170 @@
+use std::path::Path;
+
+use crate::error::FetchError;
+use crate::policy::FetchPolicy;
+
+/// Serialization formats a policy can be loaded from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl PolicyFormat {
+    /// Infer the format from a file extension (`json`, `yaml`/`yml`, `toml`).
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "json" => Some(Self::Json),
+            "yaml" | "yml" => Some(Self::Yaml),
+            "toml" => Some(Self::Toml),
+            _ => None,
+        }
+    }
+}
+
+impl FetchPolicy {
+    /// Load and validate a policy from a JSON, YAML, or TOML file, picking the format from
+    /// the file extension. Fields missing from the file take their default values; unknown
+    /// fields are rejected.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, FetchError> {
+        let path = path.as_ref();
+        let format = PolicyFormat::from_path(path).ok_or_else(|| {
+            FetchError::InvalidPolicy(format!(
+                "{}: unsupported policy file extension (expected .json, .yaml, .yml, or .toml)",
+                path.display()
+            ))
+        })?;
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            FetchError::InvalidPolicy(format!("{}: failed to read: {e}", path.display()))
+        })?;
+        Self::from_str_with_format(&contents, format)
+            .map_err(|e| FetchError::InvalidPolicy(format!("{}: {e}", path.display())))
+    }
+
+    /// Parse and validate a policy from a string in the given format.
+    pub fn from_str_with_format(contents: &str, format: PolicyFormat) -> Result<Self, FetchError> {
+        let policy: Self = match format {
+            PolicyFormat::Json => serde_json::from_str(contents).map_err(|e| e.to_string()),
+            PolicyFormat::Yaml => serde_yaml::from_str(contents).map_err(|e| e.to_string()),
+            PolicyFormat::Toml => toml::from_str(contents).map_err(|e| e.to_string()),
+        }
+        .map_err(FetchError::InvalidPolicy)?;
+
+        policy.validate()?;
+        Ok(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_from_extension() {
+        assert_eq!(
+            PolicyFormat::from_path(Path::new("policy.YML")),
+            Some(PolicyFormat::Yaml)
+        );
+        assert_eq!(
+            PolicyFormat::from_path(Path::new("policy.toml")),
+            Some(PolicyFormat::Toml)
+        );
+        assert_eq!(PolicyFormat::from_path(Path::new("policy.ini")), None);
+    }
+
+    #[test]
+    fn parses_json() {
+        let policy = FetchPolicy::from_str_with_format(
+            r#"{"allowed_domains": ["*.example.com"], "max_redirects": 3}"#,
+            PolicyFormat::Json,
+        )
+        .unwrap();
+        assert_eq!(policy.max_redirects, 3);
+        assert!(policy.check_domain("api.example.com").is_ok());
+        assert!(policy.deny_private_ips); // default preserved
+    }
+
+    #[test]
+    fn parses_yaml() {
+        let policy = FetchPolicy::from_str_with_format(
+            "blocked_domains: ['evil.com']\nblocked_ip_ranges: ['203.0.113.0/24']\n",
+            PolicyFormat::Yaml,
+        )
+        .unwrap();
+        assert!(policy.check_domain("evil.com").is_err());
+        assert_eq!(policy.blocked_ip_ranges.len(), 1);
+    }
+
+    #[test]
+    fn parses_toml() {
+        let policy = FetchPolicy::from_str_with_format(
+            r#"
+            allowed_methods = ["GET"]
+
+            [[domain_rules]]
+            pattern = "api.github.com"
+            allowed_paths = ["/repos/*"]
+            "#,
+            PolicyFormat::Toml,
+        )
+        .unwrap();
+        assert!(policy.check_path("api.github.com", "/user").is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let err = FetchPolicy::from_str_with_format(
+            r#"{"alowed_domains": ["example.com"]}"#,
+            PolicyFormat::Json,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("unknown field"), "got: {err}");
+
+        let err =
+            FetchPolicy::from_str_with_format("[idn]\nascii_ony = true\n", PolicyFormat::Toml)
+                .unwrap_err();
+        assert!(err.to_string().contains("unknown field"), "got: {err}");
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let err = FetchPolicy::from_str_with_format(
+            r#"{"blocked_domains": ["evil.*.com"]}"#,
+            PolicyFormat::Json,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("evil.*.com"), "got: {err}");
+
+        let err = FetchPolicy::from_str_with_format(
+            r#"{"allowed_schemes": ["https", "gopher"]}"#,
+            PolicyFormat::Json,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("gopher"), "got: {err}");
+
+        let err = FetchPolicy::from_str_with_format(
+            r#"{"blocked_url_patterns": ["("]}"#,
+            PolicyFormat::Json,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("regex"), "got: {err}");
+    }
+
+    #[test]
+    fn from_file_reports_path() {
+        let dir = std::env::temp_dir().join(format!("agent-fetch-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("policy.json");
+        std::fs::write(&path, r#"{"max_redirects": "three"}"#).unwrap();
+
+        let err = FetchPolicy::from_file(&path).unwrap_err();
+        assert!(err.to_string().contains("policy.json"), "got: {err}");
+
+        std::fs::write(&path, r#"{"max_redirects": 2}"#).unwrap();
+        assert_eq!(FetchPolicy::from_file(&path).unwrap().max_redirects, 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
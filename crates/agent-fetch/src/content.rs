@@ -0,0 +1,78 @@
+use encoding_rs::{Encoding, UTF_8};
+
+/// The media type (lowercased, without parameters) and `charset` parameter of a
+/// `Content-Type` header value.
+pub(crate) fn parse_content_type(value: &str) -> (String, Option<String>) {
+    let mut parts = value.split(';');
+    let media_type = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+    let charset = parts.find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches('"').to_string())
+    });
+    (media_type, charset)
+}
+
+/// `application/json` or a structured `+json` type such as `application/problem+json`.
+pub(crate) fn is_json(media_type: &str) -> bool {
+    media_type == "application/json" || media_type.ends_with("+json")
+}
+
+/// A media type whose body is text: `text/*`, JSON, XML, JavaScript, or form data.
+pub(crate) fn is_text(media_type: &str) -> bool {
+    media_type.starts_with("text/")
+        || is_json(media_type)
+        || media_type.ends_with("+xml")
+        || matches!(
+            media_type,
+            "application/xml"
+                | "application/javascript"
+                | "application/ecmascript"
+                | "application/x-www-form-urlencoded"
+        )
+}
+
+/// Decode `body` in the given charset, UTF-8 if absent or unknown. A byte-order mark
+/// takes precedence over the label; malformed sequences become U+FFFD.
+pub(crate) fn decode_text(body: &[u8], charset: Option<&str>) -> String {
+    let encoding = charset
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+        .unwrap_or(UTF_8);
+    encoding.decode(body).0.into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_media_type_and_charset() {
+        assert_eq!(
+            parse_content_type("Text/HTML; Charset=\"ISO-8859-1\""),
+            ("text/html".into(), Some("ISO-8859-1".into()))
+        );
+        assert_eq!(
+            parse_content_type("application/json"),
+            ("application/json".into(), None)
+        );
+        assert!(is_json("application/problem+json"));
+        assert!(is_text("image/svg+xml"));
+        assert!(!is_text("application/octet-stream"));
+    }
+
+    #[test]
+    fn decodes_declared_charset() {
+        assert_eq!(decode_text(b"caf\xe9", Some("iso-8859-1")), "café");
+        assert_eq!(decode_text("café".as_bytes(), None), "café");
+        assert_eq!(
+            decode_text("café".as_bytes(), Some("no-such-charset")),
+            "café"
+        );
+        // The BOM wins over a wrong label.
+        assert_eq!(
+            decode_text(b"\xef\xbb\xbfcaf\xc3\xa9", Some("latin1")),
+            "café"
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use std::fmt;
+
+/// The policy rule that decided a check, returned by the `FetchPolicy::check_*` methods
+/// and embedded in the errors they produce.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    /// Policy field that made the decision, e.g. `blocked_domains` or
+    /// `domain_rules[api.example.com].allowed_paths`. `default` when no rule applied.
+    pub rule: String,
+    /// The entry within `rule` that matched, if the decision came from a match.
+    pub pattern: Option<String>,
+}
+
+impl Decision {
+    pub fn allow(rule: impl Into<String>, pattern: impl Into<String>) -> Self {
+        Self {
+            allowed: true,
+            rule: rule.into(),
+            pattern: Some(pattern.into()),
+        }
+    }
+
+    /// Allowed because no rule restricts the value.
+    pub fn allow_default() -> Self {
+        Self {
+            allowed: true,
+            rule: "default".into(),
+            pattern: None,
+        }
+    }
+
+    pub fn deny(rule: impl Into<String>, pattern: impl Into<String>) -> Self {
+        Self {
+            allowed: false,
+            rule: rule.into(),
+            pattern: Some(pattern.into()),
+        }
+    }
+
+    /// Denied by an allowlist that nothing matched, or by a flag such as `read_only`.
+    pub fn deny_unmatched(rule: impl Into<String>) -> Self {
+        Self {
+            allowed: false,
+            rule: rule.into(),
+            pattern: None,
+        }
+    }
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.allowed, &self.pattern) {
+            (true, Some(pattern)) => write!(f, "allowed by {} pattern {pattern}", self.rule),
+            (true, None) => write!(f, "allowed by {}", self.rule),
+            (false, Some(pattern)) => write!(f, "blocked by {} pattern {pattern}", self.rule),
+            (false, None) => write!(f, "denied by {}", self.rule),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            Decision::deny("blocked_domains", "*.internal.corp").to_string(),
+            "blocked by blocked_domains pattern *.internal.corp"
+        );
+        assert_eq!(
+            Decision::deny_unmatched("allowed_domains").to_string(),
+            "denied by allowed_domains"
+        );
+        assert_eq!(
+            Decision::allow("allowed_ports", "443").to_string(),
+            "allowed by allowed_ports pattern 443"
+        );
+        assert_eq!(Decision::allow_default().to_string(), "allowed by default");
+    }
+}
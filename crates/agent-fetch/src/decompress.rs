@@ -0,0 +1,201 @@
+use std::io::{self, Write};
+
+use brotli_decompressor::DecompressorWriter;
+use bytes::Bytes;
+use flate2::write::{GzDecoder, ZlibDecoder};
+
+use crate::error::FetchError;
+
+/// `Accept-Encoding` sent when decompression is enabled and the caller didn't set one.
+pub(crate) const ACCEPT_ENCODING: &str = "gzip, deflate, br";
+
+/// Collects decoded output, failing as soon as more than `limit` bytes have been written,
+/// so inflation stops at the limit rather than after the chunk that crossed it.
+struct Sink {
+    buf: Vec<u8>,
+    written: usize,
+    limit: usize,
+}
+
+impl Write for Sink {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.written += data.len();
+        if self.written > self.limit {
+            return Err(io::Error::other("decompressed size limit exceeded"));
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Codec {
+    Gzip(GzDecoder<Sink>),
+    Deflate(ZlibDecoder<Sink>),
+    Brotli(Box<DecompressorWriter<Sink>>),
+}
+
+/// Decodes a `Content-Encoding`d response body chunk by chunk, failing once the decoded
+/// size passes a limit.
+pub(crate) struct Decoder {
+    codec: Codec,
+    encoding: &'static str,
+    fed: bool,
+}
+
+impl Decoder {
+    /// A decoder for the given `Content-Encoding`, or `None` if the body isn't encoded or
+    /// uses an encoding (or a chain of encodings) that isn't decoded.
+    pub(crate) fn for_encoding(content_encoding: &str, limit: usize) -> Option<Self> {
+        let sink = Sink {
+            buf: Vec::new(),
+            written: 0,
+            limit,
+        };
+        let (codec, encoding) = match content_encoding.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => (Codec::Gzip(GzDecoder::new(sink)), "gzip"),
+            "deflate" => (Codec::Deflate(ZlibDecoder::new(sink)), "deflate"),
+            "br" => (
+                Codec::Brotli(Box::new(DecompressorWriter::new(sink, 8 * 1024))),
+                "br",
+            ),
+            _ => return None,
+        };
+        Some(Self {
+            codec,
+            encoding,
+            fed: false,
+        })
+    }
+
+    /// Decode the next chunk of the encoded body, returning whatever output it produced.
+    pub(crate) fn decode(&mut self, chunk: &[u8]) -> Result<Bytes, FetchError> {
+        self.fed = true;
+        let result = match &mut self.codec {
+            Codec::Gzip(d) => d.write_all(chunk),
+            Codec::Deflate(d) => d.write_all(chunk),
+            Codec::Brotli(d) => d.write_all(chunk),
+        };
+        result.map_err(|e| self.error(e))?;
+        Ok(self.take())
+    }
+
+    /// Signal the end of the encoded body, returning any remaining output. Fails if the
+    /// body was truncated.
+    pub(crate) fn finish(&mut self) -> Result<Bytes, FetchError> {
+        if !self.fed {
+            return Ok(Bytes::new());
+        }
+        let result = match &mut self.codec {
+            Codec::Gzip(d) => d.try_finish(),
+            Codec::Deflate(d) => d.try_finish(),
+            Codec::Brotli(d) => d.close(),
+        };
+        result.map_err(|e| self.error(e))?;
+        Ok(self.take())
+    }
+
+    fn sink(&mut self) -> &mut Sink {
+        match &mut self.codec {
+            Codec::Gzip(d) => d.get_mut(),
+            Codec::Deflate(d) => d.get_mut(),
+            Codec::Brotli(d) => d.get_mut(),
+        }
+    }
+
+    fn take(&mut self) -> Bytes {
+        std::mem::take(&mut self.sink().buf).into()
+    }
+
+    fn error(&mut self, e: io::Error) -> FetchError {
+        let encoding = self.encoding;
+        let sink = self.sink();
+        if sink.written > sink.limit {
+            FetchError::DecompressedBodyTooLarge {
+                size: sink.written,
+                limit: sink.limit,
+            }
+        } else {
+            FetchError::DecompressionFailed(format!("{encoding}: {e}"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+
+    use super::*;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn decode_all(decoder: &mut Decoder, encoded: &[u8]) -> Result<Vec<u8>, FetchError> {
+        let mut out = Vec::new();
+        for chunk in encoded.chunks(7) {
+            out.extend_from_slice(&decoder.decode(chunk)?);
+        }
+        out.extend_from_slice(&decoder.finish()?);
+        Ok(out)
+    }
+
+    #[test]
+    fn decodes_gzip_and_deflate_in_chunks() {
+        let text = b"hello hello hello hello world".repeat(10);
+        let mut decoder = Decoder::for_encoding("GZIP", 1000).unwrap();
+        assert_eq!(decode_all(&mut decoder, &gzip(&text)).unwrap(), text);
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&text).unwrap();
+        let mut decoder = Decoder::for_encoding("deflate", 1000).unwrap();
+        assert_eq!(
+            decode_all(&mut decoder, &encoder.finish().unwrap()).unwrap(),
+            text
+        );
+    }
+
+    #[test]
+    fn passes_through_unknown_and_stacked_encodings() {
+        assert!(Decoder::for_encoding("identity", 1000).is_none());
+        assert!(Decoder::for_encoding("zstd", 1000).is_none());
+        assert!(Decoder::for_encoding("gzip, br", 1000).is_none());
+    }
+
+    #[test]
+    fn stops_inflating_at_the_limit() {
+        let bomb = gzip(&vec![0; 10 * 1024 * 1024]);
+        assert!(bomb.len() < 20 * 1024);
+        let mut decoder = Decoder::for_encoding("gzip", 64 * 1024).unwrap();
+        let err = decode_all(&mut decoder, &bomb).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                FetchError::DecompressedBodyTooLarge { limit: 65536, .. }
+            ),
+            "got: {err}"
+        );
+    }
+
+    #[test]
+    fn rejects_corrupt_and_truncated_bodies() {
+        let mut decoder = Decoder::for_encoding("br", 1000).unwrap();
+        assert!(matches!(
+            decode_all(&mut decoder, b"not brotli at all"),
+            Err(FetchError::DecompressionFailed(_))
+        ));
+
+        let encoded = gzip(b"some text that will be cut short");
+        let mut decoder = Decoder::for_encoding("gzip", 1000).unwrap();
+        assert!(matches!(
+            decode_all(&mut decoder, &encoded[..encoded.len() / 2]),
+            Err(FetchError::DecompressionFailed(_))
+        ));
+    }
+}
@@ -0,0 +1,609 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::cache::{CacheStats, CacheStore, CachedResponse};
+use crate::download::to_hex;
+
+/// A persistent [`CacheStore`] for long-running agents, so cached responses survive a
+/// restart. Bodies are stored once per distinct content, named by their SHA-256, under
+/// `bodies/`. The index mapping keys to response metadata and bodies is a snapshot,
+/// `index.json`, plus the changes made since, appended to `journal.jsonl`.
+///
+/// Files are written by a background thread, in the order the changes were made, so
+/// storing a response doesn't block the caller. Bodies are written to a temporary name,
+/// synced, and renamed into place before the journal refers to them, and the journal is
+/// synced after each batch of changes, so a crash loses at most the latest changes.
+/// [`open`](Self::open) replays the journal, drops index entries whose body is missing,
+/// and deletes bodies nothing refers to. Bodies are checked against their hash when read.
+///
+/// Like [`LruCacheStore`](crate::LruCacheStore), the store evicts the least recently used
+/// entries once either limit is reached. Uses are journaled too, so the eviction order
+/// survives a restart. File errors are ignored: a failed write is a response not cached, a
+/// failed read a cache miss.
+#[derive(Debug)]
+pub struct DiskCacheStore {
+    dir: PathBuf,
+    max_entries: usize,
+    max_bytes: usize,
+    inner: Arc<Mutex<Index>>,
+    /// Queue of the background writer, and its thread; taken on drop to flush the queue.
+    writer: Option<(mpsc::Sender<Op>, JoinHandle<()>)>,
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    entries: HashMap<String, IndexEntry>,
+    tick: u64,
+    stats: CacheStats,
+    /// Bodies queued for writing, with the number of queued writes, served from memory
+    /// until they are on disk.
+    pending: HashMap<String, (Bytes, usize)>,
+}
+
+/// A response's metadata as kept in the index.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexEntry {
+    url: String,
+    status: u16,
+    headers: HashMap<String, String>,
+    version: String,
+    stored_at_ms: u64,
+    vary: HashMap<String, String>,
+    /// SHA-256 of the body, hex-encoded: its file name under `bodies/`.
+    body: String,
+    size: usize,
+    /// When the entry was last used, on the store's own clock.
+    used: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexFile {
+    entries: HashMap<String, IndexEntry>,
+}
+
+/// A change to the index, as a line of `journal.jsonl`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    Put { key: String, entry: IndexEntry },
+    Remove { key: String },
+    Use { key: String, used: u64 },
+}
+
+impl Record {
+    fn apply(self, entries: &mut HashMap<String, IndexEntry>) {
+        match self {
+            Record::Put { key, entry } => {
+                entries.insert(key, entry);
+            }
+            Record::Remove { key } => {
+                entries.remove(&key);
+            }
+            Record::Use { key, used } => {
+                if let Some(entry) = entries.get_mut(&key) {
+                    entry.used = used;
+                }
+            }
+        }
+    }
+}
+
+/// Work for the background writer.
+#[derive(Debug)]
+enum Op {
+    /// Write a body, unless it is already on disk.
+    WriteBody(String, Bytes),
+    /// Delete a body no entry refers to any more.
+    DeleteBody(String),
+    Log(Record),
+    /// Answer once everything queued before is written.
+    Flush(mpsc::Sender<()>),
+}
+
+/// Journal records after which the writer folds the journal into a new snapshot.
+const COMPACT_AFTER: usize = 1000;
+
+/// Distinguishes temporary files written concurrently by this process.
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+impl DiskCacheStore {
+    /// Open (or create) a store in `dir`. Entries already there count toward the limits,
+    /// and are evicted if the limits have been lowered since.
+    pub fn open(dir: impl AsRef<Path>, max_entries: usize, max_bytes: usize) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(dir.join("bodies"))?;
+
+        let mut entries = fs::read(dir.join("index.json"))
+            .ok()
+            .and_then(|data| serde_json::from_slice::<IndexFile>(&data).ok())
+            .map(|index| index.entries)
+            .unwrap_or_default();
+        // A crash may leave the last line half-written: replay up to it.
+        if let Ok(journal) = fs::File::open(dir.join("journal.jsonl")) {
+            let records = io::BufReader::new(journal)
+                .lines()
+                .map_while(|line| serde_json::from_str::<Record>(&line.ok()?).ok());
+            for record in records {
+                record.apply(&mut entries);
+            }
+        }
+        // A body named anything but a hash could point outside the directory.
+        entries.retain(|_, entry| {
+            is_hash(&entry.body) && dir.join("bodies").join(&entry.body).is_file()
+        });
+        for file in fs::read_dir(dir.join("bodies"))? {
+            let file = file?;
+            let name = file.file_name();
+            if !entries.values().any(|e| name == e.body.as_str()) {
+                let _ = fs::remove_file(file.path());
+            }
+        }
+
+        let mut index = Index {
+            tick: entries.values().map(|e| e.used).max().unwrap_or(0),
+            stats: CacheStats {
+                entries: entries.len(),
+                bytes: entries.values().map(|e| e.size).sum(),
+                ..CacheStats::default()
+            },
+            entries,
+            pending: HashMap::new(),
+        };
+        // Evict over the limits, then start over from a fresh snapshot and empty journal.
+        let mut deleted = Vec::new();
+        make_room(&mut index, max_entries, max_bytes, None, &mut |op| {
+            if let Op::DeleteBody(hash) = op {
+                deleted.push(hash);
+            }
+        });
+        for hash in deleted {
+            let _ = fs::remove_file(dir.join("bodies").join(hash));
+        }
+        let snapshot = index.entries.clone();
+        write_snapshot(&dir, &snapshot)?;
+        fs::File::create(dir.join("journal.jsonl"))?.sync_all()?;
+
+        let inner = Arc::new(Mutex::new(index));
+        let (queue, ops) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("agent-fetch-disk-cache".into())
+            .spawn({
+                let dir = dir.clone();
+                let inner = inner.clone();
+                move || write_in_background(&dir, &inner, snapshot, ops)
+            })?;
+        Ok(Self {
+            dir,
+            max_entries,
+            max_bytes,
+            inner,
+            writer: Some((queue, thread)),
+        })
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock().unwrap().stats
+    }
+
+    fn body_path(&self, hash: &str) -> PathBuf {
+        self.dir.join("bodies").join(hash)
+    }
+
+    /// Wait until every change made so far is written to disk.
+    pub fn flush(&self) {
+        let (done, flushed) = mpsc::channel();
+        self.queue(Op::Flush(done));
+        let _ = flushed.recv();
+    }
+
+    fn queue(&self, op: Op) {
+        if let Some((queue, _)) = &self.writer {
+            let _ = queue.send(op);
+        }
+    }
+}
+
+impl Drop for DiskCacheStore {
+    /// Wait for queued writes to finish.
+    fn drop(&mut self) {
+        if let Some((queue, thread)) = self.writer.take() {
+            drop(queue);
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Evict least recently used entries until `index` is within the limits, with room for an
+/// incoming entry of the given size, if any.
+fn make_room(
+    index: &mut Index,
+    max_entries: usize,
+    max_bytes: usize,
+    incoming: Option<usize>,
+    queue: &mut dyn FnMut(Op),
+) {
+    let size = incoming.unwrap_or(0);
+    while index.stats.entries + usize::from(incoming.is_some()) > max_entries
+        || index.stats.bytes + size > max_bytes
+    {
+        let Some(oldest) = index
+            .entries
+            .iter()
+            .min_by_key(|(_, e)| e.used)
+            .map(|(key, _)| key.clone())
+        else {
+            break;
+        };
+        remove_entry(index, &oldest, queue);
+        index.stats.evictions += 1;
+    }
+}
+
+/// Drop an entry, and its body unless another entry shares it.
+fn remove_entry(index: &mut Index, key: &str, queue: &mut dyn FnMut(Op)) -> bool {
+    let Some(entry) = index.entries.remove(key) else {
+        return false;
+    };
+    index.stats.entries -= 1;
+    index.stats.bytes -= entry.size;
+    queue(Op::Log(Record::Remove { key: key.into() }));
+    if !index.entries.values().any(|e| e.body == entry.body) {
+        queue(Op::DeleteBody(entry.body));
+    }
+    true
+}
+
+fn write_snapshot(dir: &Path, entries: &HashMap<String, IndexEntry>) -> io::Result<()> {
+    let file = IndexFile {
+        entries: entries.clone(),
+    };
+    let data = serde_json::to_vec(&file).map_err(io::Error::other)?;
+    write_atomic(&dir.join("index.json"), &data)
+}
+
+/// The background writer: carries out `ops` in order, batching the journal records queued
+/// together into one synced append. `entries` mirrors the index as written so far, for
+/// compacting the journal into a new snapshot.
+fn write_in_background(
+    dir: &Path,
+    inner: &Mutex<Index>,
+    mut entries: HashMap<String, IndexEntry>,
+    ops: mpsc::Receiver<Op>,
+) {
+    let mut logged = 0;
+    while let Ok(op) = ops.recv() {
+        let mut lines = Vec::new();
+        let mut flushed = Vec::new();
+        for op in std::iter::once(op).chain(ops.try_iter()) {
+            match op {
+                Op::WriteBody(hash, body) => {
+                    let path = dir.join("bodies").join(&hash);
+                    // On failure the body stays missing, and the entry is dropped when read.
+                    if !path.is_file() {
+                        let _ = write_atomic(&path, &body);
+                    }
+                    let mut index = inner.lock().unwrap();
+                    if let Some((_, queued)) = index.pending.get_mut(&hash) {
+                        *queued -= 1;
+                        if *queued == 0 {
+                            index.pending.remove(&hash);
+                        }
+                    }
+                }
+                Op::DeleteBody(hash) => {
+                    let _ = fs::remove_file(dir.join("bodies").join(hash));
+                }
+                Op::Log(record) => {
+                    if let Ok(line) = serde_json::to_string(&record) {
+                        lines.push(line);
+                    }
+                    record.apply(&mut entries);
+                }
+                Op::Flush(done) => flushed.push(done),
+            }
+        }
+        if !lines.is_empty() {
+            logged += lines.len();
+            append_or_compact(dir, &lines, &entries, &mut logged);
+        }
+        for done in flushed {
+            let _ = done.send(());
+        }
+    }
+}
+
+/// Append `lines` to the journal, or once it holds [`COMPACT_AFTER`] records, write
+/// `entries`, which include them, as a new snapshot and empty the journal instead.
+fn append_or_compact(
+    dir: &Path,
+    lines: &[String],
+    entries: &HashMap<String, IndexEntry>,
+    logged: &mut usize,
+) {
+    let journal = dir.join("journal.jsonl");
+    if *logged >= COMPACT_AFTER && write_snapshot(dir, entries).is_ok() {
+        // Replaying the old journal over the new snapshot changes nothing, so a crash
+        // before it is emptied is harmless.
+        if fs::File::create(&journal).is_ok_and(|file| file.sync_all().is_ok()) {
+            *logged = 0;
+        }
+        return;
+    }
+    let _ = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&journal)
+        .and_then(|mut file| {
+            file.write_all((lines.join("\n") + "\n").as_bytes())?;
+            file.sync_data()
+        });
+}
+
+/// Write `data` to `path` so that a crash leaves either the old file or the new one.
+/// Whether `name` is a hex-encoded SHA-256, as bodies are named.
+fn is_hash(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(
+        ".tmp-{}-{}",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let temp = PathBuf::from(temp);
+    let result = (|| {
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&temp, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+fn version_name(version: http::Version) -> String {
+    format!("{version:?}")
+}
+
+fn parse_version(name: &str) -> http::Version {
+    match name {
+        "HTTP/0.9" => http::Version::HTTP_09,
+        "HTTP/1.0" => http::Version::HTTP_10,
+        "HTTP/2.0" => http::Version::HTTP_2,
+        "HTTP/3.0" => http::Version::HTTP_3,
+        _ => http::Version::HTTP_11,
+    }
+}
+
+impl CacheStore for DiskCacheStore {
+    fn get(&self, key: &str) -> Option<CachedResponse> {
+        let mut index = self.inner.lock().unwrap();
+        let found = index.entries.get(key).and_then(|entry| {
+            if let Some((body, _)) = index.pending.get(&entry.body) {
+                return Some(body.clone());
+            }
+            let body = fs::read(self.body_path(&entry.body)).ok()?;
+            (to_hex(&Sha256::digest(&body)) == entry.body).then(|| body.into())
+        });
+        let Some(body) = found else {
+            // Missing or corrupt bodies are dropped.
+            remove_entry(&mut index, key, &mut |op| self.queue(op));
+            index.stats.misses += 1;
+            return None;
+        };
+        index.stats.hits += 1;
+        index.tick += 1;
+        let tick = index.tick;
+        let entry = index.entries.get_mut(key)?;
+        entry.used = tick;
+        let response = CachedResponse {
+            url: entry.url.clone(),
+            status: entry.status,
+            headers: entry.headers.clone(),
+            body,
+            version: parse_version(&entry.version),
+            stored_at: SystemTime::UNIX_EPOCH + Duration::from_millis(entry.stored_at_ms),
+            vary: entry.vary.clone(),
+        };
+        self.queue(Op::Log(Record::Use {
+            key: key.into(),
+            used: tick,
+        }));
+        Some(response)
+    }
+
+    fn put(&self, key: &str, response: CachedResponse) {
+        let mut index = self.inner.lock().unwrap();
+        let mut queue = |op| self.queue(op);
+        remove_entry(&mut index, key, &mut queue);
+        let size = response.size();
+        if size > self.max_bytes || self.max_entries == 0 {
+            index.stats.rejected += 1;
+            return;
+        }
+
+        // Evicting first, so a body shared with an evicted entry is written again after
+        // being deleted.
+        make_room(
+            &mut index,
+            self.max_entries,
+            self.max_bytes,
+            Some(size),
+            &mut queue,
+        );
+        let hash = to_hex(&Sha256::digest(&response.body));
+        let pending = index
+            .pending
+            .entry(hash.clone())
+            .or_insert_with(|| (response.body.clone(), 0));
+        pending.1 += 1;
+        queue(Op::WriteBody(hash.clone(), response.body.clone()));
+        index.tick += 1;
+        let stored_at_ms = response
+            .stored_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let entry = IndexEntry {
+            url: response.url,
+            status: response.status,
+            headers: response.headers,
+            version: version_name(response.version),
+            stored_at_ms,
+            vary: response.vary,
+            body: hash,
+            size,
+            used: index.tick,
+        };
+        queue(Op::Log(Record::Put {
+            key: key.into(),
+            entry: entry.clone(),
+        }));
+        index.entries.insert(key.to_string(), entry);
+        index.stats.entries += 1;
+        index.stats.bytes += size;
+    }
+
+    fn remove(&self, key: &str) {
+        let mut index = self.inner.lock().unwrap();
+        remove_entry(&mut index, key, &mut |op| self.queue(op));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    fn response(body: &'static [u8]) -> CachedResponse {
+        CachedResponse {
+            url: "https://example.com/".into(),
+            status: 200,
+            headers: HashMap::from([("etag".to_string(), "\"v1\"".to_string())]),
+            body: Bytes::from_static(body),
+            version: http::Version::HTTP_2,
+            stored_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_000),
+            vary: HashMap::new(),
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("agent-fetch-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn entries_survive_reopening() {
+        let dir = temp_dir("disk-cache-reopen");
+        let store = DiskCacheStore::open(&dir, 10, 1000).unwrap();
+        store.put("a", response(b"hello"));
+        store.put("b", response(b"hello"));
+        store.put("c", response(b"other"));
+        store.remove("c");
+        drop(store);
+        // A body nothing refers to, as left by a crash between writing it and the index.
+        fs::write(dir.join("bodies").join("orphan"), b"x").unwrap();
+
+        let store = DiskCacheStore::open(&dir, 10, 1000).unwrap();
+        let cached = store.get("a").unwrap();
+        assert_eq!(cached.body, "hello");
+        assert_eq!(cached.version, http::Version::HTTP_2);
+        assert_eq!(cached.stored_at, response(b"").stored_at);
+        assert_eq!(cached.headers["etag"], "\"v1\"");
+        assert!(store.get("b").is_some());
+        assert!(store.get("c").is_none());
+        // "a" and "b" share one body file.
+        assert_eq!(fs::read_dir(dir.join("bodies")).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn entries_naming_files_outside_the_store_are_dropped() {
+        let dir = temp_dir("disk-cache-tampered");
+        let store = DiskCacheStore::open(&dir, 10, 1000).unwrap();
+        store.put("a", response(b"hello"));
+        drop(store);
+        // Reopened, so the entry is in the snapshot.
+        drop(DiskCacheStore::open(&dir, 10, 1000).unwrap());
+        fs::write(dir.join("victim"), b"keep").unwrap();
+        let mut index: IndexFile =
+            serde_json::from_slice(&fs::read(dir.join("index.json")).unwrap()).unwrap();
+        let mut entry = index.entries["a"].clone();
+        entry.body = "../victim".into();
+        index.entries.insert("evil".into(), entry);
+        write_atomic(
+            &dir.join("index.json"),
+            &serde_json::to_vec(&index).unwrap(),
+        )
+        .unwrap();
+
+        // Evicting everything would delete what the entry names.
+        let store = DiskCacheStore::open(&dir, 0, 1000).unwrap();
+        assert!(store.get("evil").is_none());
+        drop(store);
+        assert_eq!(fs::read(dir.join("victim")).unwrap(), b"keep");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn evicts_and_drops_corrupt_bodies() {
+        let dir = temp_dir("disk-cache-evict");
+        let store = DiskCacheStore::open(&dir, 2, 1000).unwrap();
+        store.put("a", response(b"first"));
+        store.put("b", response(b"second"));
+        assert!(store.get("a").is_some());
+        store.put("c", response(b"third"));
+        assert!(store.get("b").is_none());
+        assert_eq!(store.stats().evictions, 1);
+
+        store.flush();
+        let hash = to_hex(&Sha256::digest(b"first"));
+        fs::write(dir.join("bodies").join(hash), b"tampered").unwrap();
+        assert!(store.get("a").is_none());
+        assert_eq!(store.stats().entries, 1);
+        drop(store);
+
+        // Lowering the limits evicts on open.
+        let store = DiskCacheStore::open(&dir, 10, 5).unwrap();
+        assert_eq!(store.stats().entries, 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn eviction_order_survives_reopening() {
+        let dir = temp_dir("disk-cache-order");
+        let store = DiskCacheStore::open(&dir, 2, 1000).unwrap();
+        store.put("a", response(b"first"));
+        store.put("b", response(b"second"));
+        // Served from memory until the writer has caught up, then from disk.
+        assert!(store.get("a").is_some());
+        drop(store);
+
+        let store = DiskCacheStore::open(&dir, 2, 1000).unwrap();
+        store.put("c", response(b"third"));
+        assert!(store.get("b").is_none());
+        assert!(store.get("a").is_some());
+        drop(store);
+
+        // Entries added after the snapshot were replayed from the journal.
+        let store = DiskCacheStore::open(&dir, 2, 1000).unwrap();
+        assert_eq!(store.get("c").unwrap().body, "third");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,50 +1,169 @@
+use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 
+use hickory_resolver::config::{LookupIpStrategy, NameServerConfig, ResolverConfig};
+use hickory_resolver::name_server::TokioConnectionProvider;
+use hickory_resolver::proto::xfer::Protocol;
 use hickory_resolver::TokioResolver;
+use ipnet::IpNet;
+use rustls::{ClientConfig, RootCertStore};
 
+use crate::decision::Decision;
 use crate::error::FetchError;
 use crate::ip_check::is_private_ip;
+use crate::policy::{AddressFamily, DnsCachePolicy, DnsOverTls, FetchPolicy, RootCertificates};
+use crate::tls;
 
 /// DNS resolver that validates all resolved IPs against SSRF rules.
+///
+/// Answers are cached per `dns_cache`, but addresses are checked on every lookup, cached or
+/// not, so a policy change applies to them at once. With `dns_servers` or `dns_over_tls`
+/// set, lookups go only to those resolvers, never to the system's.
+#[derive(Clone)]
 pub struct SafeDnsResolver {
     resolver: TokioResolver,
+    /// The settings `resolver` was built with.
+    settings: ResolverSettings,
+    /// `dns_overrides`, keyed by lowercase host.
+    overrides: HashMap<String, Vec<IpAddr>>,
+    trust_overrides: bool,
+    timeout: Duration,
     deny_private_ips: bool,
+    blocked_ip_ranges: Vec<IpNet>,
+    allowed_ip_ranges: Vec<IpNet>,
+    allowed_address_families: Vec<AddressFamily>,
 }
 
 impl SafeDnsResolver {
-    pub fn new(deny_private_ips: bool) -> Self {
-        let resolver = TokioResolver::builder_tokio()
-            .expect("failed to read system DNS config")
-            .build();
+    pub fn new(policy: &FetchPolicy) -> Self {
+        let settings = ResolverSettings::of(policy);
+        let mut builder = match resolver_config(&settings) {
+            Some(config) => {
+                TokioResolver::builder_with_config(config, TokioConnectionProvider::default())
+            }
+            None => TokioResolver::builder_tokio().expect("failed to read system DNS config"),
+        };
+        let options = builder.options_mut();
+        // Look up both families, so dual-stack hosts can be raced and all their addresses
+        // are checked, not just the first family to answer.
+        options.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        let cache = &policy.dns_cache;
+        options.cache_size = cache.max_entries;
+        options.positive_min_ttl = Some(Duration::from_secs(cache.min_ttl_secs));
+        options.positive_max_ttl = Some(Duration::from_secs(cache.max_ttl_secs));
+        options.negative_min_ttl = None;
+        options.negative_max_ttl = Some(Duration::from_secs(cache.negative_ttl_secs));
+        if settings.dns_over_tls.is_some() {
+            options.tls_config = dot_tls_config(&settings);
+        }
+        let resolver = builder.build();
 
         Self {
             resolver,
-            deny_private_ips,
+            settings,
+            overrides: overrides(policy),
+            trust_overrides: policy.trust_dns_overrides,
+            timeout: Duration::from_millis(policy.dns_timeout_ms),
+            deny_private_ips: policy.deny_private_ips,
+            blocked_ip_ranges: policy.blocked_ip_ranges.clone(),
+            allowed_ip_ranges: policy.allowed_ip_ranges.clone(),
+            allowed_address_families: policy.allowed_address_families.clone(),
         }
     }
 
-    /// Resolve a hostname and validate all returned IPs.
-    /// Returns the set of validated socket addresses.
-    pub async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, FetchError> {
-        if let Ok(ip) = host.parse::<IpAddr>() {
-            if self.deny_private_ips && is_private_ip(ip) {
-                return Err(FetchError::PrivateIpBlocked {
-                    host: host.to_string(),
-                    resolved_ip: ip,
-                });
-            }
-            return Ok(vec![SocketAddr::new(ip, port)]);
+    /// Build a resolver for a new policy that shares this resolver's underlying lookup
+    /// machinery (connections and cache) but applies the new policy's IP rules. A policy
+    /// with different cache settings or resolvers gets a fresh resolver instead.
+    pub fn with_policy(&self, policy: &FetchPolicy) -> Self {
+        if ResolverSettings::of(policy) != self.settings {
+            return Self::new(policy);
+        }
+        Self {
+            resolver: self.resolver.clone(),
+            settings: self.settings.clone(),
+            overrides: overrides(policy),
+            trust_overrides: policy.trust_dns_overrides,
+            timeout: Duration::from_millis(policy.dns_timeout_ms),
+            deny_private_ips: policy.deny_private_ips,
+            blocked_ip_ranges: policy.blocked_ip_ranges.clone(),
+            allowed_ip_ranges: policy.allowed_ip_ranges.clone(),
+            allowed_address_families: policy.allowed_address_families.clone(),
+        }
+    }
+
+    /// Validate a single resolved IP against the private-IP rule and the blocked ranges.
+    /// Addresses in an allowed range skip the private-IP rule but not the blocked ranges.
+    /// IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) are checked as the IPv4 address they
+    /// reach.
+    fn check_ip(&self, host: &str, ip: IpAddr) -> Result<(), FetchError> {
+        let ip = ip.to_canonical();
+        let exempt = self.allowed_ip_ranges.iter().any(|net| net.contains(&ip));
+        if self.deny_private_ips && !exempt && is_private_ip(ip) {
+            return Err(FetchError::PrivateIpBlocked {
+                host: host.to_string(),
+                resolved_ip: ip,
+                decision: Decision::deny_unmatched("deny_private_ips"),
+            });
+        }
+        if let Some(net) = self.blocked_ip_ranges.iter().find(|net| net.contains(&ip)) {
+            return Err(FetchError::IpRangeBlocked {
+                host: host.to_string(),
+                resolved_ip: ip,
+                decision: Decision::deny("blocked_ip_ranges", net.to_string()),
+            });
         }
+        Ok(())
+    }
+
+    /// Resolve a hostname and validate all returned IPs, then drop addresses outside the
+    /// allowed address families.
+    /// Returns the set of validated socket addresses, ordered for connecting: IPv6 and IPv4
+    /// alternate, IPv6 first, as RFC 8305 recommends. The connector races the two families,
+    /// starting on the second 300 ms after the first if it hasn't connected yet, and gives
+    /// each address its share of the connect timeout.
+    pub async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, FetchError> {
+        self.lookup(host, port, true).await
+    }
+
+    /// Resolve a hostname the operator chose, such as the proxy's, skipping the IP checks.
+    /// The address families still apply.
+    pub(crate) async fn resolve_trusted(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> Result<Vec<SocketAddr>, FetchError> {
+        self.lookup(host, port, false).await
+    }
 
-        let response =
-            self.resolver
-                .lookup_ip(host)
+    async fn lookup(
+        &self,
+        host: &str,
+        port: u16,
+        check: bool,
+    ) -> Result<Vec<SocketAddr>, FetchError> {
+        let literal = host.trim_start_matches('[').trim_end_matches(']');
+        let mut check = check;
+        let ips: Vec<IpAddr> = if let Ok(ip) = literal.parse::<IpAddr>() {
+            vec![ip]
+        } else if let Some(ips) = self.overrides.get(&override_key(host)) {
+            check &= !self.trust_overrides;
+            ips.clone()
+        } else {
+            let response = tokio::time::timeout(self.timeout, self.resolver.lookup_ip(host))
                 .await
+                .map_err(|_| {
+                    FetchError::DnsResolutionFailed(format!(
+                        "lookup of {host} timed out after {} ms",
+                        self.timeout.as_millis()
+                    ))
+                })?
                 .map_err(|e: hickory_resolver::ResolveError| {
                     FetchError::DnsResolutionFailed(e.to_string())
                 })?;
-
-        let ips: Vec<IpAddr> = response.iter().collect();
+            response.iter().collect()
+        };
 
         if ips.is_empty() {
             return Err(FetchError::DnsResolutionFailed(format!(
@@ -52,20 +171,329 @@ impl SafeDnsResolver {
             )));
         }
 
-        if self.deny_private_ips {
+        if check {
             for &ip in &ips {
-                if is_private_ip(ip) {
-                    return Err(FetchError::PrivateIpBlocked {
-                        host: host.to_string(),
-                        resolved_ip: ip,
-                    });
-                }
+                self.check_ip(host, ip)?;
             }
         }
 
-        Ok(ips
+        let addrs: Vec<SocketAddr> = ips
             .into_iter()
+            .filter(|&ip| {
+                self.allowed_address_families
+                    .contains(&AddressFamily::of(ip))
+            })
             .map(|ip| SocketAddr::new(ip, port))
-            .collect())
+            .collect();
+        if addrs.is_empty() {
+            return Err(FetchError::AddressFamilyNotAllowed(host.to_string()));
+        }
+        Ok(interleave(addrs))
+    }
+}
+
+fn overrides(policy: &FetchPolicy) -> HashMap<String, Vec<IpAddr>> {
+    policy
+        .dns_overrides
+        .iter()
+        .map(|(host, ips)| (override_key(host), ips.clone()))
+        .collect()
+}
+
+/// Hosts match overrides case-insensitively, with or without a trailing dot.
+fn override_key(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// The parts of a policy the underlying resolver is built from.
+#[derive(Clone, PartialEq)]
+struct ResolverSettings {
+    cache: DnsCachePolicy,
+    dns_servers: Vec<SocketAddr>,
+    dns_over_tls: Option<DnsOverTls>,
+    /// The roots DNS-over-TLS resolvers are verified against; empty without them.
+    root_certificates: Vec<RootCertificates>,
+    use_system_roots: bool,
+}
+
+impl ResolverSettings {
+    fn of(policy: &FetchPolicy) -> Self {
+        let dot = policy.dns_over_tls.is_some();
+        Self {
+            cache: policy.dns_cache.clone(),
+            dns_servers: policy.dns_servers.clone(),
+            dns_over_tls: policy.dns_over_tls.clone(),
+            root_certificates: if dot {
+                policy.root_certificates.clone()
+            } else {
+                Vec::new()
+            },
+            use_system_roots: !dot || policy.use_system_roots,
+        }
+    }
+}
+
+/// The resolvers the policy names, with no search domains, or `None` to use the system's
+/// configuration. DNS over TLS without servers gets no resolvers, so lookups fail rather
+/// than go out unencrypted.
+fn resolver_config(settings: &ResolverSettings) -> Option<ResolverConfig> {
+    let servers: Vec<_> = match &settings.dns_over_tls {
+        Some(dot) => dot
+            .servers
+            .iter()
+            .map(|&addr| {
+                let mut server = NameServerConfig::new(addr, Protocol::Tls);
+                server.tls_dns_name = Some(dot.server_name.clone());
+                server
+            })
+            .collect(),
+        // UDP first, and TCP for answers too large for it.
+        None => settings
+            .dns_servers
+            .iter()
+            .flat_map(|&addr| {
+                [Protocol::Udp, Protocol::Tcp].map(|p| NameServerConfig::new(addr, p))
+            })
+            .collect(),
+    };
+    let named = !servers.is_empty() || settings.dns_over_tls.is_some();
+    named.then(|| ResolverConfig::from_parts(None, vec![], servers))
+}
+
+fn dot_tls_config(settings: &ResolverSettings) -> ClientConfig {
+    // The policy was validated, so the roots load. Should they fail to now, nothing is
+    // trusted and lookups fail rather than go out unencrypted.
+    let roots = tls::root_store(&settings.root_certificates, settings.use_system_roots)
+        .unwrap_or_else(|_| RootCertStore::empty());
+    ClientConfig::builder_with_provider(Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("aws-lc-rs supports the default protocol versions")
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+}
+
+/// Order `addrs` for Happy Eyeballs (RFC 8305 §4): alternate between the families, starting
+/// with IPv6, keeping the resolver's order within each.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let mut v4 = v4.into_iter();
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    for addr in v6 {
+        ordered.push(addr);
+        ordered.extend(v4.next());
+    }
+    ordered.extend(v4);
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn ipv4_mapped_addresses_are_checked_as_ipv4() {
+        let dns = SafeDnsResolver::new(&FetchPolicy::default());
+        for host in ["[::ffff:127.0.0.1]", "[::ffff:169.254.169.254]"] {
+            assert!(
+                matches!(
+                    dns.resolve(host, 80).await,
+                    Err(FetchError::PrivateIpBlocked { .. })
+                ),
+                "{host} got through"
+            );
+        }
+
+        let dns = SafeDnsResolver::new(&FetchPolicy {
+            deny_private_ips: false,
+            blocked_ip_ranges: vec!["169.254.0.0/16".parse().unwrap()],
+            ..Default::default()
+        });
+        assert!(matches!(
+            dns.resolve("[::ffff:169.254.169.254]", 80).await,
+            Err(FetchError::IpRangeBlocked { .. })
+        ));
+
+        let dns = SafeDnsResolver::new(&FetchPolicy {
+            allowed_ip_ranges: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Default::default()
+        });
+        assert!(dns.resolve("[::ffff:127.0.0.1]", 80).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn dns_over_tls_without_servers_never_falls_back_to_the_system() {
+        let policy = FetchPolicy {
+            dns_over_tls: Some(DnsOverTls {
+                servers: Vec::new(),
+                server_name: "dns.example".into(),
+            }),
+            ..Default::default()
+        };
+        let config = resolver_config(&ResolverSettings::of(&policy)).unwrap();
+        assert!(config.name_servers().is_empty());
+        let dns = SafeDnsResolver::new(&policy);
+        assert!(dns.resolve("example.com", 443).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn cache_settings_clamp_ttls() {
+        let policy = FetchPolicy {
+            dns_cache: DnsCachePolicy {
+                max_entries: 64,
+                min_ttl_secs: 5,
+                max_ttl_secs: 600,
+                negative_ttl_secs: 0,
+            },
+            ..Default::default()
+        };
+        let dns = SafeDnsResolver::new(&policy);
+        let options = dns.resolver.options();
+        assert_eq!(options.cache_size, 64);
+        assert_eq!(options.positive_min_ttl, Some(Duration::from_secs(5)));
+        assert_eq!(options.positive_max_ttl, Some(Duration::from_secs(600)));
+        assert_eq!(options.negative_max_ttl, Some(Duration::ZERO));
+
+        let same = dns.with_policy(&FetchPolicy {
+            deny_private_ips: false,
+            ..policy.clone()
+        });
+        assert_eq!(same.resolver.options().cache_size, 64);
+        let changed = dns.with_policy(&FetchPolicy::default());
+        assert_eq!(
+            changed.resolver.options().cache_size,
+            DnsCachePolicy::default().max_entries
+        );
+    }
+
+    #[tokio::test]
+    async fn overrides_answer_without_a_lookup_and_are_checked() {
+        let policy = FetchPolicy {
+            dns_overrides: [
+                (
+                    "Fixture.Test".to_string(),
+                    vec!["127.0.0.1".parse().unwrap()],
+                ),
+                (
+                    "api.example.com".to_string(),
+                    vec!["93.184.215.14".parse().unwrap()],
+                ),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let dns = SafeDnsResolver::new(&policy);
+        assert_eq!(
+            dns.resolve("api.example.com.", 443).await.unwrap(),
+            ["93.184.215.14:443".parse().unwrap()]
+        );
+        assert!(matches!(
+            dns.resolve("fixture.test", 80).await,
+            Err(FetchError::PrivateIpBlocked { .. })
+        ));
+
+        let trusted = dns.with_policy(&FetchPolicy {
+            trust_dns_overrides: true,
+            ..policy
+        });
+        assert_eq!(
+            trusted.resolve("fixture.test", 80).await.unwrap(),
+            ["127.0.0.1:80".parse().unwrap()]
+        );
+        // Hosts without an override are still checked.
+        assert!(trusted.resolve("127.0.0.1", 80).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn lookups_time_out() {
+        // A resolver that takes queries over UDP and TCP but never answers.
+        let udp = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = udp.local_addr().unwrap();
+        let _tcp = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let dns = SafeDnsResolver::new(&FetchPolicy {
+            dns_servers: vec![addr],
+            dns_timeout_ms: 100,
+            ..Default::default()
+        });
+
+        let started = std::time::Instant::now();
+        let err = dns.resolve("example.com", 443).await.unwrap_err();
+        assert!(
+            matches!(&err, FetchError::DnsResolutionFailed(msg) if msg.contains("timed out")),
+            "got: {err}"
+        );
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn dns_servers_replace_the_system_resolvers() {
+        let policy = FetchPolicy {
+            dns_servers: vec!["10.0.0.53:5353".parse().unwrap()],
+            ..Default::default()
+        };
+        let dns = SafeDnsResolver::new(&policy);
+        let config = dns.resolver.config();
+        let servers: Vec<_> = config
+            .name_servers()
+            .iter()
+            .map(|s| (s.socket_addr, s.protocol))
+            .collect();
+        let addr = "10.0.0.53:5353".parse().unwrap();
+        assert_eq!(servers, [(addr, Protocol::Udp), (addr, Protocol::Tcp)]);
+        assert!(config.search().is_empty());
+        assert!(config.domain().is_none());
+    }
+
+    #[tokio::test]
+    async fn dns_over_tls_replaces_the_system_resolvers() {
+        let policy = FetchPolicy {
+            dns_over_tls: Some(DnsOverTls {
+                servers: vec!["10.0.0.53:853".parse().unwrap()],
+                server_name: "dns.corp.example".into(),
+            }),
+            ..Default::default()
+        };
+        let dns = SafeDnsResolver::new(&policy);
+        let servers = dns.resolver.config().name_servers();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].socket_addr, "10.0.0.53:853".parse().unwrap());
+        assert_eq!(servers[0].protocol, Protocol::Tls);
+        assert_eq!(servers[0].tls_dns_name.as_deref(), Some("dns.corp.example"));
+        assert!(dns.resolver.config().search().is_empty());
+
+        // Turning it off goes back to the system's resolvers, none of them over TLS.
+        let plain = dns.with_policy(&FetchPolicy::default());
+        assert!(plain
+            .resolver
+            .config()
+            .name_servers()
+            .iter()
+            .all(|s| s.protocol != Protocol::Tls));
+    }
+
+    #[test]
+    fn interleaves_families_ipv6_first() {
+        let addrs = |list: &[&str]| -> Vec<SocketAddr> {
+            list.iter().map(|a| a.parse().unwrap()).collect()
+        };
+        assert_eq!(
+            interleave(addrs(&[
+                "192.0.2.1:443",
+                "192.0.2.2:443",
+                "192.0.2.3:443",
+                "[2001:db8::1]:443",
+                "[2001:db8::2]:443",
+            ])),
+            addrs(&[
+                "[2001:db8::1]:443",
+                "192.0.2.1:443",
+                "[2001:db8::2]:443",
+                "192.0.2.2:443",
+                "192.0.2.3:443",
+            ])
+        );
+        assert_eq!(
+            interleave(addrs(&["192.0.2.1:80"])),
+            addrs(&["192.0.2.1:80"])
+        );
     }
 }
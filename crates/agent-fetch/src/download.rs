@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use futures_util::StreamExt;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
+
+use crate::client::{FetchRequest, SafeClient};
+use crate::error::FetchError;
+
+/// Options for [`SafeClient::download`].
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    pub headers: HashMap<String, String>,
+    /// Mirrors to fall back to; see [`FetchRequest::mirrors`].
+    pub mirrors: Vec<String>,
+    /// Policy profile to fetch under; see [`FetchRequest::profile`].
+    pub profile: Option<String>,
+    /// Expected SHA-256 of the file, hex-encoded. A mismatch fails the download.
+    pub sha256: Option<String>,
+    pub cancel: Option<CancellationToken>,
+    /// Keep an interrupted download and continue it on the next call with a `Range`
+    /// request, if the server supports ranges and identifies the file with a strong `ETag`
+    /// or a `Last-Modified` date. The body is requested without content encoding, so byte
+    /// offsets stay meaningful.
+    pub resume: bool,
+}
+
+/// A file written by [`SafeClient::download`].
+#[derive(Debug, Clone)]
+pub struct DownloadedFile {
+    pub path: PathBuf,
+    pub size: u64,
+    /// SHA-256 of the file, hex-encoded.
+    pub sha256: String,
+    /// The URL the file was served from, after redirects.
+    pub url: String,
+    pub headers: HashMap<String, String>,
+    /// Bytes kept from an earlier, interrupted download; 0 if the file was fetched whole.
+    pub resumed_from: u64,
+}
+
+/// Where an in-progress download is kept: the partial body, and the validator that
+/// identifies the version of the file it belongs to.
+struct PartialFile {
+    body: PathBuf,
+    validator: PathBuf,
+}
+
+impl PartialFile {
+    fn new(path: &Path) -> Self {
+        let with_suffix = |suffix: &str| {
+            let mut name = OsString::from(path);
+            name.push(suffix);
+            PathBuf::from(name)
+        };
+        Self {
+            body: with_suffix(".part"),
+            validator: with_suffix(".part.meta"),
+        }
+    }
+
+    /// Length and validator of a download that can be resumed.
+    async fn resumable(&self) -> Option<(u64, String)> {
+        let validator = tokio::fs::read_to_string(&self.validator).await.ok()?;
+        let len = tokio::fs::metadata(&self.body).await.ok()?.len();
+        (len > 0 && !validator.is_empty()).then_some((len, validator))
+    }
+
+    async fn remove(&self) {
+        let _ = tokio::fs::remove_file(&self.body).await;
+        let _ = tokio::fs::remove_file(&self.validator).await;
+    }
+}
+
+impl SafeClient {
+    /// Stream the body of a `GET` for `url` to a file at `path`, without holding it in
+    /// memory. The response-size limit and the transfer budget are enforced as the body
+    /// arrives; the size limit covers the whole file, including any resumed part.
+    ///
+    /// The body is written to `<path>.part` and only renamed to `path` once it is complete
+    /// and its checksum matches, so `path` never holds a partial or unverified file. Fails
+    /// with `UnexpectedStatus` unless the status is 2xx.
+    pub async fn download(
+        &self,
+        url: impl Into<String>,
+        path: impl AsRef<Path>,
+        options: DownloadOptions,
+    ) -> Result<DownloadedFile, FetchError> {
+        let path = path.as_ref();
+        let partial = PartialFile::new(path);
+
+        let request = FetchRequest {
+            url: url.into(),
+            mirrors: options.mirrors.clone(),
+            headers: options.headers.clone(),
+            profile: options.profile.clone(),
+            cancel: options.cancel.clone(),
+            ..Default::default()
+        };
+        let mut file = match self.download_to(request, &partial, &options).await {
+            Ok(file) => file,
+            Err(e) => {
+                // A partial body is only worth keeping if it can be resumed and isn't bad.
+                let keep = options.resume
+                    && partial.resumable().await.is_some()
+                    && !matches!(
+                        e,
+                        FetchError::ChecksumMismatch { .. }
+                            | FetchError::ResponseBodyTooLarge { .. }
+                            | FetchError::UnexpectedStatus { .. }
+                    );
+                if !keep {
+                    partial.remove().await;
+                }
+                return Err(e);
+            }
+        };
+        tokio::fs::rename(&partial.body, path)
+            .await
+            .map_err(|e| io_error(path, e))?;
+        let _ = tokio::fs::remove_file(&partial.validator).await;
+        file.path = path.to_path_buf();
+        Ok(file)
+    }
+
+    async fn download_to(
+        &self,
+        mut request: FetchRequest,
+        partial: &PartialFile,
+        options: &DownloadOptions,
+    ) -> Result<DownloadedFile, FetchError> {
+        let mut resume_from = None;
+        if options.resume {
+            if !has_header(&request.headers, "accept-encoding") {
+                request
+                    .headers
+                    .insert("Accept-Encoding".into(), "identity".into());
+            }
+            if let Some((len, validator)) = partial.resumable().await {
+                // Already too large, so no need to ask for the rest.
+                let host = url::Url::parse(&request.url)
+                    .ok()
+                    .and_then(|url| url.host_str().map(str::to_string))
+                    .unwrap_or_default();
+                let limit = self.response_body_limit(options.profile.as_deref(), &host)?;
+                if len > limit as u64 {
+                    return Err(FetchError::ResponseBodyTooLarge {
+                        size: len as usize,
+                        limit,
+                    });
+                }
+                request
+                    .headers
+                    .insert("Range".into(), format!("bytes={len}-"));
+                request.headers.insert("If-Range".into(), validator);
+                resume_from = Some(len);
+            }
+        }
+
+        let mut response = self.fetch_stream(request).await?;
+        if !(200..300).contains(&response.status) {
+            return Err(FetchError::UnexpectedStatus {
+                status: response.status,
+            });
+        }
+        // A 206 continues the partial file; anything else (e.g. a 200 because the file
+        // changed and `If-Range` failed) replaces it.
+        let offset = match (response.status, resume_from) {
+            (206, Some(len)) if range_start(&response.headers) == Some(len) => len,
+            (206, _) => {
+                return Err(FetchError::HttpError(
+                    "unexpected partial content in response".into(),
+                ))
+            }
+            _ => 0,
+        };
+        let host = url::Url::parse(&response.url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default();
+        let limit = self.response_body_limit(options.profile.as_deref(), &host)?;
+
+        let mut hasher = Sha256::new();
+        let mut file = if offset > 0 {
+            hash_file(&partial.body, &mut hasher).await?;
+            tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(&partial.body)
+                .await
+        } else {
+            tokio::fs::File::create(&partial.body).await
+        }
+        .map_err(|e| io_error(&partial.body, e))?;
+
+        // A 206 needn't repeat the validators, and the one resumed against still holds.
+        if offset == 0 {
+            match validator(&response.headers).filter(|_| options.resume) {
+                Some(validator) => tokio::fs::write(&partial.validator, validator).await,
+                None => tokio::fs::remove_file(&partial.validator).await.or(Ok(())),
+            }
+            .map_err(|e| io_error(&partial.validator, e))?;
+        }
+
+        let mut size = offset;
+        while let Some(chunk) = response.body.next().await {
+            let chunk = chunk?;
+            size += chunk.len() as u64;
+            if size > limit as u64 {
+                return Err(FetchError::ResponseBodyTooLarge {
+                    size: size as usize,
+                    limit,
+                });
+            }
+            hasher.update(&chunk);
+            file.write_all(&chunk)
+                .await
+                .map_err(|e| io_error(&partial.body, e))?;
+        }
+        file.sync_all()
+            .await
+            .map_err(|e| io_error(&partial.body, e))?;
+
+        let sha256 = to_hex(&hasher.finalize());
+        if let Some(expected) = options.sha256.as_deref() {
+            if !expected.eq_ignore_ascii_case(&sha256) {
+                return Err(FetchError::ChecksumMismatch {
+                    expected: expected.to_ascii_lowercase(),
+                    actual: sha256,
+                });
+            }
+        }
+        Ok(DownloadedFile {
+            path: partial.body.clone(),
+            size,
+            sha256,
+            url: response.url,
+            headers: response.headers,
+            resumed_from: offset,
+        })
+    }
+}
+
+fn has_header(headers: &HashMap<String, String>, name: &str) -> bool {
+    headers.keys().any(|k| k.eq_ignore_ascii_case(name))
+}
+
+/// The first byte of a `Content-Range: bytes <start>-<end>/<size>` header.
+fn range_start(headers: &HashMap<String, String>) -> Option<u64> {
+    let range = headers.get("content-range")?.strip_prefix("bytes ")?;
+    range.split('-').next()?.trim().parse().ok()
+}
+
+/// The validator to resume against with `If-Range`, if the server supports byte ranges: a
+/// strong `ETag`, or failing that the `Last-Modified` date. Weak ETags can't be used.
+fn validator(headers: &HashMap<String, String>) -> Option<String> {
+    let ranges = headers
+        .get("accept-ranges")
+        .is_some_and(|v| v.eq_ignore_ascii_case("bytes"));
+    if !ranges {
+        return None;
+    }
+    headers
+        .get("etag")
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| headers.get("last-modified"))
+        .cloned()
+}
+
+async fn hash_file(path: &Path, hasher: &mut Sha256) -> Result<(), FetchError> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| io_error(path, e))?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await.map_err(|e| io_error(path, e))?;
+        if n == 0 {
+            return Ok(());
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn io_error(path: &Path, e: std::io::Error) -> FetchError {
+    FetchError::Io(format!("{}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn picks_a_strong_validator_only_when_ranges_are_supported() {
+        let date = "Wed, 21 Oct 2015 07:28:00 GMT";
+        assert_eq!(
+            validator(&headers(&[("accept-ranges", "bytes"), ("etag", "\"v1\"")])),
+            Some("\"v1\"".into())
+        );
+        assert_eq!(
+            validator(&headers(&[
+                ("accept-ranges", "bytes"),
+                ("etag", "W/\"v1\""),
+                ("last-modified", date)
+            ])),
+            Some(date.into())
+        );
+        assert_eq!(
+            validator(&headers(&[("accept-ranges", "none"), ("etag", "\"v1\"")])),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_content_range_start() {
+        assert_eq!(
+            range_start(&headers(&[("content-range", "bytes 100-199/200")])),
+            Some(100)
+        );
+        assert_eq!(
+            range_start(&headers(&[("content-range", "bytes */200")])),
+            None
+        );
+    }
+}
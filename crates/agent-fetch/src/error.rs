@@ -1,21 +1,99 @@
 use std::net::IpAddr;
 
-#[derive(Debug, thiserror::Error)]
+use crate::decision::Decision;
+
+#[derive(Debug, Clone, thiserror::Error)]
 pub enum FetchError {
-    #[error("private IP blocked: host {host} resolved to {resolved_ip}")]
-    PrivateIpBlocked { host: String, resolved_ip: IpAddr },
+    #[error("private IP blocked: host {host} resolved to {resolved_ip} ({decision})")]
+    PrivateIpBlocked {
+        host: String,
+        resolved_ip: IpAddr,
+        decision: Decision,
+    },
+
+    #[error("IP range blocked: host {host} resolved to {resolved_ip} ({decision})")]
+    IpRangeBlocked {
+        host: String,
+        resolved_ip: IpAddr,
+        decision: Decision,
+    },
+
+    #[error("top-level domain not in allowlist: {domain} ({decision})")]
+    TldNotAllowed { domain: String, decision: Decision },
+
+    #[error("top-level domain is blocked: {domain} ({decision})")]
+    TldBlocked { domain: String, decision: Decision },
+
+    #[error("internationalized domain not allowed: {domain} ({decision})")]
+    IdnNotAllowed { domain: String, decision: Decision },
+
+    #[error("mixed-script domain rejected: {domain} ({decision})")]
+    MixedScriptDomain { domain: String, decision: Decision },
+
+    #[error("domain not in allowlist: {domain} ({decision})")]
+    DomainNotAllowed { domain: String, decision: Decision },
+
+    #[error("domain is blocked: {domain} ({decision})")]
+    DomainBlocked { domain: String, decision: Decision },
+
+    #[error("URL not allowed by URL patterns: {url} ({decision})")]
+    UrlNotAllowed { url: String, decision: Decision },
+
+    #[error("URL is blocked by URL patterns: {url} ({decision})")]
+    UrlBlocked { url: String, decision: Decision },
+
+    #[error("query parameter not allowed: {name} ({decision})")]
+    QueryParamForbidden { name: String, decision: Decision },
+
+    #[error("path not allowed on {host}: {path} ({decision})")]
+    PathNotAllowed {
+        host: String,
+        path: String,
+        decision: Decision,
+    },
 
-    #[error("domain not in allowlist: {0}")]
-    DomainNotAllowed(String),
+    #[error("port not allowed: {port} ({decision})")]
+    PortNotAllowed { port: u16, decision: Decision },
 
-    #[error("domain is blocked: {0}")]
-    DomainBlocked(String),
+    #[error("port is blocked: {port} ({decision})")]
+    PortBlocked { port: u16, decision: Decision },
 
-    #[error("scheme not allowed: {0}")]
-    SchemeNotAllowed(String),
+    #[error("HTTP version not allowed: {version} ({decision})")]
+    HttpVersionNotAllowed { version: String, decision: Decision },
 
-    #[error("method not allowed: {0}")]
-    MethodNotAllowed(String),
+    #[error("request to {host} would bypass the required proxy ({decision})")]
+    ProxyRequired { host: String, decision: Decision },
+
+    #[error(
+        "certificate of {host} matches none of its pins: SPKI sha256 {fingerprint} ({decision})"
+    )]
+    CertificatePinMismatch {
+        host: String,
+        /// The base64 SHA-256 hash of the presented certificate's key, empty if none was.
+        fingerprint: String,
+        decision: Decision,
+    },
+
+    #[error("scheme not allowed: {scheme} ({decision})")]
+    SchemeNotAllowed { scheme: String, decision: Decision },
+
+    #[error("method not allowed: {method} ({decision})")]
+    MethodNotAllowed { method: String, decision: Decision },
+
+    #[error("request header not allowed: {name} ({decision})")]
+    HeaderNotAllowed { name: String, decision: Decision },
+
+    #[error("Host header {host} doesn't match the request URL ({decision})")]
+    HostHeaderMismatch { host: String, decision: Decision },
+
+    #[error("request outside allowed time window: {domain} ({decision})")]
+    OutsideTimeWindow { domain: String, decision: Decision },
+
+    #[error("request denied by policy hook: {0}")]
+    HookDenied(String),
+
+    #[error("no addresses in an allowed address family: {0}")]
+    AddressFamilyNotAllowed(String),
 
     #[error("DNS resolution failed: {0}")]
     DnsResolutionFailed(String),
@@ -26,24 +104,156 @@ pub enum FetchError {
     #[error("response body too large: {size} bytes exceeds limit of {limit} bytes")]
     ResponseBodyTooLarge { size: usize, limit: usize },
 
+    #[error("decompressed response body too large: more than {limit} bytes ({size} so far)")]
+    DecompressedBodyTooLarge { size: usize, limit: usize },
+
+    #[error("failed to decompress response body: {0}")]
+    DecompressionFailed(String),
+
+    #[error("response content type not allowed: {content_type} ({decision})")]
+    ContentTypeNotAllowed {
+        content_type: String,
+        decision: Decision,
+    },
+
+    #[error("server-sent event too large: {size} bytes exceeds limit of {limit} bytes")]
+    EventTooLarge { size: usize, limit: usize },
+
+    #[error("WebSocket message too large: {size} bytes exceeds limit of {limit} bytes")]
+    MessageTooLarge { size: usize, limit: usize },
+
+    #[error("unexpected HTTP status {status}")]
+    UnexpectedStatus { status: u16 },
+
+    #[error("unexpected content type {actual} (expected {expected})")]
+    UnexpectedContentType { expected: String, actual: String },
+
+    #[error("invalid JSON response: {0}")]
+    InvalidJson(String),
+
     #[error("too many redirects (limit: {limit})")]
     TooManyRedirects { limit: u8 },
 
     #[error("rate limit exceeded")]
     RateLimitExceeded,
 
+    #[error("request quota for {pattern} exhausted; resets in {resets_in_secs}s")]
+    QuotaExceeded {
+        pattern: String,
+        resets_in_secs: u64,
+    },
+
+    #[error("rate limit backend error: {0}")]
+    RateLimitBackend(String),
+
+    #[error("transfer budget exceeded for {scope}: limit of {limit} bytes")]
+    BudgetExceeded { scope: String, limit: u64 },
+
+    #[error("response buffer budget exceeded: {size} bytes would be buffered, over the limit of {limit} bytes")]
+    BufferBudgetExceeded { size: usize, limit: usize },
+
     #[error("connection timeout")]
     ConnectionTimeout,
 
     #[error("request timeout")]
     RequestTimeout,
 
+    /// The connection was reset or closed before the response arrived.
+    #[error("connection reset: {0}")]
+    ConnectionReset(String),
+
+    #[error("URL too long: {size} bytes exceeds limit of {limit} bytes")]
+    UrlTooLong { size: usize, limit: usize },
+
     #[error("invalid URL: {0}")]
     InvalidUrl(String),
 
     #[error("HTTP error: {0}")]
     HttpError(String),
 
+    #[error("unknown policy profile: {0}")]
+    UnknownProfile(String),
+
+    #[error("invalid policy: {0}")]
+    InvalidPolicy(String),
+
+    #[error("blocklist feed error: {0}")]
+    BlocklistFeed(String),
+
+    #[error("cross-origin redirect from {from} to {to} ({decision})")]
+    CrossOriginRedirect {
+        from: String,
+        to: String,
+        decision: Decision,
+    },
+
+    #[error("redirect downgrades https to http: {from} -> {to} ({decision})")]
+    RedirectDowngrade {
+        from: String,
+        to: String,
+        decision: Decision,
+    },
+
+    #[error("client is disabled")]
+    Disabled,
+
+    #[error("request was cancelled")]
+    Cancelled,
+
+    #[error("I/O error: {0}")]
+    Io(String),
+
+    #[error("checksum mismatch: expected SHA-256 {expected}, got {actual}")]
+    ChecksumMismatch { expected: String, actual: String },
+
     #[error("redirect to private IP: {url} resolved to {resolved_ip}")]
     RedirectToPrivateIp { url: String, resolved_ip: IpAddr },
 }
+
+impl FetchError {
+    /// Whether the failure may be transient, so that sending the request again could
+    /// succeed: DNS and connection failures, timeouts, and connections reset before the
+    /// response. Other transport errors (e.g. a malformed response), policy denials, and
+    /// local limits are never retryable.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            FetchError::DnsResolutionFailed(_)
+                | FetchError::ConnectionTimeout
+                | FetchError::RequestTimeout
+                | FetchError::ConnectionReset(_)
+        )
+    }
+
+    /// The policy decision behind a policy denial, or `None` for errors that don't come
+    /// from a policy rule (timeouts, transport failures, and so on).
+    pub fn decision(&self) -> Option<&Decision> {
+        match self {
+            FetchError::PrivateIpBlocked { decision, .. }
+            | FetchError::IpRangeBlocked { decision, .. }
+            | FetchError::TldNotAllowed { decision, .. }
+            | FetchError::TldBlocked { decision, .. }
+            | FetchError::IdnNotAllowed { decision, .. }
+            | FetchError::MixedScriptDomain { decision, .. }
+            | FetchError::DomainNotAllowed { decision, .. }
+            | FetchError::DomainBlocked { decision, .. }
+            | FetchError::UrlNotAllowed { decision, .. }
+            | FetchError::UrlBlocked { decision, .. }
+            | FetchError::QueryParamForbidden { decision, .. }
+            | FetchError::PathNotAllowed { decision, .. }
+            | FetchError::PortNotAllowed { decision, .. }
+            | FetchError::PortBlocked { decision, .. }
+            | FetchError::HttpVersionNotAllowed { decision, .. }
+            | FetchError::ProxyRequired { decision, .. }
+            | FetchError::CertificatePinMismatch { decision, .. }
+            | FetchError::SchemeNotAllowed { decision, .. }
+            | FetchError::MethodNotAllowed { decision, .. }
+            | FetchError::HeaderNotAllowed { decision, .. }
+            | FetchError::HostHeaderMismatch { decision, .. }
+            | FetchError::OutsideTimeWindow { decision, .. }
+            | FetchError::CrossOriginRedirect { decision, .. }
+            | FetchError::RedirectDowngrade { decision, .. } => Some(decision),
+            _ => None,
+        }
+    }
+}
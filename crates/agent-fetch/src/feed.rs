@@ -0,0 +1,267 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use tokio::io::AsyncReadExt;
+use tokio::task::JoinHandle;
+
+use crate::client::SafeClient;
+use crate::error::FetchError;
+
+/// Feeds larger than this are rejected rather than read into memory.
+const MAX_FEED_BYTES: usize = 64 * 1024 * 1024;
+
+/// Format of a blocklist feed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeedFormat {
+    /// Hosts-file lines such as `0.0.0.0 ads.example.com`; the address is ignored.
+    Hosts,
+    /// One domain per line.
+    Domains,
+}
+
+/// Where a blocklist feed is loaded from.
+#[derive(Debug, Clone)]
+pub enum FeedSource {
+    Url(String),
+    File(PathBuf),
+}
+
+/// A named domain blocklist, e.g. a threat-intel feed.
+#[derive(Debug, Clone)]
+pub struct BlocklistFeed {
+    /// Name the feed is registered under; reported in `Decision::rule` on a match.
+    pub name: String,
+    pub source: FeedSource,
+    pub format: FeedFormat,
+}
+
+/// A parsed blocklist. Each entry blocks the domain and all of its subdomains.
+#[derive(Debug, Clone, Default)]
+pub struct DomainBlocklist {
+    domains: HashSet<String>,
+}
+
+impl DomainBlocklist {
+    /// Parse a feed. Blank lines, `#` comments, and entries that aren't valid domain names
+    /// (including hosts-file boilerplate like `localhost`) are skipped.
+    pub fn parse(contents: &str, format: FeedFormat) -> Self {
+        let mut domains = HashSet::new();
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let mut fields = line.split_whitespace();
+            if format == FeedFormat::Hosts {
+                fields.next();
+            }
+            for entry in fields {
+                let entry = entry.trim_end_matches('.');
+                if !entry.contains('.') || entry.parse::<std::net::IpAddr>().is_ok() {
+                    continue;
+                }
+                if let Some(domain) = crate::idn::to_ascii(entry) {
+                    domains.insert(domain);
+                }
+            }
+        }
+        Self { domains }
+    }
+
+    pub fn len(&self) -> usize {
+        self.domains.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+    }
+
+    /// Return the entry blocking `domain` — the domain itself or its closest listed parent.
+    pub fn find(&self, domain: &str) -> Option<&str> {
+        let domain = crate::idn::to_ascii(domain)?;
+        let mut candidate = domain.as_str();
+        loop {
+            if let Some(entry) = self.domains.get(candidate) {
+                return Some(entry);
+            }
+            candidate = candidate.split_once('.')?.1;
+        }
+    }
+}
+
+impl BlocklistFeed {
+    /// Fetch and parse the feed. Feeds over 64 MiB fail to load.
+    pub async fn load(&self) -> Result<DomainBlocklist, FetchError> {
+        self.load_at_most(MAX_FEED_BYTES).await
+    }
+
+    async fn load_at_most(&self, max_bytes: usize) -> Result<DomainBlocklist, FetchError> {
+        let failed =
+            |e: &dyn std::fmt::Display| FetchError::BlocklistFeed(format!("{}: {e}", self.name));
+        let too_large = || failed(&format_args!("feed is larger than {max_bytes} bytes"));
+        let mut contents = Vec::new();
+        match &self.source {
+            FeedSource::File(path) => {
+                let file = tokio::fs::File::open(path).await.map_err(|e| failed(&e))?;
+                file.take(max_bytes as u64 + 1)
+                    .read_to_end(&mut contents)
+                    .await
+                    .map_err(|e| failed(&e))?;
+            }
+            FeedSource::Url(url) => {
+                let mut response = reqwest::Client::new()
+                    .get(url)
+                    .timeout(Duration::from_secs(60))
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status())
+                    .map_err(|e| failed(&e))?;
+                while let Some(chunk) = response.chunk().await.map_err(|e| failed(&e))? {
+                    if contents.len() + chunk.len() > max_bytes {
+                        return Err(too_large());
+                    }
+                    contents.extend_from_slice(&chunk);
+                }
+            }
+        }
+        if contents.len() > max_bytes {
+            return Err(too_large());
+        }
+        Ok(DomainBlocklist::parse(
+            &String::from_utf8_lossy(&contents),
+            self.format,
+        ))
+    }
+}
+
+/// Load `feed` into `client` now and again every `interval`. `on_refresh` is called after
+/// every attempt with the number of domains loaded; a failed refresh keeps the previous
+/// entries in place.
+///
+/// Feeds are fetched directly, not through the client's policy, so only point them at
+/// sources you trust. The refresher stops when the client is dropped or the returned
+/// handle is aborted.
+pub fn watch_blocklist_feed<F>(
+    client: &Arc<SafeClient>,
+    feed: BlocklistFeed,
+    interval: Duration,
+    mut on_refresh: F,
+) -> JoinHandle<()>
+where
+    F: FnMut(Result<usize, FetchError>) + Send + 'static,
+{
+    let client: Weak<SafeClient> = Arc::downgrade(client);
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let result = feed.load().await;
+            let Some(client) = client.upgrade() else {
+                return;
+            };
+            on_refresh(result.map(|list| {
+                let len = list.len();
+                client.set_blocklist(feed.name.clone(), list);
+                len
+            }));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::FetchRequest;
+
+    #[test]
+    fn parses_hosts_format() {
+        let list = DomainBlocklist::parse(
+            "# ad servers\n127.0.0.1 localhost\n0.0.0.0 ads.example.com tracker.example.net\n\n::1 ip6-localhost\n",
+            FeedFormat::Hosts,
+        );
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.find("ads.example.com"), Some("ads.example.com"));
+        assert_eq!(
+            list.find("x.tracker.example.net"),
+            Some("tracker.example.net")
+        );
+        assert_eq!(list.find("example.com"), None);
+    }
+
+    #[test]
+    fn parses_domain_format() {
+        let list = DomainBlocklist::parse(
+            "evil.com\nPhish.Example.org. # reported 2024-05-01\n10.0.0.1\n",
+            FeedFormat::Domains,
+        );
+        assert_eq!(list.len(), 2);
+        assert_eq!(
+            list.find("login.phish.example.org"),
+            Some("phish.example.org")
+        );
+        assert_eq!(list.find("notevil.com"), None);
+    }
+
+    #[tokio::test]
+    async fn oversized_feeds_fail_to_load() {
+        let dir = std::env::temp_dir().join(format!("agent-fetch-feed-cap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("blocklist.txt");
+        std::fs::write(&path, "evil.com\nworse.com\n").unwrap();
+        let feed = BlocklistFeed {
+            name: "threats".into(),
+            source: FeedSource::File(path),
+            format: FeedFormat::Domains,
+        };
+
+        assert_eq!(feed.load_at_most(19).await.unwrap().len(), 2);
+        let err = feed.load_at_most(18).await.unwrap_err();
+        assert!(
+            err.to_string().contains("larger than 18 bytes"),
+            "got: {err}"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refreshes_from_file() {
+        let dir = std::env::temp_dir().join(format!("agent-fetch-feed-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("blocklist.txt");
+        std::fs::write(&path, "evil.com\n").unwrap();
+
+        let client = Arc::new(SafeClient::new(Default::default()));
+        let feed = BlocklistFeed {
+            name: "threats".into(),
+            source: FeedSource::File(path.clone()),
+            format: FeedFormat::Domains,
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let handle = watch_blocklist_feed(&client, feed, Duration::from_millis(10), move |r| {
+            let _ = tx.send(r.ok());
+        });
+
+        assert_eq!(rx.recv().await.unwrap(), Some(1));
+        std::fs::write(&path, "evil.com\nworse.com\n").unwrap();
+        while rx.recv().await.unwrap() != Some(2) {}
+
+        let blocked = FetchRequest {
+            url: "https://www.worse.com/".into(),
+            ..Default::default()
+        };
+        let err = client.check(&blocked, false).await.unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("blocked by blocklists[threats] pattern worse.com"),
+            "got: {err}"
+        );
+
+        // A failed refresh keeps the last good list.
+        std::fs::remove_file(&path).unwrap();
+        while rx.recv().await.unwrap().is_some() {}
+        assert!(client.check(&blocked, false).await.is_err());
+
+        handle.abort();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
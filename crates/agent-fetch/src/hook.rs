@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+/// What a [`PolicyHook`] sees: the request after the built-in checks have passed and its
+/// host has been resolved to validated addresses.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    /// The normalized URL, after query-parameter stripping.
+    pub url: String,
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+    pub method: String,
+    /// Caller-supplied request headers, before the header policy is applied.
+    pub headers: HashMap<String, String>,
+    /// The policy profile the request was made under, if any.
+    pub profile: Option<String>,
+    /// Validated addresses the request will connect to. Empty for a
+    /// [`SafeClient::check`](crate::SafeClient::check) that skips DNS resolution.
+    pub resolved_addrs: Vec<SocketAddr>,
+    /// Redirects followed so far; `0` for the original request.
+    pub redirects_followed: u8,
+}
+
+/// A hook's answer for a single request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// Reject the request with `FetchError::HookDenied` carrying this reason.
+    Deny(String),
+}
+
+/// Future returned by [`PolicyHook::evaluate`].
+pub type Evaluating<'a> = Pin<Box<dyn Future<Output = Verdict> + Send + 'a>>;
+
+/// Custom allow/deny logic run after the built-in policy checks, e.g. to consult an
+/// external authorization service. Registered with [`SafeClient::with_hook`](crate::SafeClient::with_hook)
+/// and called for the original request and every redirect hop.
+///
+/// ```
+/// use agent_fetch::hook::{Evaluating, PolicyHook, RequestContext, Verdict};
+///
+/// struct DenyPosts;
+///
+/// impl PolicyHook for DenyPosts {
+///     fn evaluate<'a>(&'a self, ctx: &'a RequestContext) -> Evaluating<'a> {
+///         Box::pin(async move {
+///             if ctx.method == "POST" {
+///                 Verdict::Deny("writes need approval".into())
+///             } else {
+///                 Verdict::Allow
+///             }
+///         })
+///     }
+/// }
+/// ```
+pub trait PolicyHook: Send + Sync {
+    fn evaluate<'a>(&'a self, ctx: &'a RequestContext) -> Evaluating<'a>;
+}
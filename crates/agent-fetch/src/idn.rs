@@ -0,0 +1,94 @@
+use unicode_script::{Script, UnicodeScript};
+
+/// Convert a (possibly unicode) domain to its lowercase ASCII/punycode form.
+/// Returns `None` if the domain is not a valid IDNA domain.
+pub fn to_ascii(domain: &str) -> Option<String> {
+    if domain.is_ascii() {
+        return Some(domain.to_ascii_lowercase());
+    }
+    idna::domain_to_ascii(domain).ok()
+}
+
+/// Returns `true` if any label of the domain is punycode-encoded or non-ASCII.
+pub fn is_idn(domain: &str) -> bool {
+    !domain.is_ascii()
+        || domain
+            .split('.')
+            .any(|label| label.len() >= 4 && label[..4].eq_ignore_ascii_case("xn--"))
+}
+
+/// Returns `true` if any label of the domain mixes scripts in a way that is typical of
+/// homograph attacks (e.g. Latin with Cyrillic, as in `аpple.com`).
+///
+/// Punycode labels are decoded before inspection. The combinations that UTS #39 treats as
+/// "highly restrictive" (Latin with Han and Japanese kana, Bopomofo, or Hangul) are allowed.
+/// Whole-script confusables (a label written entirely in Cyrillic lookalikes) are not
+/// detected; use ASCII-only hosts for that guarantee.
+pub fn is_mixed_script(domain: &str) -> bool {
+    let (unicode, _) = idna::domain_to_unicode(domain);
+    unicode.split('.').any(label_is_mixed_script)
+}
+
+fn label_is_mixed_script(label: &str) -> bool {
+    let mut scripts: Vec<Script> = Vec::new();
+    for c in label.chars() {
+        let script = c.script();
+        if matches!(script, Script::Common | Script::Inherited) {
+            continue;
+        }
+        if !scripts.contains(&script) {
+            scripts.push(script);
+        }
+    }
+
+    if scripts.len() <= 1 {
+        return false;
+    }
+
+    const ALLOWED_COMBINATIONS: [&[Script]; 3] = [
+        &[
+            Script::Latin,
+            Script::Han,
+            Script::Hiragana,
+            Script::Katakana,
+        ],
+        &[Script::Latin, Script::Han, Script::Bopomofo],
+        &[Script::Latin, Script::Han, Script::Hangul],
+    ];
+    !ALLOWED_COMBINATIONS
+        .iter()
+        .any(|allowed| scripts.iter().all(|s| allowed.contains(s)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_unicode_to_punycode() {
+        assert_eq!(to_ascii("Example.COM").unwrap(), "example.com");
+        assert_eq!(to_ascii("bücher.de").unwrap(), "xn--bcher-kva.de");
+        assert_eq!(to_ascii("аpple.com").unwrap(), "xn--pple-43d.com");
+    }
+
+    #[test]
+    fn detects_idn() {
+        assert!(is_idn("xn--bcher-kva.de"));
+        assert!(is_idn("bücher.de"));
+        assert!(!is_idn("example.com"));
+    }
+
+    #[test]
+    fn detects_mixed_script() {
+        assert!(is_mixed_script("xn--pple-43d.com")); // Cyrillic а + Latin pple
+        assert!(is_mixed_script("аpple.com"));
+        assert!(!is_mixed_script("apple.com"));
+        assert!(!is_mixed_script("xn--bcher-kva.de")); // bücher is all Latin
+        assert!(!is_mixed_script("пример.com")); // scripts differ only across labels
+    }
+
+    #[test]
+    fn allows_japanese_mixed_labels() {
+        assert!(!is_mixed_script("東京タワーshop.jp"));
+    }
+}
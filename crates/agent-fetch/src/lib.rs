@@ -1,11 +1,71 @@
+pub mod approval;
+pub mod batch;
+pub mod body;
+pub mod budget;
+pub mod builder;
+pub mod cache;
 pub mod client;
+pub mod clock;
+mod coalesce;
+pub mod conditional;
+pub mod config;
+mod content;
+pub mod decision;
+mod decompress;
+pub mod disk_cache;
 pub mod dns;
+pub mod download;
 pub mod error;
+pub mod feed;
+pub mod hook;
+pub mod idn;
 pub mod ip_check;
+mod memory;
+pub mod multipart;
+pub mod overlay;
 pub mod policy;
+mod queue;
 pub mod rate_limit;
+#[cfg(feature = "redis")]
+pub mod redis_backend;
+pub mod reload;
+pub mod schedule;
+pub mod sse;
+pub mod stream;
+pub mod timing;
+mod tls;
 pub mod url_check;
+pub mod websocket;
 
-pub use client::{FetchRequest, FetchResponse, SafeClient};
+pub use approval::{Approval, ApprovalHandler, ApprovalRequest};
+pub use batch::BatchOptions;
+pub use body::FetchBody;
+pub use builder::FetchPolicyBuilder;
+pub use cache::{CacheStats, CacheStore, CachedResponse, LruCacheStore};
+pub use client::{FetchRequest, FetchResponse, RedirectHop, SafeClient, ValidationReport};
+pub use clock::{Clock, FixedClock, SystemClock};
+pub use conditional::{ConditionalResponse, MemoryValidatorStore, ValidatorStore, Validators};
+pub use config::PolicyFormat;
+pub use decision::Decision;
+pub use disk_cache::DiskCacheStore;
+pub use download::{DownloadOptions, DownloadedFile};
 pub use error::FetchError;
-pub use policy::{DomainPattern, FetchPolicy};
+pub use feed::{BlocklistFeed, DomainBlocklist, FeedFormat, FeedSource};
+pub use hook::{PolicyHook, RequestContext, Verdict};
+pub use multipart::{Multipart, Part};
+pub use overlay::{MergeMode, PolicyOverlay};
+pub use policy::{
+    AddressFamily, AllowedDomain, CertificatePin, ClientCertificate, DnsCachePolicy, DnsOverTls,
+    DomainPattern, DomainQuota, DomainRateLimit, DomainRule, FetchPolicy, HeaderViolation,
+    HttpVersion, IdnPolicy, PathPattern, ProxyPolicy, ProxyRoute, QueryParamPolicy, RedirectMode,
+    RedirectScope, RetryPolicy, RootCertificates, TlsVersion, UrlPatternSet,
+};
+pub use rate_limit::{Admission, Admitting, AdmittingAll, RateLimitBackend, ServerRateLimit};
+#[cfg(feature = "redis")]
+pub use redis_backend::RedisBackend;
+pub use schedule::{TimeOfDay, TimeWindow, Weekday};
+pub use sse::{SseEvent, SseStream};
+pub use stream::{BodyStream, StreamingResponse};
+pub use timing::FetchTimings;
+pub use tls::TlsDetails;
+pub use websocket::{SafeWebSocket, WebSocketMessage};
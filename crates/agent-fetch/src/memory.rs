@@ -0,0 +1,145 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+
+use crate::error::FetchError;
+
+/// Client-wide cap on the response bytes buffered at once by fetches in flight.
+pub(crate) struct BufferBudget {
+    state: Mutex<BufferState>,
+    /// Woken whenever buffered bytes are released.
+    released: Notify,
+}
+
+struct BufferState {
+    limit: Option<usize>,
+    used: usize,
+}
+
+/// Bytes held under a [`BufferBudget`], released when dropped.
+pub(crate) struct Reservation<'a> {
+    budget: &'a BufferBudget,
+    bytes: usize,
+}
+
+impl BufferBudget {
+    pub(crate) fn new(limit: Option<usize>) -> Self {
+        Self {
+            state: Mutex::new(BufferState { limit, used: 0 }),
+            released: Notify::new(),
+        }
+    }
+
+    /// Change the limit. Bytes already held stay held.
+    pub(crate) fn set_limit(&self, limit: Option<usize>) {
+        self.state.lock().unwrap().limit = limit;
+        self.released.notify_waiters();
+    }
+
+    /// Hold `bytes`, waiting up to `wait` for other fetches to release theirs. Fails at
+    /// once if `bytes` is more than the whole budget.
+    pub(crate) async fn reserve(
+        &self,
+        bytes: usize,
+        wait: Duration,
+    ) -> Result<Reservation<'_>, FetchError> {
+        let mut reservation = Reservation {
+            budget: self,
+            bytes: 0,
+        };
+        let wait = tokio::time::timeout(wait, async {
+            loop {
+                let released = self.released.notified();
+                match reservation.grow(bytes) {
+                    Err(_) if self.could_fit(bytes) => released.await,
+                    result => return result,
+                }
+            }
+        });
+        match wait.await {
+            Ok(result) => result.map(|()| reservation),
+            Err(_) => Err(self.exceeded(bytes)),
+        }
+    }
+
+    /// Whether `bytes` fit the budget once nothing else is held.
+    fn could_fit(&self, bytes: usize) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .limit
+            .is_none_or(|limit| bytes <= limit)
+    }
+
+    fn exceeded(&self, bytes: usize) -> FetchError {
+        let state = self.state.lock().unwrap();
+        FetchError::BufferBudgetExceeded {
+            size: state.used.saturating_add(bytes),
+            limit: state.limit.unwrap_or(usize::MAX),
+        }
+    }
+}
+
+impl Reservation<'_> {
+    /// Hold `bytes` more without waiting, failing with `BufferBudgetExceeded` if they don't
+    /// fit.
+    pub(crate) fn grow(&mut self, bytes: usize) -> Result<(), FetchError> {
+        let mut state = self.budget.state.lock().unwrap();
+        let used = state.used.saturating_add(bytes);
+        if let Some(limit) = state.limit.filter(|&limit| used > limit) {
+            return Err(FetchError::BufferBudgetExceeded { size: used, limit });
+        }
+        state.used = used;
+        self.bytes += bytes;
+        Ok(())
+    }
+
+    /// The bytes held.
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if self.bytes > 0 {
+            self.budget.state.lock().unwrap().used -= self.bytes;
+            self.budget.released.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reservations_wait_for_released_bytes() {
+        let budget = BufferBudget::new(Some(100));
+        let mut first = budget.reserve(60, Duration::ZERO).await.unwrap();
+        assert!(matches!(
+            first.grow(50),
+            Err(FetchError::BufferBudgetExceeded {
+                size: 110,
+                limit: 100
+            })
+        ));
+        assert!(matches!(
+            budget.reserve(50, Duration::from_millis(10)).await,
+            Err(FetchError::BufferBudgetExceeded { .. })
+        ));
+        // More than the whole budget never fits, so it fails without waiting.
+        assert!(matches!(
+            budget.reserve(101, Duration::from_secs(60)).await,
+            Err(FetchError::BufferBudgetExceeded { .. })
+        ));
+
+        let (second, ()) = tokio::join!(budget.reserve(50, Duration::from_secs(5)), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(first);
+        });
+        assert_eq!(second.unwrap().bytes(), 50);
+        assert_eq!(budget.state.lock().unwrap().used, 0);
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use bytes::{Bytes, BytesMut};
+use futures_util::StreamExt;
+
+use crate::body::FetchBody;
+use crate::client::FetchRequest;
+use crate::error::FetchError;
+
+/// A `multipart/form-data` request body, attached with [`FetchRequest::multipart`].
+///
+/// If every part has a known size (in memory, or e.g. [`FetchBody::from_file`]) so does the
+/// encoded body, and it is checked against `max_request_body_bytes` before anything is sent.
+/// Other streamed parts are counted against the limit as they are sent, together with
+/// everything before them.
+#[derive(Debug)]
+pub struct Multipart {
+    boundary: String,
+    parts: Vec<(String, Part)>,
+}
+
+/// One part of a [`Multipart`] form.
+#[derive(Debug)]
+pub struct Part {
+    body: FetchBody,
+    file_name: Option<String>,
+    content_type: Option<String>,
+}
+
+impl Part {
+    pub fn text(value: impl Into<String>) -> Self {
+        Self::new(FetchBody::from(value.into()))
+    }
+
+    pub fn bytes(data: impl Into<Bytes>) -> Self {
+        Self::new(FetchBody::Bytes(data.into()))
+    }
+
+    /// A part whose content is streamed, e.g. from [`FetchBody::from_file`].
+    pub fn stream(body: FetchBody) -> Self {
+        Self::new(body)
+    }
+
+    fn new(body: FetchBody) -> Self {
+        Self {
+            body,
+            file_name: None,
+            content_type: None,
+        }
+    }
+
+    /// Send the part as a file upload named `name`.
+    pub fn file_name(mut self, name: impl Into<String>) -> Self {
+        self.file_name = Some(name.into());
+        self
+    }
+
+    /// Set the part's `Content-Type`. Fails if it contains control characters, such as a
+    /// line break that would end the part's headers.
+    pub fn content_type(mut self, content_type: impl Into<String>) -> Result<Self, FetchError> {
+        let content_type = content_type.into();
+        if content_type.chars().any(char::is_control) {
+            return Err(FetchError::HttpError(format!(
+                "invalid multipart Content-Type: {content_type:?}"
+            )));
+        }
+        self.content_type = Some(content_type);
+        Ok(self)
+    }
+}
+
+impl Default for Multipart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Multipart {
+    /// An empty form with a random boundary.
+    pub fn new() -> Self {
+        let random = || RandomState::new().build_hasher().finish();
+        Self {
+            boundary: format!("agent-fetch-{:016x}{:016x}", random(), random()),
+            parts: Vec::new(),
+        }
+    }
+
+    pub fn text(self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.part(name, Part::text(value))
+    }
+
+    /// Add an in-memory file. Fails if `content_type` is invalid, as for
+    /// [`Part::content_type`].
+    pub fn file(
+        self,
+        name: impl Into<String>,
+        file_name: impl Into<String>,
+        content_type: impl Into<String>,
+        data: impl Into<Bytes>,
+    ) -> Result<Self, FetchError> {
+        let part = Part::bytes(data)
+            .file_name(file_name)
+            .content_type(content_type)?;
+        Ok(self.part(name, part))
+    }
+
+    pub fn part(mut self, name: impl Into<String>, part: Part) -> Self {
+        self.parts.push((name.into(), part));
+        self
+    }
+
+    /// The `Content-Type` header value for this form, including its boundary.
+    pub fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    /// Size of the encoded form; `None` if a part is streamed without a known length.
+    pub fn size(&self) -> Option<usize> {
+        let mut size = self.closing().len();
+        for (name, part) in &self.parts {
+            size += self.part_head(name, part).len() + part.body.size()? + 2;
+        }
+        Some(size)
+    }
+
+    fn part_head(&self, name: &str, part: &Part) -> String {
+        let mut head = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"",
+            self.boundary,
+            escape(name)
+        );
+        if let Some(file_name) = &part.file_name {
+            head.push_str(&format!("; filename=\"{}\"", escape(file_name)));
+        }
+        if let Some(content_type) = &part.content_type {
+            head.push_str(&format!("\r\nContent-Type: {content_type}"));
+        }
+        head.push_str("\r\n\r\n");
+        head
+    }
+
+    fn closing(&self) -> String {
+        format!("--{}--\r\n", self.boundary)
+    }
+
+    /// Encode the form, in memory if every part is.
+    pub(crate) fn into_body(self) -> FetchBody {
+        let in_memory: Option<Vec<&Bytes>> = self
+            .parts
+            .iter()
+            .map(|(_, part)| match &part.body {
+                FetchBody::Bytes(bytes) => Some(bytes),
+                FetchBody::Stream { .. } => None,
+            })
+            .collect();
+        if let Some(bodies) = in_memory {
+            let mut buf = BytesMut::new();
+            for ((name, part), bytes) in self.parts.iter().zip(bodies) {
+                buf.extend_from_slice(self.part_head(name, part).as_bytes());
+                buf.extend_from_slice(bytes);
+                buf.extend_from_slice(b"\r\n");
+            }
+            buf.extend_from_slice(self.closing().as_bytes());
+            return FetchBody::Bytes(buf.freeze());
+        }
+
+        let size = self.size();
+        let heads: Vec<FetchBody> = self
+            .parts
+            .iter()
+            .map(|(name, part)| FetchBody::from(self.part_head(name, part)))
+            .collect();
+        let closing = FetchBody::from(self.closing());
+        let mut streams = Vec::new();
+        for ((_, part), head) in self.parts.into_iter().zip(heads) {
+            streams.push(head.into_chunks());
+            streams.push(part.body.into_chunks());
+            streams.push(FetchBody::from("\r\n").into_chunks());
+        }
+        streams.push(closing.into_chunks());
+        let chunks = futures_util::stream::iter(streams).flatten();
+        match size {
+            Some(size) => FetchBody::from_sized_stream(chunks, size as u64),
+            None => FetchBody::from_stream(chunks),
+        }
+    }
+}
+
+/// Escape a quoted parameter value the way browsers do.
+fn escape(value: &str) -> String {
+    value
+        .replace('"', "%22")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+impl FetchRequest {
+    /// Send `form` as the body, replacing any body and `Content-Type` already set.
+    pub fn multipart(self, form: Multipart) -> Self {
+        let content_type = form.content_type();
+        self.with_body(content_type, form.into_body())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn encode(form: Multipart) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut chunks = form.into_body().into_chunks();
+        while let Some(chunk) = chunks.next().await {
+            out.extend_from_slice(&chunk.unwrap());
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn encodes_fields_and_files() {
+        let mut form = Multipart::new()
+            .text("title", "report")
+            .file("upload", "a\"b.txt", "text/plain", "hello")
+            .unwrap();
+        form.boundary = "XYZ".into();
+        let size = form.size().unwrap();
+        let encoded = encode(form).await;
+        assert_eq!(
+            String::from_utf8(encoded.clone()).unwrap(),
+            "--XYZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nreport\r\n\
+             --XYZ\r\nContent-Disposition: form-data; name=\"upload\"; filename=\"a%22b.txt\"\r\n\
+             Content-Type: text/plain\r\n\r\nhello\r\n--XYZ--\r\n"
+        );
+        assert_eq!(encoded.len(), size);
+    }
+
+    #[tokio::test]
+    async fn streamed_parts_encode_like_in_memory_ones() {
+        let mut in_memory = Multipart::new()
+            .text("a", "1")
+            .part("b", Part::bytes("data").file_name("d.bin"));
+        in_memory.boundary = "XYZ".into();
+        let mut streamed = Multipart::new().text("a", "1").part(
+            "b",
+            Part::stream(FetchBody::from_reader(&b"data"[..])).file_name("d.bin"),
+        );
+        streamed.boundary = "XYZ".into();
+        assert_eq!(streamed.size(), None);
+        assert_eq!(encode(streamed).await, encode(in_memory).await);
+    }
+
+    #[test]
+    fn rejects_control_characters_in_content_types() {
+        for content_type in [
+            "text/plain\r\nX-Injected: 1",
+            "text/plain\n",
+            "text/\0plain",
+        ] {
+            assert!(Part::text("x").content_type(content_type).is_err());
+            assert!(Multipart::new()
+                .file("upload", "a.txt", content_type, "hello")
+                .is_err());
+        }
+        assert!(Part::text("x")
+            .content_type("text/plain; charset=utf-8")
+            .is_ok());
+    }
+}
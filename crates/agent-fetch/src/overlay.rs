@@ -0,0 +1,715 @@
+use std::collections::HashMap;
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::policy::{
+    AddressFamily, AllowedDomain, DomainPattern, DomainQuota, DomainRateLimit, DomainRule,
+    FetchPolicy, HttpVersion, RedirectMode, RedirectScope, TlsVersion,
+};
+
+/// How a [`PolicyOverlay`] is combined with a base policy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeMode {
+    /// The overlay can only make the policy stricter: allowlists are intersected,
+    /// blocklists are unioned, limits take the minimum, and flags can only be switched on.
+    #[default]
+    Tighten,
+    /// Every field set in the overlay replaces the base value outright.
+    Override,
+}
+
+/// A partial policy layered on top of a base [`FetchPolicy`] with [`FetchPolicy::merge`].
+/// Fields left unset inherit the base value.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyOverlay {
+    pub mode: MergeMode,
+    pub allowed_domains: Option<Vec<AllowedDomain>>,
+    pub blocked_domains: Option<Vec<DomainPattern>>,
+    pub domain_rules: Option<Vec<DomainRule>>,
+    pub allowed_tlds: Option<Vec<String>>,
+    pub blocked_tlds: Option<Vec<String>>,
+    pub deny_private_ips: Option<bool>,
+    pub blocked_ip_ranges: Option<Vec<IpNet>>,
+    pub allowed_ip_ranges: Option<Vec<IpNet>>,
+    pub allowed_address_families: Option<Vec<AddressFamily>>,
+    pub allowed_ports: Option<Vec<u16>>,
+    pub blocked_ports: Option<Vec<u16>>,
+    pub read_only: Option<bool>,
+    pub allowed_methods: Option<Vec<String>>,
+    pub allowed_schemes: Option<Vec<String>>,
+    pub allowed_request_headers: Option<Vec<String>>,
+    pub blocked_request_headers: Option<Vec<String>>,
+    pub allow_host_header_override: Option<bool>,
+    pub default_headers: Option<HashMap<String, String>>,
+    pub max_url_bytes: Option<usize>,
+    pub max_request_body_bytes: Option<usize>,
+    pub max_response_body_bytes: Option<usize>,
+    pub decompress_responses: Option<bool>,
+    pub max_decompressed_body_bytes: Option<usize>,
+    pub allowed_content_types: Option<Vec<String>>,
+    pub preflight_head: Option<bool>,
+    pub max_sse_event_bytes: Option<usize>,
+    pub max_sse_lifetime_ms: Option<u64>,
+    pub sse_revalidate_ms: Option<u64>,
+    pub max_ws_message_bytes: Option<usize>,
+    pub max_ws_lifetime_ms: Option<u64>,
+    pub dns_timeout_ms: Option<u64>,
+    pub connect_timeout_ms: Option<u64>,
+    pub request_timeout_ms: Option<u64>,
+    pub connection_revalidate_ms: Option<u64>,
+    pub http2: Option<bool>,
+    pub http2_prior_knowledge: Option<bool>,
+    pub http2_max_concurrent_streams: Option<u32>,
+    pub allowed_http_versions: Option<Vec<HttpVersion>>,
+    pub min_tls_version: Option<TlsVersion>,
+    pub require_proxy: Option<bool>,
+    pub max_redirects: Option<u8>,
+    pub redirect_mode: Option<RedirectMode>,
+    pub redirect_scope: Option<RedirectScope>,
+    pub redirect_strip_headers: Option<Vec<String>>,
+    pub strict_ports: Option<bool>,
+    pub upgrade_insecure_requests: Option<bool>,
+    pub insecure_fallback_domains: Option<Vec<DomainPattern>>,
+    pub block_redirect_downgrade: Option<bool>,
+    pub max_concurrent_requests: Option<usize>,
+    pub max_concurrent_requests_per_domain: Option<usize>,
+    pub max_concurrency_wait_ms: Option<u64>,
+    pub max_requests_per_minute: Option<u32>,
+    pub max_requests_per_hour: Option<u32>,
+    pub max_requests_per_day: Option<u32>,
+    pub max_requests_per_minute_per_domain: Option<u32>,
+    pub min_request_interval_ms: Option<u64>,
+    pub max_queue_wait_ms: Option<u64>,
+    pub per_domain_limits: Option<Vec<DomainRateLimit>>,
+    pub domain_quotas: Option<Vec<DomainQuota>>,
+    pub rate_limit_exempt_domains: Option<Vec<DomainPattern>>,
+    pub respect_server_rate_limits: Option<bool>,
+    pub coalesce_requests: Option<bool>,
+    pub max_total_bytes: Option<u64>,
+    pub max_total_bytes_per_domain: Option<u64>,
+    pub max_buffered_bytes: Option<usize>,
+}
+
+impl FetchPolicy {
+    /// Combine this policy with an overlay, returning the merged policy.
+    ///
+    /// In [`MergeMode::Tighten`] an overlay can never loosen the base:
+    /// - allowlists (domains, TLDs, ports, methods, schemes, headers, content types) and
+    ///   rate-limit exemptions are intersected; an overlay domain or private-IP exemption
+    ///   survives only if the base already covers it
+    /// - blocklists are unioned
+    /// - numeric limits take the smaller value, except `min_request_interval_ms`, which takes
+    ///   the larger
+    /// - `deny_private_ips`, `read_only`, `strict_ports`, `block_redirect_downgrade`,
+    ///   `upgrade_insecure_requests`, `preflight_head`, `coalesce_requests`,
+    ///   `respect_server_rate_limits` and `require_proxy` can only be switched on,
+    ///   `redirect_mode` can only be set to manual, `redirect_scope` can only be narrowed,
+    ///   `min_tls_version` can only be raised, and `decompress_responses` and
+    ///   `allow_host_header_override` can only be switched off
+    /// - overlay domain rules are appended, so base rules keep precedence, and are cut down
+    ///   to the merged methods, body limits and ports; default headers already set by the
+    ///   base are kept
+    pub fn merge(&self, overlay: &PolicyOverlay) -> FetchPolicy {
+        let mut merged = self.clone();
+        match overlay.mode {
+            MergeMode::Override => merged.apply_override(overlay),
+            MergeMode::Tighten => merged.apply_tighten(overlay),
+        }
+        merged
+    }
+
+    fn apply_override(&mut self, o: &PolicyOverlay) {
+        fn set<T: Clone>(field: &mut T, value: &Option<T>) {
+            if let Some(v) = value {
+                *field = v.clone();
+            }
+        }
+
+        if let Some(ref v) = o.allowed_domains {
+            self.allowed_domains = Some(v.clone());
+        }
+        if let Some(ref v) = o.allowed_tlds {
+            self.allowed_tlds = Some(v.clone());
+        }
+        if let Some(ref v) = o.allowed_ports {
+            self.allowed_ports = Some(v.clone());
+        }
+        if let Some(ref v) = o.allowed_request_headers {
+            self.allowed_request_headers = Some(v.clone());
+        }
+        if let Some(ref v) = o.allowed_content_types {
+            self.allowed_content_types = Some(v.clone());
+        }
+        set(&mut self.blocked_domains, &o.blocked_domains);
+        set(&mut self.domain_rules, &o.domain_rules);
+        set(&mut self.blocked_tlds, &o.blocked_tlds);
+        set(&mut self.deny_private_ips, &o.deny_private_ips);
+        set(&mut self.blocked_ip_ranges, &o.blocked_ip_ranges);
+        set(&mut self.allowed_ip_ranges, &o.allowed_ip_ranges);
+        set(
+            &mut self.allowed_address_families,
+            &o.allowed_address_families,
+        );
+        set(&mut self.blocked_ports, &o.blocked_ports);
+        set(&mut self.read_only, &o.read_only);
+        set(&mut self.allowed_methods, &o.allowed_methods);
+        set(&mut self.allowed_schemes, &o.allowed_schemes);
+        set(
+            &mut self.blocked_request_headers,
+            &o.blocked_request_headers,
+        );
+        set(&mut self.default_headers, &o.default_headers);
+        set(&mut self.max_url_bytes, &o.max_url_bytes);
+        set(&mut self.max_request_body_bytes, &o.max_request_body_bytes);
+        set(
+            &mut self.max_response_body_bytes,
+            &o.max_response_body_bytes,
+        );
+        set(&mut self.decompress_responses, &o.decompress_responses);
+        set(
+            &mut self.allow_host_header_override,
+            &o.allow_host_header_override,
+        );
+        set(
+            &mut self.max_decompressed_body_bytes,
+            &o.max_decompressed_body_bytes,
+        );
+        set(&mut self.preflight_head, &o.preflight_head);
+        set(&mut self.max_sse_event_bytes, &o.max_sse_event_bytes);
+        set(&mut self.max_sse_lifetime_ms, &o.max_sse_lifetime_ms);
+        if o.sse_revalidate_ms.is_some() {
+            self.sse_revalidate_ms = o.sse_revalidate_ms;
+        }
+        set(&mut self.max_ws_message_bytes, &o.max_ws_message_bytes);
+        set(&mut self.max_ws_lifetime_ms, &o.max_ws_lifetime_ms);
+        set(&mut self.dns_timeout_ms, &o.dns_timeout_ms);
+        set(&mut self.connect_timeout_ms, &o.connect_timeout_ms);
+        set(&mut self.request_timeout_ms, &o.request_timeout_ms);
+        if o.connection_revalidate_ms.is_some() {
+            self.connection_revalidate_ms = o.connection_revalidate_ms;
+        }
+        set(&mut self.http2, &o.http2);
+        set(&mut self.http2_prior_knowledge, &o.http2_prior_knowledge);
+        if o.http2_max_concurrent_streams.is_some() {
+            self.http2_max_concurrent_streams = o.http2_max_concurrent_streams;
+        }
+        set(&mut self.allowed_http_versions, &o.allowed_http_versions);
+        set(&mut self.min_tls_version, &o.min_tls_version);
+        set(&mut self.require_proxy, &o.require_proxy);
+        set(&mut self.max_redirects, &o.max_redirects);
+        set(&mut self.redirect_mode, &o.redirect_mode);
+        set(&mut self.redirect_scope, &o.redirect_scope);
+        set(&mut self.redirect_strip_headers, &o.redirect_strip_headers);
+        set(&mut self.strict_ports, &o.strict_ports);
+        set(
+            &mut self.upgrade_insecure_requests,
+            &o.upgrade_insecure_requests,
+        );
+        set(
+            &mut self.insecure_fallback_domains,
+            &o.insecure_fallback_domains,
+        );
+        set(
+            &mut self.block_redirect_downgrade,
+            &o.block_redirect_downgrade,
+        );
+        set(
+            &mut self.max_concurrent_requests,
+            &o.max_concurrent_requests,
+        );
+        if o.max_concurrent_requests_per_domain.is_some() {
+            self.max_concurrent_requests_per_domain = o.max_concurrent_requests_per_domain;
+        }
+        set(
+            &mut self.max_concurrency_wait_ms,
+            &o.max_concurrency_wait_ms,
+        );
+        set(
+            &mut self.max_requests_per_minute,
+            &o.max_requests_per_minute,
+        );
+        if o.max_requests_per_hour.is_some() {
+            self.max_requests_per_hour = o.max_requests_per_hour;
+        }
+        if o.max_requests_per_day.is_some() {
+            self.max_requests_per_day = o.max_requests_per_day;
+        }
+        if o.max_requests_per_minute_per_domain.is_some() {
+            self.max_requests_per_minute_per_domain = o.max_requests_per_minute_per_domain;
+        }
+        set(
+            &mut self.min_request_interval_ms,
+            &o.min_request_interval_ms,
+        );
+        set(&mut self.max_queue_wait_ms, &o.max_queue_wait_ms);
+        set(&mut self.per_domain_limits, &o.per_domain_limits);
+        set(&mut self.domain_quotas, &o.domain_quotas);
+        set(
+            &mut self.rate_limit_exempt_domains,
+            &o.rate_limit_exempt_domains,
+        );
+        set(
+            &mut self.respect_server_rate_limits,
+            &o.respect_server_rate_limits,
+        );
+        set(&mut self.coalesce_requests, &o.coalesce_requests);
+        if o.max_total_bytes.is_some() {
+            self.max_total_bytes = o.max_total_bytes;
+        }
+        if o.max_total_bytes_per_domain.is_some() {
+            self.max_total_bytes_per_domain = o.max_total_bytes_per_domain;
+        }
+        if o.max_buffered_bytes.is_some() {
+            self.max_buffered_bytes = o.max_buffered_bytes;
+        }
+    }
+
+    fn apply_tighten(&mut self, o: &PolicyOverlay) {
+        fn min<T: Ord + Copy>(field: &mut T, value: Option<T>) {
+            if let Some(v) = value {
+                *field = (*field).min(v);
+            }
+        }
+
+        if let Some(ref overlay) = o.allowed_domains {
+            self.allowed_domains = Some(match self.allowed_domains {
+                None => overlay.clone(),
+                Some(ref base) => overlay
+                    .iter()
+                    .filter_map(|entry| {
+                        // Keep the entry only while some covering base entry is in effect.
+                        let base_expiry = base
+                            .iter()
+                            .filter(|b| b.pattern.covers(&entry.pattern))
+                            .map(|b| b.expires_at)
+                            .reduce(|a, b| a.zip(b).map(|(a, b)| a.max(b)))?;
+                        let expires_at = match (entry.expires_at, base_expiry) {
+                            (Some(a), Some(b)) => Some(a.min(b)),
+                            (a, b) => a.or(b),
+                        };
+                        Some(AllowedDomain {
+                            pattern: entry.pattern.clone(),
+                            expires_at,
+                        })
+                    })
+                    .collect(),
+            });
+        }
+        if let Some(ref v) = o.blocked_domains {
+            self.blocked_domains.extend(v.iter().cloned());
+        }
+
+        intersect_optional(&mut self.allowed_tlds, &o.allowed_tlds, |a, b| {
+            a.eq_ignore_ascii_case(b)
+        });
+        if let Some(ref v) = o.blocked_tlds {
+            self.blocked_tlds.extend(v.iter().cloned());
+        }
+
+        if o.deny_private_ips == Some(true) {
+            self.deny_private_ips = true;
+        }
+        if let Some(ref v) = o.blocked_ip_ranges {
+            self.blocked_ip_ranges.extend(v.iter().copied());
+        }
+        if let Some(ref overlay) = o.allowed_ip_ranges {
+            self.allowed_ip_ranges = overlay
+                .iter()
+                .filter(|net| self.allowed_ip_ranges.iter().any(|b| b.contains(*net)))
+                .copied()
+                .collect();
+        }
+
+        if let Some(ref v) = o.allowed_address_families {
+            self.allowed_address_families.retain(|f| v.contains(f));
+        }
+
+        intersect_optional(&mut self.allowed_ports, &o.allowed_ports, |a, b| a == b);
+        if let Some(ref v) = o.blocked_ports {
+            self.blocked_ports.extend(v.iter().copied());
+        }
+
+        if o.read_only == Some(true) {
+            self.read_only = true;
+        }
+        if let Some(ref v) = o.allowed_methods {
+            self.allowed_methods
+                .retain(|m| v.iter().any(|o| m.eq_ignore_ascii_case(o)));
+        }
+        if let Some(ref v) = o.allowed_schemes {
+            self.allowed_schemes
+                .retain(|s| v.iter().any(|o| s.eq_ignore_ascii_case(o)));
+        }
+
+        intersect_optional(
+            &mut self.allowed_request_headers,
+            &o.allowed_request_headers,
+            |a, b| a.eq_ignore_ascii_case(b),
+        );
+        if let Some(ref v) = o.blocked_request_headers {
+            self.blocked_request_headers.extend(v.iter().cloned());
+        }
+        if let Some(ref v) = o.default_headers {
+            for (name, value) in v {
+                if !self
+                    .default_headers
+                    .keys()
+                    .any(|h| h.eq_ignore_ascii_case(name))
+                {
+                    self.default_headers.insert(name.clone(), value.clone());
+                }
+            }
+        }
+
+        min(&mut self.max_url_bytes, o.max_url_bytes);
+        min(&mut self.max_request_body_bytes, o.max_request_body_bytes);
+        min(&mut self.max_response_body_bytes, o.max_response_body_bytes);
+        if o.allow_host_header_override == Some(false) {
+            self.allow_host_header_override = false;
+        }
+        if o.decompress_responses == Some(false) {
+            self.decompress_responses = false;
+        }
+        min(
+            &mut self.max_decompressed_body_bytes,
+            o.max_decompressed_body_bytes,
+        );
+        intersect_optional(
+            &mut self.allowed_content_types,
+            &o.allowed_content_types,
+            |a, b| a.eq_ignore_ascii_case(b),
+        );
+        if o.preflight_head == Some(true) {
+            self.preflight_head = true;
+        }
+        min(&mut self.max_sse_event_bytes, o.max_sse_event_bytes);
+        min(&mut self.max_sse_lifetime_ms, o.max_sse_lifetime_ms);
+        min_optional(&mut self.sse_revalidate_ms, o.sse_revalidate_ms);
+        min(&mut self.max_ws_message_bytes, o.max_ws_message_bytes);
+        min(&mut self.max_ws_lifetime_ms, o.max_ws_lifetime_ms);
+        min(&mut self.dns_timeout_ms, o.dns_timeout_ms);
+        min(&mut self.connect_timeout_ms, o.connect_timeout_ms);
+        min(&mut self.request_timeout_ms, o.request_timeout_ms);
+        min_optional(
+            &mut self.connection_revalidate_ms,
+            o.connection_revalidate_ms,
+        );
+        if o.http2 == Some(false) {
+            self.http2 = false;
+            self.http2_prior_knowledge = false;
+        }
+        if o.http2_prior_knowledge == Some(false) {
+            self.http2_prior_knowledge = false;
+        }
+        min_optional(
+            &mut self.http2_max_concurrent_streams,
+            o.http2_max_concurrent_streams,
+        );
+        if let Some(ref v) = o.allowed_http_versions {
+            self.allowed_http_versions
+                .retain(|version| v.contains(version));
+        }
+        if let Some(version) = o.min_tls_version {
+            self.min_tls_version = self.min_tls_version.max(version);
+        }
+        if o.require_proxy == Some(true) {
+            self.require_proxy = true;
+        }
+        min(&mut self.max_redirects, o.max_redirects);
+        if o.redirect_mode == Some(RedirectMode::Manual) {
+            self.redirect_mode = RedirectMode::Manual;
+        }
+        if let Some(scope) = o.redirect_scope {
+            self.redirect_scope = self.redirect_scope.max(scope);
+        }
+        if o.block_redirect_downgrade == Some(true) {
+            self.block_redirect_downgrade = true;
+        }
+        if let Some(ref v) = o.redirect_strip_headers {
+            self.redirect_strip_headers.extend(v.iter().cloned());
+        }
+        if o.strict_ports == Some(true) {
+            self.strict_ports = true;
+        }
+        if o.upgrade_insecure_requests == Some(true) {
+            self.upgrade_insecure_requests = true;
+        }
+        if let Some(ref v) = o.insecure_fallback_domains {
+            self.insecure_fallback_domains
+                .retain(|d| v.iter().any(|p| p.0 == d.0));
+        }
+        min(&mut self.max_concurrent_requests, o.max_concurrent_requests);
+        min_optional(
+            &mut self.max_concurrent_requests_per_domain,
+            o.max_concurrent_requests_per_domain,
+        );
+        min(&mut self.max_concurrency_wait_ms, o.max_concurrency_wait_ms);
+        min(&mut self.max_requests_per_minute, o.max_requests_per_minute);
+        min_optional(&mut self.max_requests_per_hour, o.max_requests_per_hour);
+        min_optional(&mut self.max_requests_per_day, o.max_requests_per_day);
+        min_optional(
+            &mut self.max_requests_per_minute_per_domain,
+            o.max_requests_per_minute_per_domain,
+        );
+        if let Some(interval) = o.min_request_interval_ms {
+            self.min_request_interval_ms = self.min_request_interval_ms.max(interval);
+        }
+        min(&mut self.max_queue_wait_ms, o.max_queue_wait_ms);
+        if let Some(ref v) = o.per_domain_limits {
+            self.per_domain_limits.extend(v.iter().cloned());
+        }
+        if let Some(ref v) = o.domain_quotas {
+            self.domain_quotas.extend(v.iter().cloned());
+        }
+        if let Some(ref v) = o.rate_limit_exempt_domains {
+            self.rate_limit_exempt_domains
+                .retain(|d| v.iter().any(|p| p.0 == d.0));
+        }
+        if o.respect_server_rate_limits == Some(true) {
+            self.respect_server_rate_limits = true;
+        }
+        if o.coalesce_requests == Some(true) {
+            self.coalesce_requests = true;
+        }
+        min_optional(&mut self.max_total_bytes, o.max_total_bytes);
+        min_optional(
+            &mut self.max_total_bytes_per_domain,
+            o.max_total_bytes_per_domain,
+        );
+        min_optional(&mut self.max_buffered_bytes, o.max_buffered_bytes);
+
+        // Last, so the rules are narrowed to the merged global settings.
+        if let Some(ref v) = o.domain_rules {
+            let rules: Vec<_> = v.iter().map(|rule| self.narrow_rule(rule)).collect();
+            self.domain_rules.extend(rules);
+        }
+    }
+
+    /// A domain rule replaces the global methods and body limits for the domains it
+    /// matches, and under `strict_ports` permits more ports, so a tightening overlay's rule
+    /// is cut down to what the policy already allows everywhere.
+    fn narrow_rule(&self, rule: &DomainRule) -> DomainRule {
+        let mut rule = rule.clone();
+        if let Some(ref mut methods) = rule.allowed_methods {
+            methods.retain(|m| {
+                self.allowed_methods
+                    .iter()
+                    .any(|a| a.eq_ignore_ascii_case(m))
+            });
+        }
+        if let Some(ref mut max) = rule.max_request_body_bytes {
+            *max = (*max).min(self.max_request_body_bytes);
+        }
+        if let Some(ref mut max) = rule.max_response_body_bytes {
+            *max = (*max).min(self.max_response_body_bytes);
+        }
+        if self.strict_ports {
+            if let Some(ref mut ports) = rule.allowed_ports {
+                ports.clear();
+            }
+        }
+        rule
+    }
+}
+
+/// Tighten an optional limit (`None` meaning "unlimited").
+fn min_optional<T: Ord + Copy>(field: &mut Option<T>, value: Option<T>) {
+    if let Some(v) = value {
+        *field = Some(field.map_or(v, |f| f.min(v)));
+    }
+}
+
+/// Intersect an optional allowlist (`None` meaning "anything") with an overlay allowlist.
+fn intersect_optional<T: Clone>(
+    base: &mut Option<Vec<T>>,
+    overlay: &Option<Vec<T>>,
+    eq: impl Fn(&T, &T) -> bool,
+) {
+    let Some(overlay) = overlay else {
+        return;
+    };
+    *base = Some(match base.take() {
+        None => overlay.clone(),
+        Some(base) => base
+            .into_iter()
+            .filter(|b| overlay.iter().any(|o| eq(b, o)))
+            .collect(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> FetchPolicy {
+        FetchPolicy {
+            allowed_domains: Some(vec![DomainPattern("*.example.com".into()).into()]),
+            allowed_ip_ranges: vec!["10.0.0.0/8".parse().unwrap()],
+            max_requests_per_minute: 100,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tighten_keeps_earliest_expiry() {
+        let expiry = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        let base = FetchPolicy {
+            allowed_domains: Some(vec![AllowedDomain::until(
+                DomainPattern("*.example.com".into()),
+                expiry,
+            )]),
+            ..Default::default()
+        };
+        let overlay = PolicyOverlay {
+            allowed_domains: Some(vec![DomainPattern("api.example.com".into()).into()]),
+            ..Default::default()
+        };
+        let merged = base.merge(&overlay);
+
+        let entries = merged.allowed_domains.unwrap();
+        assert_eq!(entries[0].pattern.0, "api.example.com");
+        assert_eq!(entries[0].expires_at, Some(expiry));
+    }
+
+    #[test]
+    fn tighten_intersects_allowlists() {
+        let overlay = PolicyOverlay {
+            allowed_domains: Some(vec![
+                DomainPattern("api.example.com".into()).into(),
+                DomainPattern("evil.com".into()).into(),
+            ]),
+            allowed_methods: Some(vec!["get".into(), "TRACE".into()]),
+            allowed_ports: Some(vec![443]),
+            allowed_content_types: Some(vec!["text/html".into()]),
+            allowed_ip_ranges: Some(vec![
+                "10.1.0.0/16".parse().unwrap(),
+                "192.168.0.0/16".parse().unwrap(),
+            ]),
+            ..Default::default()
+        };
+        let merged = base().merge(&overlay);
+
+        assert!(merged.check_domain("api.example.com").is_ok());
+        assert!(merged.check_domain("evil.com").is_err());
+        assert!(merged.check_domain("docs.example.com").is_err());
+        assert_eq!(merged.allowed_methods, ["GET"]);
+        assert_eq!(merged.allowed_ports, Some(vec![443]));
+        assert_eq!(merged.allowed_content_types, Some(vec!["text/html".into()]));
+        assert_eq!(
+            merged.allowed_ip_ranges,
+            ["10.1.0.0/16".parse::<IpNet>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn tighten_unions_blocklists_and_takes_min_limits() {
+        let overlay = PolicyOverlay {
+            blocked_domains: Some(vec![DomainPattern("internal.example.com".into())]),
+            deny_private_ips: Some(false),
+            read_only: Some(true),
+            max_requests_per_minute: Some(1000),
+            max_redirects: Some(2),
+            http2: Some(true),
+            http2_prior_knowledge: Some(true),
+            http2_max_concurrent_streams: Some(8),
+            require_proxy: Some(true),
+            min_tls_version: Some(TlsVersion::Tls1_3),
+            ..Default::default()
+        };
+        let merged = base().merge(&overlay);
+
+        assert!(merged.check_domain("internal.example.com").is_err());
+        assert!(merged.deny_private_ips);
+        assert!(merged.read_only);
+        assert_eq!(merged.max_requests_per_minute, 100);
+        assert_eq!(merged.max_redirects, 2);
+        assert!(merged.http2);
+        assert!(!merged.http2_prior_knowledge);
+        assert_eq!(merged.http2_max_concurrent_streams, Some(8));
+        assert!(merged.require_proxy);
+        assert_eq!(merged.min_tls_version, TlsVersion::Tls1_3);
+
+        let older = PolicyOverlay {
+            min_tls_version: Some(TlsVersion::Tls1_2),
+            ..Default::default()
+        };
+        assert_eq!(merged.merge(&older).min_tls_version, TlsVersion::Tls1_3);
+    }
+
+    #[test]
+    fn tighten_cannot_widen_through_domain_rules() {
+        let base = FetchPolicy {
+            allowed_methods: vec!["GET".into()],
+            max_request_body_bytes: 1024,
+            strict_ports: true,
+            ..Default::default()
+        };
+        let overlay = PolicyOverlay {
+            domain_rules: Some(vec![DomainRule {
+                pattern: DomainPattern("api.example.com".into()),
+                allowed_methods: Some(vec!["GET".into(), "POST".into(), "DELETE".into()]),
+                max_request_body_bytes: Some(1 << 30),
+                allowed_ports: Some(vec![8443]),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+        let merged = base.merge(&overlay);
+
+        assert!(merged.check_method("api.example.com", "GET").is_ok());
+        assert!(merged.check_method("api.example.com", "POST").is_err());
+        assert!(merged.check_method("api.example.com", "DELETE").is_err());
+        assert_eq!(merged.request_body_limit("api.example.com"), 1024);
+        assert!(merged
+            .check_strict_port("api.example.com", "https", 8443)
+            .is_err());
+
+        // Rules that only narrow are kept as they are.
+        let overlay = PolicyOverlay {
+            domain_rules: Some(vec![DomainRule {
+                pattern: DomainPattern("api.example.com".into()),
+                max_request_body_bytes: Some(100),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+        assert_eq!(
+            base.merge(&overlay).request_body_limit("api.example.com"),
+            100
+        );
+    }
+
+    #[test]
+    fn override_replaces_fields() {
+        let overlay = PolicyOverlay {
+            mode: MergeMode::Override,
+            allowed_domains: Some(vec![DomainPattern("other.org".into()).into()]),
+            deny_private_ips: Some(false),
+            max_requests_per_minute: Some(1000),
+            ..Default::default()
+        };
+        let merged = base().merge(&overlay);
+
+        assert!(merged.check_domain("other.org").is_ok());
+        assert!(merged.check_domain("api.example.com").is_err());
+        assert!(!merged.deny_private_ips);
+        assert_eq!(merged.max_requests_per_minute, 1000);
+        assert_eq!(merged.allowed_ip_ranges, base().allowed_ip_ranges);
+    }
+
+    #[test]
+    fn overlay_deserializes() {
+        let overlay: PolicyOverlay = serde_json::from_str(
+            r#"{"mode": "override", "max_redirects": 1, "min_tls_version": "1.3"}"#,
+        )
+        .unwrap();
+        assert_eq!(overlay.mode, MergeMode::Override);
+        assert_eq!(overlay.max_redirects, Some(1));
+        assert_eq!(overlay.min_tls_version, Some(TlsVersion::Tls1_3));
+        assert!(serde_json::from_str::<PolicyOverlay>(r#"{"min_tls_version": "1.1"}"#).is_err());
+        assert!(serde_json::from_str::<PolicyOverlay>(r#"{"max_redirect": 1}"#).is_err());
+    }
+}
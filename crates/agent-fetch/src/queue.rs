@@ -0,0 +1,224 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+
+/// Requests waiting for rate-limit or concurrency capacity. Hosts take turns, so a burst
+/// to one host can't hold up requests to others; requests to the same host go in order.
+pub(crate) struct FairQueue {
+    state: Mutex<QueueState>,
+    /// Woken whenever a request leaves the queue or a host is held back.
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct QueueState {
+    /// Hosts with queued requests, in the order they get their next turn.
+    turns: VecDeque<String>,
+    hosts: HashMap<String, HostQueue>,
+    next_id: u64,
+}
+
+#[derive(Default)]
+struct HostQueue {
+    /// Queued requests, oldest first.
+    waiting: VecDeque<u64>,
+    /// The host's turns are skipped until then.
+    held_until: Option<Instant>,
+    /// The host's turns are skipped until it has a concurrency slot free.
+    busy: bool,
+}
+
+/// A request's place in a [`FairQueue`], given up when dropped.
+pub(crate) struct Ticket<'a> {
+    queue: &'a FairQueue,
+    host: String,
+    id: Option<u64>,
+}
+
+impl FairQueue {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: Mutex::new(QueueState::default()),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Queue a request to `host` behind the ones already waiting for it.
+    pub(crate) fn join(&self, host: &str) -> Ticket<'_> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        if !state.hosts.contains_key(host) {
+            state.turns.push_back(host.to_string());
+        }
+        state
+            .hosts
+            .entry(host.to_string())
+            .or_default()
+            .waiting
+            .push_back(id);
+        Ticket {
+            queue: self,
+            host: host.to_string(),
+            id: Some(id),
+        }
+    }
+
+    /// Resolves once the queue changes. Create it before checking whose turn it is.
+    pub(crate) fn changed(&self) -> Notified<'_> {
+        self.changed.notified()
+    }
+
+    /// `host` has a concurrency slot free again.
+    pub(crate) fn host_freed(&self, host: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(q) = state.hosts.get_mut(host).filter(|q| q.busy) {
+            q.busy = false;
+            drop(state);
+            self.changed.notify_waiters();
+        }
+    }
+
+    /// When the next held-back host gets its turns back.
+    pub(crate) fn next_release(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let state = self.state.lock().unwrap();
+        state
+            .hosts
+            .values()
+            .filter_map(|q| q.held_until)
+            .filter(|&at| at > now)
+            .min()
+    }
+}
+
+impl QueueState {
+    /// The request whose turn it is: the oldest one for the first host in line that isn't
+    /// held back.
+    fn next(&self, now: Instant) -> Option<u64> {
+        self.turns
+            .iter()
+            .map(|host| &self.hosts[host])
+            .find(|q| !q.busy && q.held_until.is_none_or(|at| at <= now))
+            .and_then(|q| q.waiting.front().copied())
+    }
+
+    /// Send `host` to the back of the line.
+    fn rotate(&mut self, host: &str) {
+        self.turns.retain(|h| h != host);
+        self.turns.push_back(host.to_string());
+    }
+}
+
+impl Ticket<'_> {
+    /// Whether it's this request's turn.
+    pub(crate) fn is_next(&self) -> bool {
+        let state = self.queue.state.lock().unwrap();
+        self.id.is_some() && state.next(Instant::now()) == self.id
+    }
+
+    /// When this request's host gets its turns back, if it's held back.
+    pub(crate) fn held_until(&self) -> Option<Instant> {
+        let state = self.queue.state.lock().unwrap();
+        state.hosts[&self.host]
+            .held_until
+            .filter(|&at| at > Instant::now())
+    }
+
+    /// Skip this request's host until `until`, letting other hosts go first.
+    pub(crate) fn hold_host(&self, until: Instant) {
+        let mut state = self.queue.state.lock().unwrap();
+        state.hosts.get_mut(&self.host).unwrap().held_until = Some(until);
+        state.rotate(&self.host);
+        drop(state);
+        self.queue.changed.notify_waiters();
+    }
+
+    /// Skip this request's host until [`FairQueue::host_freed`] is called for it.
+    pub(crate) fn mark_host_busy(&self) {
+        let mut state = self.queue.state.lock().unwrap();
+        state.hosts.get_mut(&self.host).unwrap().busy = true;
+        state.rotate(&self.host);
+        drop(state);
+        self.queue.changed.notify_waiters();
+    }
+
+    /// Leave the queue once admitted, passing the turn to the next host.
+    pub(crate) fn admitted(mut self) {
+        self.leave(true);
+    }
+
+    fn leave(&mut self, pass_turn: bool) {
+        let Some(id) = self.id.take() else {
+            return;
+        };
+        let mut state = self.queue.state.lock().unwrap();
+        let host = state.hosts.get_mut(&self.host).unwrap();
+        host.waiting.retain(|&w| w != id);
+        if host.waiting.is_empty() {
+            state.hosts.remove(&self.host);
+            state.turns.retain(|h| *h != self.host);
+        } else if pass_turn {
+            state.rotate(&self.host);
+        }
+        drop(state);
+        self.queue.changed.notify_waiters();
+    }
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        self.leave(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn hosts_take_turns() {
+        let queue = FairQueue::new();
+        let a1 = queue.join("a.com");
+        let a2 = queue.join("a.com");
+        let b1 = queue.join("b.com");
+        let c1 = queue.join("c.com");
+        assert!(a1.is_next());
+        assert!(!a2.is_next());
+
+        a1.admitted();
+        assert!(b1.is_next());
+        // A request that gives up its place passes the turn on.
+        drop(b1);
+        assert!(c1.is_next());
+        c1.admitted();
+        assert!(a2.is_next());
+    }
+
+    #[test]
+    fn held_hosts_are_skipped() {
+        let queue = FairQueue::new();
+        let a1 = queue.join("a.com");
+        let b1 = queue.join("b.com");
+        let at = Instant::now() + Duration::from_secs(60);
+        a1.hold_host(at);
+        assert!(b1.is_next());
+        assert_eq!(a1.held_until(), Some(at));
+        assert_eq!(queue.next_release(), Some(at));
+
+        b1.admitted();
+        assert!(!a1.is_next());
+        a1.hold_host(Instant::now());
+        assert!(a1.is_next());
+
+        a1.mark_host_busy();
+        assert!(!a1.is_next());
+        queue.host_freed("a.com");
+        assert!(a1.is_next());
+    }
+}
@@ -1,60 +1,1070 @@
-use std::sync::Mutex;
-use std::time::Instant;
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use tokio::sync::Semaphore;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Semaphore, SemaphorePermit};
 
+use crate::disk_cache::write_atomic;
 use crate::error::FetchError;
+use crate::policy::{DomainPattern, DomainQuota, DomainRateLimit, FetchPolicy};
+use crate::queue::{FairQueue, Ticket};
 
-/// Simple sliding-window rate limiter with a concurrency semaphore.
+/// Whether a shared window had room for a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Admitted,
+    /// The window is full; enough requests leave it to make room after `retry_in`.
+    Full {
+        retry_in: Duration,
+    },
+}
+
+/// Future returned by [`RateLimitBackend::check`] and [`RateLimitBackend::consume`].
+pub type Admitting<'a> = Pin<Box<dyn Future<Output = Result<Admission, FetchError>> + Send + 'a>>;
+
+/// Future returned by [`RateLimitBackend::consume_all`]: `None` if the request was counted,
+/// or the index of a full window and how long until it has room.
+pub type AdmittingAll<'a> =
+    Pin<Box<dyn Future<Output = Result<Option<(usize, Duration)>, FetchError>> + Send + 'a>>;
+
+/// Storage for the per-minute windows, shared by every process using it, so a fleet of
+/// workers can enforce one limit against an external API. Registered with
+/// [`SafeClient::with_rate_limit_backend`](crate::SafeClient::with_rate_limit_backend).
+///
+/// Windows are keyed `global`, `host:<host>` and `pattern:<pattern>`, each with the
+/// policy's limit for it. A request counts its cost against a window, which has room for
+/// requests costing `max_per_minute` in total. The concurrency limit, hourly and daily
+/// limits, pacing and quotas stay per process.
+pub trait RateLimitBackend: Send + Sync {
+    /// Whether `key`'s window has room for a request costing `cost`, without counting it.
+    fn check<'a>(&'a self, key: &'a str, max_per_minute: u32, cost: u32) -> Admitting<'a>;
+
+    /// Count a request costing `cost` against `key`'s window if it has room, atomically.
+    fn consume<'a>(&'a self, key: &'a str, max_per_minute: u32, cost: u32) -> Admitting<'a>;
+
+    /// Count a request costing `cost` against every one of `windows`, given as `(key,
+    /// max_per_minute)`, if all of them have room. A backend that can should do so
+    /// atomically, so a full window never leaves the request counted in the others. The
+    /// default checks every window, then consumes each, which other processes can race.
+    fn consume_all<'a>(&'a self, windows: &'a [(String, u32)], cost: u32) -> AdmittingAll<'a> {
+        Box::pin(async move {
+            for consume in [false, true] {
+                for (i, (key, max)) in windows.iter().enumerate() {
+                    let admission = match consume {
+                        false => self.check(key, *max, cost).await?,
+                        true => self.consume(key, *max, cost).await?,
+                    };
+                    if let Admission::Full { retry_in } = admission {
+                        return Ok(Some((i, retry_in)));
+                    }
+                }
+            }
+            Ok(None)
+        })
+    }
+}
+
+/// A server's own rate limit, as reported in `RateLimit-*` or `X-RateLimit-*` response
+/// headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ServerRateLimit {
+    /// Requests allowed per window, from `*-Limit`.
+    pub limit: Option<u64>,
+    /// Requests left in the current window, from `*-Remaining`.
+    pub remaining: Option<u64>,
+    /// Time until the window resets, from `*-Reset`, which servers give either in seconds
+    /// or as a Unix timestamp.
+    pub reset: Option<Duration>,
+}
+
+impl ServerRateLimit {
+    /// Read the headers, with lowercase names, of a response received at `now`. `None` if
+    /// it has none of them.
+    pub fn from_headers(headers: &HashMap<String, String>, now: SystemTime) -> Option<Self> {
+        let field = |name: &str| {
+            let value = headers
+                .get(&format!("ratelimit-{name}"))
+                .or_else(|| headers.get(&format!("x-ratelimit-{name}")))?;
+            // Values may carry parameters, as in `100;w=60`.
+            let digits = value.trim().split(|c: char| !c.is_ascii_digit()).next()?;
+            digits.parse::<u64>().ok()
+        };
+        let reset = field("reset").map(|value| match value {
+            // No window is decades long, so this is a timestamp.
+            1_000_000_000.. => Duration::from_secs(value)
+                .saturating_sub(now.duration_since(UNIX_EPOCH).unwrap_or_default()),
+            _ => Duration::from_secs(value),
+        });
+        let limit = Self {
+            limit: field("limit"),
+            remaining: field("remaining"),
+            reset,
+        };
+        (limit != Self::default()).then_some(limit)
+    }
+
+    /// How long until requests are allowed again, if none remain.
+    pub fn exhausted_for(&self) -> Option<Duration> {
+        self.reset.filter(|_| self.remaining == Some(0))
+    }
+}
+
+/// Requests counted within the longest span limited, oldest first, with the cost of each.
+/// Those older than a minute are folded together by [`trim`].
+type Window = Vec<(Instant, u32)>;
+
+const MINUTE: Duration = Duration::from_secs(60);
+const HOUR: Duration = Duration::from_secs(3600);
+const DAY: Duration = Duration::from_secs(86_400);
+
+/// The `(span, max)` limits that are set, shortest span first.
+fn spans(
+    per_minute: Option<u32>,
+    per_hour: Option<u32>,
+    per_day: Option<u32>,
+) -> Vec<(Duration, u32)> {
+    [(MINUTE, per_minute), (HOUR, per_hour), (DAY, per_day)]
+        .into_iter()
+        .filter_map(|(span, max)| Some((span, max?)))
+        .collect()
+}
+
+/// Sliding-window rate limiter with a concurrency semaphore, plus an optional window per
+/// host, and optional per-domain windows and fixed-window quotas for domains matching a
+/// configured pattern.
 pub struct RateLimiter {
-    global_max_per_minute: u32,
-    state: Mutex<Vec<Instant>>,
+    global_max_per_minute: AtomicU32,
+    /// Hourly and daily limits on the global window.
+    global_max_per_hour_and_day: Mutex<(Option<u32>, Option<u32>)>,
+    state: Mutex<Window>,
+    host_max_per_minute: Mutex<Option<u32>>,
+    /// Window per host, kept only while it holds requests.
+    host_windows: Mutex<HashMap<String, Window>>,
+    domain_limits: RwLock<Vec<DomainRateLimit>>,
+    /// Domains left out of the global and per-host limits.
+    exempt_domains: RwLock<Vec<DomainPattern>>,
+    /// Window per `domain_limits` pattern.
+    domain_windows: Mutex<HashMap<String, Window>>,
+    quotas: RwLock<Vec<DomainQuota>>,
+    /// Current window index and cost of the requests counted in it per `quotas` pattern.
+    quota_usage: Mutex<HashMap<String, (u64, u64)>>,
     concurrency: Semaphore,
+    /// Configured concurrency limit, tracked separately from the semaphore's available
+    /// permits so the limit can be resized while permits are held.
+    max_concurrent: Mutex<usize>,
+    /// Permits still to be retired after shrinking the concurrency limit while they were
+    /// held; each one is forgotten as soon as it is next acquired.
+    shrink_debt: AtomicUsize,
+    /// How long `acquire` may wait for capacity.
+    max_queue_wait: Mutex<Duration>,
+    max_concurrency_wait: Mutex<Duration>,
+    /// Requests waiting for capacity, served round-robin by host.
+    queue: FairQueue,
+    /// Where the windows are kept instead of in memory, if anywhere.
+    backend: RwLock<Option<Arc<dyn RateLimitBackend>>>,
+    /// Hosts that asked for no more requests until then.
+    paused_hosts: Mutex<HashMap<String, Instant>>,
+    /// Least time between two requests to the same host.
+    min_interval: Mutex<Duration>,
+    /// When each host was last sent a request, kept while it still holds the next one back.
+    last_sent: Mutex<HashMap<String, Instant>>,
+    /// In-flight limit for each host, if any.
+    host_max_concurrent: Mutex<Option<usize>>,
+    /// Requests in flight per host, counted only under `host_max_concurrent`.
+    host_in_flight: Mutex<HashMap<String, usize>>,
+    /// Queue of the background thread saving the windows, quotas and pauses, and the
+    /// thread, if they are saved anywhere; taken on drop to write the latest state.
+    state_writer: Mutex<Option<(mpsc::Sender<SavedState>, JoinHandle<()>)>>,
+}
+
+/// What [`RateLimiter::persist_to`] saves, with times as Unix milliseconds.
+#[derive(Default, Serialize, Deserialize)]
+struct SavedState {
+    /// Time and cost of the requests in each window, keyed like a backend's windows.
+    windows: HashMap<String, Vec<(u64, u32)>>,
+    quotas: HashMap<String, (u64, u64)>,
+    paused_hosts: HashMap<String, u64>,
+}
+
+/// Held for the duration of a request admitted by [`RateLimiter::acquire`].
+#[derive(Debug)]
+pub struct Permit<'a> {
+    _concurrency: Option<SemaphorePermit<'a>>,
+    _host: Option<HostSlot<'a>>,
+}
+
+/// A request's place under its host's concurrency limit, given back when dropped.
+struct HostSlot<'a> {
+    limiter: &'a RateLimiter,
+    host: String,
+}
+
+impl std::fmt::Debug for HostSlot<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HostSlot")
+            .field("host", &self.host)
+            .finish()
+    }
+}
+
+impl Drop for HostSlot<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.limiter.host_in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.host) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.host);
+            }
+        }
+        drop(in_flight);
+        self.limiter.queue.host_freed(&self.host);
+    }
 }
 
 impl RateLimiter {
     pub fn new(max_per_minute: u32, max_concurrent: usize) -> Self {
         Self {
-            global_max_per_minute: max_per_minute,
+            global_max_per_minute: AtomicU32::new(max_per_minute),
+            global_max_per_hour_and_day: Mutex::new((None, None)),
             state: Mutex::new(Vec::new()),
+            host_max_per_minute: Mutex::new(None),
+            host_windows: Mutex::new(HashMap::new()),
+            domain_limits: RwLock::new(Vec::new()),
+            exempt_domains: RwLock::new(Vec::new()),
+            domain_windows: Mutex::new(HashMap::new()),
+            quotas: RwLock::new(Vec::new()),
+            quota_usage: Mutex::new(HashMap::new()),
             concurrency: Semaphore::new(max_concurrent),
+            max_concurrent: Mutex::new(max_concurrent),
+            shrink_debt: AtomicUsize::new(0),
+            max_queue_wait: Mutex::new(Duration::ZERO),
+            max_concurrency_wait: Mutex::new(Duration::ZERO),
+            queue: FairQueue::new(),
+            backend: RwLock::new(None),
+            paused_hosts: Mutex::new(HashMap::new()),
+            min_interval: Mutex::new(Duration::ZERO),
+            last_sent: Mutex::new(HashMap::new()),
+            host_max_concurrent: Mutex::new(None),
+            host_in_flight: Mutex::new(HashMap::new()),
+            state_writer: Mutex::new(None),
+        }
+    }
+
+    /// A limiter enforcing `policy`'s rate limits.
+    pub fn from_policy(policy: &FetchPolicy) -> Self {
+        let limiter = Self::new(
+            policy.max_requests_per_minute,
+            policy.max_concurrent_requests,
+        );
+        limiter.set_policy(policy);
+        limiter
+    }
+
+    /// Enforce `policy`'s rate limits, quotas and queue wait from now on, without
+    /// discarding the current windows or in-flight permits.
+    pub fn set_policy(&self, policy: &FetchPolicy) {
+        self.set_limits(
+            policy.max_requests_per_minute,
+            policy.max_concurrent_requests,
+        );
+        self.set_host_limit(policy.max_requests_per_minute_per_domain);
+        self.set_host_concurrency(policy.max_concurrent_requests_per_domain);
+        self.set_queue_wait(Duration::from_millis(policy.max_queue_wait_ms));
+        self.set_concurrency_wait(Duration::from_millis(policy.max_concurrency_wait_ms));
+        self.set_long_limits(policy.max_requests_per_hour, policy.max_requests_per_day);
+        self.set_domain_limits(policy.per_domain_limits.clone());
+        self.set_exempt_domains(policy.rate_limit_exempt_domains.clone());
+        self.set_min_interval(Duration::from_millis(policy.min_request_interval_ms));
+        self.set_quotas(policy.domain_quotas.clone());
+    }
+
+    /// Change the limits without discarding the current window or in-flight permits.
+    /// A smaller concurrency limit takes full effect once enough permits are released.
+    pub fn set_limits(&self, max_per_minute: u32, max_concurrent: usize) {
+        self.global_max_per_minute
+            .store(max_per_minute, Ordering::Relaxed);
+
+        let mut current = self.max_concurrent.lock().unwrap();
+        if max_concurrent > *current {
+            let mut grow = max_concurrent - *current;
+            let _ = self
+                .shrink_debt
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |debt| {
+                    let cancelled = debt.min(grow);
+                    grow -= cancelled;
+                    Some(debt - cancelled)
+                });
+            self.concurrency.add_permits(grow);
+        } else {
+            let shrink = *current - max_concurrent;
+            let forgotten = self.concurrency.forget_permits(shrink);
+            self.shrink_debt
+                .fetch_add(shrink - forgotten, Ordering::SeqCst);
+        }
+        *current = max_concurrent;
+    }
+
+    /// Keep the windows in `backend`, shared with other processes, instead of in memory.
+    pub fn set_backend(&self, backend: Arc<dyn RateLimitBackend>) {
+        *self.backend.write().unwrap() = Some(backend);
+    }
+
+    /// Turn requests to `host` away until `until`, e.g. because it said its own rate limit
+    /// is spent. A later pause replaces an earlier one.
+    pub fn pause_host(&self, host: &str, until: Instant) {
+        let mut paused = self.paused_hosts.lock().unwrap();
+        let now = Instant::now();
+        paused.retain(|_, at| *at > now);
+        paused.insert(host.to_string(), until);
+        drop(paused);
+        self.save();
+    }
+
+    /// Save the windows, quotas and paused hosts to `path` whenever they change, first
+    /// picking up what was saved there before, so a short-lived process doesn't start each
+    /// run with a fresh budget. Only one process should use a file at a time; concurrent
+    /// ones can share windows through a [`RateLimitBackend`] instead.
+    ///
+    /// Like [`DiskCacheStore`](crate::DiskCacheStore), the file is written by a background
+    /// thread, which skips to the latest state when several changes are queued, and errors
+    /// are ignored: a missing or unreadable file starts from nothing. Reading it here is
+    /// blocking.
+    pub fn persist_to(&self, path: impl Into<PathBuf>) {
+        let path = path.into();
+        let saved = fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<SavedState>(&data).ok());
+        if let Some(saved) = saved {
+            self.restore(saved);
+        } else if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let (queue, states) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("agent-fetch-rate-limits".into())
+            .spawn(move || save_in_background(&path, states));
+        let writer = thread.ok().map(|thread| (queue, thread));
+        let replaced = std::mem::replace(&mut *self.state_writer.lock().unwrap(), writer);
+        stop_writer(replaced);
+    }
+
+    /// Add `saved`'s requests to the windows, and its quota usage and pauses where there
+    /// are none yet.
+    fn restore(&self, saved: SavedState) {
+        let (now, now_ms) = (Instant::now(), epoch_ms(SystemTime::now()));
+        let instant = |ms: u64| match ms.checked_sub(now_ms) {
+            Some(ahead) => now.checked_add(Duration::from_millis(ahead)),
+            None => now.checked_sub(Duration::from_millis(now_ms - ms)),
+        };
+        let one_day_ago = now.checked_sub(DAY);
+
+        for (key, requests) in saved.windows {
+            let requests = requests.into_iter().filter_map(|(ms, cost)| {
+                let at = instant(ms).filter(|&at| Some(at) > one_day_ago)?;
+                // Saved ahead of now if the clock went back since; still counted.
+                Some((at.min(now), cost))
+            });
+            let mut global = self.state.lock().unwrap();
+            let mut patterns = self.domain_windows.lock().unwrap();
+            let mut hosts = self.host_windows.lock().unwrap();
+            let window = match key.split_once(':') {
+                Some(("host", host)) => hosts.entry(host.to_string()).or_default(),
+                Some(("pattern", pattern)) => patterns.entry(pattern.to_string()).or_default(),
+                _ if key == "global" => &mut *global,
+                _ => continue,
+            };
+            window.extend(requests);
+            window.sort_by_key(|&(at, _)| at);
+        }
+
+        let mut usage = self.quota_usage.lock().unwrap();
+        for (pattern, used) in saved.quotas {
+            usage.entry(pattern).or_insert(used);
+        }
+        drop(usage);
+
+        let mut paused = self.paused_hosts.lock().unwrap();
+        for (host, ms) in saved.paused_hosts {
+            if let Some(at) = instant(ms).filter(|&at| at > now) {
+                paused.entry(host).or_insert(at);
+            }
         }
     }
 
-    /// Check whether a request to `domain` is allowed.
+    /// Queue the state to be written to the file given to
+    /// [`persist_to`](Self::persist_to), if any.
+    fn save(&self) {
+        if self.state_writer.lock().unwrap().is_none() {
+            return;
+        }
+        let (now, now_ms) = (Instant::now(), epoch_ms(SystemTime::now()));
+        let ms = |at: Instant| match at.checked_duration_since(now) {
+            Some(ahead) => now_ms + duration_ms(ahead),
+            None => now_ms.saturating_sub(duration_ms(now - at)),
+        };
+        let saved_window =
+            |window: &Window| window.iter().map(|&(at, cost)| (ms(at), cost)).collect();
+
+        let mut saved = SavedState::default();
+        let global = saved_window(&self.state.lock().unwrap());
+        saved.windows.insert("global".into(), global);
+        for (host, window) in self.host_windows.lock().unwrap().iter() {
+            saved
+                .windows
+                .insert(format!("host:{host}"), saved_window(window));
+        }
+        for (pattern, window) in self.domain_windows.lock().unwrap().iter() {
+            saved
+                .windows
+                .insert(format!("pattern:{pattern}"), saved_window(window));
+        }
+        saved.quotas = self.quota_usage.lock().unwrap().clone();
+        for (host, &at) in self.paused_hosts.lock().unwrap().iter() {
+            if at > now {
+                saved.paused_hosts.insert(host.clone(), ms(at));
+            }
+        }
+        if let Some((queue, _)) = &*self.state_writer.lock().unwrap() {
+            let _ = queue.send(saved);
+        }
+    }
+
+    /// Let `acquire` wait up to `wait` for room in the windows instead of failing at once.
+    pub fn set_queue_wait(&self, wait: Duration) {
+        *self.max_queue_wait.lock().unwrap() = wait;
+    }
+
+    /// Let `acquire` wait up to `wait` for a concurrency slot instead of failing at once,
+    /// or as long as the queue wait if that's longer.
+    pub fn set_concurrency_wait(&self, wait: Duration) {
+        *self.max_concurrency_wait.lock().unwrap() = wait;
+    }
+
+    /// Limit requests per hour and per day on top of the per-minute limit, or lift either
+    /// limit with `None`. The window carries over.
+    pub fn set_long_limits(&self, max_per_hour: Option<u32>, max_per_day: Option<u32>) {
+        *self.global_max_per_hour_and_day.lock().unwrap() = (max_per_hour, max_per_day);
+    }
+
+    /// Limit every host to `max_per_minute` requests in a window of its own, or lift the
+    /// limit with `None`. Windows carry over.
+    pub fn set_host_limit(&self, max_per_minute: Option<u32>) {
+        *self.host_max_per_minute.lock().unwrap() = max_per_minute;
+    }
+
+    /// Space requests to the same host at least `interval` apart, or stop pacing them with
+    /// `Duration::ZERO`. A request that comes too soon waits for its turn, for up to
+    /// `interval` or the queue wait if that's longer.
+    pub fn set_min_interval(&self, interval: Duration) {
+        *self.min_interval.lock().unwrap() = interval;
+    }
+
+    /// When `domain` may next be sent a request under the minimum interval, if not yet.
+    fn paced_until(&self, domain: &str, now: Instant) -> Option<Instant> {
+        let interval = *self.min_interval.lock().unwrap();
+        let last_sent = self.last_sent.lock().unwrap();
+        last_sent
+            .get(domain)
+            .map(|&at| after(at, interval))
+            .filter(|&next| next > now)
+    }
+
+    /// Limit every host to `max_concurrent` requests in flight at once, or lift the limit
+    /// with `None`. Requests already in flight when a limit is first set don't count.
+    pub fn set_host_concurrency(&self, max_concurrent: Option<usize>) {
+        *self.host_max_concurrent.lock().unwrap() = max_concurrent;
+    }
+
+    /// Leave requests to domains matching `patterns` out of the global and per-host rate
+    /// and concurrency limits, e.g. traffic to internal infrastructure. Per-domain limits
+    /// and quotas covering them still apply.
+    pub fn set_exempt_domains(&self, patterns: Vec<DomainPattern>) {
+        *self.exempt_domains.write().unwrap() = patterns;
+    }
+
+    fn is_exempt(&self, domain: &str) -> bool {
+        let exempt = self.exempt_domains.read().unwrap();
+        exempt.iter().any(|pattern| pattern.matches(domain))
+    }
+
+    /// Replace the per-domain limits. Windows of patterns that are still configured carry
+    /// over; the rest are dropped.
+    pub fn set_domain_limits(&self, limits: Vec<DomainRateLimit>) {
+        let mut windows = self.domain_windows.lock().unwrap();
+        windows.retain(|pattern, _| limits.iter().any(|l| &l.pattern.0 == pattern));
+        *self.domain_limits.write().unwrap() = limits;
+    }
+
+    /// Replace the domain quotas. Usage of patterns that are still configured carries over.
+    /// A zero-length window, which has nothing to count in, is taken as one second long,
+    /// so the quota still applies.
+    pub fn set_quotas(&self, mut quotas: Vec<DomainQuota>) {
+        for quota in &mut quotas {
+            quota.window_secs = quota.window_secs.max(1);
+        }
+        let mut usage = self.quota_usage.lock().unwrap();
+        usage.retain(|pattern, _| quotas.iter().any(|q| &q.pattern.0 == pattern));
+        *self.quotas.write().unwrap() = quotas;
+    }
+
+    fn take_shrink_debt(&self) -> bool {
+        self.shrink_debt
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |debt| {
+                debt.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Check whether a request to `domain` is allowed by the concurrency limits, the global
+    /// window, `domain`'s own window, the window of every per-domain limit matching
+    /// `domain`, every matching quota, and any pause of `domain`. If a concurrency limit or
+    /// a window is full, or `domain` is paused, waits up to the queue wait for capacity
+    /// before failing with `RateLimitExceeded`; a spent quota fails at once. Waiting
+    /// requests take turns by host.
+    /// A request paced by the minimum interval may wait that long even without a queue wait.
     /// Returns a permit that must be held for the duration of the request.
-    pub async fn acquire(
+    pub async fn acquire(&self, domain: &str) -> Result<Permit<'_>, FetchError> {
+        self.acquire_weighted(domain, 1).await
+    }
+
+    /// Like [`acquire`](Self::acquire), for a request that counts `cost` against every
+    /// window and quota instead of 1, e.g. a call to an API that charges points per query.
+    /// A request costing more than a window or quota holds in total fails at once. A cost of
+    /// 0 counts as 1, so every request takes room in the windows.
+    pub async fn acquire_weighted(
         &self,
-        _domain: &str,
+        domain: &str,
+        cost: u32,
+    ) -> Result<Permit<'_>, FetchError> {
+        let cost = cost.max(1);
+        if self.is_exempt(domain) {
+            return self.acquire_exempt(domain, cost).await;
+        }
+        let now = Instant::now();
+        let mut deadline = after(now, *self.max_queue_wait.lock().unwrap());
+        // Waiting for a turn may mean waiting for others' slots, so it gets the longer wait.
+        let mut slot_deadline =
+            deadline.max(after(now, *self.max_concurrency_wait.lock().unwrap()));
+        let paced_deadline = deadline.max(after(now, *self.min_interval.lock().unwrap()));
+        let ticket = self.queue.join(domain);
+        loop {
+            let changed = self.queue.changed();
+            if !ticket.is_next() {
+                if ticket.held_until().is_some_and(|at| at > deadline) {
+                    return Err(FetchError::RateLimitExceeded);
+                }
+                let wake = self
+                    .queue
+                    .next_release()
+                    .map_or(slot_deadline, |at| at.min(slot_deadline));
+                if tokio::time::timeout_at(wake.into(), changed).await.is_err()
+                    && Instant::now() >= slot_deadline
+                    && !ticket.is_next()
+                {
+                    return Err(FetchError::RateLimitExceeded);
+                }
+                continue;
+            }
+
+            let Some(host_slot) = self.host_slot(domain, &ticket) else {
+                continue;
+            };
+            let permit = self.concurrency_permit(slot_deadline).await?;
+            match self.admit(domain, cost).await {
+                Ok(()) => {
+                    self.save();
+                    ticket.admitted();
+                    return Ok(Permit {
+                        _concurrency: Some(permit),
+                        _host: host_slot,
+                    });
+                }
+                Err(Blocked::Denied(e)) => return Err(e),
+                Err(Blocked::Paced(at)) if at > paced_deadline => {
+                    return Err(FetchError::RateLimitExceeded);
+                }
+                // Other hosts may go first meanwhile.
+                Err(Blocked::Paced(at)) => {
+                    deadline = deadline.max(at);
+                    slot_deadline = slot_deadline.max(at);
+                    ticket.hold_host(at);
+                }
+                Err(Blocked::Until(at) | Blocked::HostUntil(at)) if at > deadline => {
+                    return Err(FetchError::RateLimitExceeded);
+                }
+                // Other hosts may still have room, so let them go first.
+                Err(Blocked::HostUntil(at)) => ticket.hold_host(at),
+                // Nobody has room; keep the turn and give the slots back while waiting.
+                Err(Blocked::Until(at)) => {
+                    drop((permit, host_slot));
+                    tokio::time::sleep_until(at.into()).await;
+                }
+            }
+        }
+    }
+
+    /// Admit a request to an exempt domain. It doesn't queue or take a concurrency slot,
+    /// and waits only for the per-domain limits, quotas and pauses that still cover it.
+    async fn acquire_exempt(&self, domain: &str, cost: u32) -> Result<Permit<'_>, FetchError> {
+        self.admit_waiting(domain, cost).await?;
+        Ok(Permit {
+            _concurrency: None,
+            _host: None,
+        })
+    }
+
+    /// Count a redirect hop to `domain` costing `cost` against the windows and quotas
+    /// covering it, waiting for room like [`acquire_weighted`](Self::acquire_weighted). The
+    /// request already holds a concurrency slot, so the hop doesn't queue or take another.
+    pub async fn acquire_hop(&self, domain: &str, cost: u32) -> Result<(), FetchError> {
+        self.admit_waiting(domain, cost.max(1)).await
+    }
+
+    /// Admit a request without queueing, sleeping until the windows, quotas, pauses and
+    /// pacing covering `domain` have room, for up to the queue wait, or the minimum interval
+    /// if longer for pacing.
+    async fn admit_waiting(&self, domain: &str, cost: u32) -> Result<(), FetchError> {
+        let now = Instant::now();
+        let deadline = after(now, *self.max_queue_wait.lock().unwrap());
+        let paced_deadline = deadline.max(after(now, *self.min_interval.lock().unwrap()));
+        loop {
+            match self.admit(domain, cost).await {
+                Ok(()) => {
+                    self.save();
+                    return Ok(());
+                }
+                Err(Blocked::Denied(e)) => return Err(e),
+                Err(Blocked::Paced(at)) if at > paced_deadline => {
+                    return Err(FetchError::RateLimitExceeded);
+                }
+                Err(Blocked::Until(at) | Blocked::HostUntil(at)) if at > deadline => {
+                    return Err(FetchError::RateLimitExceeded);
+                }
+                Err(Blocked::Until(at) | Blocked::HostUntil(at) | Blocked::Paced(at)) => {
+                    tokio::time::sleep_until(at.into()).await;
+                }
+            }
+        }
+    }
+
+    /// A place under `domain`'s concurrency limit: `Some(None)` if there is no limit, `None`
+    /// if it is full, in which case other hosts get to go first until a slot is freed.
+    fn host_slot(&self, domain: &str, ticket: &Ticket<'_>) -> Option<Option<HostSlot<'_>>> {
+        let Some(max) = *self.host_max_concurrent.lock().unwrap() else {
+            return Some(None);
+        };
+        let mut in_flight = self.host_in_flight.lock().unwrap();
+        if in_flight.get(domain).is_some_and(|&count| count >= max) || max == 0 {
+            // Marked while the count is locked, so a slot freed meanwhile isn't missed.
+            ticket.mark_host_busy();
+            return None;
+        }
+        *in_flight.entry(domain.to_string()).or_default() += 1;
+        Some(Some(HostSlot {
+            limiter: self,
+            host: domain.to_string(),
+        }))
+    }
+
+    /// A concurrency permit, waiting until `deadline` for one to be released.
+    async fn concurrency_permit(
+        &self,
+        deadline: Instant,
     ) -> Result<tokio::sync::SemaphorePermit<'_>, FetchError> {
-        let permit = self
-            .concurrency
-            .try_acquire()
-            .map_err(|_| FetchError::RateLimitExceeded)?;
+        loop {
+            let permit = match self.concurrency.try_acquire() {
+                Ok(permit) => permit,
+                Err(_) if Instant::now() < deadline => {
+                    tokio::time::timeout_at(deadline.into(), self.concurrency.acquire())
+                        .await
+                        .map_err(|_| FetchError::RateLimitExceeded)?
+                        .map_err(|_| FetchError::RateLimitExceeded)?
+                }
+                Err(_) => return Err(FetchError::RateLimitExceeded),
+            };
+            if self.take_shrink_debt() {
+                permit.forget();
+                continue;
+            }
+            return Ok(permit);
+        }
+    }
 
+    /// Count a request to `domain` costing `cost` against the windows and quotas, or tell
+    /// why it can't be sent yet.
+    async fn admit(&self, domain: &str, cost: u32) -> Result<(), Blocked> {
+        let backend = self.backend.read().unwrap().clone();
+        // Checked up front too, so a paced request doesn't use up room in shared windows.
+        if let Some(at) = self
+            .paced_until(domain, Instant::now())
+            .filter(|_| backend.is_some() && !self.is_exempt(domain))
         {
-            let mut timestamps = self.state.lock().unwrap();
-            let now = Instant::now();
-            let one_minute_ago = now - std::time::Duration::from_secs(60);
+            return Err(Blocked::Paced(at));
+        }
+        match backend {
+            None => self.admit_locally(domain, cost, true),
+            Some(backend) => {
+                self.admit_shared(backend.as_ref(), domain, cost).await?;
+                self.admit_locally(domain, cost, false)
+            }
+        }
+    }
 
-            timestamps.retain(|t| *t > one_minute_ago);
+    /// Count a request to `domain` against every window covering it in `backend`, all at
+    /// once: see [`RateLimitBackend::consume_all`].
+    async fn admit_shared(
+        &self,
+        backend: &dyn RateLimitBackend,
+        domain: &str,
+        cost: u32,
+    ) -> Result<(), Blocked> {
+        let mut windows = Vec::new();
+        let mut blocked: Vec<fn(Instant) -> Blocked> = Vec::new();
+        if !self.is_exempt(domain) {
+            windows.push((
+                "global".to_string(),
+                self.global_max_per_minute.load(Ordering::Relaxed),
+            ));
+            blocked.push(Blocked::Until);
+            if let Some(max) = *self.host_max_per_minute.lock().unwrap() {
+                windows.push((format!("host:{domain}"), max));
+                blocked.push(Blocked::HostUntil);
+            }
+        }
+        for limit in self.domain_limits.read().unwrap().iter() {
+            if let Some(max) = limit
+                .max_requests_per_minute
+                .filter(|_| limit.pattern.matches(domain))
+            {
+                windows.push((format!("pattern:{}", limit.pattern.0), max));
+                blocked.push(Blocked::HostUntil);
+            }
+        }
+        if windows.iter().any(|(_, max)| *max < cost) {
+            return Err(Blocked::Denied(FetchError::RateLimitExceeded));
+        }
+        if windows.is_empty() {
+            return Ok(());
+        }
 
-            if timestamps.len() as u32 >= self.global_max_per_minute {
-                drop(permit);
-                return Err(FetchError::RateLimitExceeded);
+        let full = backend
+            .consume_all(&windows, cost)
+            .await
+            .map_err(Blocked::Denied)?;
+        match full {
+            Some((i, retry_in)) => {
+                let until = blocked.get(i).copied().unwrap_or(Blocked::Until);
+                Err(until(after(Instant::now(), retry_in)))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Count a request to `domain` against the quotas, and against the in-memory windows
+    /// unless they're kept in a backend.
+    fn admit_locally(
+        &self,
+        domain: &str,
+        cost: u32,
+        windows_in_memory: bool,
+    ) -> Result<(), Blocked> {
+        let exempt = self.is_exempt(domain);
+        let limits = self.domain_limits.read().unwrap();
+        let mut timestamps = self.state.lock().unwrap();
+        let mut windows = self.domain_windows.lock().unwrap();
+        let now = Instant::now();
+
+        if let Some(&at) = self.paused_hosts.lock().unwrap().get(domain) {
+            if at > now {
+                return Err(Blocked::HostUntil(at));
+            }
+        }
+        if let Some(at) = self.paced_until(domain, now).filter(|_| !exempt) {
+            return Err(Blocked::Paced(at));
+        }
+
+        // A backend keeps only the per-minute windows; hourly and daily ones stay here.
+        let per_minute = |max: Option<u32>| max.filter(|_| windows_in_memory);
+        let (per_hour, per_day) = *self.global_max_per_hour_and_day.lock().unwrap();
+        let global_limits = match exempt {
+            true => Vec::new(),
+            false => spans(
+                per_minute(Some(self.global_max_per_minute.load(Ordering::Relaxed))),
+                per_hour,
+                per_day,
+            ),
+        };
+        if !global_limits.is_empty() {
+            trim(&mut timestamps, &global_limits, now);
+        }
+        for &limit in &global_limits {
+            make_room(&timestamps, limit, cost, now, Blocked::Until)?;
+        }
+
+        let host_max = self
+            .host_max_per_minute
+            .lock()
+            .unwrap()
+            .filter(|_| windows_in_memory && !exempt);
+        let mut hosts = self.host_windows.lock().unwrap();
+        if let Some(max) = host_max {
+            hosts.retain(|_, window| {
+                trim(window, &[(MINUTE, max)], now);
+                !window.is_empty()
+            });
+            let window = hosts.get(domain).map_or(&[][..], Vec::as_slice);
+            make_room(window, (MINUTE, max), cost, now, Blocked::HostUntil)?;
+        }
+
+        let matching: Vec<(&DomainRateLimit, Vec<(Duration, u32)>)> = limits
+            .iter()
+            .filter(|l| l.pattern.matches(domain))
+            .map(|l| {
+                let limits = spans(
+                    per_minute(l.max_requests_per_minute),
+                    l.max_requests_per_hour,
+                    l.max_requests_per_day,
+                );
+                (l, limits)
+            })
+            .filter(|(_, limits)| !limits.is_empty())
+            .collect();
+        for (limit, spans) in &matching {
+            let window = windows.entry(limit.pattern.0.clone()).or_default();
+            trim(window, spans, now);
+            for &span in spans {
+                make_room(window, span, cost, now, Blocked::HostUntil)?;
             }
+        }
+
+        let quotas = self.quotas.read().unwrap();
+        let mut usage = self.quota_usage.lock().unwrap();
+        let epoch_secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let matching_quotas: Vec<&DomainQuota> = quotas
+            .iter()
+            .filter(|q| q.pattern.matches(domain))
+            .collect();
+        for quota in &matching_quotas {
+            let window = epoch_secs / quota.window_secs;
+            let used = match usage.get(&quota.pattern.0) {
+                Some(&(w, count)) if w == window => count,
+                _ => 0,
+            };
+            if used + u64::from(cost) > quota.max_requests {
+                return Err(Blocked::Denied(FetchError::QuotaExceeded {
+                    pattern: quota.pattern.0.clone(),
+                    resets_in_secs: (window + 1) * quota.window_secs - epoch_secs,
+                }));
+            }
+        }
+
+        if host_max.is_some() {
+            hosts
+                .entry(domain.to_string())
+                .or_default()
+                .push((now, cost));
+        }
+        let interval = *self.min_interval.lock().unwrap();
+        if !interval.is_zero() && !exempt {
+            let mut last_sent = self.last_sent.lock().unwrap();
+            last_sent.retain(|_, &mut at| after(at, interval) > now);
+            last_sent.insert(domain.to_string(), now);
+        }
+        for (limit, _) in matching {
+            windows
+                .entry(limit.pattern.0.clone())
+                .or_default()
+                .push((now, cost));
+        }
+        for quota in matching_quotas {
+            let window = epoch_secs / quota.window_secs;
+            let entry = usage.entry(quota.pattern.0.clone()).or_insert((window, 0));
+            if entry.0 != window {
+                *entry = (window, 0);
+            }
+            entry.1 += u64::from(cost);
+        }
+        if !global_limits.is_empty() {
+            timestamps.push((now, cost));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RateLimiter {
+    /// Wait for the latest state to be written.
+    fn drop(&mut self) {
+        stop_writer(self.state_writer.get_mut().unwrap().take());
+    }
+}
 
-            timestamps.push(now);
+/// Close the queue of a background writer started by [`RateLimiter::persist_to`], and wait
+/// for it to write what was queued.
+fn stop_writer(writer: Option<(mpsc::Sender<SavedState>, JoinHandle<()>)>) {
+    if let Some((queue, thread)) = writer {
+        drop(queue);
+        let _ = thread.join();
+    }
+}
+
+/// The background writer: writes each state received from `states` to `path`, skipping
+/// those already replaced by a later one.
+fn save_in_background(path: &Path, states: mpsc::Receiver<SavedState>) {
+    while let Ok(state) = states.recv() {
+        let latest = states.try_iter().last().unwrap_or(state);
+        if let Ok(data) = serde_json::to_vec(&latest) {
+            let _ = write_atomic(path, &data);
         }
+    }
+}
 
-        Ok(permit)
+/// Far enough off to never come, as tokio also takes it.
+const FOREVER: Duration = Duration::from_secs(86_400 * 365 * 30);
+
+/// `wait` after `now`, or [`FOREVER`] after it if that can't be represented: a wait too
+/// long to represent is as good as no limit on the wait.
+pub(crate) fn after(now: Instant, wait: Duration) -> Instant {
+    now.checked_add(wait).unwrap_or(now + FOREVER)
+}
+
+fn epoch_ms(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH).map_or(0, duration_ms)
+}
+
+fn duration_ms(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Drop the requests in `window` older than the longest of `limits`' spans, and fold those
+/// older than a minute into one entry per minute of age, stamped with the latest of them.
+/// A window for hours or days then holds at most one entry per minute of its span besides
+/// the last minute's requests, which the per-minute limit counts exactly; a folded request
+/// counts for up to a minute longer than it would on its own.
+fn trim(window: &mut Window, limits: &[(Duration, u32)], now: Instant) {
+    let keep = limits.last().map_or(Duration::ZERO, |&(span, _)| span);
+    window.drain(..window.partition_point(|&(at, _)| at + keep <= now));
+    if keep <= MINUTE {
+        return;
     }
+    let old = window.partition_point(|&(at, _)| at + MINUTE <= now);
+    let minutes = |at: Instant| now.saturating_duration_since(at).as_secs() / 60;
+    let mut kept = 0;
+    for i in 0..old {
+        let (at, cost) = window[i];
+        if kept > 0 && minutes(window[kept - 1].0) == minutes(at) {
+            let last = &mut window[kept - 1];
+            *last = (at, last.1.saturating_add(cost));
+        } else {
+            window[kept] = (at, cost);
+            kept += 1;
+        }
+    }
+    window.drain(kept..old);
+}
+
+/// Check that the requests in `window` within the last `span` leave room for `cost` more
+/// under `max`, or tell when enough of them leave the span to make room. A request costing
+/// more than `max` never fits.
+fn make_room(
+    window: &[(Instant, u32)],
+    (span, max): (Duration, u32),
+    cost: u32,
+    now: Instant,
+    until: fn(Instant) -> Blocked,
+) -> Result<(), Blocked> {
+    let Some(room) = max.checked_sub(cost) else {
+        return Err(Blocked::Denied(FetchError::RateLimitExceeded));
+    };
+    let window = &window[window.partition_point(|&(at, _)| at + span <= now)..];
+    let used: u64 = window.iter().map(|&(_, c)| u64::from(c)).sum();
+    let mut excess = used.saturating_sub(u64::from(room));
+    if excess == 0 {
+        return Ok(());
+    }
+    let leaving = window.iter().find(|&&(_, c)| {
+        excess = excess.saturating_sub(u64::from(c));
+        excess == 0
+    });
+    leaving.map_or(Ok(()), |&(at, _)| Err(until(at + span)))
+}
+
+/// Why [`RateLimiter::admit`] turned a request away.
+enum Blocked {
+    /// The global window is full until then.
+    Until(Instant),
+    /// A window covering this host is full until then.
+    HostUntil(Instant),
+    /// This host was sent a request too recently to be sent another until then.
+    Paced(Instant),
+    /// Waiting won't help.
+    Denied(FetchError),
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Cost counted per window, standing in for a store shared between processes.
+    #[derive(Default)]
+    struct SharedWindows(Mutex<HashMap<String, u32>>);
+
+    impl SharedWindows {
+        fn admission(&self, key: &str, max: u32, cost: u32, consume: bool) -> Admission {
+            let mut counts = self.0.lock().unwrap();
+            let count = counts.entry(key.to_string()).or_default();
+            if *count + cost > max {
+                return Admission::Full {
+                    retry_in: Duration::from_secs(60),
+                };
+            }
+            if consume {
+                *count += cost;
+            }
+            Admission::Admitted
+        }
+    }
+
+    impl RateLimitBackend for SharedWindows {
+        fn check<'a>(&'a self, key: &'a str, max: u32, cost: u32) -> Admitting<'a> {
+            Box::pin(async move { Ok(self.admission(key, max, cost, false)) })
+        }
+
+        fn consume<'a>(&'a self, key: &'a str, max: u32, cost: u32) -> Admitting<'a> {
+            Box::pin(async move { Ok(self.admission(key, max, cost, true)) })
+        }
+    }
+
+    #[tokio::test]
+    async fn backends_share_windows_between_limiters() {
+        let backend = Arc::new(SharedWindows::default());
+        let (first, second) = (RateLimiter::new(3, 10), RateLimiter::new(3, 10));
+        for rl in [&first, &second] {
+            rl.set_host_limit(Some(2));
+            rl.set_backend(backend.clone());
+        }
+
+        assert!(first.acquire("a.com").await.is_ok());
+        assert!(second.acquire("a.com").await.is_ok());
+        // a.com's window is full, and a request it turns away doesn't count globally.
+        assert!(first.acquire("a.com").await.is_err());
+        assert!(second.acquire("b.com").await.is_ok());
+        assert!(first.acquire("c.com").await.is_err());
+
+        let counts = backend.0.lock().unwrap();
+        assert_eq!(counts["global"], 3);
+        assert_eq!(counts["host:a.com"], 2);
+    }
+
     #[tokio::test]
     async fn allows_within_limit() {
         let rl = RateLimiter::new(10, 5);
@@ -73,6 +1083,502 @@ mod tests {
         assert!(rl.acquire("example.com").await.is_err());
     }
 
+    #[tokio::test]
+    async fn per_domain_limits() {
+        let rl = RateLimiter::new(10, 100);
+        rl.set_domain_limits(vec![DomainRateLimit {
+            pattern: crate::policy::DomainPattern("*.github.com".into()),
+            max_requests_per_minute: Some(2),
+            max_requests_per_hour: None,
+            max_requests_per_day: None,
+        }]);
+
+        assert!(rl.acquire("api.github.com").await.is_ok());
+        assert!(rl.acquire("raw.github.com").await.is_ok());
+        assert!(rl.acquire("api.github.com").await.is_err());
+        for _ in 0..8 {
+            assert!(rl.acquire("example.com").await.is_ok());
+        }
+        // The global window is full now.
+        assert!(rl.acquire("example.com").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn hourly_and_daily_limits_apply_alongside_the_minute() {
+        let rl = RateLimiter::new(10, 100);
+        rl.set_long_limits(Some(4), Some(3));
+        for _ in 0..3 {
+            assert!(rl.acquire("a.com").await.is_ok());
+        }
+        assert!(rl.acquire("a.com").await.is_err());
+
+        // A full daily window frees up too late to wait for.
+        rl.set_queue_wait(Duration::from_secs(5));
+        let started = Instant::now();
+        assert!(rl.acquire("a.com").await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        rl.set_long_limits(None, None);
+        rl.set_domain_limits(vec![DomainRateLimit {
+            pattern: crate::policy::DomainPattern("api.slow.com".into()),
+            max_requests_per_minute: None,
+            max_requests_per_hour: Some(1),
+            max_requests_per_day: None,
+        }]);
+        assert!(rl.acquire("api.slow.com").await.is_ok());
+        assert!(rl.acquire("api.slow.com").await.is_err());
+        assert!(rl.acquire("b.com").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn every_host_gets_its_own_window() {
+        let rl = RateLimiter::new(4, 100);
+        rl.set_host_limit(Some(2));
+
+        assert!(rl.acquire("chatty.com").await.is_ok());
+        assert!(rl.acquire("chatty.com").await.is_ok());
+        assert!(rl.acquire("chatty.com").await.is_err());
+        assert!(rl.acquire("quiet.com").await.is_ok());
+        assert!(rl.acquire("other.com").await.is_ok());
+        // The global window is full now.
+        assert!(rl.acquire("fourth.com").await.is_err());
+
+        rl.set_host_limit(None);
+        rl.set_limits(10, 100);
+        assert!(rl.acquire("chatty.com").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn queued_requests_wait_for_capacity() {
+        let rl = RateLimiter::new(100, 1);
+        rl.set_queue_wait(Duration::from_millis(50));
+        let permit = rl.acquire("a.com").await.unwrap();
+        assert!(rl.acquire("a.com").await.is_err());
+
+        let (queued, ()) = tokio::join!(rl.acquire("b.com"), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(permit);
+        });
+        assert!(queued.is_ok());
+    }
+
+    #[tokio::test]
+    async fn queue_waits_too_long_to_represent_never_run_out() {
+        let rl = RateLimiter::new(1, 100);
+        rl.set_queue_wait(Duration::MAX);
+        let _permit = rl.acquire("a.com").await.unwrap();
+        let queued = tokio::time::timeout(Duration::from_millis(50), rl.acquire("a.com")).await;
+        assert!(queued.is_err(), "still waiting for room");
+
+        rl.set_exempt_domains(vec![DomainPattern("*.internal".into())]);
+        assert!(rl.acquire("git.internal").await.is_ok());
+
+        let rl = RateLimiter::new(100, 1);
+        rl.set_concurrency_wait(Duration::MAX);
+        let _permit = rl.acquire("a.com").await.unwrap();
+        let queued = tokio::time::timeout(Duration::from_millis(50), rl.acquire("b.com")).await;
+        assert!(queued.is_err(), "still waiting for a slot");
+    }
+
+    #[tokio::test]
+    async fn queued_hosts_take_turns() {
+        let rl = RateLimiter::new(100, 1);
+        rl.set_queue_wait(Duration::from_secs(5));
+        let order = Mutex::new(Vec::new());
+        let request = |host: &'static str| {
+            let (rl, order) = (&rl, &order);
+            async move {
+                let _permit = rl.acquire(host).await.unwrap();
+                order.lock().unwrap().push(host);
+                tokio::task::yield_now().await;
+            }
+        };
+
+        let permit = rl.acquire("a.com").await.unwrap();
+        tokio::join!(
+            request("a.com"),
+            request("a.com"),
+            request("a.com"),
+            request("b.com"),
+            async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                drop(permit);
+            },
+        );
+        assert_eq!(*order.lock().unwrap(), ["a.com", "b.com", "a.com", "a.com"]);
+    }
+
+    #[tokio::test]
+    async fn full_host_windows_let_other_hosts_go_first() {
+        let rl = RateLimiter::new(100, 100);
+        rl.set_host_limit(Some(1));
+        rl.set_queue_wait(Duration::from_secs(120));
+        drop(rl.acquire("a.com").await.unwrap());
+        // a.com's window is full for a minute, so b.com doesn't wait behind it.
+        tokio::select! {
+            biased;
+            _ = rl.acquire("a.com") => panic!("a.com's window is full"),
+            b = rl.acquire("b.com") => assert!(b.is_ok()),
+        }
+    }
+
+    #[tokio::test]
+    async fn full_windows_fail_fast_when_they_free_up_too_late() {
+        let rl = RateLimiter::new(1, 100);
+        rl.set_queue_wait(Duration::from_secs(30));
+        drop(rl.acquire("a.com").await.unwrap());
+        // The window only has room again a minute after the first request.
+        let start = Instant::now();
+        assert!(matches!(
+            rl.acquire("a.com").await,
+            Err(FetchError::RateLimitExceeded)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn weighted_requests_use_up_more_of_the_window() {
+        let rl = RateLimiter::new(10, 100);
+        rl.set_quotas(vec![DomainQuota {
+            pattern: crate::policy::DomainPattern("api.paid.com".into()),
+            max_requests: 8,
+            window_secs: 86_400,
+        }]);
+
+        assert!(rl.acquire_weighted("api.paid.com", 5).await.is_ok());
+        assert!(matches!(
+            rl.acquire_weighted("api.paid.com", 4).await,
+            Err(FetchError::QuotaExceeded { .. })
+        ));
+        assert!(rl.acquire_weighted("other.com", 4).await.is_ok());
+        assert!(rl.acquire("other.com").await.is_ok());
+        assert!(rl.acquire("other.com").await.is_err());
+        // Too costly to ever fit, so it fails without waiting for the window.
+        rl.set_queue_wait(Duration::from_secs(120));
+        assert!(rl.acquire_weighted("other.com", 11).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn zero_length_quota_windows_last_a_second() {
+        let rl = RateLimiter::new(100, 100);
+        rl.set_quotas(vec![DomainQuota {
+            pattern: DomainPattern("api.paid.com".into()),
+            max_requests: 1,
+            window_secs: 0,
+        }]);
+        assert!(rl.acquire("api.paid.com").await.is_ok());
+        let err = rl.acquire("api.paid.com").await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                FetchError::QuotaExceeded {
+                    resets_in_secs: 1,
+                    ..
+                }
+            ),
+            "got: {err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn zero_cost_requests_still_count() {
+        let rl = RateLimiter::new(2, 100);
+        assert!(rl.acquire_weighted("a.com", 0).await.is_ok());
+        assert!(rl.acquire_weighted("a.com", 0).await.is_ok());
+        assert!(matches!(
+            rl.acquire_weighted("a.com", 0).await,
+            Err(FetchError::RateLimitExceeded)
+        ));
+    }
+
+    #[test]
+    fn full_windows_free_up_once_enough_cost_leaves() {
+        let start = Instant::now();
+        let window = [
+            (start, 3),
+            (start + Duration::from_secs(1), 2),
+            (start + Duration::from_secs(2), 4),
+        ];
+        let room = |span, cost| make_room(&window, (span, 10), cost, start, Blocked::Until);
+        assert!(room(MINUTE, 1).is_ok());
+        let at = |cost| match room(MINUTE, cost) {
+            Err(Blocked::Until(at)) => at - start,
+            _ => panic!("cost {cost} should wait"),
+        };
+        assert_eq!(at(2), Duration::from_secs(60));
+        assert_eq!(at(6), Duration::from_secs(61));
+        assert!(matches!(room(MINUTE, 11), Err(Blocked::Denied(_))));
+
+        // Only the requests within the span count.
+        let later = start + Duration::from_secs(90);
+        assert!(make_room(&window, (MINUTE, 5), 5, later, Blocked::Until).is_ok());
+        assert!(make_room(&window, (HOUR, 10), 2, later, Blocked::Until).is_err());
+    }
+
+    #[test]
+    fn long_windows_fold_old_requests_by_minute() {
+        let start = Instant::now();
+        let mut window: Window = (0..3000)
+            .map(|i| (start + Duration::from_secs(i), 1))
+            .collect();
+        let now = start + Duration::from_secs(3000);
+        trim(&mut window, &[(MINUTE, 60), (DAY, 3000)], now);
+
+        assert!(window.len() <= 50 + 60, "{} entries", window.len());
+        let total: u32 = window.iter().map(|&(_, cost)| cost).sum();
+        assert_eq!(total, 3000);
+        assert!(make_room(&window, (MINUTE, 60), 1, now, Blocked::Until).is_ok());
+        assert!(make_room(&window, (DAY, 3000), 1, now, Blocked::Until).is_err());
+
+        // A window limited only per minute keeps every request.
+        let mut window: Window = (0..90)
+            .map(|i| (start + Duration::from_secs(i), 1))
+            .collect();
+        trim(
+            &mut window,
+            &[(MINUTE, 60)],
+            start + Duration::from_secs(90),
+        );
+        assert_eq!(window.len(), 59);
+    }
+
+    #[test]
+    fn parses_server_rate_limit_headers() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let headers = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+
+        let github = ServerRateLimit::from_headers(
+            &headers(&[
+                ("x-ratelimit-limit", "5000"),
+                ("x-ratelimit-remaining", "0"),
+                ("x-ratelimit-reset", "1700000042"),
+            ]),
+            now,
+        )
+        .unwrap();
+        assert_eq!(github.limit, Some(5000));
+        assert_eq!(github.exhausted_for(), Some(Duration::from_secs(42)));
+
+        let draft = ServerRateLimit::from_headers(
+            &headers(&[
+                ("ratelimit-limit", "100, 100;w=60"),
+                ("ratelimit-remaining", "7"),
+                ("ratelimit-reset", "30"),
+            ]),
+            now,
+        )
+        .unwrap();
+        assert_eq!(draft.limit, Some(100));
+        assert_eq!(draft.reset, Some(Duration::from_secs(30)));
+        assert_eq!(draft.exhausted_for(), None);
+
+        assert_eq!(ServerRateLimit::from_headers(&headers(&[]), now), None);
+
+        let far_off = ServerRateLimit::from_headers(
+            &headers(&[("x-ratelimit-reset", &u64::MAX.to_string())]),
+            now,
+        )
+        .unwrap();
+        assert_eq!(
+            far_off.reset,
+            Some(Duration::from_secs(u64::MAX - 1_700_000_000))
+        );
+    }
+
+    #[tokio::test]
+    async fn paused_hosts_wait_or_fail() {
+        let rl = RateLimiter::new(100, 100);
+        rl.pause_host("a.com", Instant::now() + Duration::from_millis(50));
+        assert!(rl.acquire("a.com").await.is_err());
+        assert!(rl.acquire("b.com").await.is_ok());
+
+        rl.set_queue_wait(Duration::from_secs(5));
+        let start = Instant::now();
+        assert!(rl.acquire("a.com").await.is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[tokio::test]
+    async fn persisted_state_carries_over_to_the_next_limiter() {
+        let path = std::env::temp_dir().join(format!(
+            "agent-fetch-rate-limits-{}.json",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        let limiter = || {
+            let rl = RateLimiter::new(3, 100);
+            rl.set_host_limit(Some(2));
+            rl.set_quotas(vec![DomainQuota {
+                pattern: crate::policy::DomainPattern("api.paid.com".into()),
+                max_requests: 2,
+                window_secs: 86_400,
+            }]);
+            rl.persist_to(&path);
+            rl
+        };
+
+        let rl = limiter();
+        assert!(rl.acquire("api.paid.com").await.is_ok());
+        assert!(rl.acquire("a.com").await.is_ok());
+        rl.pause_host("paused.com", Instant::now() + Duration::from_secs(60));
+        drop(rl);
+
+        let rl = limiter();
+        assert!(rl.acquire("paused.com").await.is_err());
+        assert!(rl.acquire("a.com").await.is_ok());
+        assert!(
+            rl.acquire("a.com").await.is_err(),
+            "host window carried over"
+        );
+        assert!(
+            rl.acquire("b.com").await.is_err(),
+            "global window carried over"
+        );
+
+        let rl = RateLimiter::new(100, 100);
+        rl.set_quotas(vec![DomainQuota {
+            pattern: crate::policy::DomainPattern("api.paid.com".into()),
+            max_requests: 2,
+            window_secs: 86_400,
+        }]);
+        rl.persist_to(&path);
+        assert!(rl.acquire("api.paid.com").await.is_ok());
+        assert!(matches!(
+            rl.acquire("api.paid.com").await,
+            Err(FetchError::QuotaExceeded { .. })
+        ));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn saved_times_far_ahead_dont_overflow() {
+        let path = std::env::temp_dir().join(format!(
+            "agent-fetch-rate-limits-far-off-{}.json",
+            std::process::id()
+        ));
+        let saved = SavedState {
+            windows: HashMap::from([("global".into(), vec![(u64::MAX, 1)])]),
+            paused_hosts: HashMap::from([("a.com".into(), u64::MAX)]),
+            ..Default::default()
+        };
+        fs::write(&path, serde_json::to_vec(&saved).unwrap()).unwrap();
+
+        let rl = RateLimiter::new(2, 100);
+        rl.persist_to(&path);
+        assert!(rl.acquire("a.com").await.is_err(), "still paused");
+        assert!(rl.acquire("b.com").await.is_ok());
+        assert!(rl.acquire("c.com").await.is_err(), "saved request counted");
+        drop(rl);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn hosts_get_their_own_concurrency_limit() {
+        let rl = RateLimiter::new(100, 10);
+        rl.set_host_concurrency(Some(2));
+        let _a1 = rl.acquire("a.com").await.unwrap();
+        let a2 = rl.acquire("a.com").await.unwrap();
+        assert!(rl.acquire("a.com").await.is_err());
+        let _b1 = rl.acquire("b.com").await.unwrap();
+
+        // A queued request takes the slot freed by another to the same host.
+        rl.set_queue_wait(Duration::from_secs(5));
+        let (queued, ()) = tokio::join!(rl.acquire("a.com"), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(a2);
+        });
+        assert!(queued.is_ok());
+        assert!(rl.acquire("b.com").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn domain_quotas() {
+        let rl = RateLimiter::new(100, 100);
+        let quota = |max_requests| DomainQuota {
+            pattern: crate::policy::DomainPattern("api.paid.com".into()),
+            max_requests,
+            window_secs: 86_400,
+        };
+        rl.set_quotas(vec![quota(2)]);
+
+        assert!(rl.acquire("api.paid.com").await.is_ok());
+        assert!(rl.acquire("api.paid.com").await.is_ok());
+        let err = rl.acquire("api.paid.com").await.unwrap_err();
+        assert!(
+            matches!(err, FetchError::QuotaExceeded { resets_in_secs, .. } if resets_in_secs <= 86_400),
+            "got: {err}"
+        );
+        assert!(rl.acquire("other.com").await.is_ok());
+
+        // Raising the quota keeps the usage so far.
+        rl.set_quotas(vec![quota(3)]);
+        assert!(rl.acquire("api.paid.com").await.is_ok());
+        assert!(rl.acquire("api.paid.com").await.is_err());
+
+        // A policy that skipped validation can't make a quota divide by zero.
+        rl.set_quotas(vec![DomainQuota {
+            window_secs: 0,
+            ..quota(1)
+        }]);
+        assert!(rl.acquire("api.paid.com").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn exempt_domains_skip_global_and_host_limits() {
+        let rl = RateLimiter::new(1, 1);
+        rl.set_host_limit(Some(1));
+        rl.set_exempt_domains(vec![crate::policy::DomainPattern("*.internal".into())]);
+        rl.set_domain_limits(vec![DomainRateLimit {
+            pattern: crate::policy::DomainPattern("cache.internal".into()),
+            max_requests_per_minute: Some(2),
+            max_requests_per_hour: None,
+            max_requests_per_day: None,
+        }]);
+
+        let _held = rl.acquire("api.example.com").await.unwrap();
+        let _a = rl.acquire("cache.internal").await.unwrap();
+        let _b = rl.acquire("cache.internal").await.unwrap();
+        // The pattern's own limit still applies.
+        assert!(rl.acquire("cache.internal").await.is_err());
+        assert!(rl.acquire("git.internal").await.is_ok());
+        assert!(rl.acquire("other.example.com").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn requests_to_a_host_are_paced() {
+        let rl = RateLimiter::new(100, 100);
+        rl.set_min_interval(Duration::from_millis(50));
+        // Without a queue wait, a paced request still waits up to one interval for its turn.
+        let start = Instant::now();
+        assert!(rl.acquire("a.com").await.is_ok());
+        assert!(rl.acquire("b.com").await.is_ok());
+        assert!(start.elapsed() < Duration::from_millis(50));
+        assert!(rl.acquire("a.com").await.is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        // The second of two at once would need two intervals.
+        let (first, second) = tokio::join!(rl.acquire("a.com"), rl.acquire("a.com"));
+        assert!(first.is_ok() != second.is_ok());
+        drop((first, second));
+
+        rl.set_queue_wait(Duration::from_secs(5));
+        let start = Instant::now();
+        assert!(rl.acquire("a.com").await.is_ok());
+        assert!(rl.acquire("a.com").await.is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(80));
+
+        // An interval too long to represent never lets a second request through.
+        rl.set_min_interval(Duration::MAX);
+        assert!(rl.acquire("c.com").await.is_ok());
+        let paced = tokio::time::timeout(Duration::from_millis(50), rl.acquire("c.com")).await;
+        assert!(paced.is_err(), "still waiting for its turn");
+        assert!(rl.acquire("d.com").await.is_ok());
+    }
+
     #[tokio::test]
     async fn rejects_over_concurrency() {
         let rl = RateLimiter::new(100, 2);
@@ -81,4 +1587,60 @@ mod tests {
         // Third should fail — concurrency limit reached
         assert!(rl.acquire("c.com").await.is_err());
     }
+
+    #[tokio::test]
+    async fn concurrency_slots_are_waited_for() {
+        let rl = RateLimiter::new(100, 1);
+        rl.set_concurrency_wait(Duration::from_secs(5));
+        let first = rl.acquire("a.com").await.unwrap();
+        let (second, ()) = tokio::join!(rl.acquire("b.com"), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(first);
+        });
+        let _second = second.unwrap();
+
+        // Waiting for a slot doesn't extend the wait for a full window.
+        let rl = RateLimiter::new(1, 10);
+        rl.set_concurrency_wait(Duration::from_secs(60));
+        let _permit = rl.acquire("a.com").await.unwrap();
+        let started = Instant::now();
+        assert!(rl.acquire("a.com").await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let rl = RateLimiter::new(100, 1);
+        rl.set_concurrency_wait(Duration::from_millis(20));
+        let _held = rl.acquire("a.com").await.unwrap();
+        assert!(rl.acquire("a.com").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn set_limits_keeps_window() {
+        let rl = RateLimiter::new(3, 100);
+        for _ in 0..2 {
+            let _permit = rl.acquire("example.com").await.unwrap();
+        }
+        rl.set_limits(2, 100);
+        assert!(rl.acquire("example.com").await.is_err());
+        rl.set_limits(10, 100);
+        assert!(rl.acquire("example.com").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn set_limits_shrinks_concurrency_with_held_permits() {
+        let rl = RateLimiter::new(100, 3);
+        let p1 = rl.acquire("a.com").await.unwrap();
+        let p2 = rl.acquire("a.com").await.unwrap();
+
+        rl.set_limits(100, 1);
+        assert!(rl.acquire("a.com").await.is_err());
+        drop(p1);
+        // One held permit is retired as it comes back; the limit of one is still in use.
+        assert!(rl.acquire("a.com").await.is_err());
+        drop(p2);
+        let _p3 = rl.acquire("a.com").await.unwrap();
+        assert!(rl.acquire("a.com").await.is_err());
+
+        rl.set_limits(100, 2);
+        assert!(rl.acquire("a.com").await.is_ok());
+    }
 }
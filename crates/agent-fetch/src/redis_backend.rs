@@ -0,0 +1,187 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use redis::aio::MultiplexedConnection;
+use redis::Script;
+
+use crate::error::FetchError;
+use crate::rate_limit::{Admission, Admitting, AdmittingAll, RateLimitBackend};
+
+/// Sliding one-minute windows, one per key, each in a sorted set of requests scored by
+/// timestamp, each member prefixed with the request's cost, timed by the Redis server's
+/// clock so workers with skewed clocks still agree. `ARGV` holds the request's cost,
+/// whether to count it (`1`), its member name, then each key's limit. Returns an empty
+/// array if every window has room, counting the request in all of them if asked to, or
+/// the 0-based index of the first full window and the milliseconds until enough requests
+/// leave it to make room.
+const WINDOWS_SCRIPT: &str = r"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local cost = tonumber(ARGV[1])
+for k = 1, #KEYS do
+  redis.call('ZREMRANGEBYSCORE', KEYS[k], '-inf', now - 60000)
+  local entries = redis.call('ZRANGE', KEYS[k], 0, -1, 'WITHSCORES')
+  local function used(i) return tonumber(string.match(entries[i], '^(%d+):')) end
+  local excess = cost - tonumber(ARGV[k + 3])
+  for i = 1, #entries, 2 do excess = excess + used(i) end
+  if excess > 0 then
+    local wait = 60000
+    for i = 1, #entries, 2 do
+      excess = excess - used(i)
+      if excess <= 0 then
+        wait = math.max(tonumber(entries[i + 1]) + 60000 - now, 0)
+        break
+      end
+    end
+    return {k - 1, wait}
+  end
+end
+if ARGV[2] == '1' then
+  for k = 1, #KEYS do
+    redis.call('ZADD', KEYS[k], now, ARGV[1] .. ':' .. ARGV[3])
+    redis.call('PEXPIRE', KEYS[k], 60000)
+  end
+end
+return {}
+";
+
+/// A [`RateLimitBackend`] keeping the windows in Redis, so every worker pointed at the
+/// same server shares them. Enabled with the `redis` feature.
+///
+/// A request's windows are checked and counted in one script, so they are updated
+/// atomically. On Redis Cluster, that needs them in one hash slot: give the prefix a hash
+/// tag, e.g. `{agent-fetch}:rate:`.
+pub struct RedisBackend {
+    connection: MultiplexedConnection,
+    prefix: String,
+    script: Script,
+    timeout: Duration,
+    fail_open: bool,
+    /// Makes each counted request a distinct member of its windows.
+    instance: u128,
+    sequence: AtomicU64,
+}
+
+impl RedisBackend {
+    /// Connect to the Redis server at `url`, e.g. `redis://127.0.0.1/`.
+    pub async fn connect(url: &str) -> Result<Self, FetchError> {
+        let client = redis::Client::open(url).map_err(backend_error)?;
+        let connection = client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(backend_error)?;
+        Ok(Self {
+            connection,
+            prefix: "agent-fetch:rate:".into(),
+            script: Script::new(WINDOWS_SCRIPT),
+            timeout: Duration::from_secs(1),
+            fail_open: false,
+            instance: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or(0)
+                ^ (u128::from(std::process::id()) << 96),
+            sequence: AtomicU64::new(0),
+        })
+    }
+
+    /// Prefix every window's key with `prefix` (default: `agent-fetch:rate:`), e.g. to
+    /// keep separate fleets sharing a server apart.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Give up on a call to the server after `timeout` (default: 1 second).
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Admit requests when the server fails or doesn't answer in time, instead of failing
+    /// them with `RateLimitBackend`. The limits kept in the process still apply.
+    pub fn fail_open(mut self) -> Self {
+        self.fail_open = true;
+        self
+    }
+
+    async fn windows(
+        &self,
+        windows: &[(&str, u32)],
+        cost: u32,
+        consume: bool,
+    ) -> Result<Option<(usize, Duration)>, FetchError> {
+        let member = format!(
+            "{:x}-{}",
+            self.instance,
+            self.sequence.fetch_add(1, Ordering::Relaxed)
+        );
+        let mut invocation = self.script.prepare_invoke();
+        invocation
+            .arg(cost)
+            .arg(if consume { "1" } else { "0" })
+            .arg(member);
+        for &(key, max_per_minute) in windows {
+            invocation
+                .key(format!("{}{key}", self.prefix))
+                .arg(max_per_minute);
+        }
+        let mut connection = self.connection.clone();
+        let call = invocation.invoke_async::<Vec<i64>>(&mut connection);
+        let full = match tokio::time::timeout(self.timeout, call).await {
+            Ok(Ok(full)) => full,
+            _ if self.fail_open => return Ok(None),
+            Ok(Err(e)) => return Err(backend_error(e)),
+            Err(_) => {
+                return Err(FetchError::RateLimitBackend(format!(
+                    "no answer from Redis within {:?}",
+                    self.timeout
+                )))
+            }
+        };
+        Ok(match full[..] {
+            [i, ms] => Some((
+                usize::try_from(i).unwrap_or_default(),
+                Duration::from_millis(u64::try_from(ms).unwrap_or_default()),
+            )),
+            _ => None,
+        })
+    }
+
+    async fn window(
+        &self,
+        key: &str,
+        max_per_minute: u32,
+        cost: u32,
+        consume: bool,
+    ) -> Result<Admission, FetchError> {
+        let full = self
+            .windows(&[(key, max_per_minute)], cost, consume)
+            .await?;
+        Ok(match full {
+            Some((_, retry_in)) => Admission::Full { retry_in },
+            None => Admission::Admitted,
+        })
+    }
+}
+
+impl RateLimitBackend for RedisBackend {
+    fn check<'a>(&'a self, key: &'a str, max_per_minute: u32, cost: u32) -> Admitting<'a> {
+        Box::pin(self.window(key, max_per_minute, cost, false))
+    }
+
+    fn consume<'a>(&'a self, key: &'a str, max_per_minute: u32, cost: u32) -> Admitting<'a> {
+        Box::pin(self.window(key, max_per_minute, cost, true))
+    }
+
+    fn consume_all<'a>(&'a self, windows: &'a [(String, u32)], cost: u32) -> AdmittingAll<'a> {
+        Box::pin(async move {
+            let windows: Vec<_> = windows.iter().map(|(k, max)| (k.as_str(), *max)).collect();
+            self.windows(&windows, cost, true).await
+        })
+    }
+}
+
+fn backend_error(e: redis::RedisError) -> FetchError {
+    FetchError::RateLimitBackend(e.to_string())
+}
@@ -0,0 +1,94 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime};
+
+use tokio::task::JoinHandle;
+
+use crate::client::SafeClient;
+use crate::error::FetchError;
+use crate::policy::FetchPolicy;
+
+/// Poll a policy file every `interval` and apply it to `client` whenever its modification
+/// time changes. `on_reload` is called after every reload attempt; a file that fails to load
+/// or validate leaves the current policy in place.
+///
+/// The file is checked with `tokio::fs`, and read and applied on a blocking thread, so a
+/// slow filesystem doesn't hold up the runtime. The watcher stops when the client is dropped
+/// or the returned handle is aborted.
+pub fn watch_policy_file<F>(
+    client: &Arc<SafeClient>,
+    path: impl Into<PathBuf>,
+    interval: Duration,
+    mut on_reload: F,
+) -> JoinHandle<()>
+where
+    F: FnMut(Result<(), FetchError>) + Send + 'static,
+{
+    let client: Weak<SafeClient> = Arc::downgrade(client);
+    let path = path.into();
+
+    tokio::spawn(async move {
+        let mut last_modified = modified(&path).await;
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+            let Some(client) = client.upgrade() else {
+                return;
+            };
+
+            let current = modified(&path).await;
+            if current.is_none() || current == last_modified {
+                continue;
+            }
+            last_modified = current;
+
+            let path = path.clone();
+            let reload = tokio::task::spawn_blocking(move || {
+                FetchPolicy::from_file(&path).map(|policy| client.update_policy(policy))
+            });
+            on_reload(
+                reload
+                    .await
+                    .unwrap_or_else(|e| Err(FetchError::InvalidPolicy(e.to_string()))),
+            );
+        }
+    })
+}
+
+async fn modified(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path).await.ok()?.modified().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reloads_on_change() {
+        let dir = std::env::temp_dir().join(format!("agent-fetch-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("policy.json");
+        std::fs::write(&path, r#"{"max_redirects": 1}"#).unwrap();
+
+        let client = Arc::new(SafeClient::new(FetchPolicy::from_file(&path).unwrap()));
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let handle = watch_policy_file(&client, &path, Duration::from_millis(10), move |r| {
+            let _ = tx.send(r.is_ok());
+        });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        std::fs::write(&path, r#"{"max_redirects": 7}"#).unwrap();
+        assert!(rx.recv().await.unwrap());
+        assert_eq!(client.policy().max_redirects, 7);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        std::fs::write(&path, r#"{"max_redirects": "bad"}"#).unwrap();
+        assert!(!rx.recv().await.unwrap());
+        assert_eq!(client.policy().max_redirects, 7);
+
+        handle.abort();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// Day of the week, serialized as a lowercase three-letter name (`mon`, `tue`, ...).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Weekday {
+    const ALL: [Weekday; 7] = [
+        Weekday::Mon,
+        Weekday::Tue,
+        Weekday::Wed,
+        Weekday::Thu,
+        Weekday::Fri,
+        Weekday::Sat,
+        Weekday::Sun,
+    ];
+}
+
+/// Time of day in minutes since midnight, serialized as `"HH:MM"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(u16);
+
+impl TimeOfDay {
+    pub fn new(hour: u8, minute: u8) -> Option<Self> {
+        (hour < 24 && minute < 60).then(|| Self(u16::from(hour) * 60 + u16::from(minute)))
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.split_once(':')
+            .and_then(|(h, m)| Self::new(h.parse().ok()?, m.parse().ok()?))
+            .ok_or_else(|| format!("invalid time of day {s:?} (expected HH:MM)"))
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(t: TimeOfDay) -> Self {
+        t.to_string()
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
+
+/// A recurring window of time, in UTC: `start` (inclusive) to `end` (exclusive) on each of
+/// `days`. A window whose `end` is earlier than its `start` runs past midnight, and the days
+/// refer to the day it starts on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeWindow {
+    /// Days the window opens on. Empty means every day.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+}
+
+impl TimeWindow {
+    /// Returns `true` if `time` falls inside the window.
+    pub fn contains(&self, time: SystemTime) -> bool {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let days = secs / 86_400;
+        let minute = TimeOfDay(((secs % 86_400) / 60) as u16);
+        // 1970-01-01 was a Thursday.
+        let weekday = |days: u64| Weekday::ALL[((days + 3) % 7) as usize];
+        let opens_on = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+
+        if self.start <= self.end {
+            opens_on(weekday(days)) && self.start <= minute && minute < self.end
+        } else {
+            (opens_on(weekday(days)) && minute >= self.start)
+                || (days > 0 && opens_on(weekday(days - 1)) && minute < self.end)
+        }
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.days.is_empty() {
+            let days: Vec<_> = self
+                .days
+                .iter()
+                .map(|d| format!("{d:?}").to_lowercase())
+                .collect();
+            write!(f, "{} ", days.join(","))?;
+        }
+        write!(f, "{}-{} UTC", self.start, self.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// 2024-01-01 was a Monday.
+    fn monday_at(hour: u64, minute: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_704_067_200 + hour * 3600 + minute * 60)
+    }
+
+    fn window(days: Vec<Weekday>, start: &str, end: &str) -> TimeWindow {
+        TimeWindow {
+            days,
+            start: TimeOfDay::try_from(start.to_string()).unwrap(),
+            end: TimeOfDay::try_from(end.to_string()).unwrap(),
+        }
+    }
+
+    #[test]
+    fn weekday_business_hours() {
+        let w = window(
+            vec![
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+            ],
+            "09:00",
+            "18:00",
+        );
+        assert!(w.contains(monday_at(9, 0)));
+        assert!(w.contains(monday_at(17, 59)));
+        assert!(!w.contains(monday_at(18, 0)));
+        assert!(!w.contains(monday_at(8, 59)));
+        // Saturday and Sunday.
+        assert!(!w.contains(monday_at(5 * 24 + 12, 0)));
+        assert!(!w.contains(monday_at(6 * 24 + 12, 0)));
+        assert_eq!(w.to_string(), "mon,tue,wed,thu,fri 09:00-18:00 UTC");
+    }
+
+    #[test]
+    fn overnight_window() {
+        let w = window(vec![Weekday::Mon], "22:00", "06:00");
+        assert!(w.contains(monday_at(23, 0)));
+        assert!(w.contains(monday_at(24 + 5, 0))); // early Tuesday
+        assert!(!w.contains(monday_at(24 + 23, 0))); // Tuesday night
+        assert!(!w.contains(monday_at(3, 0))); // early Monday belongs to Sunday
+    }
+
+    #[test]
+    fn parses_time_of_day() {
+        assert_eq!(
+            TimeOfDay::try_from("07:05".to_string())
+                .unwrap()
+                .to_string(),
+            "07:05"
+        );
+        assert!(TimeOfDay::try_from("24:00".to_string()).is_err());
+        assert!(TimeOfDay::try_from("9am".to_string()).is_err());
+    }
+}
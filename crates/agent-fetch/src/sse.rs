@@ -0,0 +1,342 @@
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::Stream;
+use tokio::time::Instant;
+
+use crate::client::{cancellable, until, FetchRequest, SafeClient, LIFETIME};
+use crate::content::parse_content_type;
+use crate::error::FetchError;
+use crate::stream::BodyReader;
+
+/// One event of a `text/event-stream` response.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SseEvent {
+    /// The event type; `"message"` if the server didn't name one.
+    pub event: String,
+    pub data: String,
+    /// The last event ID the stream has set, to send as `Last-Event-ID` when reconnecting.
+    pub id: Option<String>,
+    /// The reconnection delay the stream last asked for, in milliseconds.
+    pub retry: Option<u64>,
+}
+
+/// The events of a response, returned by [`SafeClient::fetch_sse`]. The stream ends after
+/// the first error, and keeps the request's concurrency slot until it is dropped.
+pub struct SseStream<'a> {
+    /// The URL the stream was served from, after redirects.
+    pub url: String,
+    pub headers: HashMap<String, String>,
+    inner: Pin<Box<dyn Stream<Item = Result<SseEvent, FetchError>> + Send + 'a>>,
+}
+
+impl Stream for SseStream<'_> {
+    type Item = Result<SseEvent, FetchError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+impl SafeClient {
+    /// Open a `text/event-stream` and yield its events as they arrive. The request is
+    /// validated and sent like any other fetch, with `Accept: text/event-stream` unless it
+    /// sets its own `Accept`. Fails with `UnexpectedStatus` unless the status is 2xx, and
+    /// with `UnexpectedContentType` unless the response is an event stream.
+    ///
+    /// While the stream is open:
+    /// - an event over `max_sse_event_bytes` fails it with `EventTooLarge`
+    /// - the response-size limit and the transfer budget cap its total bytes
+    /// - it fails with `RequestTimeout` once open for `max_sse_lifetime_ms`
+    /// - every `sse_revalidate_ms`, its URL is checked against the current policy, hooks, and
+    ///   kill switch, and its host re-resolved and the addresses checked, as with
+    ///   [`check`](Self::check); the first failure ends the stream with that error
+    pub async fn fetch_sse(&self, mut request: FetchRequest) -> Result<SseStream<'_>, FetchError> {
+        let policy = self.policy_for(request.profile.as_deref())?;
+        if !request
+            .headers
+            .keys()
+            .any(|name| name.eq_ignore_ascii_case("accept"))
+        {
+            request
+                .headers
+                .insert("Accept".into(), "text/event-stream".into());
+        }
+        let lifetime = Duration::from_millis(policy.max_sse_lifetime_ms);
+        // A lifetime too long to represent as an instant never runs out.
+        let deadline = Instant::now().checked_add(lifetime);
+        let cancel = request.cancel.clone();
+        // The request timeout still bounds the wait for the response headers.
+        let send = LIFETIME.scope(lifetime, self.send(request.clone()));
+        let reader =
+            tokio::time::timeout(Duration::from_millis(policy.request_timeout_ms), async {
+                cancellable(cancel.as_ref(), send).await
+            })
+            .await
+            .unwrap_or(Err(FetchError::RequestTimeout))?
+            .cancel_on(cancel);
+
+        let status = reader.status();
+        if !(200..300).contains(&status) {
+            return Err(FetchError::UnexpectedStatus { status });
+        }
+        let headers = reader.headers();
+        let media_type = headers
+            .get("content-type")
+            .map(|value| parse_content_type(value).0)
+            .unwrap_or_default();
+        if media_type != "text/event-stream" {
+            return Err(FetchError::UnexpectedContentType {
+                expected: "text/event-stream".into(),
+                actual: media_type,
+            });
+        }
+
+        let url = reader.url();
+        let revalidate = policy.sse_revalidate_ms.map(Duration::from_millis);
+        let events = EventReader {
+            client: self,
+            request: FetchRequest {
+                url: url.clone(),
+                method: "GET".into(),
+                body: None,
+                mirrors: Vec::new(),
+                ..request
+            },
+            reader,
+            parser: Parser::new(policy.max_sse_event_bytes),
+            events: VecDeque::new(),
+            deadline,
+            revalidate: revalidate.map(|every| (every, Instant::now().checked_add(every))),
+        };
+        let inner = futures_util::stream::unfold(Some(events), |events| async move {
+            let mut events = events?;
+            match events.next_event().await {
+                Ok(Some(event)) => Some((Ok(event), Some(events))),
+                Ok(None) => None,
+                Err(e) => Some((Err(e), None)),
+            }
+        });
+        Ok(SseStream {
+            url,
+            headers,
+            inner: Box::pin(inner),
+        })
+    }
+}
+
+struct EventReader<'a> {
+    client: &'a SafeClient,
+    /// The request to re-check, for the URL the stream was served from.
+    request: FetchRequest,
+    reader: BodyReader<'a>,
+    parser: Parser,
+    events: VecDeque<SseEvent>,
+    /// When the lifetime runs out, if it ever does.
+    deadline: Option<Instant>,
+    /// The re-check interval and when the next check is due, if it ever is.
+    revalidate: Option<(Duration, Option<Instant>)>,
+}
+
+impl EventReader<'_> {
+    async fn next_event(&mut self) -> Result<Option<SseEvent>, FetchError> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Some(event));
+            }
+            let wake = match self.revalidate.and_then(|(_, at)| at) {
+                Some(at) => Some(self.deadline.map_or(at, |deadline| at.min(deadline))),
+                None => self.deadline,
+            };
+            let expired = |deadline: Option<Instant>| deadline.is_some_and(|d| Instant::now() >= d);
+            match until(wake, self.reader.next_chunk()).await {
+                // The lifetime also bounds the underlying request, which may see it pass first.
+                Ok(Err(_)) if expired(self.deadline) => return Err(FetchError::RequestTimeout),
+                Ok(chunk) => match chunk? {
+                    Some(chunk) => self.parser.push(&chunk, &mut self.events)?,
+                    None => return Ok(None),
+                },
+                Err(_) if expired(self.deadline) => return Err(FetchError::RequestTimeout),
+                Err(_) => {
+                    self.client.ensure_enabled()?;
+                    self.client.check(&self.request, true).await?;
+                    if let Some((every, at)) = self.revalidate.as_mut() {
+                        *at = Instant::now().checked_add(*every);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Incremental `text/event-stream` parser, per the HTML Living Standard §9.2.6.
+struct Parser {
+    /// The current line, not yet terminated.
+    line: Vec<u8>,
+    /// The chunk before ended with `\r`, so a leading `\n` finishes that line break.
+    after_cr: bool,
+    started: bool,
+    event: String,
+    data: String,
+    id: Option<String>,
+    retry: Option<u64>,
+    limit: usize,
+}
+
+impl Parser {
+    fn new(limit: usize) -> Self {
+        Self {
+            line: Vec::new(),
+            after_cr: false,
+            started: false,
+            event: String::new(),
+            data: String::new(),
+            id: None,
+            retry: None,
+            limit,
+        }
+    }
+
+    /// Parse `chunk`, appending the events it completes to `events`.
+    fn push(
+        &mut self,
+        mut chunk: &[u8],
+        events: &mut VecDeque<SseEvent>,
+    ) -> Result<(), FetchError> {
+        if std::mem::take(&mut self.after_cr) && chunk.first() == Some(&b'\n') {
+            chunk = &chunk[1..];
+        }
+        while let Some(pos) = chunk.iter().position(|b| matches!(b, b'\r' | b'\n')) {
+            self.line.extend_from_slice(&chunk[..pos]);
+            self.check_size()?;
+            let line = std::mem::take(&mut self.line);
+            if let Some(event) = self.process_line(&line) {
+                events.push_back(event);
+            }
+            let crlf = chunk[pos] == b'\r' && chunk.get(pos + 1) == Some(&b'\n');
+            self.after_cr = chunk[pos] == b'\r' && pos + 1 == chunk.len();
+            chunk = &chunk[pos + if crlf { 2 } else { 1 }..];
+        }
+        self.line.extend_from_slice(chunk);
+        self.check_size()
+    }
+
+    fn check_size(&self) -> Result<(), FetchError> {
+        let size = self.event.len() + self.data.len() + self.line.len();
+        if size > self.limit {
+            return Err(FetchError::EventTooLarge {
+                size,
+                limit: self.limit,
+            });
+        }
+        Ok(())
+    }
+
+    fn process_line(&mut self, line: &[u8]) -> Option<SseEvent> {
+        let mut line = String::from_utf8_lossy(line).into_owned();
+        if !std::mem::replace(&mut self.started, true) && line.starts_with('\u{feff}') {
+            line.remove(0);
+        }
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line.as_str(), ""),
+        };
+        match field {
+            // A comment, e.g. a keep-alive.
+            "" => {}
+            "event" => self.event = value.to_string(),
+            "data" => {
+                self.data.push_str(value);
+                self.data.push('\n');
+            }
+            "id" if !value.contains('\0') => self.id = Some(value.to_string()),
+            "retry" if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+                self.retry = value.parse().ok();
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = std::mem::take(&mut self.event);
+        let mut data = std::mem::take(&mut self.data);
+        if data.is_empty() {
+            return None;
+        }
+        data.pop();
+        Some(SseEvent {
+            event: if event.is_empty() {
+                "message".into()
+            } else {
+                event
+            },
+            data,
+            id: self.id.clone(),
+            retry: self.retry,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(chunks: &[&str], limit: usize) -> Result<Vec<SseEvent>, FetchError> {
+        let mut parser = Parser::new(limit);
+        let mut events = VecDeque::new();
+        for chunk in chunks {
+            parser.push(chunk.as_bytes(), &mut events)?;
+        }
+        Ok(events.into())
+    }
+
+    #[test]
+    fn parses_events_split_across_chunks() {
+        let events = parse(
+            &[
+                "\u{feff}: keep-alive\r\nretry: 3000\r",
+                "\nevent: update\r\nid: 7\ndata: {\"a\":",
+                "1}\ndata:second line\r\rdata",
+                "\n\nevent: ignored\n\n",
+            ],
+            1024,
+        )
+        .unwrap();
+        assert_eq!(
+            events,
+            [
+                SseEvent {
+                    event: "update".into(),
+                    data: "{\"a\":1}\nsecond line".into(),
+                    id: Some("7".into()),
+                    retry: Some(3000),
+                },
+                SseEvent {
+                    event: "message".into(),
+                    data: String::new(),
+                    id: Some("7".into()),
+                    retry: Some(3000),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_events() {
+        let err = parse(&["data: 0123456789\n", "data: 0123456789\n"], 20).unwrap_err();
+        assert!(matches!(err, FetchError::EventTooLarge { limit: 20, .. }));
+        // The limit applies per event.
+        assert_eq!(
+            parse(&["data: 0123456789\n\n", "data: 0123456789\n\n"], 20)
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_util::Stream;
+use tokio_util::sync::CancellationToken;
+
+use crate::budget::TransferBudget;
+use crate::cache::{CacheWriter, CachedResponse};
+use crate::client::{cancellable, RedirectHop, StreamSlot};
+use crate::decompress::Decoder;
+use crate::error::FetchError;
+use crate::rate_limit::{Permit, ServerRateLimit};
+use crate::timing::FetchTimings;
+use crate::tls::TlsDetails;
+
+/// A response whose body is read incrementally, returned by
+/// [`SafeClient::fetch_stream`](crate::SafeClient::fetch_stream).
+pub struct StreamingResponse<'a> {
+    pub status: u16,
+    pub headers: HashMap<String, String>,
+    /// The URL the response was served from, after redirects.
+    pub url: String,
+    /// The redirects followed to get here, in order.
+    pub redirects: Vec<RedirectHop>,
+    pub version: http::Version,
+    pub timings: FetchTimings,
+    /// The delay the response's `Retry-After` header asks for, e.g. on a 429 or 503.
+    pub retry_after: Option<Duration>,
+    /// The server's own rate limit, if the response reports it.
+    pub rate_limit: Option<ServerRateLimit>,
+    /// The TLS session of the final response, under `capture_tls_details`.
+    pub tls: Option<TlsDetails>,
+    pub body: BodyStream<'a>,
+}
+
+/// The chunks of a response body. The response-size limit and the transfer budget are
+/// enforced as chunks arrive; the stream ends after the first error. The request keeps its
+/// concurrency slot until the stream is dropped.
+pub struct BodyStream<'a> {
+    inner: Pin<Box<dyn Stream<Item = Result<Bytes, FetchError>> + Send + 'a>>,
+}
+
+impl Stream for BodyStream<'_> {
+    type Item = Result<Bytes, FetchError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+/// Reads a response body chunk by chunk under the response-size limit and the transfer
+/// budget, shared by the buffered and the streaming fetch.
+pub(crate) struct BodyReader<'a> {
+    response: reqwest::Response,
+    host: String,
+    redirects: Vec<RedirectHop>,
+    limit: usize,
+    read: usize,
+    /// `None` for a response served from the cache, which transfers nothing.
+    budget: Option<&'a TransferBudget>,
+    cancel: Option<CancellationToken>,
+    decoder: Option<Decoder>,
+    cache: Option<CacheWriter>,
+    _permit: Option<Permit<'a>>,
+    /// The request's slot under `http2_max_concurrent_streams`, if one applies.
+    _stream: Option<StreamSlot>,
+}
+
+impl<'a> BodyReader<'a> {
+    /// Fails early if the response's `Content-Length` already exceeds the size limit or
+    /// the remaining budget.
+    pub(crate) fn new(
+        response: reqwest::Response,
+        host: String,
+        redirects: Vec<RedirectHop>,
+        limit: usize,
+        budget: &'a TransferBudget,
+        permit: Permit<'a>,
+    ) -> Result<Self, FetchError> {
+        if let Some(cl) = response.content_length() {
+            if cl as usize > limit {
+                return Err(FetchError::ResponseBodyTooLarge {
+                    size: cl as usize,
+                    limit,
+                });
+            }
+            budget.check(&host, cl)?;
+        }
+        Ok(Self {
+            response,
+            host,
+            redirects,
+            limit,
+            read: 0,
+            budget: Some(budget),
+            cancel: None,
+            decoder: None,
+            cache: None,
+            _permit: Some(permit),
+            _stream: None,
+        })
+    }
+
+    /// Replay a cached response. It still has to fit the size limit, but uses no
+    /// concurrency slot or transfer budget.
+    pub(crate) fn cached(
+        entry: CachedResponse,
+        host: String,
+        limit: usize,
+    ) -> Result<Self, FetchError> {
+        use reqwest::ResponseBuilderExt;
+
+        if entry.body.len() > limit {
+            return Err(FetchError::ResponseBodyTooLarge {
+                size: entry.body.len(),
+                limit,
+            });
+        }
+        let url = url::Url::parse(&entry.url).map_err(|e| FetchError::InvalidUrl(e.to_string()))?;
+        let mut builder = http::Response::builder()
+            .status(entry.status)
+            .version(entry.version)
+            .url(url);
+        for (name, value) in &entry.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        let response = builder
+            .body(entry.body)
+            .map_err(|e| FetchError::HttpError(e.to_string()))?;
+        Ok(Self {
+            response: response.into(),
+            host,
+            redirects: Vec::new(),
+            limit,
+            read: 0,
+            budget: None,
+            cancel: None,
+            decoder: None,
+            cache: None,
+            _permit: None,
+            _stream: None,
+        })
+    }
+
+    /// Store the response in the cache once its body has been read in full.
+    pub(crate) fn cache_into(mut self, writer: CacheWriter) -> Self {
+        self.cache = Some(writer);
+        self
+    }
+
+    /// Fail reads with `FetchError::Cancelled` once `cancel` fires.
+    pub(crate) fn cancel_on(mut self, cancel: Option<CancellationToken>) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Keep `slot` until the body is read or dropped.
+    pub(crate) fn holding(mut self, slot: Option<StreamSlot>) -> Self {
+        self._stream = slot;
+        self
+    }
+
+    /// Decode the body if it has a supported `Content-Encoding`, failing once the decoded
+    /// size passes `limit`. The size limit and the budget still apply to the encoded bytes.
+    pub(crate) fn decompress(mut self, limit: usize) -> Self {
+        self.decoder = self
+            .response
+            .headers()
+            .get(http::header::CONTENT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|encoding| Decoder::for_encoding(encoding, limit));
+        self
+    }
+
+    pub(crate) fn status(&self) -> u16 {
+        self.response.status().as_u16()
+    }
+
+    pub(crate) fn url(&self) -> String {
+        self.response.url().to_string()
+    }
+
+    /// The declared size of the body, as received.
+    pub(crate) fn content_length(&self) -> Option<u64> {
+        self.response.content_length()
+    }
+
+    pub(crate) fn version(&self) -> http::Version {
+        self.response.version()
+    }
+
+    pub(crate) fn tls(&self) -> Option<TlsDetails> {
+        self.response
+            .extensions()
+            .get::<reqwest::tls::TlsInfo>()
+            .and_then(TlsDetails::of)
+    }
+
+    pub(crate) fn take_redirects(&mut self) -> Vec<RedirectHop> {
+        std::mem::take(&mut self.redirects)
+    }
+
+    /// The response headers. Once the body is being decoded, `Content-Encoding` and
+    /// `Content-Length` describe the encoded body and are left out.
+    pub(crate) fn headers(&self) -> HashMap<String, String> {
+        let decoded = self.decoder.is_some();
+        self.response
+            .headers()
+            .iter()
+            .filter(|(k, _)| {
+                !decoded
+                    || (*k != http::header::CONTENT_ENCODING && *k != http::header::CONTENT_LENGTH)
+            })
+            .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
+            .collect()
+    }
+
+    /// The next chunk of the body, decoded if the body is being decompressed.
+    pub(crate) async fn next_chunk(&mut self) -> Result<Option<Bytes>, FetchError> {
+        let chunk = self.next_decoded_chunk().await?;
+        match (&chunk, self.cache.as_mut()) {
+            (Some(chunk), Some(cache)) => cache.push(chunk),
+            (None, Some(_)) => self.cache.take().unwrap().finish(),
+            _ => {}
+        }
+        Ok(chunk)
+    }
+
+    async fn next_decoded_chunk(&mut self) -> Result<Option<Bytes>, FetchError> {
+        loop {
+            let chunk = self.next_wire_chunk().await?;
+            let Some(decoder) = self.decoder.as_mut() else {
+                return Ok(chunk);
+            };
+            let decoded = match chunk {
+                Some(chunk) => decoder.decode(&chunk)?,
+                None => return Ok(Some(decoder.finish()?).filter(|tail| !tail.is_empty())),
+            };
+            if !decoded.is_empty() {
+                return Ok(Some(decoded));
+            }
+        }
+    }
+
+    async fn next_wire_chunk(&mut self) -> Result<Option<Bytes>, FetchError> {
+        let chunk = self.response.chunk();
+        let chunk = cancellable(self.cancel.as_ref(), async {
+            chunk
+                .await
+                .map_err(|e| FetchError::HttpError(e.to_string()))
+        });
+        let Some(chunk) = chunk.await? else {
+            return Ok(None);
+        };
+        if let Some(budget) = self.budget {
+            budget.record(&self.host, chunk.len() as u64)?;
+        }
+        self.read += chunk.len();
+        if self.read > self.limit {
+            return Err(FetchError::ResponseBodyTooLarge {
+                size: self.read,
+                limit: self.limit,
+            });
+        }
+        Ok(Some(chunk))
+    }
+
+    pub(crate) fn into_stream(self) -> BodyStream<'a> {
+        let inner = futures_util::stream::unfold(Some(self), |reader| async move {
+            let mut reader = reader?;
+            match reader.next_chunk().await {
+                Ok(Some(chunk)) => Some((Ok(chunk), Some(reader))),
+                Ok(None) => None,
+                Err(e) => Some((Err(e), None)),
+            }
+        });
+        BodyStream {
+            inner: Box::pin(inner),
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// How long each phase of a fetch took. When redirects are followed, the phases add up
+/// over every hop.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FetchTimings {
+    /// DNS resolution: the lookups made to validate each host, plus any made while opening
+    /// a connection (usually answered from the resolver's cache).
+    pub dns: Duration,
+    /// Opening new connections: the TCP connect, and the tunnel through any proxy. `None`
+    /// if only pooled connections were used.
+    pub connect: Option<Duration>,
+    /// The TLS handshakes of new `https` connections. `None` if no handshake was made.
+    pub tls: Option<Duration>,
+    /// From sending the request to receiving the final response's headers, including
+    /// connecting and any redirect hops.
+    pub time_to_first_byte: Duration,
+    /// Reading the response body. Zero for a [`StreamingResponse`](crate::StreamingResponse),
+    /// whose body is read by the caller.
+    pub body: Duration,
+    /// The whole fetch, including policy checks, hooks, and rate-limit waits.
+    pub total: Duration,
+}
+
+impl FetchTimings {
+    pub(crate) fn finish(mut self, body: Duration, total: Duration) -> Self {
+        self.body = body;
+        self.total = total;
+        self
+    }
+}
+
+#[derive(Default)]
+struct Phases {
+    timings: FetchTimings,
+    /// When the latest TLS handshake started.
+    handshake: Option<Instant>,
+}
+
+tokio::task_local! {
+    static PHASES: Arc<Mutex<Phases>>;
+}
+
+/// Run `fut`, collecting the phases recorded while it runs.
+pub(crate) async fn measure<F: Future>(fut: F) -> (F::Output, FetchTimings) {
+    let phases = Arc::new(Mutex::new(Phases::default()));
+    let output = PHASES.scope(phases.clone(), fut).await;
+    let timings = phases.lock().unwrap().timings;
+    (output, timings)
+}
+
+/// Record a phase of the fetch being measured on this task, if any. Work that happens
+/// outside a measured fetch (e.g. a connection finished in the background) isn't recorded.
+fn record(f: impl FnOnce(&mut FetchTimings)) {
+    let _ = PHASES.try_with(|phases| f(&mut phases.lock().unwrap().timings));
+}
+
+fn recorded_dns() -> Duration {
+    PHASES
+        .try_with(|phases| phases.lock().unwrap().timings.dns)
+        .unwrap_or_default()
+}
+
+/// Mark the start of a TLS handshake, for the connection being opened on this task.
+pub(crate) fn handshake_started() {
+    let _ = PHASES.try_with(|phases| phases.lock().unwrap().handshake = Some(Instant::now()));
+}
+
+fn take_handshake() -> Option<Instant> {
+    PHASES
+        .try_with(|phases| phases.lock().unwrap().handshake.take())
+        .ok()
+        .flatten()
+}
+
+/// Run a DNS lookup, recording its duration.
+pub(crate) async fn time_dns<F: Future>(fut: F) -> F::Output {
+    let start = Instant::now();
+    let output = fut.await;
+    let elapsed = start.elapsed();
+    record(|t| t.dns += elapsed);
+    output
+}
+
+pub(crate) fn record_first_byte(elapsed: Duration) {
+    record(|t| t.time_to_first_byte = elapsed);
+}
+
+/// Connector layer that records how long opening each new connection takes, split at the
+/// start of its TLS handshake, excluding the DNS lookup made by the connector, which is
+/// recorded separately.
+#[derive(Clone)]
+pub(crate) struct ConnectTimer;
+
+impl<S> Layer<S> for ConnectTimer {
+    type Service = TimedConnect<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TimedConnect { inner }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct TimedConnect<S> {
+    inner: S,
+}
+
+impl<S, R> Service<R> for TimedConnect<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let fut = self.inner.call(request);
+        Box::pin(async move {
+            let start = Instant::now();
+            let dns_before = recorded_dns();
+            let output = fut.await;
+            let end = Instant::now();
+            // A handshake marked before this connection started belongs to another one.
+            let tls = take_handshake()
+                .filter(|&at| at >= start)
+                .map(|at| end - at);
+            let elapsed = (end - start)
+                .saturating_sub(recorded_dns().saturating_sub(dns_before))
+                .saturating_sub(tls.unwrap_or_default());
+            record(|t| {
+                *t.connect.get_or_insert(Duration::ZERO) += elapsed;
+                if let Some(tls) = tls {
+                    *t.tls.get_or_insert(Duration::ZERO) += tls;
+                }
+            });
+            output
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn records_phases_only_inside_a_measured_fetch() {
+        time_dns(async {}).await;
+        record_first_byte(Duration::from_millis(5));
+
+        let ((), timings) = measure(async {
+            time_dns(tokio::time::sleep(Duration::from_millis(10))).await;
+            record_first_byte(Duration::from_millis(20));
+        })
+        .await;
+        assert!(timings.dns >= Duration::from_millis(10));
+        assert_eq!(timings.time_to_first_byte, Duration::from_millis(20));
+        assert_eq!(timings.connect, None);
+        assert_eq!(timings.tls, None);
+
+        let timings = timings.finish(Duration::from_millis(1), Duration::from_millis(30));
+        assert_eq!(timings.body, Duration::from_millis(1));
+        assert_eq!(timings.total, Duration::from_millis(30));
+    }
+}
@@ -0,0 +1,548 @@
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper_util::client::legacy::connect::Connection;
+use p12_keystore::{KeyStore, Pkcs12ImportPolicy};
+use reqwest::Certificate;
+use rustls::client::{
+    ClientSessionMemoryCache, ClientSessionStore, ResolvesClientCert, Resumption,
+    Tls12ClientSessionValue, Tls13ClientSessionValue,
+};
+use rustls::crypto::aws_lc_rs;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::sign::CertifiedKey;
+use rustls::{ClientConfig, NamedGroup, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use tower_layer::Layer;
+use tower_service::Service;
+use x509_cert::der::{Decode, Encode};
+use x509_cert::ext::pkix::name::GeneralName;
+use x509_cert::ext::pkix::SubjectAltName;
+
+use crate::decision::Decision;
+use crate::error::FetchError;
+use crate::policy::{CertificatePin, ClientCertificate, RootCertificates, TlsVersion};
+use crate::timing;
+
+/// The TLS session a response came over and the server's leaf certificate, captured when
+/// `capture_tls_details` is on. The HTTP stack doesn't report the cipher suite.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsDetails {
+    /// The negotiated protocol version.
+    pub version: Option<TlsVersion>,
+    /// The subject's distinguished name, e.g. `CN=api.example.com`.
+    pub subject: String,
+    pub issuer: String,
+    /// The DNS names and IP addresses the certificate is valid for.
+    pub subject_alt_names: Vec<String>,
+    pub not_before: SystemTime,
+    pub not_after: SystemTime,
+    /// The base64 SHA-256 hash of the DER certificate.
+    pub sha256: String,
+    /// The base64 SHA-256 hash of the certificate's key, as pinned by `certificate_pins`.
+    pub spki_sha256: String,
+}
+
+impl TlsDetails {
+    pub(crate) fn of(info: &reqwest::tls::TlsInfo) -> Option<Self> {
+        let der = info.peer_certificate()?;
+        let cert = x509_cert::Certificate::from_der(der).ok()?;
+        let tbs = cert.tbs_certificate();
+        let subject_alt_names = match tbs.get_extension::<SubjectAltName>() {
+            Ok(Some((_, names))) => names.0.iter().filter_map(general_name).collect(),
+            _ => Vec::new(),
+        };
+        Some(TlsDetails {
+            version: info.version().and_then(|version| match version {
+                reqwest::tls::Version::TLS_1_2 => Some(TlsVersion::Tls1_2),
+                reqwest::tls::Version::TLS_1_3 => Some(TlsVersion::Tls1_3),
+                _ => None,
+            }),
+            subject: tbs.subject().to_string(),
+            issuer: tbs.issuer().to_string(),
+            subject_alt_names,
+            not_before: tbs.validity().not_before.to_system_time(),
+            not_after: tbs.validity().not_after.to_system_time(),
+            sha256: STANDARD.encode(Sha256::digest(der)),
+            spki_sha256: STANDARD.encode(spki_sha256(der)?),
+        })
+    }
+}
+
+fn general_name(name: &GeneralName) -> Option<String> {
+    match name {
+        GeneralName::DnsName(dns) => Some(dns.to_string()),
+        GeneralName::IpAddress(ip) => {
+            let ip = match ip.as_bytes().len() {
+                4 => IpAddr::from(<[u8; 4]>::try_from(ip.as_bytes()).ok()?),
+                _ => IpAddr::from(<[u8; 16]>::try_from(ip.as_bytes()).ok()?),
+            };
+            Some(ip.to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Read `cert`'s certificate chain and private key. Errors are reported as text, for
+/// `FetchPolicy::problems` and `FetchError::InvalidPolicy`.
+pub(crate) fn load_identity(cert: &ClientCertificate) -> Result<Arc<CertifiedKey>, String> {
+    let read = |path: &std::path::Path| {
+        std::fs::read(path).map_err(|e| format!("failed to read {}: {e}", path.display()))
+    };
+    let pem = match (&cert.pem_file, &cert.pkcs12_file) {
+        (Some(path), None) => read(path)?,
+        (None, Some(path)) => {
+            let password = cert.pkcs12_password.as_deref().unwrap_or("");
+            pkcs12_to_pem(&read(path)?, password)
+                .map_err(|e| format!("invalid PKCS#12 archive {}: {e}", path.display()))?
+        }
+        _ => return Err("set exactly one of pem_file and pkcs12_file".into()),
+    };
+    let chain = CertificateDer::pem_slice_iter(&pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid certificate: {e}"))?;
+    if chain.is_empty() {
+        return Err("no certificate".into());
+    }
+    let key = PrivateKeyDer::from_pem_slice(&pem).map_err(|e| format!("invalid key: {e}"))?;
+    CertifiedKey::from_der(chain, key, &aws_lc_rs::default_provider())
+        .map(Arc::new)
+        .map_err(|e| e.to_string())
+}
+
+/// Read the certificates of every entry of `root_certificates`.
+pub(crate) fn load_roots(roots: &[RootCertificates]) -> Result<Vec<Certificate>, String> {
+    load_root_ders(roots)?
+        .iter()
+        .map(|der| Certificate::from_der(der).map_err(|e| e.to_string()))
+        .collect()
+}
+
+/// Roots for TLS connections made outside reqwest, such as DNS over TLS: the system's
+/// roots unless `use_system_roots` is off, plus every entry of `root_certificates`.
+pub(crate) fn root_store(
+    roots: &[RootCertificates],
+    use_system_roots: bool,
+) -> Result<RootCertStore, String> {
+    let mut store = RootCertStore::empty();
+    if use_system_roots {
+        store.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+    }
+    for der in load_root_ders(roots)? {
+        store
+            .add(der)
+            .map_err(|e| format!("invalid root certificate: {e}"))?;
+    }
+    Ok(store)
+}
+
+/// The TLS configuration of requests' connections: verifying servers against `roots`,
+/// presenting `identity` if set, and offering the `alpn` protocols. Handshakes are marked
+/// for [`FetchTimings::tls`](crate::FetchTimings::tls).
+pub(crate) fn client_config(
+    roots: Arc<RootCertStore>,
+    identity: Option<Arc<CertifiedKey>>,
+    min_version: TlsVersion,
+    alpn: &[&[u8]],
+) -> ClientConfig {
+    let versions: &[_] = match min_version {
+        TlsVersion::Tls1_2 => &[&rustls::version::TLS13, &rustls::version::TLS12],
+        TlsVersion::Tls1_3 => &[&rustls::version::TLS13],
+    };
+    let builder = ClientConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+        .with_protocol_versions(versions)
+        .expect("aws-lc-rs supports TLS 1.2 and 1.3")
+        .with_root_certificates(roots);
+    let mut config = match identity {
+        Some(key) => builder.with_client_cert_resolver(Arc::new(Present(key))),
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    config.resumption = Resumption::store(Arc::new(MarkHandshakes::default()));
+    config
+}
+
+/// Make the TLS handshake with `host` over `stream` as a pooled connection would, and check
+/// the server's certificate against `pins`, if any.
+pub(crate) async fn handshake(
+    config: Arc<ClientConfig>,
+    pins: Option<&PinSet>,
+    host: &str,
+    stream: tokio::net::TcpStream,
+) -> Result<(), FetchError> {
+    let name = host.trim_start_matches('[').trim_end_matches(']');
+    let name = ServerName::try_from(name.to_string())
+        .map_err(|e| FetchError::InvalidUrl(format!("{host}: {e}")))?;
+    let stream = tokio_rustls::TlsConnector::from(config)
+        .connect(name, stream)
+        .await
+        .map_err(|e| FetchError::HttpError(format!("TLS handshake with {host}: {e}")))?;
+    if let Some(pins) = pins {
+        let leaf = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(<[_]>::first);
+        pins.check(leaf.map(|cert| cert.as_ref())).map_err(|e| {
+            FetchError::CertificatePinMismatch {
+                host: host.into(),
+                fingerprint: e.fingerprint,
+                decision: Decision::deny("certificate_pins", e.pattern),
+            }
+        })?;
+    }
+    Ok(())
+}
+
+/// Presents the same certificate to every server asking for one.
+#[derive(Debug)]
+struct Present(Arc<CertifiedKey>);
+
+impl ResolvesClientCert for Present {
+    fn resolve(&self, _: &[&[u8]], _: &[SignatureScheme]) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+/// Session cache that marks the start of each handshake: rustls asks it for the server's
+/// key exchange group while writing the first message.
+#[derive(Debug)]
+struct MarkHandshakes(ClientSessionMemoryCache);
+
+impl Default for MarkHandshakes {
+    fn default() -> Self {
+        Self(ClientSessionMemoryCache::new(256))
+    }
+}
+
+impl ClientSessionStore for MarkHandshakes {
+    fn set_kx_hint(&self, server_name: ServerName<'static>, group: NamedGroup) {
+        self.0.set_kx_hint(server_name, group);
+    }
+
+    fn kx_hint(&self, server_name: &ServerName<'_>) -> Option<NamedGroup> {
+        timing::handshake_started();
+        self.0.kx_hint(server_name)
+    }
+
+    fn set_tls12_session(&self, server_name: ServerName<'static>, value: Tls12ClientSessionValue) {
+        self.0.set_tls12_session(server_name, value);
+    }
+
+    fn tls12_session(&self, server_name: &ServerName<'_>) -> Option<Tls12ClientSessionValue> {
+        self.0.tls12_session(server_name)
+    }
+
+    fn remove_tls12_session(&self, server_name: &ServerName<'static>) {
+        self.0.remove_tls12_session(server_name);
+    }
+
+    fn insert_tls13_ticket(
+        &self,
+        server_name: ServerName<'static>,
+        value: Tls13ClientSessionValue,
+    ) {
+        self.0.insert_tls13_ticket(server_name, value);
+    }
+
+    fn take_tls13_ticket(
+        &self,
+        server_name: &ServerName<'static>,
+    ) -> Option<Tls13ClientSessionValue> {
+        self.0.take_tls13_ticket(server_name)
+    }
+}
+
+fn load_root_ders(roots: &[RootCertificates]) -> Result<Vec<CertificateDer<'static>>, String> {
+    let mut certs = Vec::new();
+    for root in roots {
+        let (pem, source) = match (&root.file, &root.pem) {
+            (Some(path), None) => (
+                std::fs::read(path).map_err(|e| {
+                    format!("failed to read root certificates {}: {e}", path.display())
+                })?,
+                path.display().to_string(),
+            ),
+            (None, Some(pem)) => (pem.clone().into_bytes(), "inline PEM".to_string()),
+            _ => return Err("root certificates need exactly one of file and pem".into()),
+        };
+        let found = CertificateDer::pem_slice_iter(&pem)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid root certificates in {source}: {e}"))?;
+        if found.is_empty() {
+            return Err(format!("no root certificates in {source}"));
+        }
+        certs.extend(found);
+    }
+    Ok(certs)
+}
+
+/// The key and certificate chain of a PKCS#12 archive, as PEM: rustls only loads PEM.
+fn pkcs12_to_pem(der: &[u8], password: &str) -> Result<Vec<u8>, String> {
+    let store = KeyStore::from_pkcs12(der, password, Pkcs12ImportPolicy::Strict)
+        .map_err(|e| e.to_string())?;
+    let (_, chain) = store
+        .private_key_chain()
+        .ok_or("no private key with a certificate")?;
+    let mut pem = String::new();
+    for cert in chain.certs() {
+        push_pem(&mut pem, "CERTIFICATE", cert.as_der());
+    }
+    push_pem(&mut pem, "PRIVATE KEY", chain.key().as_der());
+    Ok(pem.into_bytes())
+}
+
+fn push_pem(pem: &mut String, label: &str, der: &[u8]) {
+    pem.push_str(&format!("-----BEGIN {label}-----\n"));
+    for line in STANDARD.encode(der).as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {label}-----\n"));
+}
+
+/// The decoded hashes of a [`CertificatePin`].
+#[derive(Debug)]
+pub(crate) struct PinSet {
+    pattern: String,
+    spki: Vec<[u8; 32]>,
+    cert: Vec<[u8; 32]>,
+}
+
+/// A server certificate that matched none of its host's pins, with the base64 hash of its
+/// key.
+#[derive(Debug, thiserror::Error)]
+#[error("certificate matches none of the pins for {pattern}")]
+pub(crate) struct PinMismatch {
+    pub pattern: String,
+    pub fingerprint: String,
+}
+
+impl PinSet {
+    pub(crate) fn new(pin: &CertificatePin) -> Result<Self, String> {
+        let decode = |hashes: &[String]| {
+            hashes
+                .iter()
+                .map(|hash| {
+                    STANDARD
+                        .decode(hash)
+                        .ok()
+                        .and_then(|bytes| bytes.try_into().ok())
+                        .ok_or_else(|| {
+                            format!(
+                                "certificate pin for {}: {hash:?} is not a base64 SHA-256 hash",
+                                pin.pattern.0
+                            )
+                        })
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let pins = PinSet {
+            pattern: pin.pattern.0.clone(),
+            spki: decode(&pin.spki_sha256)?,
+            cert: decode(&pin.cert_sha256)?,
+        };
+        if pins.spki.is_empty() && pins.cert.is_empty() {
+            return Err(format!(
+                "certificate pin for {} has no hashes",
+                pin.pattern.0
+            ));
+        }
+        Ok(pins)
+    }
+
+    /// Check the DER leaf certificate a server presented, if any.
+    fn check(&self, der: Option<&[u8]>) -> Result<(), PinMismatch> {
+        let cert = der.map(|der| Sha256::digest(der).into());
+        let spki = der.and_then(spki_sha256);
+        if cert.is_some_and(|hash| self.cert.contains(&hash))
+            || spki.is_some_and(|hash| self.spki.contains(&hash))
+        {
+            return Ok(());
+        }
+        Err(PinMismatch {
+            pattern: self.pattern.clone(),
+            fingerprint: spki.map(|hash| STANDARD.encode(hash)).unwrap_or_default(),
+        })
+    }
+}
+
+fn spki_sha256(der: &[u8]) -> Option<[u8; 32]> {
+    let cert = x509_cert::Certificate::from_der(der).ok()?;
+    let spki = cert
+        .tbs_certificate()
+        .subject_public_key_info()
+        .to_der()
+        .ok()?;
+    Some(Sha256::digest(spki).into())
+}
+
+/// Connector layer that fails every new connection whose server certificate doesn't match
+/// `pins`. Set on clients that only serve hosts matching the pins' pattern, with
+/// `tls_info` on so the certificate is kept.
+#[derive(Clone)]
+pub(crate) struct CheckPins(pub Arc<PinSet>);
+
+impl<S> Layer<S> for CheckPins {
+    type Service = PinnedConnect<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PinnedConnect {
+            inner,
+            pins: self.0.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct PinnedConnect<S> {
+    inner: S,
+    pins: Arc<PinSet>,
+}
+
+impl<S, R> Service<R> for PinnedConnect<S>
+where
+    S: Service<R>,
+    S::Response: Connection,
+    S::Future: Send + 'static,
+    S::Error: From<PinMismatch>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let fut = self.inner.call(request);
+        let pins = self.pins.clone();
+        Box::pin(async move {
+            let conn = fut.await?;
+            let mut extras = http::Extensions::new();
+            conn.connected().get_extras(&mut extras);
+            let info = extras.get::<reqwest::tls::TlsInfo>();
+            pins.check(info.and_then(|info| info.peer_certificate()))?;
+            Ok(conn)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::DomainPattern;
+
+    fn fixture(name: &str) -> Option<std::path::PathBuf> {
+        Some(
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures")
+                .join(name),
+        )
+    }
+
+    #[test]
+    fn loads_pem_and_pkcs12() {
+        let pem = ClientCertificate {
+            pattern: DomainPattern("api.example.com".into()),
+            pem_file: fixture("client.pem"),
+            ..Default::default()
+        };
+        assert!(load_identity(&pem).is_ok());
+
+        let mut pkcs12 = ClientCertificate {
+            pattern: DomainPattern("api.example.com".into()),
+            pkcs12_file: fixture("client.p12"),
+            pkcs12_password: Some("secret".into()),
+            ..Default::default()
+        };
+        assert!(load_identity(&pkcs12).is_ok());
+        pkcs12.pkcs12_password = Some("wrong".into());
+        assert!(load_identity(&pkcs12).is_err());
+    }
+
+    #[test]
+    fn loads_root_certificates() {
+        let pem = std::fs::read_to_string(fixture("ca.pem").unwrap()).unwrap();
+        let roots = [
+            RootCertificates {
+                file: fixture("ca.pem"),
+                ..Default::default()
+            },
+            RootCertificates {
+                pem: Some(pem),
+                ..Default::default()
+            },
+        ];
+        assert_eq!(load_roots(&roots).unwrap().len(), 2);
+        let garbage = RootCertificates {
+            pem: Some("not a certificate".into()),
+            ..Default::default()
+        };
+        assert!(load_roots(&[garbage]).is_err());
+        assert!(load_roots(&[RootCertificates::default()]).is_err());
+    }
+
+    #[test]
+    fn needs_exactly_one_file() {
+        let neither = ClientCertificate::default();
+        assert!(load_identity(&neither).is_err());
+        let missing = ClientCertificate {
+            pem_file: fixture("missing.pem"),
+            ..Default::default()
+        };
+        assert!(load_identity(&missing)
+            .unwrap_err()
+            .starts_with("failed to read"));
+    }
+
+    #[test]
+    fn checks_pins_against_the_leaf_certificate() {
+        let pem = std::fs::read_to_string(fixture("server.pem").unwrap()).unwrap();
+        let base64: String = pem
+            .lines()
+            .skip_while(|line| *line != "-----BEGIN CERTIFICATE-----")
+            .skip(1)
+            .take_while(|line| !line.starts_with("-----END"))
+            .collect();
+        let der = STANDARD.decode(base64).unwrap();
+        let pin = |spki: &str, cert: &str| CertificatePin {
+            pattern: DomainPattern("localhost".into()),
+            spki_sha256: vec![spki.into()],
+            cert_sha256: vec![cert.into()],
+        };
+        let spki = "5kzOd9gRUYqBpgpI4jnvzL/L8TGRWDrfepGnCiZ6rpU=";
+        let cert = "cFSyMVYPbDaeZEqMSJh60OE0VqMwVbNvC249B9TY9NU=";
+        let other = STANDARD.encode([0u8; 32]);
+
+        assert!(PinSet::new(&pin(spki, &other))
+            .unwrap()
+            .check(Some(&der))
+            .is_ok());
+        assert!(PinSet::new(&pin(&other, cert))
+            .unwrap()
+            .check(Some(&der))
+            .is_ok());
+        let err = PinSet::new(&pin(&other, &other))
+            .unwrap()
+            .check(Some(&der))
+            .unwrap_err();
+        assert_eq!(err.fingerprint, spki);
+        assert!(PinSet::new(&pin(spki, cert)).unwrap().check(None).is_err());
+
+        assert!(PinSet::new(&pin("not base64", cert)).is_err());
+        assert!(PinSet::new(&CertificatePin::default()).is_err());
+    }
+}
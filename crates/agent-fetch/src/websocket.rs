@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::error::CapacityError;
+use tokio_tungstenite::tungstenite::handshake::client::generate_key;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{Role, WebSocketConfig};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::budget::TransferBudget;
+use crate::client::{cancellable, until, FetchRequest, SafeClient};
+use crate::error::FetchError;
+use crate::rate_limit::Permit;
+
+/// A data message sent or received over a [`SafeWebSocket`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebSocketMessage {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// A WebSocket connection opened by [`SafeClient::websocket`]. It keeps the request's
+/// concurrency slot until it is dropped.
+pub struct SafeWebSocket<'a> {
+    /// The URL the connection was opened to.
+    pub url: String,
+    /// The headers of the server's handshake response.
+    pub headers: HashMap<String, String>,
+    client: &'a SafeClient,
+    stream: WebSocketStream<reqwest::Upgraded>,
+    host: String,
+    budget: &'a TransferBudget,
+    max_message_bytes: usize,
+    /// When the lifetime runs out; `None` if it's too long to represent.
+    deadline: Option<Instant>,
+    _permit: Permit<'a>,
+}
+
+impl SafeClient {
+    /// Open a WebSocket connection to a `ws` or `wss` URL. Neither scheme is allowed by
+    /// default: add it to `allowed_schemes`. The URL is checked like any other fetch, the
+    /// handshake sent to a pinned address that passed the IP checks, and the hooks
+    /// consulted; redirects are not followed. Fails with `UnexpectedStatus` unless the
+    /// server switches protocols.
+    ///
+    /// While the connection is open:
+    /// - a message over `max_ws_message_bytes`, either way, fails with `MessageTooLarge`
+    /// - the transfer budget caps the bytes received
+    /// - it fails with `RequestTimeout` once open for `max_ws_lifetime_ms`
+    /// - the kill switch fails every send and receive with `Disabled`
+    pub async fn websocket(&self, request: FetchRequest) -> Result<SafeWebSocket<'_>, FetchError> {
+        let policy = self.policy_for(request.profile.as_deref())?;
+        let request = FetchRequest {
+            method: "GET".into(),
+            body: None,
+            ..request
+        };
+        let key = generate_key();
+        let headers = [
+            (http::header::CONNECTION, "Upgrade".to_string()),
+            (http::header::UPGRADE, "websocket".to_string()),
+            (http::header::SEC_WEBSOCKET_VERSION, "13".to_string()),
+            (http::header::SEC_WEBSOCKET_KEY, key.clone()),
+        ];
+        let handshake = cancellable(request.cancel.as_ref(), async {
+            let handshake = self.handshake(&request, &headers).await?;
+            let response = &handshake.response;
+            if response.status() != http::StatusCode::SWITCHING_PROTOCOLS {
+                return Err(FetchError::UnexpectedStatus {
+                    status: response.status().as_u16(),
+                });
+            }
+            let accept = response
+                .headers()
+                .get(http::header::SEC_WEBSOCKET_ACCEPT)
+                .and_then(|v| v.to_str().ok());
+            if accept != Some(derive_accept_key(key.as_bytes()).as_str()) {
+                return Err(FetchError::HttpError(
+                    "WebSocket handshake failed: bad Sec-WebSocket-Accept".into(),
+                ));
+            }
+            Ok(handshake)
+        })
+        .await?;
+
+        let headers = handshake
+            .response
+            .headers()
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
+            .collect();
+        let upgraded = handshake
+            .response
+            .upgrade()
+            .await
+            .map_err(|e| FetchError::HttpError(e.to_string()))?;
+        let limit = policy.max_ws_message_bytes;
+        let config = WebSocketConfig::default()
+            .max_message_size(Some(limit))
+            .max_frame_size(Some(limit));
+        let stream = WebSocketStream::from_raw_socket(upgraded, Role::Client, Some(config)).await;
+        Ok(SafeWebSocket {
+            url: request.url,
+            headers,
+            client: self,
+            stream,
+            host: handshake.host,
+            budget: handshake.budget,
+            max_message_bytes: limit,
+            deadline: Instant::now().checked_add(Duration::from_millis(policy.max_ws_lifetime_ms)),
+            _permit: handshake.permit,
+        })
+    }
+}
+
+impl SafeWebSocket<'_> {
+    pub async fn send(&mut self, message: WebSocketMessage) -> Result<(), FetchError> {
+        self.client.ensure_enabled()?;
+        let message = match message {
+            WebSocketMessage::Text(text) => Message::text(text),
+            WebSocketMessage::Binary(data) => Message::binary(data),
+        };
+        if message.len() > self.max_message_bytes {
+            return Err(FetchError::MessageTooLarge {
+                size: message.len(),
+                limit: self.max_message_bytes,
+            });
+        }
+        until(self.deadline, self.stream.send(message))
+            .await?
+            .map_err(ws_error)
+    }
+
+    /// The next data message, or `None` once the server has closed the connection.
+    /// Pings are answered and other control frames skipped.
+    pub async fn recv(&mut self) -> Result<Option<WebSocketMessage>, FetchError> {
+        loop {
+            self.client.ensure_enabled()?;
+            let next = until(self.deadline, self.stream.next()).await?;
+            let message = match next {
+                None | Some(Err(tungstenite::Error::ConnectionClosed)) => return Ok(None),
+                Some(message) => message.map_err(ws_error)?,
+            };
+            self.budget.record(&self.host, message.len() as u64)?;
+            match message {
+                Message::Text(text) => return Ok(Some(WebSocketMessage::Text(text.to_string()))),
+                Message::Binary(data) => return Ok(Some(WebSocketMessage::Binary(data.into()))),
+                Message::Close(_) => return Ok(None),
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {}
+            }
+        }
+    }
+
+    /// Close the connection, waiting for the server to acknowledge it.
+    pub async fn close(mut self) -> Result<(), FetchError> {
+        let close = async {
+            self.stream.close(None).await?;
+            while self.stream.next().await.transpose()?.is_some() {}
+            Ok(())
+        };
+        match until(self.deadline, close).await? {
+            Ok(()) | Err(tungstenite::Error::ConnectionClosed) => Ok(()),
+            Err(e) => Err(ws_error(e)),
+        }
+    }
+}
+
+fn ws_error(e: tungstenite::Error) -> FetchError {
+    match e {
+        tungstenite::Error::Capacity(CapacityError::MessageTooLong { size, max_size }) => {
+            FetchError::MessageTooLarge {
+                size,
+                limit: max_size,
+            }
+        }
+        other => FetchError::HttpError(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::FetchPolicy;
+
+    #[test]
+    fn oversized_messages_map_to_message_too_large() {
+        let err = ws_error(tungstenite::Error::Capacity(
+            CapacityError::MessageTooLong {
+                size: 20,
+                max_size: 10,
+            },
+        ));
+        assert!(matches!(
+            err,
+            FetchError::MessageTooLarge {
+                size: 20,
+                limit: 10
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn only_ws_urls_are_opened() {
+        let client = SafeClient::new(FetchPolicy {
+            allowed_schemes: vec!["https".into(), "wss".into()],
+            ..FetchPolicy::default()
+        });
+        let open = |url: &str| {
+            client.websocket(FetchRequest {
+                url: url.into(),
+                ..Default::default()
+            })
+        };
+        assert!(matches!(
+            open("https://example.com/").await,
+            Err(FetchError::InvalidUrl(_))
+        ));
+        assert!(matches!(
+            open("ws://example.com/").await,
+            Err(FetchError::SchemeNotAllowed { .. })
+        ));
+    }
+}
@@ -1,4 +1,99 @@
-use agent_fetch::{FetchPolicy, FetchRequest, SafeClient};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use agent_fetch::approval::Approving;
+use agent_fetch::hook::Evaluating;
+use agent_fetch::{
+    Approval, ApprovalHandler, ApprovalRequest, BatchOptions, CertificatePin, ClientCertificate,
+    DomainPattern, FetchError, FetchPolicy, FetchRequest, FixedClock, HttpVersion, PolicyHook,
+    ProxyPolicy, ProxyRoute, RequestContext, RootCertificates, SafeClient, ServerRateLimit,
+    TlsVersion, Verdict, WebSocketMessage,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::client::danger::HandshakeSignatureValid;
+use tokio_rustls::rustls::pki_types::{CertificateDer, UnixTime};
+use tokio_rustls::rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use tokio_rustls::rustls::{
+    self, crypto, DigitallySignedStruct, DistinguishedName, SignatureScheme,
+};
+
+/// Minimal HTTP/1.1 server for exercising the client end to end. Every connection serves a
+/// single request: `respond` receives the raw request (head and body) and returns the raw
+/// response bytes.
+async fn spawn_server<F>(respond: F) -> SocketAddr
+where
+    F: Fn(&str) -> Vec<u8> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let respond = Arc::new(respond);
+
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut stream, _)) = listener.accept().await else {
+                return;
+            };
+            let respond = respond.clone();
+            tokio::spawn(async move {
+                let mut buf = Vec::new();
+                let mut chunk = [0u8; 4096];
+                let head_end = loop {
+                    let n = stream.read(&mut chunk).await.unwrap_or(0);
+                    if n == 0 {
+                        return;
+                    }
+                    buf.extend_from_slice(&chunk[..n]);
+                    if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break pos + 4;
+                    }
+                };
+                let head = String::from_utf8_lossy(&buf[..head_end]).to_lowercase();
+                let content_length = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length:"))
+                    .and_then(|v| v.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                while buf.len() < head_end + content_length {
+                    let n = stream.read(&mut chunk).await.unwrap_or(0);
+                    if n == 0 {
+                        break;
+                    }
+                    buf.extend_from_slice(&chunk[..n]);
+                }
+                let response = respond(&String::from_utf8_lossy(&buf));
+                let _ = stream.write_all(&response).await;
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+
+    addr
+}
+
+fn http_response(status: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let mut out = format!("HTTP/1.1 {status}\r\n");
+    for (name, value) in headers {
+        out.push_str(&format!("{name}: {value}\r\n"));
+    }
+    out.push_str(&format!(
+        "content-length: {}\r\nconnection: close\r\n\r\n",
+        body.len()
+    ));
+    let mut out = out.into_bytes();
+    out.extend_from_slice(body);
+    out
+}
+
+/// Policy that permits connecting to the loopback test server.
+fn local_policy() -> FetchPolicy {
+    FetchPolicy {
+        allowed_ip_ranges: vec!["127.0.0.1/32".parse().unwrap()],
+        ..Default::default()
+    }
+}
 
 #[tokio::test]
 async fn rejects_private_ip_direct() {
@@ -8,6 +103,7 @@ async fn rejects_private_ip_direct() {
         method: "GET".into(),
         headers: Default::default(),
         body: None,
+        ..Default::default()
     };
     let err = client.fetch(req).await.unwrap_err();
     assert!(
@@ -24,6 +120,7 @@ async fn rejects_metadata_ip() {
         method: "GET".into(),
         headers: Default::default(),
         body: None,
+        ..Default::default()
     };
     let err = client.fetch(req).await.unwrap_err();
     assert!(
@@ -44,6 +141,7 @@ async fn rejects_blocked_domain() {
         method: "GET".into(),
         headers: Default::default(),
         body: None,
+        ..Default::default()
     };
     let err = client.fetch(req).await.unwrap_err();
     assert!(err.to_string().contains("blocked"), "got: {err}");
@@ -52,7 +150,7 @@ async fn rejects_blocked_domain() {
 #[tokio::test]
 async fn rejects_domain_not_in_allowlist() {
     let policy = FetchPolicy {
-        allowed_domains: Some(vec![agent_fetch::DomainPattern("good.com".into())]),
+        allowed_domains: Some(vec![agent_fetch::DomainPattern("good.com".into()).into()]),
         ..Default::default()
     };
     let client = SafeClient::new(policy);
@@ -61,6 +159,7 @@ async fn rejects_domain_not_in_allowlist() {
         method: "GET".into(),
         headers: Default::default(),
         body: None,
+        ..Default::default()
     };
     let err = client.fetch(req).await.unwrap_err();
     assert!(err.to_string().contains("allowlist"), "got: {err}");
@@ -74,6 +173,7 @@ async fn rejects_disallowed_method() {
         method: "TRACE".into(),
         headers: Default::default(),
         body: None,
+        ..Default::default()
     };
     let err = client.fetch(req).await.unwrap_err();
     assert!(err.to_string().contains("method"), "got: {err}");
@@ -87,6 +187,7 @@ async fn rejects_ftp_scheme() {
         method: "GET".into(),
         headers: Default::default(),
         body: None,
+        ..Default::default()
     };
     let err = client.fetch(req).await.unwrap_err();
     assert!(
@@ -106,7 +207,8 @@ async fn rejects_oversized_request_body() {
         url: "https://example.com/".into(),
         method: "POST".into(),
         headers: Default::default(),
-        body: Some(vec![0u8; 200]),
+        body: Some(vec![0u8; 200].into()),
+        ..Default::default()
     };
     let err = client.fetch(req).await.unwrap_err();
     assert!(
@@ -114,3 +216,3687 @@ async fn rejects_oversized_request_body() {
         "got: {err}"
     );
 }
+
+#[tokio::test]
+async fn rejects_ip_in_blocked_range() {
+    let policy = FetchPolicy {
+        blocked_ip_ranges: vec!["93.184.216.0/24".parse().unwrap()],
+        ..Default::default()
+    };
+    let client = SafeClient::new(policy);
+    let req = FetchRequest {
+        url: "http://93.184.216.34/".into(),
+        method: "GET".into(),
+        headers: Default::default(),
+        body: None,
+        ..Default::default()
+    };
+    let err = client.fetch(req).await.unwrap_err();
+    assert!(err.to_string().contains("IP range blocked"), "got: {err}");
+}
+
+#[tokio::test]
+async fn allowed_ip_range_exempts_private_ip() {
+    let policy = FetchPolicy {
+        allowed_ip_ranges: vec!["127.0.0.1/32".parse().unwrap()],
+        connect_timeout_ms: 1_000,
+        ..Default::default()
+    };
+    let client = SafeClient::new(policy);
+    let req = FetchRequest {
+        url: "http://127.0.0.1:1/".into(),
+        method: "GET".into(),
+        headers: Default::default(),
+        body: None,
+        ..Default::default()
+    };
+    // Nothing listens on port 1, so the request gets past validation and fails to connect.
+    let err = client.fetch(req).await.unwrap_err();
+    assert!(
+        !err.to_string().contains("private IP blocked"),
+        "got: {err}"
+    );
+}
+
+#[tokio::test]
+async fn blocked_range_wins_over_allowed_range() {
+    let policy = FetchPolicy {
+        allowed_ip_ranges: vec!["10.0.0.0/8".parse().unwrap()],
+        blocked_ip_ranges: vec!["10.1.0.0/16".parse().unwrap()],
+        ..Default::default()
+    };
+    let client = SafeClient::new(policy);
+    let req = FetchRequest {
+        url: "http://10.1.2.3/".into(),
+        method: "GET".into(),
+        headers: Default::default(),
+        body: None,
+        ..Default::default()
+    };
+    let err = client.fetch(req).await.unwrap_err();
+    assert!(err.to_string().contains("IP range blocked"), "got: {err}");
+}
+
+#[tokio::test]
+async fn dns_overrides_reach_local_fixtures() {
+    let addr = spawn_server(|req| {
+        let host = req.lines().find(|l| l.to_lowercase().starts_with("host:"));
+        http_response("200 OK", &[], host.unwrap_or("").as_bytes())
+    })
+    .await;
+    let policy = FetchPolicy {
+        dns_overrides: [("fixture.test".to_string(), vec![addr.ip()])].into(),
+        ..Default::default()
+    };
+    let req = || FetchRequest {
+        url: format!("http://fixture.test:{}/", addr.port()),
+        ..Default::default()
+    };
+
+    // The override's address is checked like a looked-up one.
+    let err = SafeClient::new(policy.clone())
+        .fetch(req())
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, FetchError::PrivateIpBlocked { .. }),
+        "got: {err}"
+    );
+
+    let client = SafeClient::new(FetchPolicy {
+        trust_dns_overrides: true,
+        ..policy
+    });
+    let resp = client.fetch(req()).await.unwrap();
+    assert_eq!(resp.status, 200);
+    assert_eq!(
+        String::from_utf8_lossy(&resp.body).to_lowercase(),
+        format!("host: fixture.test:{}", addr.port())
+    );
+}
+
+#[tokio::test]
+async fn rejects_port_not_in_allowlist() {
+    let policy = FetchPolicy {
+        allowed_ports: Some(vec![80, 443]),
+        ..Default::default()
+    };
+    let client = SafeClient::new(policy);
+    let req = FetchRequest {
+        url: "https://internal.example.com:9200/".into(),
+        method: "GET".into(),
+        headers: Default::default(),
+        body: None,
+        ..Default::default()
+    };
+    let err = client.fetch(req).await.unwrap_err();
+    assert!(err.to_string().contains("port not allowed"), "got: {err}");
+}
+
+#[tokio::test]
+async fn rejects_path_outside_domain_rule() {
+    let policy = FetchPolicy {
+        domain_rules: vec![agent_fetch::DomainRule {
+            pattern: agent_fetch::DomainPattern("api.github.com".into()),
+            allowed_paths: Some(vec![agent_fetch::PathPattern("/repos/*".into())]),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let client = SafeClient::new(policy);
+    let req = FetchRequest {
+        url: "https://api.github.com/user/keys".into(),
+        method: "GET".into(),
+        headers: Default::default(),
+        body: None,
+        ..Default::default()
+    };
+    let err = client.fetch(req).await.unwrap_err();
+    assert!(err.to_string().contains("path not allowed"), "got: {err}");
+}
+
+#[tokio::test]
+async fn domain_rule_request_body_limit() {
+    let policy = FetchPolicy {
+        max_request_body_bytes: 1024,
+        domain_rules: vec![agent_fetch::DomainRule {
+            pattern: agent_fetch::DomainPattern("upload.example.com".into()),
+            max_request_body_bytes: Some(100),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let client = SafeClient::new(policy);
+    let req = FetchRequest {
+        url: "https://upload.example.com/".into(),
+        method: "POST".into(),
+        headers: Default::default(),
+        body: Some(vec![0u8; 200].into()),
+        ..Default::default()
+    };
+    let err = client.fetch(req).await.unwrap_err();
+    assert!(
+        err.to_string().contains("exceeds limit of 100 bytes"),
+        "got: {err}"
+    );
+}
+
+#[tokio::test]
+async fn rejects_blocked_url_pattern() {
+    let policy = FetchPolicy {
+        blocked_url_patterns: agent_fetch::UrlPatternSet::new([r".*/admin/.*"]).unwrap(),
+        ..Default::default()
+    };
+    let client = SafeClient::new(policy);
+    let req = FetchRequest {
+        url: "https://example.com/admin/users".into(),
+        method: "GET".into(),
+        headers: Default::default(),
+        body: None,
+        ..Default::default()
+    };
+    let err = client.fetch(req).await.unwrap_err();
+    assert!(err.to_string().contains("URL is blocked"), "got: {err}");
+}
+
+#[tokio::test]
+async fn rejects_forbidden_query_param() {
+    let policy = FetchPolicy {
+        query_params: agent_fetch::QueryParamPolicy {
+            forbid: vec!["api_key".into()],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let client = SafeClient::new(policy);
+    let req = FetchRequest {
+        url: "https://example.com/data?api_key=secret".into(),
+        method: "GET".into(),
+        headers: Default::default(),
+        body: None,
+        ..Default::default()
+    };
+    let err = client.fetch(req).await.unwrap_err();
+    assert!(
+        err.to_string().contains("query parameter not allowed"),
+        "got: {err}"
+    );
+}
+
+#[tokio::test]
+async fn rejects_overlong_url() {
+    let policy = FetchPolicy {
+        max_url_bytes: 1024,
+        ..Default::default()
+    };
+    let client = SafeClient::new(policy);
+    let req = FetchRequest {
+        url: format!("https://example.com/?q={}", "x".repeat(2048)),
+        method: "GET".into(),
+        headers: Default::default(),
+        body: None,
+        ..Default::default()
+    };
+    let err = client.fetch(req).await.unwrap_err();
+    assert!(err.to_string().contains("URL too long"), "got: {err}");
+}
+
+#[tokio::test]
+async fn rejects_mixed_script_homograph() {
+    let policy = FetchPolicy {
+        idn: agent_fetch::IdnPolicy {
+            reject_mixed_script: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let client = SafeClient::new(policy);
+    let req = FetchRequest {
+        url: "https://\u{430}pple.com/".into(),
+        method: "GET".into(),
+        headers: Default::default(),
+        body: None,
+        ..Default::default()
+    };
+    let err = client.fetch(req).await.unwrap_err();
+    assert!(err.to_string().contains("mixed-script"), "got: {err}");
+}
+
+#[tokio::test]
+async fn rejects_blocked_request_header() {
+    let policy = FetchPolicy {
+        deny_private_ips: false,
+        blocked_request_headers: vec!["X-Forwarded-For".into()],
+        ..Default::default()
+    };
+    let client = SafeClient::new(policy);
+    let req = FetchRequest {
+        url: "http://127.0.0.1:1/".into(),
+        method: "GET".into(),
+        headers: [("x-forwarded-for".to_string(), "10.0.0.1".to_string())].into(),
+        body: None,
+        ..Default::default()
+    };
+    let err = client.fetch(req).await.unwrap_err();
+    assert!(
+        err.to_string().contains("request header not allowed"),
+        "got: {err}"
+    );
+}
+
+#[tokio::test]
+async fn default_headers_are_enforced() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let log = seen.clone();
+    let addr = spawn_server(move |req| {
+        log.lock().unwrap().push(req.to_string());
+        http_response("200 OK", &[], b"ok")
+    })
+    .await;
+
+    let policy = FetchPolicy {
+        default_headers: [("User-Agent".to_string(), "my-agent/1.0".to_string())].into(),
+        ..local_policy()
+    };
+    let client = SafeClient::new(policy);
+    let req = FetchRequest {
+        url: format!("http://{addr}/"),
+        method: "GET".into(),
+        headers: [("user-agent".to_string(), "spoofed/1.0".to_string())].into(),
+        body: None,
+        ..Default::default()
+    };
+    let resp = client.fetch(req).await.unwrap();
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.body, b"ok");
+
+    let seen = seen.lock().unwrap();
+    let request = seen[0].to_lowercase();
+    assert!(
+        request.contains("user-agent: my-agent/1.0"),
+        "got: {request}"
+    );
+    assert!(!request.contains("spoofed"), "got: {request}");
+}
+
+#[tokio::test]
+async fn update_policy_applies_to_next_request() {
+    let client = SafeClient::new(FetchPolicy::default());
+    let req = || FetchRequest {
+        url: "https://evil.com/".into(),
+        method: "GET".into(),
+        headers: Default::default(),
+        body: None,
+        ..Default::default()
+    };
+
+    client.update_policy(FetchPolicy {
+        blocked_domains: vec![agent_fetch::DomainPattern("evil.com".into())],
+        ..Default::default()
+    });
+    let err = client.fetch(req()).await.unwrap_err();
+    assert!(err.to_string().contains("blocked"), "got: {err}");
+}
+
+#[tokio::test]
+async fn request_selects_policy_profile() {
+    let client = SafeClient::new(FetchPolicy::default());
+    client.add_profile(
+        "api-calls",
+        FetchPolicy {
+            allowed_domains: Some(vec![
+                agent_fetch::DomainPattern("api.example.com".into()).into()
+            ]),
+            ..Default::default()
+        },
+    );
+
+    let err = client
+        .fetch(FetchRequest {
+            url: "https://other.example.com/".into(),
+            profile: Some("api-calls".into()),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("allowlist"), "got: {err}");
+
+    let err = client
+        .fetch(FetchRequest {
+            url: "https://api.example.com/".into(),
+            profile: Some("downloads".into()),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("unknown policy profile"),
+        "got: {err}"
+    );
+}
+
+#[tokio::test]
+async fn check_validates_without_sending() {
+    let policy = FetchPolicy {
+        query_params: agent_fetch::QueryParamPolicy {
+            strip: vec!["utm_*".into()],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let client = SafeClient::new(policy);
+
+    let report = client
+        .check(
+            &FetchRequest {
+                url: "https://Example.com/a?utm_source=x&q=1".into(),
+                method: "post".into(),
+                ..Default::default()
+            },
+            false,
+        )
+        .await
+        .unwrap();
+    assert_eq!(report.url, "https://example.com/a?q=1");
+    assert_eq!(report.host, "example.com");
+    assert_eq!(report.port, 443);
+    assert_eq!(report.method, "POST");
+    assert!(report.resolved_addrs.is_none());
+    assert!(report.decisions.iter().all(|d| d.allowed));
+    assert_eq!(report.decisions[0].rule, "allowed_schemes");
+
+    let err = client
+        .check(
+            &FetchRequest {
+                url: "http://169.254.169.254/".into(),
+                ..Default::default()
+            },
+            true,
+        )
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("private IP blocked"), "got: {err}");
+}
+
+/// Hook that denies any URL containing "/secret", recording every URL it sees.
+struct DenySecret {
+    seen: Arc<Mutex<Vec<String>>>,
+}
+
+impl PolicyHook for DenySecret {
+    fn evaluate<'a>(&'a self, ctx: &'a RequestContext) -> Evaluating<'a> {
+        Box::pin(async move {
+            self.seen.lock().unwrap().push(ctx.url.clone());
+            if ctx.url.contains("/secret") {
+                Verdict::Deny(format!("{} is off limits", ctx.url))
+            } else {
+                Verdict::Allow
+            }
+        })
+    }
+}
+
+#[tokio::test]
+async fn policy_hook_runs_on_every_hop() {
+    let addr = spawn_server(|req| {
+        if req.starts_with("GET /start ") {
+            http_response("302 Found", &[("location", "/secret")], b"")
+        } else {
+            http_response("200 OK", &[], b"ok")
+        }
+    })
+    .await;
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let client = SafeClient::new(local_policy()).with_hook(DenySecret { seen: seen.clone() });
+
+    let resp = client
+        .fetch(FetchRequest {
+            url: format!("http://{addr}/public"),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(resp.body, b"ok");
+
+    let err = client
+        .fetch(FetchRequest {
+            url: format!("http://{addr}/start"),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("denied by policy hook"),
+        "got: {err}"
+    );
+    assert_eq!(seen.lock().unwrap().len(), 3);
+}
+
+/// Approval handler that answers with a fixed approval and counts how often it's asked.
+struct FixedApproval {
+    answer: Approval,
+    asked: Arc<Mutex<Vec<String>>>,
+}
+
+impl ApprovalHandler for FixedApproval {
+    fn request_approval<'a>(&'a self, request: &'a ApprovalRequest) -> Approving<'a> {
+        Box::pin(async move {
+            self.asked.lock().unwrap().push(request.host.clone());
+            self.answer
+        })
+    }
+}
+
+#[tokio::test]
+async fn approval_grants_exception_to_allowlist() {
+    let addr = spawn_server(|_| http_response("200 OK", &[], b"ok")).await;
+    let policy = FetchPolicy {
+        allowed_domains: Some(vec![agent_fetch::DomainPattern("example.com".into()).into()]),
+        ..local_policy()
+    };
+    let req = FetchRequest {
+        url: format!("http://{addr}/"),
+        ..Default::default()
+    };
+
+    let asked = Arc::new(Mutex::new(Vec::new()));
+    let client = SafeClient::new(policy.clone()).with_approval(FixedApproval {
+        answer: Approval::Deny,
+        asked: asked.clone(),
+    });
+    let err = client.fetch(req.clone()).await.unwrap_err();
+    assert!(
+        err.to_string().contains("domain not in allowlist"),
+        "got: {err}"
+    );
+    assert_eq!(asked.lock().unwrap().as_slice(), ["127.0.0.1"]);
+
+    let asked = Arc::new(Mutex::new(Vec::new()));
+    let client = SafeClient::new(policy).with_approval(FixedApproval {
+        answer: Approval::For(Duration::from_secs(60)),
+        asked: asked.clone(),
+    });
+    // A dry run never prompts.
+    assert!(client.check(&req, false).await.is_err());
+    assert_eq!(client.fetch(req.clone()).await.unwrap().status, 200);
+    assert_eq!(client.fetch(req.clone()).await.unwrap().status, 200);
+    assert!(client.check(&req, false).await.is_ok());
+    assert_eq!(asked.lock().unwrap().len(), 1);
+
+    assert!(client.revoke_approval("127.0.0.1"));
+    assert_eq!(client.fetch(req).await.unwrap().status, 200);
+    assert_eq!(asked.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn approvals_hold_per_profile() {
+    let addr = spawn_server(|_| http_response("200 OK", &[], b"ok")).await;
+    let policy = FetchPolicy {
+        allowed_domains: Some(vec![agent_fetch::DomainPattern("example.com".into()).into()]),
+        ..local_policy()
+    };
+    let asked = Arc::new(Mutex::new(Vec::new()));
+    // A grant too long to represent as an `Instant` never expires.
+    let client = SafeClient::new(policy.clone()).with_approval(FixedApproval {
+        answer: Approval::For(Duration::MAX),
+        asked: asked.clone(),
+    });
+    client.add_profile("strict", policy);
+    let req = |profile: Option<&str>| FetchRequest {
+        url: format!("http://{addr}/"),
+        profile: profile.map(str::to_string),
+        ..Default::default()
+    };
+
+    assert_eq!(client.fetch(req(None)).await.unwrap().status, 200);
+    assert!(client.check(&req(None), false).await.is_ok());
+    // The approval granted under the default policy doesn't carry over to the profile.
+    assert!(client.check(&req(Some("strict")), false).await.is_err());
+    assert_eq!(client.fetch(req(Some("strict"))).await.unwrap().status, 200);
+    assert_eq!(asked.lock().unwrap().len(), 2);
+
+    assert!(client.revoke_approval("127.0.0.1"));
+    assert!(client.check(&req(None), false).await.is_err());
+    assert!(client.check(&req(Some("strict")), false).await.is_err());
+}
+
+#[tokio::test]
+async fn time_window_uses_injected_clock() {
+    let addr = spawn_server(|_| http_response("200 OK", &[], b"ok")).await;
+    let policy = FetchPolicy {
+        domain_rules: vec![agent_fetch::DomainRule {
+            pattern: agent_fetch::DomainPattern("127.0.0.1".into()),
+            allowed_times: Some(vec![agent_fetch::TimeWindow {
+                days: Vec::new(),
+                start: agent_fetch::TimeOfDay::new(9, 0).unwrap(),
+                end: agent_fetch::TimeOfDay::new(18, 0).unwrap(),
+            }]),
+            ..Default::default()
+        }],
+        ..local_policy()
+    };
+    let req = FetchRequest {
+        url: format!("http://{addr}/"),
+        ..Default::default()
+    };
+    let midnight = std::time::UNIX_EPOCH + Duration::from_secs(1_704_067_200);
+
+    let client = SafeClient::new(policy.clone())
+        .with_clock(FixedClock(midnight + Duration::from_secs(12 * 3600)));
+    assert_eq!(client.fetch(req.clone()).await.unwrap().status, 200);
+
+    let client = SafeClient::new(policy).with_clock(FixedClock(midnight));
+    let err = client.fetch(req).await.unwrap_err();
+    assert!(
+        err.to_string().contains("outside allowed time window"),
+        "got: {err}"
+    );
+}
+
+#[tokio::test]
+async fn address_family_filter() {
+    let addr = spawn_server(|_| http_response("200 OK", &[], b"ok")).await;
+    let req = FetchRequest {
+        url: format!("http://{addr}/"),
+        ..Default::default()
+    };
+
+    let ipv4_only = FetchPolicy {
+        allowed_address_families: vec![agent_fetch::AddressFamily::Ipv4],
+        ..local_policy()
+    };
+    assert_eq!(
+        SafeClient::new(ipv4_only)
+            .fetch(req.clone())
+            .await
+            .unwrap()
+            .status,
+        200
+    );
+
+    let ipv6_only = FetchPolicy {
+        allowed_address_families: vec![agent_fetch::AddressFamily::Ipv6],
+        ..local_policy()
+    };
+    let err = SafeClient::new(ipv6_only).fetch(req).await.unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("no addresses in an allowed address family"),
+        "got: {err}"
+    );
+}
+
+#[tokio::test]
+async fn transfer_budget_stops_reads() {
+    let addr = spawn_server(|_| http_response("200 OK", &[], &[b'x'; 400])).await;
+    let req = FetchRequest {
+        url: format!("http://{addr}/"),
+        ..Default::default()
+    };
+    let client = SafeClient::new(FetchPolicy {
+        max_total_bytes: Some(1000),
+        ..local_policy()
+    });
+
+    client.fetch(req.clone()).await.unwrap();
+    client.fetch(req.clone()).await.unwrap();
+    assert_eq!(client.bytes_transferred(), 800);
+
+    // The third body would overrun the budget; its Content-Length stops it unread.
+    let err = client.fetch(req).await.unwrap_err();
+    assert!(
+        matches!(
+            err,
+            agent_fetch::FetchError::BudgetExceeded { limit: 1000, .. }
+        ),
+        "got: {err}"
+    );
+    assert_eq!(client.bytes_transferred(), 800);
+}
+
+#[tokio::test]
+async fn redirect_scope_rejects_other_hosts() {
+    let addr =
+        spawn_server(|_| http_response("302 Found", &[("location", "http://localhost/")], b""))
+            .await;
+    let client = SafeClient::new(FetchPolicy {
+        redirect_scope: agent_fetch::RedirectScope::SameHost,
+        ..local_policy()
+    });
+
+    let err = client
+        .fetch(FetchRequest {
+            url: format!("http://{addr}/"),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, agent_fetch::FetchError::CrossOriginRedirect { ref to, .. } if to == "localhost"),
+        "got: {err}"
+    );
+}
+
+#[tokio::test]
+async fn upgrades_insecure_requests() {
+    let client = SafeClient::new(FetchPolicy {
+        allowed_schemes: vec!["https".into()],
+        upgrade_insecure_requests: true,
+        ..Default::default()
+    });
+    let report = client
+        .check(
+            &FetchRequest {
+                url: "http://example.com/page".into(),
+                ..Default::default()
+            },
+            false,
+        )
+        .await
+        .unwrap();
+    assert_eq!(report.url, "https://example.com/page");
+    assert_eq!(report.port, 443);
+
+    // Explicit ports are left alone, and plain http is still not an allowed scheme.
+    let err = client
+        .check(
+            &FetchRequest {
+                url: "http://example.com:8080/".into(),
+                ..Default::default()
+            },
+            false,
+        )
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, agent_fetch::FetchError::SchemeNotAllowed { .. }),
+        "got: {err}"
+    );
+}
+
+#[tokio::test]
+async fn kill_switch_halts_fetches() {
+    let addr = spawn_server(|_| http_response("200 OK", &[], b"ok")).await;
+    let req = FetchRequest {
+        url: format!("http://{addr}/"),
+        ..Default::default()
+    };
+    let client = SafeClient::new(local_policy());
+
+    client.disable();
+    assert!(!client.is_enabled());
+    let err = client.fetch(req.clone()).await.unwrap_err();
+    assert!(
+        matches!(err, agent_fetch::FetchError::Disabled),
+        "got: {err}"
+    );
+
+    client.enable();
+    assert_eq!(client.fetch(req).await.unwrap().status, 200);
+}
+
+#[tokio::test]
+async fn fetch_stream_yields_chunks_under_the_limit() {
+    use futures_util::StreamExt;
+
+    let addr = spawn_server(|req| {
+        let body = if req.starts_with("GET /big") {
+            vec![b'x'; 3000]
+        } else {
+            b"{\"a\":1}\n{\"a\":2}\n".to_vec()
+        };
+        // No Content-Length, so only the incremental check can catch an oversized body.
+        let mut out = b"HTTP/1.1 200 OK\r\nconnection: close\r\n\r\n".to_vec();
+        out.extend_from_slice(&body);
+        out
+    })
+    .await;
+    let client = SafeClient::new(FetchPolicy {
+        max_response_body_bytes: 1000,
+        ..local_policy()
+    });
+
+    let response = client
+        .fetch_stream(FetchRequest {
+            url: format!("http://{addr}/events"),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(response.status, 200);
+    let mut body = Vec::new();
+    let mut chunks = response.body;
+    while let Some(chunk) = chunks.next().await {
+        body.extend_from_slice(&chunk.unwrap());
+    }
+    assert_eq!(body, b"{\"a\":1}\n{\"a\":2}\n");
+
+    let response = client
+        .fetch_stream(FetchRequest {
+            url: format!("http://{addr}/big"),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let results: Vec<_> = response.body.collect().await;
+    assert!(
+        matches!(
+            results.last(),
+            Some(Err(agent_fetch::FetchError::ResponseBodyTooLarge {
+                limit: 1000,
+                ..
+            }))
+        ),
+        "got: {results:?}"
+    );
+}
+
+#[tokio::test]
+async fn streams_request_bodies_under_the_limit() {
+    // Reads a chunked upload to the end, then reports how many bytes arrived.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = Vec::new();
+                let mut chunk = [0u8; 4096];
+                while !buf.ends_with(b"\r\n0\r\n\r\n") {
+                    match stream.read(&mut chunk).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                    }
+                }
+                let received = buf.iter().filter(|&&b| b == b'x').count().to_string();
+                let _ = stream
+                    .write_all(&http_response("200 OK", &[], received.as_bytes()))
+                    .await;
+            });
+        }
+    });
+    let client = SafeClient::new(FetchPolicy {
+        max_request_body_bytes: 100_000,
+        ..local_policy()
+    });
+    let upload = |size: usize| FetchRequest {
+        url: format!("http://{addr}/upload"),
+        method: "POST".into(),
+        body: Some(agent_fetch::FetchBody::from_stream(
+            futures_util::stream::iter(
+                (0..size / 1000).map(|_| Ok(bytes::Bytes::from(vec![b'x'; 1000]))),
+            ),
+        )),
+        ..Default::default()
+    };
+
+    let response = client.fetch(upload(50_000)).await.unwrap();
+    assert_eq!(response.body, b"50000");
+
+    let err = client.fetch(upload(200_000)).await.unwrap_err();
+    assert!(
+        matches!(
+            err,
+            agent_fetch::FetchError::RequestBodyTooLarge { limit: 100_000, .. }
+        ),
+        "got: {err}"
+    );
+}
+
+/// Keep-alive server answering every request with `ok`, and the count of connections it
+/// has accepted.
+async fn spawn_keep_alive_server() -> (SocketAddr, Arc<Mutex<usize>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = Arc::new(Mutex::new(0));
+    let counter = connections.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            *counter.lock().unwrap() += 1;
+            tokio::spawn(async move {
+                let mut buf = Vec::new();
+                let mut chunk = [0u8; 4096];
+                loop {
+                    while let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                        let head = buf.starts_with(b"HEAD ");
+                        buf.drain(..pos + 4);
+                        let response = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok";
+                        let response = if head {
+                            &response[..response.len() - 2]
+                        } else {
+                            response
+                        };
+                        if stream.write_all(response).await.is_err() {
+                            return;
+                        }
+                    }
+                    match stream.read(&mut chunk).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                    }
+                }
+            });
+        }
+    });
+    (addr, connections)
+}
+
+#[tokio::test]
+async fn reuses_connections_across_requests() {
+    let (addr, connections) = spawn_keep_alive_server().await;
+
+    let client = SafeClient::new(local_policy());
+    for _ in 0..3 {
+        let response = client
+            .fetch(FetchRequest {
+                url: format!("http://{addr}/"),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.body, b"ok");
+    }
+    assert_eq!(*connections.lock().unwrap(), 1);
+}
+
+#[tokio::test]
+async fn clients_can_share_a_rate_limiter() {
+    let (addr, _) = spawn_keep_alive_server().await;
+    let first = SafeClient::new(FetchPolicy {
+        max_requests_per_minute: 2,
+        ..local_policy()
+    });
+    // The second client's own limit is ignored in favour of the shared one.
+    let second = SafeClient::new(local_policy()).with_rate_limiter(first.rate_limiter());
+    let get = || FetchRequest {
+        url: format!("http://{addr}/"),
+        ..Default::default()
+    };
+
+    first.fetch(get()).await.unwrap();
+    second.fetch(get()).await.unwrap();
+    assert!(matches!(
+        second.fetch(get()).await,
+        Err(agent_fetch::FetchError::RateLimitExceeded)
+    ));
+    second.update_policy(local_policy());
+    assert!(matches!(
+        first.fetch(get()).await,
+        Err(agent_fetch::FetchError::RateLimitExceeded)
+    ));
+}
+
+#[tokio::test]
+async fn preconnect_connects_without_sending_a_request() {
+    // Counts connections and the bytes sent over them.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let seen = Arc::new(Mutex::new((0, 0)));
+    let log = seen.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            log.lock().unwrap().0 += 1;
+            let log = log.clone();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4096];
+                while let Ok(n @ 1..) = stream.read(&mut buf).await {
+                    log.lock().unwrap().1 += n;
+                }
+            });
+        }
+    });
+
+    // No method needs to be allowed, since none is sent.
+    let client = SafeClient::new(FetchPolicy {
+        allowed_methods: Vec::new(),
+        ..local_policy()
+    });
+    client.preconnect(&format!("http://{addr}/")).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(*seen.lock().unwrap(), (1, 0));
+
+    assert!(matches!(
+        SafeClient::new(FetchPolicy::default())
+            .preconnect(&format!("http://{addr}/"))
+            .await,
+        Err(FetchError::PrivateIpBlocked { .. })
+    ));
+    assert_eq!(seen.lock().unwrap().0, 1);
+}
+
+#[tokio::test]
+async fn old_connections_are_revalidated_before_reuse() {
+    let (addr, connections) = spawn_keep_alive_server().await;
+    let client = SafeClient::new(FetchPolicy {
+        connection_revalidate_ms: Some(300),
+        ..local_policy()
+    });
+    let fetch = || {
+        client.fetch(FetchRequest {
+            url: format!("http://{addr}/"),
+            ..Default::default()
+        })
+    };
+    fetch().await.unwrap();
+    assert!(fetch().await.unwrap().timings.connect.is_none());
+    assert_eq!(*connections.lock().unwrap(), 1);
+
+    // The peer still checks out, so its connection is kept.
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert!(fetch().await.unwrap().timings.connect.is_none());
+    assert_eq!(*connections.lock().unwrap(), 1);
+}
+
+#[tokio::test]
+async fn redirects_forward_headers_but_strip_credentials_across_origins() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let log = seen.clone();
+    let other = spawn_server(move |req| {
+        log.lock().unwrap().push(req.to_lowercase());
+        http_response("200 OK", &[], b"ok")
+    })
+    .await;
+    let log = seen.clone();
+    let origin = spawn_server(move |req| {
+        log.lock().unwrap().push(req.to_lowercase());
+        if req.starts_with("GET /start ") {
+            http_response("302 Found", &[("location", "/same")], b"")
+        } else {
+            let target = format!("http://{other}/end");
+            http_response("302 Found", &[("location", &target)], b"")
+        }
+    })
+    .await;
+
+    let client = SafeClient::new(local_policy());
+    let response = client
+        .fetch(FetchRequest {
+            url: format!("http://{origin}/start"),
+            headers: [
+                ("Authorization", "Bearer secret"),
+                ("Cookie", "session=1"),
+                ("X-Trace", "abc"),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(response.body, b"ok");
+
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 3);
+    // Same-origin hop keeps everything.
+    assert!(seen[1].starts_with("get /same "));
+    assert!(seen[1].contains("authorization: bearer secret"));
+    assert!(seen[1].contains("x-trace: abc"));
+    // Cross-origin hop drops credentials but keeps other headers.
+    assert!(seen[2].starts_with("get /end "));
+    assert!(!seen[2].contains("authorization"));
+    assert!(!seen[2].contains("cookie"));
+    assert!(seen[2].contains("x-trace: abc"));
+}
+
+#[tokio::test]
+async fn manual_redirect_mode_returns_the_redirect() {
+    let addr = spawn_server(|req| {
+        if req.starts_with("GET /login ") {
+            http_response("302 Found", &[("location", "/callback?code=1")], b"")
+        } else {
+            http_response("200 OK", &[], b"followed")
+        }
+    })
+    .await;
+    let client = SafeClient::new(local_policy());
+    let login = FetchRequest {
+        url: format!("http://{addr}/login"),
+        ..Default::default()
+    };
+
+    let response = client
+        .fetch(FetchRequest {
+            redirect: Some(agent_fetch::RedirectMode::Manual),
+            ..login.clone()
+        })
+        .await
+        .unwrap();
+    assert_eq!(response.status, 302);
+    assert_eq!(response.headers["location"], "/callback?code=1");
+
+    assert_eq!(client.fetch(login).await.unwrap().body, b"followed");
+}
+
+#[tokio::test]
+async fn response_reports_final_url_and_redirect_chain() {
+    let addr = spawn_server(|req| {
+        if req.starts_with("GET /a ") {
+            http_response("302 Found", &[("location", "/b")], b"")
+        } else if req.starts_with("GET /b ") {
+            http_response("301 Moved Permanently", &[("location", "/c")], b"")
+        } else {
+            http_response("200 OK", &[], b"ok")
+        }
+    })
+    .await;
+    let client = SafeClient::new(local_policy());
+
+    let response = client
+        .fetch(FetchRequest {
+            url: format!("http://{addr}/a"),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(response.url, format!("http://{addr}/c"));
+    assert_eq!(
+        response.redirects,
+        vec![
+            agent_fetch::RedirectHop {
+                url: format!("http://{addr}/a"),
+                status: 302
+            },
+            agent_fetch::RedirectHop {
+                url: format!("http://{addr}/b"),
+                status: 301
+            },
+        ]
+    );
+    assert_eq!(response.version, http::Version::HTTP_11);
+}
+
+#[tokio::test]
+async fn cancelling_a_fetch_releases_its_slot() {
+    // Sends the headers and part of the body, then stalls.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stalled = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 1000\r\n\r\npartial")
+                    .await;
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            });
+        }
+    });
+    let addr = spawn_server(|_| http_response("200 OK", &[], b"ok")).await;
+    let client = SafeClient::new(FetchPolicy {
+        max_concurrent_requests: 1,
+        ..local_policy()
+    });
+
+    let cancel = tokio_util::sync::CancellationToken::new();
+    let trigger = cancel.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        trigger.cancel();
+    });
+    let err = client
+        .fetch(FetchRequest {
+            url: format!("http://{stalled}/"),
+            cancel: Some(cancel),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, agent_fetch::FetchError::Cancelled),
+        "got: {err}"
+    );
+
+    let response = tokio::time::timeout(
+        Duration::from_secs(2),
+        client.fetch(FetchRequest {
+            url: format!("http://{addr}/"),
+            ..Default::default()
+        }),
+    )
+    .await
+    .expect("slot was not released")
+    .unwrap();
+    assert_eq!(response.body, b"ok");
+}
+
+#[tokio::test]
+async fn per_request_limits_only_tighten_the_policy() {
+    // Answers after a delay.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let _ = stream
+                    .write_all(&http_response("200 OK", &[], &[b'x'; 100]))
+                    .await;
+            });
+        }
+    });
+    let client = SafeClient::new(FetchPolicy {
+        max_response_body_bytes: 50,
+        ..local_policy()
+    });
+    let request = FetchRequest {
+        url: format!("http://{addr}/"),
+        ..Default::default()
+    };
+
+    let err = client
+        .fetch(FetchRequest {
+            request_timeout_ms: Some(100),
+            ..request.clone()
+        })
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, agent_fetch::FetchError::RequestTimeout),
+        "got: {err}"
+    );
+
+    let err = client
+        .fetch(FetchRequest {
+            max_response_body_bytes: Some(10),
+            ..request.clone()
+        })
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            agent_fetch::FetchError::ResponseBodyTooLarge { limit: 10, .. }
+        ),
+        "got: {err}"
+    );
+
+    // A value above the policy's is clamped, not honored.
+    let err = client
+        .fetch(FetchRequest {
+            max_response_body_bytes: Some(1000),
+            ..request
+        })
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            agent_fetch::FetchError::ResponseBodyTooLarge { limit: 50, .. }
+        ),
+        "got: {err}"
+    );
+}
+
+#[tokio::test]
+async fn decompresses_responses_under_a_decoded_size_limit() {
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    let text = b"hello world ".repeat(100);
+    let encoded_text = gzip(&text);
+    let bomb = gzip(&vec![0; 10 * 1024 * 1024]);
+    let addr = spawn_server(move |req| {
+        let req = req.to_lowercase();
+        let body = if req.starts_with("get /bomb ") {
+            &bomb
+        } else {
+            &encoded_text
+        };
+        let accepted = req.contains("accept-encoding: gzip, deflate, br");
+        http_response(
+            "200 OK",
+            &[
+                ("content-encoding", "gzip"),
+                ("x-accepted", if accepted { "yes" } else { "no" }),
+            ],
+            body,
+        )
+    })
+    .await;
+    let client = SafeClient::new(FetchPolicy {
+        decompress_responses: true,
+        max_decompressed_body_bytes: 64 * 1024,
+        ..local_policy()
+    });
+    let response = client
+        .fetch(FetchRequest {
+            url: format!("http://{addr}/text"),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(response.body, text);
+    assert_eq!(response.headers["x-accepted"], "yes");
+    assert!(!response.headers.contains_key("content-encoding"));
+    assert!(!response.headers.contains_key("content-length"));
+
+    let err = client
+        .fetch(FetchRequest {
+            url: format!("http://{addr}/bomb"),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            agent_fetch::FetchError::DecompressedBodyTooLarge { limit: 65536, .. }
+        ),
+        "got: {err}"
+    );
+
+    // Off by default.
+    let client = SafeClient::new(local_policy());
+    let response = client
+        .fetch(FetchRequest {
+            url: format!("http://{addr}/text"),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(response.body, gzip(&text));
+    assert_eq!(response.headers["x-accepted"], "no");
+    assert_eq!(response.headers["content-encoding"], "gzip");
+}
+
+#[tokio::test]
+async fn fetch_json_and_text_check_status_and_content_type() {
+    #[derive(Debug, serde::Deserialize)]
+    struct Item {
+        id: u32,
+    }
+
+    let addr = spawn_server(|req| {
+        if req.starts_with("GET /item ") {
+            http_response(
+                "200 OK",
+                &[("content-type", "application/json; charset=utf-8")],
+                br#"{"id": 7}"#,
+            )
+        } else if req.starts_with("GET /latin1 ") {
+            http_response(
+                "200 OK",
+                &[("content-type", "text/plain; charset=iso-8859-1")],
+                b"caf\xe9",
+            )
+        } else if req.starts_with("GET /image ") {
+            http_response("200 OK", &[("content-type", "image/png")], b"\x89PNG")
+        } else {
+            http_response(
+                "404 Not Found",
+                &[("content-type", "application/json")],
+                br#"{"error": "not found"}"#,
+            )
+        }
+    })
+    .await;
+    let client = SafeClient::new(local_policy());
+    let request = |path: &str| FetchRequest {
+        url: format!("http://{addr}{path}"),
+        ..Default::default()
+    };
+
+    let item: Item = client.fetch_json(request("/item")).await.unwrap();
+    assert_eq!(item.id, 7);
+    assert_eq!(client.fetch_text(request("/latin1")).await.unwrap(), "café");
+
+    let err = client
+        .fetch_json::<Item>(request("/latin1"))
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, agent_fetch::FetchError::UnexpectedContentType { .. }),
+        "got: {err}"
+    );
+    let err = client.fetch_text(request("/image")).await.unwrap_err();
+    assert!(
+        matches!(err, agent_fetch::FetchError::UnexpectedContentType { .. }),
+        "got: {err}"
+    );
+    let err = client
+        .fetch_json::<Item>(request("/missing"))
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            agent_fetch::FetchError::UnexpectedStatus { status: 404 }
+        ),
+        "got: {err}"
+    );
+    let err = client
+        .fetch_json::<Vec<Item>>(request("/item"))
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, agent_fetch::FetchError::InvalidJson(_)),
+        "got: {err}"
+    );
+}
+
+#[tokio::test]
+async fn multipart_forms_are_sent_under_the_body_limit() {
+    use agent_fetch::{Multipart, Part};
+
+    let addr = spawn_server(|req| {
+        let (head, body) = req.split_once("\r\n\r\n").unwrap();
+        let boundary = head
+            .lines()
+            .find_map(|l| {
+                l.to_lowercase()
+                    .split_once("multipart/form-data; boundary=")
+                    .map(|(_, b)| b.to_string())
+            })
+            .unwrap_or_default();
+        let files = body.matches("filename=").count();
+        let ok = body.ends_with(&format!("--{boundary}--\r\n")) && !boundary.is_empty();
+        http_response("200 OK", &[], format!("{ok} {files}").as_bytes())
+    })
+    .await;
+    let client = SafeClient::new(FetchPolicy {
+        max_request_body_bytes: 1000,
+        ..local_policy()
+    });
+    let request = FetchRequest {
+        url: format!("http://{addr}/upload"),
+        method: "POST".into(),
+        ..Default::default()
+    };
+
+    let response = client
+        .fetch(
+            request.clone().multipart(
+                Multipart::new()
+                    .text("title", "report")
+                    .file("a", "a.txt", "text/plain", "hello")
+                    .unwrap()
+                    .part("b", Part::bytes(vec![1, 2, 3]).file_name("b.bin")),
+            ),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.body, b"true 2");
+
+    let err = client
+        .fetch(
+            request.multipart(
+                Multipart::new()
+                    .file(
+                        "big",
+                        "big.bin",
+                        "application/octet-stream",
+                        vec![0u8; 1000],
+                    )
+                    .unwrap(),
+            ),
+        )
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            agent_fetch::FetchError::RequestBodyTooLarge { limit: 1000, .. }
+        ),
+        "got: {err}"
+    );
+}
+
+#[tokio::test]
+async fn file_uploads_stream_from_disk_under_the_body_limit() {
+    let addr = spawn_server(|req| {
+        let (head, body) = req.split_once("\r\n\r\n").unwrap();
+        let sized = head.to_lowercase().contains("content-length: 200000");
+        http_response("200 OK", &[], format!("{sized} {}", body.len()).as_bytes())
+    })
+    .await;
+    let client = SafeClient::new(FetchPolicy {
+        max_request_body_bytes: 300_000,
+        ..local_policy()
+    });
+    let dir = std::env::temp_dir().join(format!("agent-fetch-upload-{}", std::process::id()));
+    tokio::fs::create_dir_all(&dir).await.unwrap();
+    let small = dir.join("small.bin");
+    let big = dir.join("big.bin");
+    tokio::fs::write(&small, vec![b'x'; 200_000]).await.unwrap();
+    tokio::fs::write(&big, vec![b'x'; 400_000]).await.unwrap();
+    let upload = |body| FetchRequest {
+        url: format!("http://{addr}/upload"),
+        method: "PUT".into(),
+        body: Some(body),
+        ..Default::default()
+    };
+
+    let sent = Arc::new(AtomicU64::new(0));
+    let progress = sent.clone();
+    let body = agent_fetch::FetchBody::from_file(&small)
+        .await
+        .unwrap()
+        .with_progress(move |sent, _| progress.store(sent, Ordering::SeqCst));
+    let response = client.fetch(upload(body)).await.unwrap();
+    assert_eq!(response.text(), "true 200000");
+    assert_eq!(sent.load(Ordering::SeqCst), 200_000);
+
+    // Rejected from the file size, before anything is read.
+    let sent = Arc::new(AtomicU64::new(0));
+    let progress = sent.clone();
+    let body = agent_fetch::FetchBody::from_file(&big)
+        .await
+        .unwrap()
+        .with_progress(move |sent, _| progress.store(sent, Ordering::SeqCst));
+    let err = client.fetch(upload(body)).await.unwrap_err();
+    assert!(
+        matches!(
+            err,
+            agent_fetch::FetchError::RequestBodyTooLarge {
+                size: 400_000,
+                limit: 300_000
+            }
+        ),
+        "got: {err}"
+    );
+    assert_eq!(sent.load(Ordering::SeqCst), 0);
+    tokio::fs::remove_dir_all(&dir).await.unwrap();
+}
+
+#[tokio::test]
+async fn head_preflight_rejects_before_downloading() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let log = seen.clone();
+    let addr = spawn_server(move |req| {
+        let line = req.lines().next().unwrap_or_default();
+        log.lock().unwrap().push(line.to_string());
+        let head = |headers: &str| {
+            format!("HTTP/1.1 200 OK\r\n{headers}connection: close\r\n\r\n").into_bytes()
+        };
+        match line {
+            "HEAD /big HTTP/1.1" => head("content-type: text/plain\r\ncontent-length: 1000000\r\n"),
+            "HEAD /exe HTTP/1.1" => {
+                head("content-type: application/x-msdownload\r\ncontent-length: 10\r\n")
+            }
+            "HEAD /moved HTTP/1.1" => http_response("302 Found", &[("location", "/big")], b""),
+            l if l.starts_with("HEAD /nohead ") => {
+                http_response("405 Method Not Allowed", &[], b"")
+            }
+            l if l.starts_with("HEAD ") => {
+                head("content-type: text/plain\r\ncontent-length: 5\r\n")
+            }
+            "GET /exe HTTP/1.1" => http_response(
+                "200 OK",
+                &[("content-type", "application/x-msdownload")],
+                b"MZ",
+            ),
+            _ => http_response("200 OK", &[("content-type", "text/plain")], b"hello"),
+        }
+    })
+    .await;
+    let policy = FetchPolicy {
+        max_response_body_bytes: 1000,
+        allowed_content_types: Some(vec!["text/*".into()]),
+        preflight_head: true,
+        ..local_policy()
+    };
+    let client = SafeClient::new(policy.clone());
+    let get = |path: &str| FetchRequest {
+        url: format!("http://{addr}{path}"),
+        ..Default::default()
+    };
+
+    for path in ["/ok", "/nohead"] {
+        assert_eq!(client.fetch(get(path)).await.unwrap().body, b"hello");
+    }
+    for path in ["/big", "/moved"] {
+        let err = client.fetch(get(path)).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                agent_fetch::FetchError::ResponseBodyTooLarge {
+                    size: 1_000_000,
+                    limit: 1000
+                }
+            ),
+            "got: {err}"
+        );
+    }
+    let err = client.fetch(get("/exe")).await.unwrap_err();
+    assert!(
+        matches!(err, agent_fetch::FetchError::ContentTypeNotAllowed { ref content_type, .. } if content_type == "application/x-msdownload"),
+        "got: {err}"
+    );
+    assert_eq!(
+        *seen.lock().unwrap(),
+        [
+            "HEAD /ok HTTP/1.1",
+            "GET /ok HTTP/1.1",
+            "HEAD /nohead HTTP/1.1",
+            "GET /nohead HTTP/1.1",
+            "HEAD /big HTTP/1.1",
+            "HEAD /moved HTTP/1.1",
+            "HEAD /big HTTP/1.1",
+            "HEAD /exe HTTP/1.1",
+        ]
+    );
+
+    // Without the preflight, the content type is still enforced once the GET's headers arrive.
+    let client = SafeClient::new(FetchPolicy {
+        preflight_head: false,
+        ..policy
+    });
+    let err = client.fetch(get("/exe")).await.unwrap_err();
+    assert!(
+        matches!(err, agent_fetch::FetchError::ContentTypeNotAllowed { .. }),
+        "got: {err}"
+    );
+}
+
+#[tokio::test]
+async fn conditional_fetches_revalidate_with_stored_validators() {
+    use agent_fetch::ConditionalResponse;
+
+    let addr = spawn_server(|req| {
+        let head = req.to_lowercase();
+        if head.contains("if-none-match: \"v2\"") {
+            http_response("304 Not Modified", &[("etag", "\"v2\"")], b"")
+        } else if head.contains("if-none-match: \"v1\"")
+            && head.contains("if-modified-since: mon, 01 jan 2024 00:00:00 gmt")
+        {
+            http_response("200 OK", &[("etag", "\"v2\"")], b"second")
+        } else {
+            http_response(
+                "200 OK",
+                &[
+                    ("etag", "\"v1\""),
+                    ("last-modified", "Mon, 01 Jan 2024 00:00:00 GMT"),
+                ],
+                b"first",
+            )
+        }
+    })
+    .await;
+    let client = SafeClient::new(local_policy());
+    let request = FetchRequest {
+        url: format!("http://{addr}/feed"),
+        ..Default::default()
+    };
+
+    let first = client.fetch_conditional(request.clone()).await.unwrap();
+    assert!(first.is_modified());
+    assert_eq!(first.response().body, b"first");
+    let second = client.fetch_conditional(request.clone()).await.unwrap();
+    assert_eq!(second.into_response().body, b"second");
+    match client.fetch_conditional(request.clone()).await.unwrap() {
+        ConditionalResponse::NotModified(response) => {
+            assert_eq!(response.status, 304);
+            assert!(response.body.is_empty());
+        }
+        ConditionalResponse::Modified(_) => panic!("expected 304"),
+    }
+
+    // A fresh client has no validators, so the resource is fetched whole.
+    let fresh = SafeClient::new(local_policy());
+    let response = fresh.fetch_conditional(request).await.unwrap();
+    assert_eq!(response.response().body, b"first");
+}
+
+/// Unbounded `CacheStore` for tests.
+#[derive(Default)]
+struct TestCache(Mutex<std::collections::HashMap<String, agent_fetch::CachedResponse>>);
+
+impl agent_fetch::CacheStore for TestCache {
+    fn get(&self, key: &str) -> Option<agent_fetch::CachedResponse> {
+        self.0.lock().unwrap().get(key).cloned()
+    }
+
+    fn put(&self, key: &str, response: agent_fetch::CachedResponse) {
+        self.0.lock().unwrap().insert(key.to_string(), response);
+    }
+
+    fn remove(&self, key: &str) {
+        self.0.lock().unwrap().remove(key);
+    }
+}
+
+#[tokio::test]
+async fn cached_responses_skip_the_network_but_not_the_policy() {
+    let hits = Arc::new(Mutex::new(Vec::new()));
+    let log = hits.clone();
+    let addr = spawn_server(move |req| {
+        let line = req.lines().next().unwrap_or_default().to_string();
+        let revalidating = req.to_lowercase().contains("if-none-match: \"v1\"");
+        log.lock().unwrap().push(format!("{line} {revalidating}"));
+        if line.starts_with("GET /fresh ") {
+            http_response(
+                "200 OK",
+                &[
+                    ("cache-control", "max-age=60"),
+                    ("content-type", "text/plain"),
+                ],
+                b"fresh",
+            )
+        } else if revalidating {
+            http_response("304 Not Modified", &[("etag", "\"v1\"")], b"")
+        } else {
+            http_response(
+                "200 OK",
+                &[("cache-control", "no-cache"), ("etag", "\"v1\"")],
+                b"validated",
+            )
+        }
+    })
+    .await;
+    let client = SafeClient::new(FetchPolicy {
+        max_requests_per_minute: 3,
+        ..local_policy()
+    })
+    .with_cache(TestCache::default());
+    let get = |path: &str| FetchRequest {
+        url: format!("http://{addr}{path}"),
+        ..Default::default()
+    };
+
+    // Cache hits don't use up the rate limit.
+    for _ in 0..5 {
+        let response = client.fetch(get("/fresh")).await.unwrap();
+        assert_eq!((response.status, response.text()), (200, "fresh".into()));
+    }
+    for _ in 0..2 {
+        let response = client.fetch(get("/etag")).await.unwrap();
+        assert_eq!(
+            (response.status, response.text()),
+            (200, "validated".into())
+        );
+    }
+    assert_eq!(
+        *hits.lock().unwrap(),
+        [
+            "GET /fresh HTTP/1.1 false",
+            "GET /etag HTTP/1.1 false",
+            "GET /etag HTTP/1.1 true",
+        ]
+    );
+
+    client.disable();
+    assert!(matches!(
+        client.fetch(get("/fresh")).await,
+        Err(agent_fetch::FetchError::Disabled)
+    ));
+    client.enable();
+    client.update_policy(FetchPolicy {
+        allowed_content_types: Some(vec!["application/json".into()]),
+        ..local_policy()
+    });
+    assert!(matches!(
+        client.fetch(get("/fresh")).await,
+        Err(agent_fetch::FetchError::ContentTypeNotAllowed { .. })
+    ));
+}
+
+#[tokio::test]
+async fn cache_entries_are_kept_per_profile() {
+    let hits = Arc::new(AtomicU64::new(0));
+    let count = hits.clone();
+    let addr = spawn_server(move |_| {
+        count.fetch_add(1, Ordering::SeqCst);
+        http_response("200 OK", &[("cache-control", "max-age=60")], b"ok")
+    })
+    .await;
+    let client = SafeClient::new(local_policy()).with_cache(TestCache::default());
+    client.add_profile("other", local_policy());
+    let request = |method: &str, profile: Option<&str>| FetchRequest {
+        url: format!("http://{addr}/"),
+        method: method.into(),
+        profile: profile.map(Into::into),
+        ..Default::default()
+    };
+
+    for profile in [None, None, Some("other"), Some("other")] {
+        client.fetch(request("GET", profile)).await.unwrap();
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+    // An unsafe request invalidates the URL under every profile.
+    client.fetch(request("POST", Some("other"))).await.unwrap();
+    client.fetch(request("GET", None)).await.unwrap();
+    assert_eq!(hits.load(Ordering::SeqCst), 4);
+}
+
+#[tokio::test]
+async fn memory_cache_serves_repeat_fetches() {
+    let hits = Arc::new(AtomicU64::new(0));
+    let count = hits.clone();
+    let addr = spawn_server(move |_| {
+        count.fetch_add(1, Ordering::SeqCst);
+        http_response("200 OK", &[("cache-control", "max-age=60")], b"ok")
+    })
+    .await;
+    let client = SafeClient::new(local_policy()).with_memory_cache();
+    for _ in 0..3 {
+        let response = client
+            .fetch(FetchRequest {
+                url: format!("http://{addr}/"),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.body, b"ok");
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn ranged_requests_bypass_the_cache() {
+    let hits = Arc::new(AtomicU64::new(0));
+    let count = hits.clone();
+    let addr = spawn_server(move |req| {
+        count.fetch_add(1, Ordering::SeqCst);
+        if req.to_ascii_lowercase().contains("\r\nrange: bytes=1-") {
+            http_response(
+                "206 Partial Content",
+                &[("content-range", "bytes 1-1/2")],
+                b"k",
+            )
+        } else {
+            http_response("200 OK", &[("cache-control", "max-age=60")], b"ok")
+        }
+    })
+    .await;
+    let client = SafeClient::new(local_policy()).with_memory_cache();
+    let get = |range: Option<&str>| FetchRequest {
+        url: format!("http://{addr}/"),
+        headers: range
+            .map(|r| [("Range".to_string(), r.to_string())].into())
+            .unwrap_or_default(),
+        ..Default::default()
+    };
+
+    assert_eq!(client.fetch(get(None)).await.unwrap().body, b"ok");
+    let partial = client.fetch(get(Some("bytes=1-"))).await.unwrap();
+    assert_eq!((partial.status, &partial.body[..]), (206, &b"k"[..]));
+    // The full response is still cached.
+    assert_eq!(client.fetch(get(None)).await.unwrap().body, b"ok");
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn concurrent_identical_gets_share_one_request() {
+    // Answers after a delay, so concurrent fetches overlap.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let hits = Arc::new(AtomicU64::new(0));
+    let count = hits.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            count.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                let _ = stream
+                    .write_all(&http_response("200 OK", &[], b"shared"))
+                    .await;
+            });
+        }
+    });
+    let get = || FetchRequest {
+        url: format!("http://{addr}/"),
+        ..Default::default()
+    };
+    let mut other_headers = get();
+    other_headers
+        .headers
+        .insert("Accept".into(), "text/html".into());
+
+    let client = SafeClient::new(FetchPolicy {
+        coalesce_requests: true,
+        ..local_policy()
+    });
+    let (a, b, c, d) = tokio::join!(
+        client.fetch(get()),
+        client.fetch(get()),
+        client.fetch(get()),
+        client.fetch(other_headers),
+    );
+    for response in [a, b, c, d] {
+        assert_eq!(response.unwrap().body, b"shared");
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+    // Off by default.
+    let client = SafeClient::new(local_policy());
+    let (a, b) = tokio::join!(client.fetch(get()), client.fetch(get()));
+    assert!(a.is_ok() && b.is_ok());
+    assert_eq!(hits.load(Ordering::SeqCst), 4);
+}
+
+#[tokio::test]
+async fn batches_stay_under_the_concurrency_limit() {
+    // `/slow` answers after two seconds, everything else after 100 ms.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4096];
+                let n = stream.read(&mut buf).await.unwrap_or(0);
+                let slow = buf[..n].starts_with(b"GET /slow ");
+                let delay = if slow { 2000 } else { 100 };
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                let _ = stream.write_all(&http_response("200 OK", &[], b"ok")).await;
+            });
+        }
+    });
+    let client = SafeClient::new(FetchPolicy {
+        max_concurrent_requests: 2,
+        ..local_policy()
+    });
+    let get = |path: &str| FetchRequest {
+        url: format!("http://{addr}{path}"),
+        ..Default::default()
+    };
+
+    let results = client
+        .fetch_all((0..5).map(|_| get("/")).collect(), BatchOptions::default())
+        .await;
+    assert_eq!(results.len(), 5);
+    assert!(results.iter().all(|r| r.as_ref().unwrap().body == b"ok"));
+
+    let results = client
+        .fetch_all(
+            vec![get("/"), get("/slow")],
+            BatchOptions {
+                deadline: Some(Duration::from_millis(500)),
+                ..Default::default()
+            },
+        )
+        .await;
+    assert!(results[0].is_ok());
+    assert!(matches!(results[1], Err(FetchError::RequestTimeout)));
+
+    let started = std::time::Instant::now();
+    let results = client
+        .fetch_all(
+            vec![
+                get("/slow"),
+                FetchRequest {
+                    url: format!("ftp://{addr}/"),
+                    ..Default::default()
+                },
+            ],
+            BatchOptions {
+                fail_fast: true,
+                ..Default::default()
+            },
+        )
+        .await;
+    assert!(matches!(results[0], Err(FetchError::Cancelled)));
+    assert!(matches!(
+        results[1],
+        Err(FetchError::SchemeNotAllowed { .. })
+    ));
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn transient_failures_are_retried_with_backoff() {
+    // Drops every other connection without answering.
+    let hits = Arc::new(AtomicU64::new(0));
+    let count = hits.clone();
+    let addr = spawn_server(move |_| {
+        if count.fetch_add(1, Ordering::SeqCst).is_multiple_of(2) {
+            Vec::new()
+        } else {
+            http_response("200 OK", &[], b"ok")
+        }
+    })
+    .await;
+    let get = |method: &str| FetchRequest {
+        url: format!("http://{addr}/"),
+        method: method.into(),
+        ..Default::default()
+    };
+
+    let client = SafeClient::new(local_policy());
+    let err = client.fetch(get("GET")).await.unwrap_err();
+    assert!(matches!(err, FetchError::ConnectionReset(_)), "got: {err}");
+    assert!(err.is_retryable());
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+    hits.store(0, Ordering::SeqCst);
+    let mut policy = local_policy();
+    policy.retry.max_attempts = 3;
+    policy.retry.initial_backoff_ms = 10;
+    let client = SafeClient::new(policy);
+    let response = client.fetch(get("GET")).await.unwrap();
+    assert_eq!(response.body, b"ok");
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+    // A POST isn't idempotent, so it isn't sent twice.
+    hits.store(0, Ordering::SeqCst);
+    assert!(client.fetch(get("POST")).await.is_err());
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn malformed_responses_are_not_retried() {
+    let hits = Arc::new(AtomicU64::new(0));
+    let count = hits.clone();
+    let addr = spawn_server(move |_| {
+        count.fetch_add(1, Ordering::SeqCst);
+        b"NOT HTTP\r\n\r\n".to_vec()
+    })
+    .await;
+    let mut policy = local_policy();
+    policy.retry.max_attempts = 3;
+    policy.retry.initial_backoff_ms = 10;
+    let err = SafeClient::new(policy)
+        .fetch(FetchRequest {
+            url: format!("http://{addr}/"),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert!(matches!(err, FetchError::HttpError(_)), "got: {err}");
+    assert!(!err.is_retryable());
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn retries_wait_for_retry_after() {
+    let hits = Arc::new(AtomicU64::new(0));
+    let count = hits.clone();
+    let addr = spawn_server(move |req| {
+        let first = count.fetch_add(1, Ordering::SeqCst) == 0;
+        if req.starts_with("GET /busy ") {
+            http_response("429 Too Many Requests", &[("retry-after", "3600")], b"")
+        } else if first {
+            http_response("503 Service Unavailable", &[("retry-after", "1")], b"")
+        } else {
+            http_response("200 OK", &[], b"ok")
+        }
+    })
+    .await;
+    let mut policy = local_policy();
+    policy.retry.max_attempts = 2;
+    policy.retry.max_retry_after_ms = 5_000;
+    let client = SafeClient::new(policy);
+    let get = |path: &str| FetchRequest {
+        url: format!("http://{addr}{path}"),
+        ..Default::default()
+    };
+
+    let started = std::time::Instant::now();
+    let response = client.fetch(get("/")).await.unwrap();
+    assert_eq!(response.status, 200);
+    assert!(started.elapsed() >= Duration::from_secs(1));
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+    // Asking for longer than the policy allows returns the response as it is.
+    let response = client.fetch(get("/busy")).await.unwrap();
+    assert_eq!(response.status, 429);
+    assert_eq!(response.retry_after, Some(Duration::from_secs(3600)));
+    assert_eq!(hits.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn hosts_reporting_their_limit_spent_are_paused() {
+    let addr = spawn_server(|_| {
+        http_response(
+            "200 OK",
+            &[
+                ("x-ratelimit-limit", "5000"),
+                ("x-ratelimit-remaining", "0"),
+                ("x-ratelimit-reset", "60"),
+            ],
+            b"ok",
+        )
+    })
+    .await;
+    let get = || FetchRequest {
+        url: format!("http://{addr}/"),
+        ..Default::default()
+    };
+
+    let client = SafeClient::new(local_policy());
+    let response = client.fetch(get()).await.unwrap();
+    assert_eq!(
+        response.rate_limit,
+        Some(ServerRateLimit {
+            limit: Some(5000),
+            remaining: Some(0),
+            reset: Some(Duration::from_secs(60)),
+        })
+    );
+    assert!(matches!(
+        client.fetch(get()).await,
+        Err(agent_fetch::FetchError::RateLimitExceeded)
+    ));
+
+    let client = SafeClient::new(FetchPolicy {
+        respect_server_rate_limits: false,
+        ..local_policy()
+    });
+    client.fetch(get()).await.unwrap();
+    client.fetch(get()).await.unwrap();
+}
+
+#[tokio::test]
+async fn huge_server_pauses_dont_overflow() {
+    let never = u64::MAX.to_string();
+    let addr = spawn_server(move |_| {
+        http_response(
+            "429 Too Many Requests",
+            &[
+                ("retry-after", &never),
+                ("x-ratelimit-remaining", "0"),
+                ("x-ratelimit-reset", &never),
+            ],
+            b"",
+        )
+    })
+    .await;
+    let get = || FetchRequest {
+        url: format!("http://{addr}/"),
+        ..Default::default()
+    };
+
+    let client = SafeClient::new(local_policy());
+    let response = client.fetch(get()).await.unwrap();
+    assert_eq!(response.status, 429);
+    assert!(matches!(
+        client.fetch(get()).await,
+        Err(agent_fetch::FetchError::RateLimitExceeded)
+    ));
+}
+
+#[tokio::test]
+async fn redirect_hops_count_against_quotas() {
+    let addr = spawn_server(|req| match req.split_whitespace().nth(1) {
+        Some("/a") => http_response("302 Found", &[("location", "/b")], b""),
+        Some("/b") => http_response("302 Found", &[("location", "/c")], b""),
+        _ => http_response("200 OK", &[], b"ok"),
+    })
+    .await;
+    let client = |max_requests| {
+        SafeClient::new(FetchPolicy {
+            domain_quotas: vec![agent_fetch::DomainQuota {
+                pattern: DomainPattern("127.0.0.1".into()),
+                max_requests,
+                window_secs: 3600,
+            }],
+            ..local_policy()
+        })
+    };
+    let get = |rate_limit_cost| FetchRequest {
+        url: format!("http://{addr}/a"),
+        rate_limit_cost,
+        ..Default::default()
+    };
+
+    // The request and both hops cost 3 in all.
+    let client3 = client(3);
+    client3.fetch(get(1)).await.unwrap();
+    assert!(matches!(
+        client3.fetch(get(1)).await,
+        Err(FetchError::QuotaExceeded { .. })
+    ));
+    assert!(matches!(
+        client(5).fetch(get(2)).await,
+        Err(FetchError::QuotaExceeded { .. })
+    ));
+    client(6).fetch(get(2)).await.unwrap();
+}
+
+#[tokio::test]
+async fn hedged_requests_beat_stragglers() {
+    // The first connection stalls; later ones answer at once.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let hits = Arc::new(AtomicU64::new(0));
+    let count = hits.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let first = count.fetch_add(1, Ordering::SeqCst) == 0;
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                if first {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+                let _ = stream.write_all(&http_response("200 OK", &[], b"ok")).await;
+            });
+        }
+    });
+    let client = SafeClient::new(FetchPolicy {
+        hedge_after_ms: Some(100),
+        ..local_policy()
+    });
+
+    let started = std::time::Instant::now();
+    let response = client
+        .fetch(FetchRequest {
+            url: format!("http://{addr}/"),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(response.body, b"ok");
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn mirrors_are_tried_in_order_under_the_policy() {
+    let addr = spawn_server(|req| {
+        if req.starts_with("GET /down ") {
+            // Dropped without an answer.
+            Vec::new()
+        } else {
+            http_response("200 OK", &[], b"mirror")
+        }
+    })
+    .await;
+    let client = SafeClient::new(local_policy());
+
+    let response = client
+        .fetch(FetchRequest {
+            url: format!("http://{addr}/down"),
+            mirrors: vec![format!("http://{addr}/down"), format!("http://{addr}/up")],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(response.body, b"mirror");
+    assert_eq!(response.url, format!("http://{addr}/up"));
+
+    // A mirror the policy denies isn't fetched, and ends the fallback.
+    let err = client
+        .fetch(FetchRequest {
+            url: format!("http://{addr}/down"),
+            mirrors: vec!["ftp://mirror.example/".into(), format!("http://{addr}/up")],
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, FetchError::SchemeNotAllowed { .. }),
+        "got: {err}"
+    );
+}
+
+#[tokio::test]
+async fn event_streams_outlive_the_request_timeout_but_not_their_lifetime() {
+    use futures_util::StreamExt;
+
+    // Sends an event, another one 400 ms later, then stalls.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\n\r\n\
+                          event: greeting\ndata: hello\n\n",
+                    )
+                    .await;
+                tokio::time::sleep(Duration::from_millis(400)).await;
+                let _ = stream.write_all(b"id: 2\ndata: world\n\n").await;
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            });
+        }
+    });
+    let get = || FetchRequest {
+        url: format!("http://{addr}/events"),
+        ..Default::default()
+    };
+
+    let client = SafeClient::new(FetchPolicy {
+        request_timeout_ms: 300,
+        max_sse_lifetime_ms: 1_000,
+        sse_revalidate_ms: None,
+        ..local_policy()
+    });
+    let mut events = client.fetch_sse(get()).await.unwrap();
+    let first = events.next().await.unwrap().unwrap();
+    assert_eq!(
+        (first.event.as_str(), first.data.as_str()),
+        ("greeting", "hello")
+    );
+    let second = events.next().await.unwrap().unwrap();
+    assert_eq!(
+        (second.event.as_str(), second.data.as_str()),
+        ("message", "world")
+    );
+    assert_eq!(second.id.as_deref(), Some("2"));
+    assert!(matches!(
+        events.next().await,
+        Some(Err(FetchError::RequestTimeout))
+    ));
+    assert!(events.next().await.is_none());
+
+    // A lifetime or re-check interval too long to represent never runs out.
+    let client = SafeClient::new(FetchPolicy {
+        max_sse_lifetime_ms: u64::MAX,
+        sse_revalidate_ms: Some(u64::MAX),
+        ..local_policy()
+    });
+    let mut events = client.fetch_sse(get()).await.unwrap();
+    assert!(events.next().await.unwrap().is_ok());
+    assert!(events.next().await.unwrap().is_ok());
+
+    // Open streams are re-checked, so the kill switch ends them.
+    let client = SafeClient::new(FetchPolicy {
+        sse_revalidate_ms: Some(100),
+        ..local_policy()
+    });
+    let mut events = client.fetch_sse(get()).await.unwrap();
+    assert!(events.next().await.unwrap().is_ok());
+    client.disable();
+    assert!(matches!(
+        events.next().await,
+        Some(Err(FetchError::Disabled))
+    ));
+}
+
+#[tokio::test]
+async fn websockets_are_gated_by_scheme_and_limited() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::WebSocketStream;
+
+    // Completes the handshake, then echoes every message twice over.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4096];
+                let n = stream.read(&mut buf).await.unwrap_or(0);
+                let head = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+                let Some(key) = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("sec-websocket-key:"))
+                else {
+                    let _ = stream
+                        .write_all(&http_response("400 Bad Request", &[], b""))
+                        .await;
+                    return;
+                };
+                // The key is case-sensitive: take it from the raw request.
+                let start = head.find(key).unwrap();
+                let raw = String::from_utf8_lossy(&buf[start..start + key.len()]).into_owned();
+                let accept = derive_accept_key(raw.trim().as_bytes());
+                let _ = stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 101 Switching Protocols\r\nconnection: Upgrade\r\n\
+                             upgrade: websocket\r\nsec-websocket-accept: {accept}\r\n\r\n"
+                        )
+                        .as_bytes(),
+                    )
+                    .await;
+                let mut ws = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+                while let Some(Ok(message)) = ws.next().await {
+                    if let Message::Text(text) = message {
+                        let doubled = text.repeat(2);
+                        if ws.send(Message::text(doubled)).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    let open = || FetchRequest {
+        url: format!("ws://{addr}/socket"),
+        ..Default::default()
+    };
+
+    // ws isn't an allowed scheme by default.
+    let Err(err) = SafeClient::new(local_policy()).websocket(open()).await else {
+        panic!("ws should be refused");
+    };
+    assert!(
+        matches!(err, FetchError::SchemeNotAllowed { .. }),
+        "got: {err}"
+    );
+    // The handshake goes through the same IP checks as a fetch.
+    let Err(err) = SafeClient::new(FetchPolicy {
+        allowed_schemes: vec!["ws".into()],
+        ..Default::default()
+    })
+    .websocket(open())
+    .await
+    else {
+        panic!("a private address should be refused");
+    };
+    assert!(
+        matches!(err, FetchError::PrivateIpBlocked { .. }),
+        "got: {err}"
+    );
+
+    let client = SafeClient::new(FetchPolicy {
+        allowed_schemes: vec!["ws".into()],
+        max_ws_message_bytes: 10,
+        max_ws_lifetime_ms: 500,
+        ..local_policy()
+    });
+    let mut ws = client.websocket(open()).await.unwrap();
+    ws.send(WebSocketMessage::Text("ping".into()))
+        .await
+        .unwrap();
+    assert_eq!(
+        ws.recv().await.unwrap(),
+        Some(WebSocketMessage::Text("pingping".into()))
+    );
+    assert_eq!(client.bytes_transferred(), 8);
+    let err = ws
+        .send(WebSocketMessage::Binary(vec![0; 11]))
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, FetchError::MessageTooLarge { limit: 10, .. }),
+        "got: {err}"
+    );
+    // The echo is twice the size of what was sent, so over the limit.
+    ws.send(WebSocketMessage::Text("abcdef".into()))
+        .await
+        .unwrap();
+    let err = ws.recv().await.unwrap_err();
+    assert!(
+        matches!(err, FetchError::MessageTooLarge { limit: 10, .. }),
+        "got: {err}"
+    );
+    drop(ws);
+
+    let mut ws = client.websocket(open()).await.unwrap();
+    assert!(matches!(ws.recv().await, Err(FetchError::RequestTimeout)));
+
+    // A lifetime too long to represent never runs out.
+    let client = SafeClient::new(FetchPolicy {
+        allowed_schemes: vec!["ws".into()],
+        max_ws_lifetime_ms: u64::MAX,
+        ..local_policy()
+    });
+    let mut ws = client.websocket(open()).await.unwrap();
+    ws.send(WebSocketMessage::Text("hi".into())).await.unwrap();
+    assert_eq!(
+        ws.recv().await.unwrap(),
+        Some(WebSocketMessage::Text("hihi".into()))
+    );
+
+    // A ws URL isn't fetched as plain HTTP.
+    let err = client.fetch(open()).await.unwrap_err();
+    assert!(matches!(err, FetchError::InvalidUrl(_)), "got: {err}");
+}
+
+#[tokio::test]
+async fn http2_stream_cap_holds_requests_per_origin() {
+    let addr = spawn_server(|_| http_response("200 OK", &[], b"ok")).await;
+    let get = |timeout_ms| FetchRequest {
+        url: format!("http://{addr}/"),
+        request_timeout_ms: Some(timeout_ms),
+        ..Default::default()
+    };
+    let client = SafeClient::new(FetchPolicy {
+        http2_max_concurrent_streams: Some(1),
+        ..local_policy()
+    });
+
+    // An unread streaming body keeps its slot, so the next request waits until it times out.
+    let open = client.fetch_stream(get(5_000)).await.unwrap();
+    let err = client.fetch(get(200)).await.unwrap_err();
+    assert!(matches!(err, FetchError::RequestTimeout), "got: {err}");
+    drop(open);
+    assert_eq!(client.fetch(get(200)).await.unwrap().status, 200);
+
+    // Without HTTP/2 the cap doesn't apply.
+    let client = SafeClient::new(FetchPolicy {
+        http2: false,
+        http2_max_concurrent_streams: Some(1),
+        ..local_policy()
+    });
+    let _open = client.fetch_stream(get(5_000)).await.unwrap();
+    assert_eq!(client.fetch(get(200)).await.unwrap().status, 200);
+}
+
+#[tokio::test]
+async fn waiting_for_a_stream_slot_counts_against_the_timeout() {
+    // Answers `/slow` after 300 ms, anything else at once.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4096];
+                let n = stream.read(&mut buf).await.unwrap_or(0);
+                if buf[..n].starts_with(b"GET /slow ") {
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                }
+                let _ = stream.write_all(&http_response("200 OK", &[], b"ok")).await;
+            });
+        }
+    });
+    let get = |path: &str, timeout_ms| FetchRequest {
+        url: format!("http://{addr}{path}"),
+        request_timeout_ms: Some(timeout_ms),
+        ..Default::default()
+    };
+    let client = SafeClient::new(FetchPolicy {
+        http2_max_concurrent_streams: Some(1),
+        ..local_policy()
+    });
+
+    // 250 ms queued plus 300 ms for the response is over the 400 ms timeout.
+    let open = client.fetch_stream(get("/", 5_000)).await.unwrap();
+    let release = async {
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        drop(open);
+    };
+    let (_, result) = tokio::join!(release, client.fetch(get("/slow", 400)));
+    let err = result.unwrap_err();
+    assert!(matches!(err, FetchError::RequestTimeout), "got: {err}");
+    assert_eq!(client.fetch(get("/slow", 400)).await.unwrap().status, 200);
+}
+
+#[tokio::test]
+async fn prior_knowledge_speaks_h2c_to_http_urls() {
+    let prefaces = Arc::new(AtomicU64::new(0));
+    let count = prefaces.clone();
+    let addr = spawn_server(move |req| {
+        if req.starts_with("PRI * HTTP/2.0\r\n") {
+            count.fetch_add(1, Ordering::SeqCst);
+        }
+        http_response("200 OK", &[], b"ok")
+    })
+    .await;
+    let get = || FetchRequest {
+        url: format!("http://{addr}/"),
+        ..Default::default()
+    };
+
+    // By default a cleartext request is plain HTTP/1.1.
+    let client = SafeClient::new(local_policy());
+    let response = client.fetch(get()).await.unwrap();
+    assert_eq!(response.version, http::Version::HTTP_11);
+    assert_eq!(prefaces.load(Ordering::SeqCst), 0);
+
+    // With prior knowledge it opens with the HTTP/2 preface, which this server can't answer.
+    let client = SafeClient::new(FetchPolicy {
+        http2_prior_knowledge: true,
+        ..local_policy()
+    });
+    assert!(client.fetch(get()).await.is_err());
+    assert_eq!(prefaces.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn responses_over_disallowed_http_versions_are_rejected() {
+    let addr = spawn_server(|_| http_response("200 OK", &[], b"ok")).await;
+    let get = || FetchRequest {
+        url: format!("http://{addr}/"),
+        ..Default::default()
+    };
+
+    let client = SafeClient::new(FetchPolicy {
+        allowed_http_versions: vec![HttpVersion::Http1],
+        ..local_policy()
+    });
+    assert_eq!(client.fetch(get()).await.unwrap().status, 200);
+
+    // HTTP/2 is allowed but off, so the server answers over HTTP/1.1, which isn't.
+    let client = SafeClient::new(FetchPolicy {
+        http2: false,
+        allowed_http_versions: vec![HttpVersion::Http2],
+        ..local_policy()
+    });
+    let err = client.fetch(get()).await.unwrap_err();
+    assert!(
+        matches!(err, FetchError::HttpVersionNotAllowed { ref version, .. } if version == "HTTP/1.1"),
+        "got: {err}"
+    );
+}
+
+#[tokio::test]
+async fn form_bodies_are_url_encoded() {
+    let addr = spawn_server(|req| {
+        let (head, body) = req.split_once("\r\n\r\n").unwrap();
+        let typed = head
+            .to_lowercase()
+            .contains("content-type: application/x-www-form-urlencoded");
+        http_response("200 OK", &[], format!("{typed} {body}").as_bytes())
+    })
+    .await;
+    let client = SafeClient::new(FetchPolicy {
+        max_request_body_bytes: 100,
+        ..local_policy()
+    });
+    let request = FetchRequest {
+        url: format!("http://{addr}/token"),
+        method: "POST".into(),
+        ..Default::default()
+    };
+
+    let response = client
+        .fetch(request.clone().form(&[
+            ("grant_type", "client_credentials"),
+            ("scope", "read write&admin"),
+        ]))
+        .await
+        .unwrap();
+    assert_eq!(
+        response.text(),
+        "true grant_type=client_credentials&scope=read+write%26admin"
+    );
+
+    let err = client
+        .fetch(request.form(&[("data", "x".repeat(100))]))
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            agent_fetch::FetchError::RequestBodyTooLarge { limit: 100, .. }
+        ),
+        "got: {err}"
+    );
+}
+
+#[tokio::test]
+async fn downloads_stream_to_disk_and_verify_checksums() {
+    let addr = spawn_server(|req| {
+        if req.starts_with("GET /big ") {
+            http_response("200 OK", &[], &[b'x'; 2000])
+        } else {
+            http_response("200 OK", &[], b"hello world")
+        }
+    })
+    .await;
+    let client = SafeClient::new(FetchPolicy {
+        max_response_body_bytes: 1000,
+        ..local_policy()
+    });
+    let dir = std::env::temp_dir().join(format!("agent-fetch-download-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("hello.txt");
+    let hello_sha = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    let file = client
+        .download(
+            format!("http://{addr}/hello"),
+            &path,
+            agent_fetch::DownloadOptions {
+                sha256: Some(hello_sha.to_uppercase()),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(file.size, 11);
+    assert_eq!(file.sha256, hello_sha);
+    assert_eq!(std::fs::read(&path).unwrap(), b"hello world");
+
+    let other = dir.join("other.txt");
+    let err = client
+        .download(
+            format!("http://{addr}/hello"),
+            &other,
+            agent_fetch::DownloadOptions {
+                sha256: Some("00".repeat(32)),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, agent_fetch::FetchError::ChecksumMismatch { .. }),
+        "got: {err}"
+    );
+    let err = client
+        .download(format!("http://{addr}/big"), &other, Default::default())
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            agent_fetch::FetchError::ResponseBodyTooLarge { limit: 1000, .. }
+        ),
+        "got: {err}"
+    );
+    // Neither failure leaves a file behind.
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn interrupted_downloads_resume_with_a_range_request() {
+    let addr = spawn_server(|req| {
+        let req = req.to_lowercase();
+        let validated = req.contains("if-range: \"v1\"");
+        if req.contains("range: bytes=8-") && validated {
+            b"HTTP/1.1 206 Partial Content\r\ncontent-range: bytes 8-10/11\r\n\
+              content-length: 3\r\n\r\nrld"
+                .to_vec()
+        } else if req.contains("range: bytes=5-") && validated {
+            // Without repeating the validators, and dropped again halfway.
+            b"HTTP/1.1 206 Partial Content\r\ncontent-range: bytes 5-10/11\r\n\
+              content-length: 6\r\n\r\n wo"
+                .to_vec()
+        } else {
+            // Promise the whole file but drop the connection halfway.
+            b"HTTP/1.1 200 OK\r\ncontent-length: 11\r\netag: \"v1\"\r\n\
+              accept-ranges: bytes\r\n\r\nhello"
+                .to_vec()
+        }
+    })
+    .await;
+    let client = SafeClient::new(local_policy());
+    let dir = std::env::temp_dir().join(format!("agent-fetch-resume-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("file.txt");
+    let options = agent_fetch::DownloadOptions {
+        resume: true,
+        sha256: Some("b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9".into()),
+        ..Default::default()
+    };
+
+    client
+        .download(format!("http://{addr}/file"), &path, options.clone())
+        .await
+        .unwrap_err();
+    assert_eq!(std::fs::read(dir.join("file.txt.part")).unwrap(), b"hello");
+    client
+        .download(format!("http://{addr}/file"), &path, options.clone())
+        .await
+        .unwrap_err();
+    assert_eq!(
+        std::fs::read(dir.join("file.txt.part")).unwrap(),
+        b"hello wo"
+    );
+
+    let file = client
+        .download(format!("http://{addr}/file"), &path, options.clone())
+        .await
+        .unwrap();
+    assert_eq!(file.resumed_from, 8);
+    assert_eq!(file.size, 11);
+    assert_eq!(std::fs::read(&path).unwrap(), b"hello world");
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+    // A partial file already over the size limit fails without a request.
+    std::fs::write(dir.join("file.txt.part"), b"hello world").unwrap();
+    std::fs::write(dir.join("file.txt.part.meta"), b"\"v1\"").unwrap();
+    let client = SafeClient::new(FetchPolicy {
+        max_response_body_bytes: 8,
+        ..local_policy()
+    });
+    let err = client
+        .download("http://127.0.0.1:9/file", &path, options)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, FetchError::ResponseBodyTooLarge { size: 11, .. }),
+        "got: {err}"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn requests_go_through_the_proxy_after_destination_checks() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let log = seen.clone();
+    let proxy = spawn_server(move |req| {
+        let line = req.lines().next().unwrap_or("").to_string();
+        let auth = req
+            .lines()
+            .find_map(|l| {
+                l.to_ascii_lowercase()
+                    .strip_prefix("proxy-authorization: ")
+                    .map(str::to_string)
+            })
+            .unwrap_or_default();
+        log.lock().unwrap().push((line.clone(), auth));
+        if line.starts_with("CONNECT") {
+            http_response("403 Forbidden", &[], b"")
+        } else {
+            http_response("200 OK", &[], b"via proxy")
+        }
+    })
+    .await;
+    // The proxy is named by a host that resolves to loopback, which the IP checks would
+    // reject for a destination.
+    let client = SafeClient::new(FetchPolicy {
+        proxy: Some(ProxyPolicy {
+            url: format!("http://localhost:{}", proxy.port()),
+            username: Some("user".into()),
+            password: Some("pass".into()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    let get = |url: &str| {
+        client.fetch(FetchRequest {
+            url: url.into(),
+            ..Default::default()
+        })
+    };
+
+    let resp = get("http://93.184.215.14/page").await.unwrap();
+    assert_eq!(resp.body, b"via proxy");
+    assert!(get("https://93.184.215.14/page").await.is_err());
+    // Destinations are checked before anything is sent to the proxy.
+    assert!(matches!(
+        get(&format!("http://127.0.0.1:{}/", proxy.port())).await,
+        Err(FetchError::PrivateIpBlocked { .. })
+    ));
+    assert!(matches!(
+        get(&format!("http://localhost:{}/", proxy.port())).await,
+        Err(FetchError::PrivateIpBlocked { .. })
+    ));
+
+    let seen = seen.lock().unwrap().clone();
+    let basic = "basic dxnlcjpwyxnz".to_string();
+    assert_eq!(
+        seen.iter()
+            .map(|(line, auth)| (line.as_str(), auth.to_ascii_lowercase()))
+            .collect::<Vec<_>>(),
+        [
+            ("GET http://93.184.215.14/page HTTP/1.1", basic.clone()),
+            ("CONNECT 93.184.215.14:443 HTTP/1.1", basic),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn no_proxy_destinations_are_reached_directly() {
+    let addr = spawn_server(|_| http_response("200 OK", &[], b"direct")).await;
+    // Nothing listens on the proxy's port.
+    let client = SafeClient::new(FetchPolicy {
+        proxy: Some(ProxyPolicy {
+            url: "http://127.0.0.1:1".into(),
+            no_proxy: vec![agent_fetch::DomainPattern("127.0.0.1".into())],
+            ..Default::default()
+        }),
+        ..local_policy()
+    });
+    let resp = client
+        .fetch(FetchRequest {
+            url: format!("http://{addr}/"),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(resp.body, b"direct");
+
+    let invalid = SafeClient::new(FetchPolicy {
+        proxy: Some(ProxyPolicy {
+            url: "not a url".into(),
+            ..Default::default()
+        }),
+        ..local_policy()
+    });
+    let err = invalid
+        .fetch(FetchRequest {
+            url: format!("http://{addr}/"),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert!(matches!(err, FetchError::InvalidPolicy(_)));
+}
+
+/// Minimal SOCKS5 proxy that records each connection's target, prefixed with the
+/// credentials it was given, and answers the tunnelled HTTP request itself.
+async fn spawn_socks_proxy(seen: Arc<Mutex<Vec<String>>>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let seen = seen.clone();
+            tokio::spawn(async move {
+                let mut head = [0u8; 2];
+                stream.read_exact(&mut head).await?;
+                let mut methods = vec![0u8; head[1] as usize];
+                stream.read_exact(&mut methods).await?;
+                let mut target = String::new();
+                if methods.contains(&2) {
+                    stream.write_all(&[5, 2]).await?;
+                    let mut len = [0u8; 2];
+                    stream.read_exact(&mut len).await?;
+                    let mut user = vec![0u8; len[1] as usize];
+                    stream.read_exact(&mut user).await?;
+                    stream.read_exact(&mut len[..1]).await?;
+                    let mut pass = vec![0u8; len[0] as usize];
+                    stream.read_exact(&mut pass).await?;
+                    target = format!(
+                        "{}:{}@",
+                        String::from_utf8_lossy(&user),
+                        String::from_utf8_lossy(&pass)
+                    );
+                    stream.write_all(&[1, 0]).await?;
+                } else {
+                    stream.write_all(&[5, 0]).await?;
+                }
+                let mut request = [0u8; 4];
+                stream.read_exact(&mut request).await?;
+                match request[3] {
+                    1 => {
+                        let mut ip = [0u8; 4];
+                        stream.read_exact(&mut ip).await?;
+                        target.push_str(&std::net::Ipv4Addr::from(ip).to_string());
+                    }
+                    3 => {
+                        let mut len = [0u8; 1];
+                        stream.read_exact(&mut len).await?;
+                        let mut name = vec![0u8; len[0] as usize];
+                        stream.read_exact(&mut name).await?;
+                        target.push_str(&String::from_utf8_lossy(&name));
+                    }
+                    _ => {
+                        let mut ip = [0u8; 16];
+                        stream.read_exact(&mut ip).await?;
+                        target.push_str(&format!("[{}]", std::net::Ipv6Addr::from(ip)));
+                    }
+                }
+                let port = stream.read_u16().await?;
+                seen.lock().unwrap().push(format!("{target}:{port}"));
+                stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+
+                let mut buf = Vec::new();
+                let mut chunk = [0u8; 4096];
+                while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut chunk).await?;
+                    if n == 0 {
+                        return Ok(());
+                    }
+                    buf.extend_from_slice(&chunk[..n]);
+                }
+                stream
+                    .write_all(&http_response("200 OK", &[], b"via socks"))
+                    .await?;
+                stream.shutdown().await
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn socks_proxies_are_routed_per_domain_after_destination_checks() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let proxy = spawn_socks_proxy(seen.clone()).await;
+    let socks = |scheme: &str| ProxyPolicy {
+        url: format!("{scheme}://{proxy}"),
+        username: Some("user".into()),
+        password: Some("pass".into()),
+        routes: vec![ProxyRoute {
+            pattern: agent_fetch::DomainPattern("localhost".into()),
+            url: format!("socks5h://{proxy}"),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let get = |url: &str| FetchRequest {
+        url: url.into(),
+        ..Default::default()
+    };
+
+    let client = SafeClient::new(FetchPolicy {
+        allowed_ip_ranges: vec!["127.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()],
+        proxy: Some(socks("socks5")),
+        ..Default::default()
+    });
+    // socks5 hands the proxy the checked address; the socks5h route hands it the name.
+    let resp = client.fetch(get("http://127.0.0.2:8080/")).await.unwrap();
+    assert_eq!(resp.body, b"via socks");
+    client.fetch(get("http://localhost:8080/")).await.unwrap();
+    assert_eq!(
+        *seen.lock().unwrap(),
+        ["user:pass@127.0.0.2:8080", "localhost:8080"]
+    );
+
+    // Without the loopback exemption, remote resolution doesn't get around the IP checks:
+    // the name is still resolved and checked before the proxy sees it.
+    let client = SafeClient::new(FetchPolicy {
+        proxy: Some(socks("socks5h")),
+        ..Default::default()
+    });
+    assert!(matches!(
+        client.fetch(get("http://localhost:8080/")).await,
+        Err(FetchError::PrivateIpBlocked { .. })
+    ));
+    assert!(matches!(
+        client.fetch(get("http://127.0.0.2:8080/")).await,
+        Err(FetchError::PrivateIpBlocked { .. })
+    ));
+    assert_eq!(seen.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn required_proxy_fails_closed() {
+    let hits = Arc::new(AtomicU64::new(0));
+    let counter = hits.clone();
+    let addr = spawn_server(move |_| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        http_response("200 OK", &[], b"direct")
+    })
+    .await;
+    let fetch = |proxy: Option<ProxyPolicy>| async move {
+        let client = SafeClient::new(FetchPolicy {
+            proxy,
+            require_proxy: true,
+            ..local_policy()
+        });
+        client
+            .fetch(FetchRequest {
+                url: format!("http://{addr}/"),
+                ..Default::default()
+            })
+            .await
+    };
+
+    // Nothing listens on the proxy's port.
+    let unreachable = ProxyPolicy {
+        url: "http://127.0.0.1:1".into(),
+        ..Default::default()
+    };
+    assert!(matches!(
+        fetch(Some(unreachable.clone())).await,
+        Err(FetchError::ConnectionTimeout)
+    ));
+    assert!(matches!(
+        fetch(None).await,
+        Err(FetchError::ProxyRequired { .. })
+    ));
+    let bypassed = ProxyPolicy {
+        no_proxy: vec![agent_fetch::DomainPattern("127.0.0.1".into())],
+        ..unreachable
+    };
+    let Err(FetchError::ProxyRequired { decision, .. }) = fetch(Some(bypassed)).await else {
+        panic!("expected ProxyRequired");
+    };
+    assert_eq!(decision.rule, "proxy.no_proxy");
+    assert_eq!(hits.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn client_certificates_are_picked_by_host() {
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+
+    let fixture = |name: &str| {
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    };
+    let expected = CertificateDer::from_pem_file(fixture("client.pem")).unwrap();
+    let addr = spawn_tls_server_verifying(
+        tokio_rustls::rustls::ALL_VERSIONS,
+        Arc::new(RequireCertificate(expected)),
+    )
+    .await;
+    let policy = |client_certificates| FetchPolicy {
+        client_certificates,
+        root_certificates: vec![RootCertificates {
+            file: Some(fixture("ca.pem")),
+            ..Default::default()
+        }],
+        ..local_policy()
+    };
+    let client = SafeClient::new(policy(vec![
+        ClientCertificate {
+            pattern: agent_fetch::DomainPattern("127.0.0.1".into()),
+            pem_file: Some(fixture("client.pem")),
+            ..Default::default()
+        },
+        ClientCertificate {
+            pattern: agent_fetch::DomainPattern("broken.example".into()),
+            pem_file: Some(fixture("missing.pem")),
+            ..Default::default()
+        },
+    ]));
+    let get = |url: String| FetchRequest {
+        url,
+        ..Default::default()
+    };
+    let resp = client.fetch(get(format!("https://{addr}/"))).await.unwrap();
+    assert_eq!(resp.body, b"secure");
+    // A certificate that can't be loaded fails requests to its hosts, not the others.
+    let err = client
+        .fetch(get("https://broken.example/".into()))
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(&err, FetchError::InvalidPolicy(msg) if msg.contains("broken.example")),
+        "{err}"
+    );
+
+    // Without a certificate for the host, the server refuses the handshake.
+    let client = SafeClient::new(policy(vec![ClientCertificate {
+        pattern: agent_fetch::DomainPattern("other.example".into()),
+        pem_file: Some(fixture("client.pem")),
+        ..Default::default()
+    }]));
+    assert!(client.fetch(get(format!("https://{addr}/"))).await.is_err());
+}
+
+/// Client certificate verifier accepting only the given certificate, with a valid signature
+/// from its key.
+#[derive(Debug)]
+struct RequireCertificate(CertificateDer<'static>);
+
+impl ClientCertVerifier for RequireCertificate {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _: &[CertificateDer<'_>],
+        _: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        if *end_entity != self.0 {
+            return Err(rustls::Error::General(
+                "unexpected client certificate".into(),
+            ));
+        }
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &algorithms())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &algorithms())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        algorithms().supported_schemes()
+    }
+}
+
+fn algorithms() -> crypto::WebPkiSupportedAlgorithms {
+    crypto::aws_lc_rs::default_provider().signature_verification_algorithms
+}
+
+/// An HTTPS server with the `server.pem` certificate, speaking only the given TLS versions.
+async fn spawn_tls_server(
+    versions: &'static [&'static tokio_rustls::rustls::SupportedProtocolVersion],
+) -> SocketAddr {
+    use tokio_rustls::rustls::server::WebPkiClientVerifier;
+
+    spawn_tls_server_verifying(versions, WebPkiClientVerifier::no_client_auth()).await
+}
+
+/// [`spawn_tls_server`], checking client certificates with `verifier`.
+async fn spawn_tls_server_verifying(
+    versions: &'static [&'static tokio_rustls::rustls::SupportedProtocolVersion],
+    verifier: Arc<dyn ClientCertVerifier>,
+) -> SocketAddr {
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::PrivateKeyDer;
+    use tokio_rustls::rustls::ServerConfig;
+
+    let pem = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/server.pem");
+    let certs = CertificateDer::pem_file_iter(&pem)
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let key = PrivateKeyDer::from_pem_file(&pem).unwrap();
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(crypto::aws_lc_rs::default_provider()))
+            .with_protocol_versions(versions)
+            .unwrap()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs, key)
+            .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let Ok(mut stream) = acceptor.accept(stream).await else {
+                    return;
+                };
+                let mut buf = Vec::new();
+                let mut chunk = [0u8; 4096];
+                while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut chunk).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                    }
+                }
+                let _ = stream
+                    .write_all(&http_response("200 OK", &[], b"secure"))
+                    .await;
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+    addr
+}
+
+/// HTTP proxy that tunnels every `CONNECT` to `tunnel_to` and answers plain requests
+/// itself with `200 OK`.
+async fn spawn_tunnelling_proxy(tunnel_to: SocketAddr) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = Vec::new();
+                let mut chunk = [0u8; 4096];
+                while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut chunk).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                    }
+                }
+                if !buf.starts_with(b"CONNECT ") {
+                    let _ = stream
+                        .write_all(&http_response("200 OK", &[], b"plain"))
+                        .await;
+                    return;
+                }
+                let Ok(mut upstream) = tokio::net::TcpStream::connect(tunnel_to).await else {
+                    return;
+                };
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .await;
+                let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn insecure_fallback_only_for_listed_domains() {
+    // Upgraded requests reach a server whose certificate isn't trusted, so they fail to
+    // connect; plain http requests are answered by the proxy.
+    let tls = spawn_tls_server(tokio_rustls::rustls::ALL_VERSIONS).await;
+    let proxy = spawn_tunnelling_proxy(tls).await;
+    let public: std::net::IpAddr = "93.184.215.14".parse().unwrap();
+    let client = SafeClient::new(FetchPolicy {
+        upgrade_insecure_requests: true,
+        insecure_fallback_domains: vec![agent_fetch::DomainPattern("listed.test".into())],
+        dns_overrides: [
+            ("listed.test".to_string(), vec![public]),
+            ("unlisted.test".to_string(), vec![public]),
+        ]
+        .into(),
+        proxy: Some(ProxyPolicy {
+            url: format!("http://{proxy}"),
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    let get = |url: &str| {
+        client.fetch(FetchRequest {
+            url: url.into(),
+            ..Default::default()
+        })
+    };
+
+    let resp = get("http://listed.test/").await.unwrap();
+    assert_eq!(resp.body, b"plain");
+    assert_eq!(resp.url, "http://listed.test/");
+
+    let err = get("http://unlisted.test/").await.unwrap_err();
+    assert!(matches!(err, FetchError::ConnectionTimeout), "got: {err}");
+}
+
+#[tokio::test]
+async fn timings_report_each_phase() {
+    let (addr, _) = spawn_keep_alive_server().await;
+    let client = SafeClient::new(local_policy());
+    for i in 0..2 {
+        let response = client
+            .fetch(FetchRequest {
+                url: format!("http://{addr}/"),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        // Only the first request pays for a connection, and neither for a handshake.
+        let timings = response.timings;
+        assert_eq!(
+            timings.connect.is_some(),
+            i == 0,
+            "request {i}: {timings:?}"
+        );
+        assert_eq!(timings.tls, None);
+        assert!(timings.total >= timings.time_to_first_byte + timings.body);
+    }
+
+    let addr = spawn_tls_server(tokio_rustls::rustls::ALL_VERSIONS).await;
+    let ca = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/ca.pem");
+    let response = SafeClient::new(FetchPolicy {
+        root_certificates: vec![RootCertificates {
+            file: Some(ca),
+            ..Default::default()
+        }],
+        ..local_policy()
+    })
+    .fetch(FetchRequest {
+        url: format!("https://{addr}/"),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let timings = response.timings;
+    assert!(timings.connect.is_some(), "{timings:?}");
+    assert!(
+        timings.tls.is_some_and(|tls| tls > Duration::ZERO),
+        "{timings:?}"
+    );
+    assert!(timings.time_to_first_byte >= timings.connect.unwrap() + timings.tls.unwrap());
+}
+
+#[tokio::test]
+async fn extra_root_certificates_are_trusted() {
+    let addr = spawn_tls_server(tokio_rustls::rustls::ALL_VERSIONS).await;
+    let ca = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/ca.pem");
+    let fetch = |policy: FetchPolicy| async move {
+        SafeClient::new(policy)
+            .fetch(FetchRequest {
+                url: format!("https://{addr}/"),
+                ..Default::default()
+            })
+            .await
+    };
+
+    // Not issued by a system root: the connection fails.
+    assert!(matches!(
+        fetch(local_policy()).await,
+        Err(FetchError::ConnectionTimeout)
+    ));
+    let resp = fetch(FetchPolicy {
+        root_certificates: vec![RootCertificates {
+            file: Some(ca.clone()),
+            ..Default::default()
+        }],
+        ..local_policy()
+    })
+    .await
+    .unwrap();
+    assert_eq!(resp.body, b"secure");
+    let resp = fetch(FetchPolicy {
+        root_certificates: vec![RootCertificates {
+            pem: Some(std::fs::read_to_string(&ca).unwrap()),
+            ..Default::default()
+        }],
+        use_system_roots: false,
+        ..local_policy()
+    })
+    .await
+    .unwrap();
+    assert_eq!(resp.body, b"secure");
+    // Roots that can't be loaded fail every request rather than falling back.
+    assert!(matches!(
+        fetch(FetchPolicy {
+            root_certificates: vec![RootCertificates {
+                pem: Some("not a certificate".into()),
+                ..Default::default()
+            }],
+            ..local_policy()
+        })
+        .await,
+        Err(FetchError::InvalidPolicy(_))
+    ));
+}
+
+#[tokio::test]
+async fn min_tls_version_is_enforced() {
+    use tokio_rustls::rustls::{version, SupportedProtocolVersion};
+    static TLS12_ONLY: &[&SupportedProtocolVersion] = &[&version::TLS12];
+
+    let addr = spawn_tls_server(TLS12_ONLY).await;
+    let ca = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/ca.pem");
+    let fetch = |min_tls_version: TlsVersion| {
+        SafeClient::new(FetchPolicy {
+            root_certificates: vec![RootCertificates {
+                file: Some(ca.clone()),
+                ..Default::default()
+            }],
+            min_tls_version,
+            ..local_policy()
+        })
+    };
+    let get = || FetchRequest {
+        url: format!("https://{addr}/"),
+        ..Default::default()
+    };
+
+    let resp = fetch(TlsVersion::Tls1_2).fetch(get()).await.unwrap();
+    assert_eq!(resp.body, b"secure");
+    // The server can't go past TLS 1.2, so the handshake fails.
+    assert!(matches!(
+        fetch(TlsVersion::Tls1_3).fetch(get()).await,
+        Err(FetchError::ConnectionTimeout)
+    ));
+}
+
+#[tokio::test]
+async fn certificate_pins_are_checked_before_sending() {
+    let addr = spawn_tls_server(tokio_rustls::rustls::ALL_VERSIONS).await;
+    let ca = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/ca.pem");
+    let spki = "5kzOd9gRUYqBpgpI4jnvzL/L8TGRWDrfepGnCiZ6rpU=";
+    let client = |pin: &str| {
+        SafeClient::new(FetchPolicy {
+            root_certificates: vec![RootCertificates {
+                file: Some(ca.clone()),
+                ..Default::default()
+            }],
+            certificate_pins: vec![CertificatePin {
+                pattern: DomainPattern("127.0.0.1".into()),
+                spki_sha256: vec![pin.into()],
+                ..Default::default()
+            }],
+            ..local_policy()
+        })
+    };
+    let get = |scheme: &str| FetchRequest {
+        url: format!("{scheme}://{addr}/"),
+        ..Default::default()
+    };
+
+    let resp = client(spki).fetch(get("https")).await.unwrap();
+    assert_eq!(resp.body, b"secure");
+
+    let other = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+    let Err(FetchError::CertificatePinMismatch {
+        host, fingerprint, ..
+    }) = client(other).fetch(get("https")).await
+    else {
+        panic!("expected CertificatePinMismatch");
+    };
+    assert_eq!(host, "127.0.0.1");
+    assert_eq!(fingerprint, spki);
+    // Preconnecting makes the handshake, so it checks them too.
+    let url = format!("https://{addr}/");
+    client(spki).preconnect(&url).await.unwrap();
+    assert!(matches!(
+        client(other).preconnect(&url).await,
+        Err(FetchError::CertificatePinMismatch { .. })
+    ));
+    // Pins can't be checked without TLS.
+    assert!(matches!(
+        client(spki).fetch(get("http")).await,
+        Err(FetchError::SchemeNotAllowed { .. })
+    ));
+}
+
+#[tokio::test]
+async fn tls_details_are_captured_on_request() {
+    let addr = spawn_tls_server(tokio_rustls::rustls::ALL_VERSIONS).await;
+    let ca = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/ca.pem");
+    let client = |capture_tls_details: bool| {
+        SafeClient::new(FetchPolicy {
+            root_certificates: vec![RootCertificates {
+                file: Some(ca.clone()),
+                ..Default::default()
+            }],
+            capture_tls_details,
+            ..local_policy()
+        })
+    };
+    let get = || FetchRequest {
+        url: format!("https://{addr}/"),
+        ..Default::default()
+    };
+
+    assert_eq!(client(false).fetch(get()).await.unwrap().tls, None);
+    let tls = client(true).fetch(get()).await.unwrap().tls.unwrap();
+    assert_eq!(tls.version, Some(TlsVersion::Tls1_3));
+    assert_eq!(tls.subject, "CN=127.0.0.1");
+    assert_eq!(tls.issuer, "CN=agent-fetch test CA");
+    assert_eq!(tls.subject_alt_names, ["127.0.0.1", "localhost"]);
+    assert!(tls.not_after > std::time::SystemTime::now());
+    assert_eq!(
+        tls.spki_sha256,
+        "5kzOd9gRUYqBpgpI4jnvzL/L8TGRWDrfepGnCiZ6rpU="
+    );
+    assert_eq!(tls.sha256, "cFSyMVYPbDaeZEqMSJh60OE0VqMwVbNvC249B9TY9NU=");
+}
+
+#[tokio::test]
+async fn expect_continue_holds_large_bodies_back() {
+    // Answers 413 as soon as the headers are in, and counts the body bytes that follow.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let received = Arc::new(Mutex::new(None));
+    let seen = received.clone();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 4096];
+        while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut chunk).await.unwrap();
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        let head = String::from_utf8_lossy(&buf).to_lowercase();
+        let _ = stream
+            .write_all(&http_response("413 Payload Too Large", &[], b""))
+            .await;
+        let mut body = buf.len() - buf.windows(4).position(|w| w == b"\r\n\r\n").unwrap() - 4;
+        while let Ok(n @ 1..) = stream.read(&mut chunk).await {
+            body += n;
+        }
+        *seen.lock().unwrap() = Some((head.contains("expect: 100-continue"), body));
+    });
+
+    let client = SafeClient::new(FetchPolicy {
+        expect_continue_bytes: Some(1024),
+        expect_continue_timeout_ms: 10_000,
+        ..local_policy()
+    });
+    let upload = |url: String, body: &'static str| FetchRequest {
+        url,
+        method: "POST".into(),
+        body: Some(body.repeat(1024 * 1024).into()),
+        ..Default::default()
+    };
+    let started = std::time::Instant::now();
+    let resp = client
+        .fetch(upload(format!("http://{addr}/upload"), "x"))
+        .await
+        .unwrap();
+    assert_eq!(resp.status, 413);
+    assert!(started.elapsed() < Duration::from_secs(5));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(*received.lock().unwrap(), Some((true, 0)));
+
+    // A server that waits gets the body once the timeout passes.
+    let addr = spawn_server(|req| {
+        let expected = req.to_lowercase().contains("expect: 100-continue");
+        let len = req.split("\r\n\r\n").nth(1).unwrap_or("").len();
+        http_response("200 OK", &[], format!("{expected} {len}").as_bytes())
+    })
+    .await;
+    let client = SafeClient::new(FetchPolicy {
+        expect_continue_bytes: Some(1024),
+        expect_continue_timeout_ms: 50,
+        ..local_policy()
+    });
+    let resp = client
+        .fetch(upload(format!("http://{addr}/upload"), "y"))
+        .await
+        .unwrap();
+    assert_eq!(resp.text(), format!("true {}", 1024 * 1024));
+}
+
+#[tokio::test]
+async fn mismatched_host_headers_are_rejected() {
+    let addr = spawn_server(|req| {
+        let host = req
+            .lines()
+            .find_map(|l| l.to_lowercase().strip_prefix("host:").map(str::to_string))
+            .unwrap_or_default();
+        http_response("200 OK", &[], host.trim().as_bytes())
+    })
+    .await;
+    let get = |host: &str| FetchRequest {
+        url: format!("http://{addr}/"),
+        headers: [("Host".to_string(), host.to_string())].into(),
+        ..Default::default()
+    };
+
+    let client = SafeClient::new(local_policy());
+    let resp = client.fetch(get(&addr.to_string())).await.unwrap();
+    assert_eq!(resp.text(), addr.to_string());
+    assert!(matches!(
+        client.fetch(get("metadata.internal")).await,
+        Err(FetchError::HostHeaderMismatch { .. })
+    ));
+
+    let client = SafeClient::new(FetchPolicy {
+        allow_host_header_override: true,
+        ..local_policy()
+    });
+    let resp = client.fetch(get("metadata.internal")).await.unwrap();
+    assert_eq!(resp.text(), "metadata.internal");
+}
+
+#[tokio::test]
+async fn large_request_bodies_are_gzipped() {
+    let addr = spawn_server(|req| {
+        let head = req.to_lowercase();
+        let header = |name: &str| {
+            head.lines()
+                .find_map(|l| l.strip_prefix(name))
+                .map(|v| v.trim().to_string())
+                .unwrap_or_default()
+        };
+        let seen = format!(
+            "{} {}",
+            header("content-encoding:"),
+            header("content-length:")
+        );
+        http_response("200 OK", &[], seen.as_bytes())
+    })
+    .await;
+    let upload = |len: usize| FetchRequest {
+        url: format!("http://{addr}/upload"),
+        method: "POST".into(),
+        body: Some("x".repeat(len).into()),
+        ..Default::default()
+    };
+    let policy = FetchPolicy {
+        max_request_body_bytes: 64 * 1024,
+        ..local_policy()
+    };
+
+    let client = SafeClient::new(policy.clone());
+    assert!(matches!(
+        client.fetch(upload(1024 * 1024)).await,
+        Err(FetchError::RequestBodyTooLarge { .. })
+    ));
+
+    // The limit applies to the compressed body.
+    let client = SafeClient::new(FetchPolicy {
+        gzip_request_bytes: Some(1024),
+        ..policy
+    });
+    let resp = client.fetch(upload(1024 * 1024)).await.unwrap();
+    let text = resp.text();
+    let (encoding, len) = text.split_once(' ').unwrap();
+    assert_eq!(encoding, "gzip");
+    assert!(len.parse::<usize>().unwrap() < 64 * 1024, "got: {text}");
+
+    let resp = client.fetch(upload(100)).await.unwrap();
+    assert_eq!(resp.text(), " 100");
+
+    // An incompressible body within the limit grows past it once gzipped.
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let noise: Vec<u8> = (0..64 * 1024)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    let err = client
+        .fetch(FetchRequest {
+            body: Some(noise.into()),
+            ..upload(0)
+        })
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, FetchError::RequestBodyTooLarge { size, limit } if size > limit),
+        "got: {err}"
+    );
+}
+
+#[tokio::test]
+async fn buffered_bodies_share_a_memory_budget() {
+    let addr = spawn_server(|req| {
+        let size = if req.starts_with("GET /large") {
+            2000
+        } else {
+            600
+        };
+        if req.starts_with("GET /unsized") {
+            // No Content-Length: the body runs until the connection closes.
+            let mut out = b"HTTP/1.1 200 OK\r\nconnection: close\r\n\r\n".to_vec();
+            out.extend_from_slice(&[b'x'; 2000]);
+            return out;
+        }
+        http_response("200 OK", &[], &vec![b'x'; size])
+    })
+    .await;
+    let client = SafeClient::new(FetchPolicy {
+        max_buffered_bytes: Some(1000),
+        ..local_policy()
+    });
+    let get = |path: &str| {
+        client.fetch(FetchRequest {
+            url: format!("http://{addr}{path}"),
+            ..Default::default()
+        })
+    };
+
+    // Two bodies that don't fit at once take turns.
+    let (a, b) = tokio::join!(get("/a"), get("/b"));
+    assert_eq!(a.unwrap().body.len(), 600);
+    assert_eq!(b.unwrap().body.len(), 600);
+
+    for path in ["/large", "/unsized"] {
+        assert!(
+            matches!(
+                get(path).await,
+                Err(FetchError::BufferBudgetExceeded { limit: 1000, .. })
+            ),
+            "{path}"
+        );
+    }
+}